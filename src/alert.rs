@@ -0,0 +1,264 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::SyncStatus;
+use crate::notify::{agent_name, host_name, RepoRef};
+use crate::schema::{self, Documented, Field};
+use log::{error, info};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::json;
+
+// An incident service to page when syncing keeps failing
+#[derive(Deserialize)]
+pub struct AlertConfig {
+    pub kind: AlertKind,
+    // PagerDuty Events v2 integration key
+    pub routing_key: Option<String>,
+    // Opsgenie API (GenieKey) integration key
+    pub api_key: Option<String>,
+    // Overrides the service's API base URL (e.g. https://api.eu.opsgenie.com)
+    pub api_url: Option<String>,
+    // Consecutive failed cycles before an alert is opened
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertKind {
+    PagerDuty,
+    Opsgenie,
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+impl Documented for AlertConfig {
+    const SECTION: &'static str = "[[alerts]]";
+    const ABOUT: &'static str =
+        "Incident services paged after repeated failures, resolved once syncing recovers.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "kind",
+            "\"pagerduty\" or \"opsgenie\"",
+            r#""pagerduty""#,
+            "Incident service",
+        ),
+        schema::optional(
+            "routing_key",
+            "string",
+            r#""<events v2 integration key>""#,
+            "PagerDuty only: Events v2 integration key",
+        ),
+        schema::optional(
+            "api_key",
+            "string",
+            r#""<opsgenie api key>""#,
+            "Opsgenie only: API key",
+        ),
+        schema::optional(
+            "api_url",
+            "string",
+            r#""https://api.eu.opsgenie.com""#,
+            "API base URL override (e.g. Opsgenie EU)",
+        ),
+        schema::defaulted(
+            "failure_threshold",
+            "integer",
+            "3",
+            "Consecutive failed checks or syncs before the alert opens",
+        ),
+    ];
+}
+
+// The configured alerts, the failure streak and which alerts are currently open
+pub struct Alerts {
+    alerts: Vec<AlertConfig>,
+    consecutive_failures: u32,
+    open: Vec<bool>,
+    client: Client,
+}
+
+impl Alerts {
+    pub fn new(alerts: Vec<AlertConfig>, client: Client) -> Self {
+        let open = vec![false; alerts.len()];
+        Alerts {
+            alerts,
+            consecutive_failures: 0,
+            open,
+            client,
+        }
+    }
+
+    // Counts a failed cycle and opens every alert whose threshold has been reached
+    async fn failure(&mut self, repo: &RepoRef<'_>, reason: &str) {
+        self.consecutive_failures += 1;
+
+        for (alert, open) in self.alerts.iter().zip(self.open.iter_mut()) {
+            if *open || self.consecutive_failures < alert.failure_threshold {
+                continue;
+            }
+
+            let summary = format!(
+                "{} ({}) on {} failed to sync {} times in a row: {}",
+                repo.repository,
+                repo.branch,
+                agent_name(repo),
+                self.consecutive_failures,
+                reason
+            );
+            match send(&self.client, alert, repo, Action::Trigger(&summary)).await {
+                Ok(()) => {
+                    info!("Opened {:?} alert for repeated sync failures.", alert.kind);
+                    *open = true;
+                }
+                Err(e) => error!("Failed to open {:?} alert: {}", alert.kind, e),
+            }
+        }
+    }
+
+    // Resets the failure streak and resolves any open alerts
+    async fn success(&mut self, repo: &RepoRef<'_>) {
+        self.consecutive_failures = 0;
+
+        for (alert, open) in self.alerts.iter().zip(self.open.iter_mut()) {
+            if !*open {
+                continue;
+            }
+
+            match send(&self.client, alert, repo, Action::Resolve).await {
+                Ok(()) => {
+                    info!("Resolved {:?} alert, syncing recovered.", alert.kind);
+                    *open = false;
+                }
+                Err(e) => error!("Failed to resolve {:?} alert: {}", alert.kind, e),
+            }
+        }
+    }
+}
+
+impl Subscriber for Alerts {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            match event {
+                SyncEvent::UpToDate { .. } => self.success(repo).await,
+                SyncEvent::CheckFailed { error } => self.failure(repo, error).await,
+                // A plugin stopping a sync on purpose is not something to page about
+                SyncEvent::SyncFinished { record } if record.status == SyncStatus::Aborted => {}
+                SyncEvent::SyncFinished { record } => match &record.error {
+                    None => self.success(repo).await,
+                    Some(error) => self.failure(repo, error).await,
+                },
+                _ => {}
+            }
+            Directive::default()
+        })
+    }
+}
+
+enum Action<'a> {
+    Trigger(&'a str),
+    Resolve,
+}
+
+// One alert per repo and host, so the services group repeats and match the resolve
+fn dedup_key(repo: &RepoRef<'_>) -> String {
+    format!(
+        "devops-sync:{}:{}:{}:{}",
+        repo.organization,
+        repo.project,
+        repo.repository,
+        host_name()
+    )
+}
+
+async fn send(
+    client: &Client,
+    alert: &AlertConfig,
+    repo: &RepoRef<'_>,
+    action: Action<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dedup_key = dedup_key(repo);
+
+    let request = match alert.kind {
+        AlertKind::PagerDuty => pagerduty_request(client, alert, &dedup_key, action)?,
+        AlertKind::Opsgenie => opsgenie_request(client, alert, &dedup_key, action)?,
+    };
+
+    let response = request.send().await.map_err(|e| e.without_url())?;
+    if !response.status().is_success() {
+        return Err(format!("{:?} returned {}", alert.kind, response.status()).into());
+    }
+
+    Ok(())
+}
+
+fn pagerduty_request(
+    client: &Client,
+    alert: &AlertConfig,
+    dedup_key: &str,
+    action: Action<'_>,
+) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+    let routing_key = alert
+        .routing_key
+        .as_deref()
+        .ok_or("PagerDuty alerts need a routing_key")?;
+    let base = alert
+        .api_url
+        .as_deref()
+        .unwrap_or("https://events.pagerduty.com");
+
+    let body = match action {
+        Action::Trigger(summary) => json!({
+            "routing_key": routing_key,
+            "event_action": "trigger",
+            "dedup_key": dedup_key,
+            "payload": {
+                "summary": summary,
+                "source": host_name(),
+                "severity": "error",
+                "component": "devops-repository-sync",
+            },
+        }),
+        Action::Resolve => json!({
+            "routing_key": routing_key,
+            "event_action": "resolve",
+            "dedup_key": dedup_key,
+        }),
+    };
+
+    Ok(client.post(format!("{}/v2/enqueue", base)).json(&body))
+}
+
+fn opsgenie_request(
+    client: &Client,
+    alert: &AlertConfig,
+    dedup_key: &str,
+    action: Action<'_>,
+) -> Result<RequestBuilder, Box<dyn std::error::Error>> {
+    let api_key = alert
+        .api_key
+        .as_deref()
+        .ok_or("Opsgenie alerts need an api_key")?;
+    let base = alert
+        .api_url
+        .as_deref()
+        .unwrap_or("https://api.opsgenie.com");
+    let authorization = format!("GenieKey {}", api_key);
+
+    let request = match action {
+        Action::Trigger(summary) => client.post(format!("{}/v2/alerts", base)).json(&json!({
+            "message": summary.chars().take(130).collect::<String>(),
+            "alias": dedup_key,
+            "description": summary,
+            "source": host_name(),
+            "priority": "P2",
+        })),
+        Action::Resolve => client
+            .post(format!("{}/v2/alerts/{}/close", base, dedup_key))
+            .query(&[("identifierType", "alias")])
+            .json(&json!({ "source": host_name() })),
+    };
+
+    Ok(request.header("Authorization", authorization))
+}
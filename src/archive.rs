@@ -0,0 +1,194 @@
+// An archive of every synced commit, written to a directory that keeps the last few, for
+// consumers that ingest archives rather than a checkout
+use crate::git;
+use crate::post_sync::run_command;
+use crate::schema::{self, Documented, Field};
+use crate::unpack;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Characters of the commit id in an archive's name
+const SHORT_COMMIT: usize = 8;
+
+// The [post_sync.archive] table
+#[derive(Deserialize)]
+pub struct ArchiveConfig {
+    // Directory the archives are written to, outside the checkout
+    pub directory: String,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    // Archives kept, the newest included
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    // Start of every archive's name, followed by the short commit id; defaults to the name of
+    // the checkout directory
+    pub name: Option<String>,
+    // Directory of the repo to archive, defaults to all of it
+    #[serde(default)]
+    pub source: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+fn default_keep() -> usize {
+    10
+}
+
+impl Documented for ArchiveConfig {
+    const SECTION: &'static str = "[post_sync.archive]";
+    const ABOUT: &'static str =
+        "An archive of every synced commit, in a directory keeping the last few.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "directory",
+            "string",
+            r#""/srv/archives/website""#,
+            "Directory the archives are written to, outside repo_path",
+        ),
+        schema::defaulted(
+            "format",
+            "\"tar.gz\" or \"zip\"",
+            r#""tar.gz""#,
+            "Archive format",
+        ),
+        schema::defaulted(
+            "keep",
+            "integer",
+            "10",
+            "Archives kept, the newest included; older ones are removed",
+        ),
+        schema::defaulted(
+            "name",
+            "string",
+            "the checkout's directory name",
+            "Start of the archive names, as in <name>-<short commit>.tar.gz",
+        ),
+        schema::defaulted(
+            "source",
+            "string",
+            r#""""#,
+            "Directory of the repo to archive, e.g. \"public\" (empty means the whole repo)",
+        ),
+    ];
+}
+
+impl ArchiveConfig {
+    pub fn check(&self, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.keep == 0 {
+            return Err("[post_sync.archive] keep must be at least 1".into());
+        }
+        let absolute = |path: &str| {
+            std::path::absolute(path).map_err(|e| format!("Invalid path '{}': {}", path, e))
+        };
+        if absolute(&self.directory)?.starts_with(absolute(repo_path)?) {
+            return Err(format!(
+                "[post_sync.archive] directory '{}' is inside repo_path '{}'",
+                self.directory, repo_path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn name(&self, repo_path: &str) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            Path::new(repo_path)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "repo".to_string())
+        })
+    }
+
+    // The archives written under this name, oldest first
+    fn existing(&self, name: &str) -> Vec<PathBuf> {
+        let prefix = format!("{}-", name);
+        let suffix = format!(".{}", self.format.extension());
+        let mut archives: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(&self.directory)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                file_name.starts_with(&prefix) && file_name.ends_with(&suffix)
+            })
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect();
+        archives.sort();
+        archives.into_iter().map(|(_, path)| path).collect()
+    }
+}
+
+// Writes the archive of the commit and removes the oldest beyond `keep`. The archive is written
+// beside its final name and renamed once complete, so consumers never pick up a partial one.
+pub async fn create(
+    archive: &ArchiveConfig,
+    repo_path: &str,
+    commit: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    fs::create_dir_all(&archive.directory)?;
+    let name = archive.name(repo_path);
+    let short: String = commit.chars().take(SHORT_COMMIT).collect();
+    let path = Path::new(&archive.directory).join(format!(
+        "{}-{}.{}",
+        name,
+        short,
+        archive.format.extension()
+    ));
+    let partial = unpack::sibling(&path, "partial");
+
+    // commit:dir archives the directory's contents, as if it were the root
+    let tree = match archive.source.trim_matches('/') {
+        "" => commit.to_string(),
+        source => format!("{}:{}", commit, source),
+    };
+    let partial_arg = partial.to_string_lossy().to_string();
+    let result = run_command(
+        git::program(),
+        &[
+            "-C",
+            repo_path,
+            "archive",
+            "--format",
+            archive.format.extension(),
+            "-o",
+            &partial_arg,
+            &tree,
+        ],
+        None,
+    )
+    .await;
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, &path)?;
+    info!("Archived {} as {}.", commit, path.display());
+
+    let existing = archive.existing(&name);
+    let excess = existing.len().saturating_sub(archive.keep);
+    for old in existing.iter().filter(|old| **old != path).take(excess) {
+        match fs::remove_file(old) {
+            Ok(()) => info!("Removed old archive {}.", old.display()),
+            Err(e) => warn!("Couldn't remove old archive {}: {}", old.display(), e),
+        }
+    }
+    Ok(path)
+}
@@ -0,0 +1,430 @@
+// Tamper-evident audit log of the syncs: every record is chained to the entry before it by a
+// SHA-256 hash and, with audit_key_file, signed with HMAC-SHA256, so `audit verify` finds
+// entries that were edited, removed, inserted or reordered.
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::SyncRecord;
+use crate::notify::RepoRef;
+use crate::secrets;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// What the first entry chains to
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// One line of the audit log
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    seq: u64,
+    repository: String,
+    // The sync record as written to the history file
+    record: Value,
+    // Hash of the entry before, GENESIS for the first
+    prev: String,
+    hash: String,
+    // HMAC of the hash with the audit key, when one is configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+// Hash over everything in the entry but the hash and signature. JSON objects serialize with
+// sorted keys, so a record read back from the file hashes the same as when it was written.
+fn entry_hash(seq: u64, repository: &str, prev: &str, record: &Value) -> String {
+    hex(&Sha256::digest(
+        format!("{}\n{}\n{}\n{}", seq, repository, prev, record).as_bytes(),
+    ))
+}
+
+fn signature(key: &[u8], hash: &str) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(hash.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+// Lowercase hex, as hashes and signatures are written to the file
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{:02x}", byte);
+        out
+    })
+}
+
+// Reads the signing key, which may be encrypted like other secrets
+pub fn read_key(
+    path: &str,
+    secrets_identity: Option<&str>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let raw = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read audit key '{}': {}", path, e))?;
+    let key = secrets::resolve_secret(raw.trim(), secrets_identity)?;
+    if key.is_empty() {
+        return Err(format!("Audit key '{}' is empty", path).into());
+    }
+    Ok(key.into_bytes())
+}
+
+// Where the chain of an audit log breaks
+#[derive(Debug)]
+pub struct Tampered {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for Tampered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for Tampered {}
+
+// What a successful verification covered
+pub struct Verified {
+    pub entries: u64,
+    pub signed: u64,
+    // Hash of the last entry, to compare with a copy kept elsewhere
+    pub head: String,
+}
+
+// Walks the whole log, checking every entry's hash, its link to the one before and, with a
+// key, its signature. A log cut short at the end still verifies; compare `head` with a copy
+// kept elsewhere to catch that.
+pub fn verify(text: &str, key: Option<&[u8]>) -> Result<Verified, Tampered> {
+    let mut verified = Verified {
+        entries: 0,
+        signed: 0,
+        head: GENESIS.to_string(),
+    };
+
+    for (index, line) in text.lines().enumerate() {
+        let tampered = |reason: String| Tampered {
+            line: index + 1,
+            reason,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: Entry = serde_json::from_str(line)
+            .map_err(|e| tampered(format!("not an audit entry ({})", e)))?;
+        if entry.seq != verified.entries + 1 {
+            return Err(tampered(format!(
+                "sequence number {} where {} was expected, entries were removed or reordered",
+                entry.seq,
+                verified.entries + 1
+            )));
+        }
+        if entry.prev != verified.head {
+            return Err(tampered(
+                "doesn't chain to the entry before it, entries were removed or replaced"
+                    .to_string(),
+            ));
+        }
+        if entry.hash != entry_hash(entry.seq, &entry.repository, &entry.prev, &entry.record) {
+            return Err(tampered("the entry was modified".to_string()));
+        }
+        match (key, &entry.signature) {
+            (Some(key), Some(signed)) if *signed != signature(key, &entry.hash) => {
+                return Err(tampered(
+                    "the signature doesn't match, the entry was rewritten without the key"
+                        .to_string(),
+                ))
+            }
+            (Some(_), None) => return Err(tampered("the entry isn't signed".to_string())),
+            _ => {}
+        }
+
+        verified.entries = entry.seq;
+        verified.signed += u64::from(entry.signature.is_some());
+        verified.head = entry.hash;
+    }
+    Ok(verified)
+}
+
+// The end of one audit file's chain, shared by every repo appending to it
+struct Chain {
+    key: Option<Vec<u8>>,
+    // Sequence number and hash of the last entry written
+    last: (u64, String),
+}
+
+// The chains of the audit files opened so far, by canonical path. Repos that share an
+// audit_file, e.g. one set at the top level, append to one chain instead of forking it.
+static CHAINS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<Chain>>>>> = OnceLock::new();
+
+// Appends every finished sync to the audit log, continuing the chain already in the file
+pub struct AuditLog {
+    path: String,
+    chain: Arc<Mutex<Chain>>,
+}
+
+impl AuditLog {
+    // Refuses a log that fails verification, unless allow_broken lets the agent append after its
+    // last entry anyway. A file another repo already opened is shared with it.
+    pub fn open(
+        path: String,
+        key: Option<Vec<u8>>,
+        allow_broken: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut chains = CHAINS
+            .get_or_init(Default::default)
+            .lock()
+            .map_err(|_| "the audit log lock is poisoned")?;
+        let canonical = canonical_path(&path);
+        if let Some(chain) = chains.get(&canonical) {
+            if chain
+                .lock()
+                .map_err(|_| "the audit log lock is poisoned")?
+                .key
+                != key
+            {
+                return Err(format!(
+                    "Audit log '{}' is shared by repos with different audit_key_file keys",
+                    path
+                )
+                .into());
+            }
+            return Ok(AuditLog {
+                path,
+                chain: chain.clone(),
+            });
+        }
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read audit log '{}': {}", path, e).into()),
+        };
+
+        // A broken chain is never repaired: when allowed, new entries continue from the last one
+        // so the damage stays visible to `audit verify`
+        let last = match verify(&text, key.as_deref()) {
+            Ok(verified) => (verified.entries, verified.head),
+            Err(e) if !allow_broken => {
+                return Err(format!(
+                    "Audit log '{}' failed verification ({}). Check it with `audit verify`, then move it aside to start a new chain, or set audit_allow_broken_chain = true to keep appending to it.",
+                    path, e
+                )
+                .into())
+            }
+            Err(e) => {
+                warn!(
+                    "Audit log '{}' failed verification ({}), appending after its last entry.",
+                    path, e
+                );
+                text.lines()
+                    .rev()
+                    .find_map(|line| serde_json::from_str::<Entry>(line).ok())
+                    .map(|entry| (entry.seq, entry.hash))
+                    .unwrap_or((0, GENESIS.to_string()))
+            }
+        };
+        let chain = Arc::new(Mutex::new(Chain { key, last }));
+        chains.insert(canonical, chain.clone());
+        Ok(AuditLog { path, chain })
+    }
+
+    fn append(
+        &mut self,
+        repository: &str,
+        record: &SyncRecord,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let record = serde_json::to_value(record)?;
+        // Held until the line is written, so entries of other repos can't slip in between
+        let mut chain = self
+            .chain
+            .lock()
+            .map_err(|_| "the audit log lock is poisoned")?;
+        let seq = chain.last.0 + 1;
+        let hash = entry_hash(seq, repository, &chain.last.1, &record);
+        let entry = Entry {
+            seq,
+            repository: repository.to_string(),
+            record,
+            prev: chain.last.1.clone(),
+            signature: chain.key.as_deref().map(|key| signature(key, &hash)),
+            hash,
+        };
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        file.sync_data()?;
+        chain.last = (seq, entry.hash);
+        Ok(())
+    }
+}
+
+// The path with links and relative parts resolved, also for a file that doesn't exist yet
+fn canonical_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Subscriber for AuditLog {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            if let SyncEvent::SyncFinished { record } = event {
+                if let Err(e) = self.append(repo.repository, record) {
+                    error!("Failed to write audit log: {}", e);
+                }
+            }
+            Directive::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_sync::ScratchFile;
+    use serde_json::json;
+
+    // A valid chain of one entry per record, one JSON line each
+    fn chain(records: &[Value], key: Option<&[u8]>) -> Vec<String> {
+        let mut prev = GENESIS.to_string();
+        let mut lines = Vec::new();
+        for (index, record) in records.iter().enumerate() {
+            let seq = index as u64 + 1;
+            let hash = entry_hash(seq, "website", &prev, record);
+            let entry = Entry {
+                seq,
+                repository: "website".to_string(),
+                record: record.clone(),
+                prev: prev.clone(),
+                signature: key.map(|key| signature(key, &hash)),
+                hash: hash.clone(),
+            };
+            lines.push(serde_json::to_string(&entry).unwrap());
+            prev = hash;
+        }
+        lines
+    }
+
+    fn records() -> Vec<Value> {
+        (1..=4)
+            .map(|n| json!({ "new_commit": format!("c{}", n), "status": "success" }))
+            .collect()
+    }
+
+    fn failure(lines: &[String], key: Option<&[u8]>) -> Tampered {
+        match verify(&lines.join("\n"), key) {
+            Ok(_) => panic!("the tampered log verified"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn an_untouched_chain_verifies() {
+        let lines = chain(&records(), Some(b"key"));
+        let verified = verify(&lines.join("\n"), Some(b"key")).unwrap();
+        assert_eq!((verified.entries, verified.signed), (4, 4));
+        assert!(lines[3].contains(&verified.head));
+        assert_eq!(verify("", None).unwrap().head, GENESIS);
+    }
+
+    #[test]
+    fn edited_entries_are_caught() {
+        let mut lines = chain(&records(), None);
+        lines[1] = lines[1].replace("success", "failure");
+        let e = failure(&lines, None);
+        assert_eq!(e.line, 2);
+        assert!(e.reason.contains("modified"), "{}", e);
+    }
+
+    #[test]
+    fn reordered_and_removed_entries_are_caught() {
+        let mut lines = chain(&records(), None);
+        lines.swap(1, 2);
+        assert_eq!(failure(&lines, None).line, 2);
+
+        let mut lines = chain(&records(), None);
+        lines.remove(1);
+        let e = failure(&lines, None);
+        assert_eq!(e.line, 2);
+        assert!(e.reason.contains("sequence number 3"), "{}", e);
+
+        // Renumbering after a removal still leaves the next entry pointing at the removed one
+        let mut records = records();
+        records.remove(1);
+        let mut lines = chain(&records, None);
+        let original = chain(&self::records(), None);
+        lines[2] = original[3].replace("\"seq\":4", "\"seq\":3");
+        assert!(failure(&lines, None).reason.contains("doesn't chain"));
+    }
+
+    #[test]
+    fn a_chain_rewritten_without_the_key_is_caught() {
+        let mut records = records();
+        records[2] = json!({ "new_commit": "evil", "status": "success" });
+        let forged = chain(&records, Some(b"guessed"));
+        let e = failure(&forged, Some(b"key"));
+        assert_eq!(e.line, 1);
+        assert!(e.reason.contains("signature"), "{}", e);
+
+        let unsigned = chain(&records, None);
+        assert!(failure(&unsigned, Some(b"key"))
+            .reason
+            .contains("isn't signed"));
+    }
+
+    #[test]
+    fn a_broken_log_is_only_opened_when_allowed() {
+        let mut lines = chain(&records(), None);
+        lines.remove(1);
+        let file = ScratchFile::create("audit", "test", lines.join("\n").as_bytes()).unwrap();
+        let path = file.path().to_string_lossy().to_string();
+
+        let refused = AuditLog::open(path.clone(), None, false).err().unwrap();
+        assert!(refused.to_string().contains("line 2"), "{}", refused);
+
+        let log = AuditLog::open(path, None, true).unwrap();
+        assert_eq!(log.chain.lock().unwrap().last.0, 4);
+    }
+
+    #[test]
+    fn repos_sharing_a_file_continue_one_chain() {
+        let file = ScratchFile::create("audit", "shared", b"").unwrap();
+        let path = file.path().to_string_lossy().to_string();
+        let mut first = AuditLog::open(path.clone(), Some(b"key".to_vec()), false).unwrap();
+        let mut second = AuditLog::open(path.clone(), Some(b"key".to_vec()), false).unwrap();
+        assert!(AuditLog::open(path.clone(), None, false).is_err());
+
+        let record: SyncRecord = serde_json::from_value(json!({
+            "timestamp": "2026-10-16T08:00:00Z",
+            "old_commit": "a",
+            "new_commit": "b",
+            "status": "success",
+            "error": null,
+        }))
+        .unwrap();
+        first.append("website", &record).unwrap();
+        second.append("api", &record).unwrap();
+        first.append("website", &record).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let verified = verify(&text, Some(b"key")).unwrap();
+        assert_eq!((verified.entries, verified.signed), (3, 3));
+    }
+}
@@ -0,0 +1,959 @@
+use crate::history::{CommitSummary, PullRequestRef, WorkItemRef};
+use crate::network::Resolution;
+use crate::notify::RepoRef;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// Azure DevOps Services. server_url points the agent at an Azure DevOps Server instead.
+pub const DEFAULT_SERVER_URL: &str = "https://dev.azure.com";
+
+// Where Azure DevOps is reached: the top-level server_url, or the stand-in server of `simulate`
+static BASE_URL: OnceLock<String> = OnceLock::new();
+
+pub fn base_url() -> &'static str {
+    BASE_URL
+        .get()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_SERVER_URL)
+}
+
+// Only the first URL set counts, so `simulate` keeps its stand-in server
+pub fn set_base_url(url: String) {
+    let _ = BASE_URL.set(url.trim_end_matches('/').to_string());
+}
+
+// Azure DevOps Services answers feeds, packages and releases on subdomains of their own, e.g.
+// "vsrm"; a server answers them all at its own URL
+pub fn service_url(service: &str) -> String {
+    if base_url() == DEFAULT_SERVER_URL {
+        format!("https://{}.dev.azure.com", service)
+    } else {
+        base_url().to_string()
+    }
+}
+
+// Host and port of the server, for the reachability probe and git's DNS override
+pub fn server_host() -> (String, u16) {
+    reqwest::Url::parse(base_url())
+        .ok()
+        .and_then(|url| Some((url.host_str()?.to_string(), url.port_or_known_default()?)))
+        .unwrap_or_else(|| ("dev.azure.com".to_string(), 443))
+}
+
+// Checks a configured server_url, which needs a scheme and a host
+pub fn check_server_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.host().is_some() => {
+            Ok(())
+        }
+        _ => Err(format!(
+            "server_url '{}' must be an http(s) URL, e.g. \"https://devops.contoso.com/tfs\"",
+            url
+        )),
+    }
+}
+
+// Percent-encodes a name for one part of a URL, so organizations, projects and repositories
+// with spaces or other reserved characters reach Azure DevOps as they are named
+pub fn segment(value: &str) -> String {
+    utf8_percent_encode(value, SEGMENT).to_string()
+}
+
+// Everything but the unreserved characters of RFC 3986
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+// URL git fetches the repository from, with the PAT as the password
+pub fn git_url(organization: &str, project: &str, repository: &str, pat: &str) -> String {
+    let (scheme, host) = base_url()
+        .split_once("://")
+        .unwrap_or(("https", base_url()));
+    format!(
+        "{}://{}:{}@{}/{}/{}/_git/{}",
+        scheme,
+        segment(organization),
+        segment(pat),
+        host,
+        segment(organization),
+        segment(project),
+        segment(repository)
+    )
+}
+
+// REST API version used unless the config asks for another, e.g. for an older on-prem server
+pub const DEFAULT_API_VERSION: &str = "7.0";
+
+// User-Agent used unless the config sets one
+pub const DEFAULT_USER_AGENT: &str = concat!("DevOps_Repository_Sync/", env!("CARGO_PKG_VERSION"));
+
+// The work items API accepts at most this many ids per request
+const WORK_ITEMS_PAGE: usize = 200;
+
+// Commits looked up per commitsbatch request, the API's page size
+const COMMITS_PAGE: usize = 100;
+
+#[derive(Deserialize)]
+struct CommitBatch {
+    value: Vec<LinkedCommit>,
+}
+
+#[derive(Deserialize)]
+struct LinkedCommit {
+    #[serde(rename = "commitId")]
+    commit_id: String,
+    #[serde(default, rename = "workItems")]
+    work_items: Vec<ResourceRef>,
+}
+
+#[derive(Deserialize)]
+struct ResourceRef {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct WorkItemList {
+    value: Vec<Option<WorkItem>>,
+}
+
+#[derive(Deserialize)]
+struct WorkItem {
+    id: u64,
+    #[serde(default)]
+    fields: HashMap<String, serde_json::Value>,
+}
+
+// Completed pull requests looked at per request; the latest ones are all that matter
+const PULL_REQUESTS_PAGE: &str = "50";
+
+#[derive(Deserialize)]
+struct PullRequestList {
+    value: Vec<PullRequest>,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    #[serde(rename = "pullRequestId")]
+    pull_request_id: u64,
+    #[serde(default)]
+    title: String,
+    #[serde(rename = "createdBy")]
+    created_by: Option<Identity>,
+    #[serde(default)]
+    reviewers: Vec<Reviewer>,
+    #[serde(rename = "lastMergeCommit")]
+    last_merge_commit: Option<MergeCommit>,
+    #[serde(rename = "closedDate", default)]
+    closed_date: String,
+}
+
+#[derive(Deserialize)]
+struct Identity {
+    #[serde(rename = "displayName")]
+    display_name: String,
+}
+
+#[derive(Deserialize)]
+struct Reviewer {
+    #[serde(rename = "displayName")]
+    display_name: String,
+    #[serde(rename = "isContainer", default)]
+    is_container: bool,
+}
+
+#[derive(Deserialize)]
+struct MergeCommit {
+    #[serde(rename = "commitId")]
+    commit_id: String,
+}
+
+// Returned when the target branch has no commit to sync to
+#[derive(Debug)]
+pub enum BranchError {
+    // The branch doesn't exist (yet) on the remote
+    NotFound(String),
+    // The repository was just created and has no commits
+    Empty(String),
+}
+
+impl fmt::Display for BranchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchError::NotFound(branch) => {
+                write!(f, "branch '{}' not found on the remote", branch)
+            }
+            BranchError::Empty(branch) => write!(
+                f,
+                "branch '{}' has no commits, the repository may be empty",
+                branch
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BranchError {}
+
+// The API answered 404 for the repository: it was deleted, renamed or moved to another project,
+// or the config names it wrong
+#[derive(Debug)]
+pub struct RepositoryNotFound(pub String);
+
+impl fmt::Display for RepositoryNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "remote API returned 404 Not Found: {} (run `list-repos` to check the project and repository names)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for RepositoryNotFound {}
+
+// How long an idle pooled connection is kept, long enough to outlast typical check intervals
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long opening a connection may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Default for request_timeout_seconds
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+
+// Total time allowed for an artifact or package download. A stalled download still fails
+// after the request timeout passes without data.
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+// The one HTTP client shared by every request the agent makes, identifying itself with the
+// user agent. Connections (and their TLS sessions) stay pooled between checks, and HTTP/2 is
+// negotiated through ALPN wherever the server offers it, so a quiet cycle doesn't pay for a
+// new handshake.
+// Names resolve through `resolution` when ip_version or dns_servers are set, and `identity` is
+// presented to servers that ask for a client certificate. Every request must finish within
+// request_timeout, and so a peer that stalls can't hold up a repo's loop.
+pub fn client(
+    user_agent: &str,
+    resolution: &Resolution,
+    identity: Option<reqwest::Identity>,
+    request_timeout: Duration,
+) -> Result<Client, reqwest::Error> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(KEEP_ALIVE_INTERVAL)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(request_timeout)
+        .read_timeout(request_timeout);
+    if resolution.is_custom() {
+        builder = builder.dns_resolver(Arc::new(resolution.clone()));
+    }
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    builder.build()
+}
+
+// A repository as Azure DevOps knows it
+#[derive(Deserialize)]
+pub struct Repository {
+    // GUID, which stays the same when the repository is renamed
+    pub id: String,
+    pub name: String,
+    pub project: NamedResource,
+    #[serde(rename = "defaultBranch")]
+    default_branch: Option<String>,
+}
+
+// Looks up a repository by its name or its ID
+pub async fn repository(
+    client: &Client,
+    organization: &str,
+    project: &str,
+    repository: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<Repository, Box<dyn std::error::Error>> {
+    let request = client
+        .get(format!(
+            "{}/{}/{}/_apis/git/repositories/{}",
+            base_url(),
+            segment(organization),
+            segment(project),
+            segment(repository)
+        ))
+        .query(&[("api-version", api_version)]);
+    let (repo, _) = get_json(request, pat).await?;
+    Ok(repo)
+}
+
+// Looks up a repository by its ID in whichever project of the organization it is in now
+pub async fn repository_by_id(
+    client: &Client,
+    organization: &str,
+    id: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<Repository, Box<dyn std::error::Error>> {
+    let request = client
+        .get(format!(
+            "{}/{}/_apis/git/repositories/{}",
+            base_url(),
+            segment(organization),
+            segment(id)
+        ))
+        .query(&[("api-version", api_version)]);
+    let (repo, _) = get_json(request, pat).await?;
+    Ok(repo)
+}
+
+// Looks up the repository's current default branch, without the refs/heads/ prefix
+pub async fn default_branch(
+    client: &Client,
+    organization: &str,
+    project: &str,
+    repository: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let repo =
+        self::repository(client, organization, project, repository, pat, api_version).await?;
+
+    let branch = repo
+        .default_branch
+        .ok_or("the repository has no default branch")?;
+    Ok(branch
+        .strip_prefix("refs/heads/")
+        .unwrap_or(&branch)
+        .to_string())
+}
+
+#[derive(Deserialize)]
+struct List<T> {
+    value: Vec<T>,
+}
+
+#[derive(Deserialize)]
+pub struct NamedResource {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct RepositoryListing {
+    id: String,
+    name: String,
+    #[serde(rename = "defaultBranch")]
+    default_branch: Option<String>,
+}
+
+// A repository as listed by `list-repos`
+pub struct RepositoryInfo {
+    pub id: String,
+    pub name: String,
+    pub default_branch: Option<String>,
+}
+
+// Sends an authenticated GET and fails with the status and Azure DevOps' message when it isn't a success
+async fn get_json<T: for<'de> Deserialize<'de>>(
+    request: reqwest::RequestBuilder,
+    pat: &str,
+) -> Result<(T, Option<String>), Box<dyn std::error::Error>> {
+    let response = request.basic_auth("", Some(pat)).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|value| value["message"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        return Err(format!("{}: {}", status, message).into());
+    }
+    // Listings longer than a page are continued with this token
+    let continuation = response
+        .headers()
+        .get("x-ms-continuationtoken")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    Ok((response.json().await?, continuation))
+}
+
+// Names of every project in the organization the PAT can see
+pub async fn list_projects(
+    client: &Client,
+    organization: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut projects = Vec::new();
+    let mut continuation: Option<String> = None;
+    loop {
+        let mut request = client
+            .get(format!(
+                "{}/{}/_apis/projects",
+                base_url(),
+                segment(organization)
+            ))
+            .query(&[("api-version", api_version)]);
+        if let Some(token) = &continuation {
+            request = request.query(&[("continuationToken", token)]);
+        }
+        let (page, next): (List<NamedResource>, _) = get_json(request, pat).await?;
+        projects.extend(page.value.into_iter().map(|project| project.name));
+        match next {
+            Some(token) if !token.is_empty() => continuation = Some(token),
+            _ => break,
+        }
+    }
+    projects.sort_by_key(|name| name.to_lowercase());
+    Ok(projects)
+}
+
+// Git repositories of a project, with their default branches
+pub async fn list_repositories(
+    client: &Client,
+    organization: &str,
+    project: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<Vec<RepositoryInfo>, Box<dyn std::error::Error>> {
+    let request = client
+        .get(format!(
+            "{}/{}/{}/_apis/git/repositories",
+            base_url(),
+            segment(organization),
+            segment(project)
+        ))
+        .query(&[("api-version", api_version)]);
+    let (list, _): (List<RepositoryListing>, _) = get_json(request, pat).await?;
+    let mut repositories: Vec<RepositoryInfo> = list
+        .value
+        .into_iter()
+        .map(|repo| RepositoryInfo {
+            id: repo.id,
+            name: repo.name,
+            default_branch: repo
+                .default_branch
+                .map(|branch| branch.trim_start_matches("refs/heads/").to_string()),
+        })
+        .collect();
+    repositories.sort_by_key(|repo| repo.name.to_lowercase());
+    Ok(repositories)
+}
+
+// The refs endpoint, listing the refs whose names start with refs/<filter>, e.g. "heads/main".
+// The filter is a query value, so slashes and other characters in branch names are encoded.
+pub fn refs_url(
+    organization: &str,
+    project: &str,
+    repository: &str,
+    filter: &str,
+    api_version: &str,
+) -> Result<reqwest::Url, Box<dyn std::error::Error>> {
+    Ok(reqwest::Url::parse_with_params(
+        &format!(
+            "{}/{}/{}/_apis/git/repositories/{}/refs",
+            base_url(),
+            segment(organization),
+            segment(project),
+            segment(repository)
+        ),
+        &[("filter", filter), ("api-version", api_version)],
+    )?)
+}
+
+// Branch names of a repository, without the refs/heads/ prefix
+pub async fn list_branches(
+    client: &Client,
+    organization: &str,
+    project: &str,
+    repository: &str,
+    pat: &str,
+    api_version: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut branches = Vec::new();
+    let mut continuation: Option<String> = None;
+    loop {
+        let mut request = client.get(refs_url(
+            organization,
+            project,
+            repository,
+            "heads/",
+            api_version,
+        )?);
+        if let Some(token) = &continuation {
+            request = request.query(&[("continuationToken", token)]);
+        }
+        let (page, next): (List<NamedResource>, _) = get_json(request, pat).await?;
+        branches.extend(
+            page.value
+                .into_iter()
+                .map(|reference| reference.name.trim_start_matches("refs/heads/").to_string()),
+        );
+        match next {
+            Some(token) if !token.is_empty() => continuation = Some(token),
+            _ => break,
+        }
+    }
+    Ok(branches)
+}
+
+#[derive(Deserialize)]
+struct Item {
+    #[serde(default)]
+    content: String,
+}
+
+// Text of a file as of a commit, or None when the commit doesn't have it
+pub async fn file_content(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    path: &str,
+    commit: &str,
+    api_version: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let response = client
+        .get(format!(
+            "{}/{}/{}/_apis/git/repositories/{}/items",
+            base_url(),
+            segment(repo.organization),
+            segment(repo.project),
+            segment(repo.repository)
+        ))
+        .query(&[
+            ("path", path),
+            ("versionDescriptor.version", commit),
+            ("versionDescriptor.versionType", "commit"),
+            ("includeContent", "true"),
+            ("$format", "json"),
+            ("api-version", api_version),
+        ])
+        .basic_auth("", Some(repo.pat))
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let item: Item = response.error_for_status()?.json().await?;
+    Ok(Some(item.content))
+}
+
+#[derive(Deserialize)]
+struct CommitDiffs {
+    #[serde(rename = "behindCount", default)]
+    behind_count: u64,
+}
+
+// Whether `new` lacks commits that `old` has, meaning the branch was rewritten between them
+// rather than moved forward
+pub async fn is_rewrite(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    old: &str,
+    new: &str,
+    api_version: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let diffs: CommitDiffs = client
+        .get(format!(
+            "{}/{}/{}/_apis/git/repositories/{}/diffs/commits",
+            base_url(),
+            segment(repo.organization),
+            segment(repo.project),
+            segment(repo.repository)
+        ))
+        .query(&[
+            ("baseVersion", old),
+            ("baseVersionType", "commit"),
+            ("targetVersion", new),
+            ("targetVersionType", "commit"),
+            ("$top", "1"),
+            ("api-version", api_version),
+        ])
+        .basic_auth("", Some(repo.pat))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(diffs.behind_count > 0)
+}
+
+// Web link to the repository in Azure DevOps
+pub fn repository_url(organization: &str, project: &str, repository: &str) -> String {
+    format!(
+        "{}/{}/{}/_git/{}",
+        base_url(),
+        segment(organization),
+        segment(project),
+        segment(repository)
+    )
+}
+
+// Web link to a single commit
+pub fn commit_url(organization: &str, project: &str, repository: &str, commit: &str) -> String {
+    format!(
+        "{}/commit/{}",
+        repository_url(organization, project, repository),
+        commit
+    )
+}
+
+// Web link to the diff between two commits
+pub fn compare_url(
+    organization: &str,
+    project: &str,
+    repository: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> String {
+    format!(
+        "{}/branchCompare?baseVersion=GC{}&targetVersion=GC{}",
+        repository_url(organization, project, repository),
+        old_commit,
+        new_commit
+    )
+}
+
+// Downloads the profile picture for an email and returns it as a data URI,
+// since chat clients can't load the authenticated Azure DevOps image URL themselves
+pub async fn avatar_data_uri(
+    client: &Client,
+    organization: &str,
+    email: &str,
+    pat: &str,
+) -> Option<String> {
+    let response = client
+        .get(format!(
+            "{}/{}/_api/_common/identityImage",
+            base_url(),
+            segment(organization)
+        ))
+        .query(&[("email", email), ("size", "2")])
+        .basic_auth("", Some(pat))
+        .send()
+        .await
+        .ok()?;
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("image/"))?
+        .to_string();
+    let bytes = response.bytes().await.ok()?;
+
+    Some(format!(
+        "data:{};base64,{}",
+        content_type,
+        BASE64.encode(bytes)
+    ))
+}
+
+// Web link to a pull request
+pub fn pull_request_url(organization: &str, project: &str, repository: &str, id: u64) -> String {
+    format!(
+        "{}/pullrequest/{}",
+        repository_url(organization, project, repository),
+        id
+    )
+}
+
+// The pull requests completed into the branch, most recently completed first
+pub async fn completed_pull_requests(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    api_version: &str,
+) -> Result<Vec<PullRequestRef>, Box<dyn std::error::Error>> {
+    let target = format!("refs/heads/{}", repo.branch);
+    let response = client
+        .get(format!(
+            "{}/{}/{}/_apis/git/repositories/{}/pullrequests",
+            base_url(),
+            segment(repo.organization),
+            segment(repo.project),
+            segment(repo.repository)
+        ))
+        .query(&[
+            ("searchCriteria.status", "completed"),
+            ("searchCriteria.targetRefName", target.as_str()),
+            ("$top", PULL_REQUESTS_PAGE),
+            ("api-version", api_version),
+        ])
+        .basic_auth("", Some(repo.pat))
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut list: PullRequestList = response.json().await?;
+
+    // The API lists them newest first by creation, which isn't the order they were completed in
+    list.value.sort_by(|a, b| b.closed_date.cmp(&a.closed_date));
+    Ok(list
+        .value
+        .into_iter()
+        .filter_map(|pull_request| {
+            let merge_commit = pull_request.last_merge_commit?.commit_id;
+            Some(PullRequestRef {
+                id: pull_request.pull_request_id,
+                title: pull_request.title,
+                author: pull_request
+                    .created_by
+                    .map(|identity| identity.display_name)
+                    .unwrap_or_default(),
+                reviewers: pull_request
+                    .reviewers
+                    .into_iter()
+                    .filter(|reviewer| !reviewer.is_container)
+                    .map(|reviewer| reviewer.display_name)
+                    .collect(),
+                merge_commit,
+                url: pull_request_url(
+                    repo.organization,
+                    repo.project,
+                    repo.repository,
+                    pull_request.pull_request_id,
+                ),
+            })
+        })
+        .collect())
+}
+
+// Adds a status to a commit, shown next to it in the Azure DevOps UI
+pub async fn post_commit_status(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    commit: &str,
+    status: &serde_json::Value,
+    api_version: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = client
+        .post(format!(
+            "{}/{}/{}/_apis/git/repositories/{}/commits/{}/statuses",
+            base_url(),
+            segment(repo.organization),
+            segment(repo.project),
+            segment(repo.repository),
+            commit
+        ))
+        .query(&[("api-version", api_version)])
+        .json(status);
+    let _: (serde_json::Value, _) = get_json(request, repo.pat).await?;
+    Ok(())
+}
+
+// Web link to a work item
+pub fn work_item_url(organization: &str, project: &str, id: u64) -> String {
+    format!(
+        "{}/{}/{}/_workitems/edit/{}",
+        base_url(),
+        segment(organization),
+        segment(project),
+        id
+    )
+}
+
+// Adds the work items linked to each commit in Azure DevOps to the ones mentioned in its message,
+// then looks up the title, type and state of every referenced work item
+pub async fn link_work_items(
+    client: &Client,
+    organization: &str,
+    project: &str,
+    repository: &str,
+    pat: &str,
+    api_version: &str,
+    commits: &mut [CommitSummary],
+) -> Result<Vec<WorkItemRef>, Box<dyn std::error::Error>> {
+    if commits.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Long ranges are looked up a page at a time rather than in one oversized request
+    let ids: Vec<&str> = commits.iter().map(|commit| commit.id.as_str()).collect();
+    let mut linked_commits = Vec::new();
+    for page in ids.chunks(COMMITS_PAGE) {
+        let response = client
+            .post(format!(
+                "{}/{}/{}/_apis/git/repositories/{}/commitsbatch",
+                base_url(),
+                segment(organization),
+                segment(project),
+                segment(repository)
+            ))
+            .query(&[("api-version", api_version)])
+            .basic_auth("", Some(pat))
+            .json(&json!({ "ids": page, "includeWorkItems": true, "$top": page.len() }))
+            .send()
+            .await?
+            .error_for_status()?;
+        let batch: CommitBatch = response.json().await?;
+        linked_commits.extend(batch.value);
+    }
+
+    for linked in linked_commits {
+        let Some(commit) = commits.iter_mut().find(|c| c.id == linked.commit_id) else {
+            continue;
+        };
+        for work_item in linked.work_items {
+            if let Ok(id) = work_item.id.parse() {
+                if !commit.work_item_ids.contains(&id) {
+                    commit.work_item_ids.push(id);
+                }
+            }
+        }
+    }
+
+    let mut work_item_ids: Vec<u64> = commits
+        .iter()
+        .flat_map(|commit| commit.work_item_ids.iter().copied())
+        .collect();
+    work_item_ids.sort_unstable();
+    work_item_ids.dedup();
+    if work_item_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut work_items = Vec::new();
+    for page in work_item_ids.chunks(WORK_ITEMS_PAGE) {
+        let id_list: Vec<String> = page.iter().map(u64::to_string).collect();
+        let response = client
+            .get(format!(
+                "{}/{}/_apis/wit/workitems",
+                base_url(),
+                segment(organization)
+            ))
+            .query(&[
+                ("ids", id_list.join(",").as_str()),
+                ("fields", "System.Title,System.WorkItemType,System.State"),
+                // Mentions like "#12" may not be work items at all, skip those instead of failing
+                ("errorPolicy", "omit"),
+                ("api-version", api_version),
+            ])
+            .basic_auth("", Some(pat))
+            .send()
+            .await?
+            .error_for_status()?;
+        let list: WorkItemList = response.json().await?;
+        work_items.extend(list.value.into_iter().flatten());
+    }
+
+    Ok(work_items
+        .into_iter()
+        .map(|item| {
+            let field = |name: &str| {
+                item.fields
+                    .get(name)
+                    .and_then(|value| value.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            WorkItemRef {
+                id: item.id,
+                title: field("System.Title"),
+                work_item_type: field("System.WorkItemType"),
+                state: field("System.State"),
+                url: work_item_url(organization, project, item.id),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies;
+    use proptest::prelude::*;
+
+    #[test]
+    fn server_url_needs_a_scheme_and_a_host() {
+        assert!(check_server_url("https://dev.azure.com").is_ok());
+        assert!(check_server_url("https://devops.contoso.com:8443/tfs/").is_ok());
+        assert!(check_server_url("http://tfs01/tfs").is_ok());
+        assert!(check_server_url("devops.contoso.com/tfs").is_err());
+        assert!(check_server_url("ftp://devops.contoso.com").is_err());
+    }
+
+    // Undoes segment(), failing on anything it wouldn't have written
+    fn unsegment(encoded: &str) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        let mut rest = encoded.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'%' {
+                let hex = std::str::from_utf8(tail.get(..2).ok_or("cut short")?)
+                    .map_err(|e| e.to_string())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|e| e.to_string())?);
+                rest = &tail[2..];
+            } else if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                bytes.push(byte);
+                rest = tail;
+            } else {
+                return Err(format!(
+                    "{:?} left unencoded in {:?}",
+                    char::from(byte),
+                    encoded
+                ));
+            }
+        }
+        String::from_utf8(bytes).map_err(|e| e.to_string())
+    }
+
+    proptest! {
+        #[test]
+        fn names_reach_the_url_unchanged(
+            names in [strategies::name(), strategies::name(), strategies::name()],
+        ) {
+            let url = refs_url(
+                &names[0],
+                &names[1],
+                &names[2],
+                "heads/main",
+                DEFAULT_API_VERSION,
+            )
+            .unwrap();
+            let segments: Vec<&str> = url.path_segments().unwrap().collect();
+            prop_assert_eq!(segments.len(), 7, "{:?} split the path: {}", names, url);
+            for (name, sent) in names.iter().zip([segments[0], segments[1], segments[5]]) {
+                prop_assert_eq!(unsegment(sent), Ok(name.clone()), "sent as {:?}", sent);
+            }
+        }
+
+        #[test]
+        fn git_url_keeps_names_and_pat_apart(
+            names in [strategies::name(), strategies::name(), strategies::name()],
+            pat in strategies::secret(),
+        ) {
+            let url = reqwest::Url::parse(&git_url(&names[0], &names[1], &names[2], &pat)).unwrap();
+            let segments: Vec<&str> = url.path_segments().unwrap().collect();
+            prop_assert_eq!(url.host_str(), Some("dev.azure.com"), "{:?} broke the URL: {}", names, url);
+            prop_assert_eq!(segments.len(), 4, "{:?} broke the URL: {}", names, url);
+            prop_assert_eq!(unsegment(url.username()), Ok(names[0].clone()));
+            prop_assert_eq!(unsegment(url.password().unwrap_or_default()), Ok(pat));
+            for (name, sent) in names.iter().zip([segments[0], segments[1], segments[3]]) {
+                prop_assert_eq!(unsegment(sent), Ok(name.clone()), "sent as {:?}", sent);
+            }
+        }
+
+        #[test]
+        fn refs_url_keeps_the_branch_in_the_filter(branch in strategies::branch()) {
+            let filter = format!("heads/{}", branch);
+            let url = refs_url("org", "project", "repo", &filter, DEFAULT_API_VERSION).unwrap();
+            let sent = url
+                .query_pairs()
+                .find(|(key, _)| key == "filter")
+                .map(|(_, value)| value.into_owned());
+            prop_assert_eq!(sent.as_deref(), Some(filter.as_str()), "in {}", url);
+            prop_assert_eq!(url.path(), "/org/project/_apis/git/repositories/repo/refs");
+            prop_assert!(url.fragment().is_none(), "{:?} leaked out of the query: {}", branch, url);
+        }
+    }
+}
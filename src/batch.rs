@@ -0,0 +1,129 @@
+// Batches of repos deployed together. A member that finds new changes has every other member
+// check right away, fetches, and only applies its changes once every member has fetched or is
+// in sync, so one failed fetch holds back the whole batch instead of leaving it half old and
+// half new.
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tokio::time::timeout;
+
+// How long a member waits for the others to report before holding until its next check
+const BATCH_WAIT: Duration = Duration::from_secs(600);
+
+// How a member's latest fetch or check ended: ready to go, or why not
+#[derive(Clone)]
+struct Report {
+    at: Instant,
+    outcome: Result<(), String>,
+}
+
+#[derive(Default)]
+struct Member {
+    name: String,
+    // Asks the member to check now instead of at the end of its interval
+    check_now: Notify,
+    report: watch::Sender<Option<Report>>,
+}
+
+// A repo's view of its batch
+pub struct Batch {
+    name: String,
+    own: Rc<Member>,
+    others: Vec<Rc<Member>>,
+}
+
+// The batch of each repo, given each one's name and `batch` setting in config order
+pub fn form(repos: &[(String, Option<String>)]) -> Vec<Option<Batch>> {
+    let members: Vec<Rc<Member>> = repos
+        .iter()
+        .map(|(name, _)| {
+            Rc::new(Member {
+                name: name.clone(),
+                ..Member::default()
+            })
+        })
+        .collect();
+
+    let mut batches: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (position, (_, batch)) in repos.iter().enumerate() {
+        if let Some(batch) = batch {
+            batches.entry(batch.as_str()).or_default().push(position);
+        }
+    }
+
+    repos
+        .iter()
+        .enumerate()
+        .map(|(position, (_, batch))| {
+            let batch = batch.as_deref()?;
+            Some(Batch {
+                name: batch.to_string(),
+                own: members[position].clone(),
+                others: batches[batch]
+                    .iter()
+                    .filter(|&&other| other != position)
+                    .map(|&other| members[other].clone())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+impl Batch {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Starts a wave: every other member is asked to check now. Returns when the wave started,
+    // for wait_for_others.
+    pub fn start_wave(&self) -> Instant {
+        let started = Instant::now();
+        for member in &self.others {
+            member.check_now.notify_one();
+        }
+        started
+    }
+
+    // Records that this member fetched (or is in sync), or why it isn't ready
+    pub fn report(&self, outcome: Result<(), String>) {
+        self.own.report.send_replace(Some(Report {
+            at: Instant::now(),
+            outcome,
+        }));
+    }
+
+    // Resolves when another member asks this one to check now
+    pub async fn check_requested(&self) {
+        self.own.check_now.notified().await
+    }
+
+    // Waits for every other member to report after the wave started, failing with the reason
+    // to hold back if one isn't ready or doesn't report in time
+    pub async fn wait_for_others(&self, wave: Instant) -> Result<(), String> {
+        for member in &self.others {
+            let mut reports = member.report.subscribe();
+            let fresh = timeout(
+                BATCH_WAIT,
+                reports.wait_for(|report| report.as_ref().is_some_and(|report| report.at >= wave)),
+            )
+            .await;
+            let outcome = match fresh {
+                Ok(Ok(report)) => report.as_ref().map(|report| report.outcome.clone()),
+                _ => None,
+            };
+            match outcome {
+                Some(Ok(())) => {}
+                Some(Err(reason)) => return Err(format!("'{}' {}", member.name, reason)),
+                None => {
+                    return Err(format!(
+                        "'{}' didn't report within {} seconds",
+                        member.name,
+                        BATCH_WAIT.as_secs()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
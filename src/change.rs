@@ -0,0 +1,326 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::{SyncRecord, SyncStatus};
+use crate::notify::{self, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use crate::template;
+use log::{error, info};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// A change-management system (e.g. ServiceNow) that gets a change record for every sync that
+// applies changes
+#[derive(Deserialize)]
+pub struct ChangeRecordConfig {
+    // Endpoint records are created at, e.g. a ServiceNow table API URL; updates go to <url>/<id>
+    pub url: String,
+    // Basic authentication; ignored when token is set
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    // Sent as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    // Record fields with {{placeholders}}, sent once the sync finished
+    #[serde(default = "default_fields")]
+    pub fields: BTreeMap<String, String>,
+    // When set, the record is filed with these fields as soon as changes are detected, and
+    // updated with `fields` once the sync finished
+    #[serde(default)]
+    pub open_fields: BTreeMap<String, String>,
+    // Dotted path of the record's ID in the response to the create request
+    #[serde(default = "default_id_field")]
+    pub id_field: String,
+}
+
+fn default_id_field() -> String {
+    "result.sys_id".to_string()
+}
+
+// A ServiceNow change_request describing the sync and how it went
+fn default_fields() -> BTreeMap<String, String> {
+    [
+        (
+            "short_description",
+            "Sync {{repo}} ({{branch}}) on {{host}} to {{short_commit}}",
+        ),
+        (
+            "description",
+            "{{commit_count}} commit(s) in {{commit_range}}: {{compare_url}}\n{% for commit in commits %}- {{commit.short_id}} {{commit.message}} ({{commit.author}})\n{% endfor %}{% if pull_requests %}Pull requests:\n{% for pr in pull_requests %}- !{{pr.id}} {{pr.title}} by {{pr.author}}{% if pr.reviewers %}, approved by {{pr.reviewers}}{% endif %} {{pr.url}}\n{% endfor %}{% endif %}",
+        ),
+        ("close_code", "{{outcome}}"),
+        (
+            "close_notes",
+            "Synced on {{host}} with status {{status}}{% if error %}: {{error}}{% endif %}",
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect()
+}
+
+impl Documented for ChangeRecordConfig {
+    const SECTION: &'static str = "[[change_records]]";
+    const ABOUT: &'static str =
+        "Change records filed (e.g. in ServiceNow) for every sync that applies changes.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "url",
+            "string",
+            r#""https://acme.service-now.com/api/now/table/change_request""#,
+            "Endpoint records are created at with POST; updates PATCH <url>/<id>",
+        ),
+        schema::optional("username", "string", r#""svc-devops-sync""#, "Basic authentication user"),
+        schema::optional(
+            "password",
+            "string",
+            r#""enc:...""#,
+            "Basic authentication password; plain or enc: encrypted",
+        ),
+        schema::optional(
+            "token",
+            "string",
+            r#""enc:...""#,
+            "Sent as `Authorization: Bearer <token>` instead of basic authentication; plain or enc: encrypted",
+        ),
+        schema::defaulted(
+            "fields",
+            "table of strings",
+            "a ServiceNow change_request",
+            "Record fields sent once the sync finished; notification placeholders plus {{commit_range}}, {{approvers}} and {{outcome}}",
+        ),
+        schema::defaulted(
+            "open_fields",
+            "table of strings",
+            "{}",
+            "When set, the record is filed with these as soon as changes are detected and updated with `fields` afterwards",
+        ),
+        schema::defaulted(
+            "id_field",
+            "string",
+            r#""result.sys_id""#,
+            "Dotted path of the new record's ID in the create response",
+        ),
+    ];
+}
+
+impl ChangeRecordConfig {
+    // Decrypts an enc: password or token, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.password = secrets::resolve_secret(&self.password, secrets_identity)?;
+        if let Some(token) = &mut self.token {
+            *token = secrets::resolve_secret(token, secrets_identity)?;
+        }
+        Ok(())
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.token, &self.username) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some(username)) => request.basic_auth(username, Some(&self.password)),
+            (None, None) => request,
+        }
+    }
+}
+
+// Files and updates the change records, remembering per system the record still open for
+// the sync in progress
+pub struct ChangeRecords {
+    systems: Vec<ChangeRecordConfig>,
+    open: Vec<Option<String>>,
+    client: Client,
+}
+
+impl ChangeRecords {
+    pub fn new(systems: Vec<ChangeRecordConfig>, client: Client) -> Self {
+        let open = vec![None; systems.len()];
+        ChangeRecords {
+            systems,
+            open,
+            client,
+        }
+    }
+
+    // Files the records of the systems that open them before the sync
+    async fn changes_detected(&mut self, repo: &RepoRef<'_>, old_commit: &str, new_commit: &str) {
+        let record = SyncRecord::new(old_commit, new_commit);
+        for (system, open) in self.systems.iter().zip(self.open.iter_mut()) {
+            // A sync held back and retried keeps the record already filed for it
+            if system.open_fields.is_empty() || open.is_some() {
+                continue;
+            }
+
+            match create(&self.client, system, &system.open_fields, repo, &record).await {
+                Ok(id) => {
+                    info!("Filed change record {} at {}.", id, system.url);
+                    *open = Some(id);
+                }
+                Err(e) => error!("Failed to file change record at {}: {}", system.url, e),
+            }
+        }
+    }
+
+    // Updates the open records with the outcome, or files new ones when the sync applied changes
+    async fn sync_finished(&mut self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        for (system, open) in self.systems.iter().zip(self.open.iter_mut()) {
+            let result = match open.take() {
+                Some(id) => update(&self.client, system, &id, repo, record)
+                    .await
+                    .map(|()| format!("Updated change record {}", id)),
+                None if record.commits.is_empty() => continue,
+                None => create(&self.client, system, &system.fields, repo, record)
+                    .await
+                    .map(|id| format!("Filed change record {}", id)),
+            };
+
+            match result {
+                Ok(done) => info!("{} at {}.", done, system.url),
+                Err(e) => error!("Failed to record the change at {}: {}", system.url, e),
+            }
+        }
+    }
+}
+
+impl Subscriber for ChangeRecords {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            match event {
+                SyncEvent::ChangesDetected {
+                    old_commit,
+                    new_commit,
+                    ..
+                } => self.changes_detected(repo, old_commit, new_commit).await,
+                SyncEvent::SyncFinished { record } => self.sync_finished(repo, record).await,
+                _ => {}
+            }
+            Directive::default()
+        })
+    }
+}
+
+// The record's fields, with the notification placeholders and those about the change itself
+fn render_fields(
+    fields: &BTreeMap<String, String>,
+    repo: &RepoRef<'_>,
+    record: &SyncRecord,
+) -> BTreeMap<String, String> {
+    let (mut vars, lists) = notify::template_values(repo, record);
+    vars.push(("commit_range", commit_range(record)));
+    vars.push(("approvers", approvers(record).join(", ")));
+    vars.push((
+        "outcome",
+        match record.status {
+            SyncStatus::Success => "successful",
+            _ => "unsuccessful",
+        }
+        .to_string(),
+    ));
+
+    fields
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                template::render_with_lists(value, &vars, &lists),
+            )
+        })
+        .collect()
+}
+
+// "old..new" in short commit IDs
+fn commit_range(record: &SyncRecord) -> String {
+    let short = |commit: &str| commit.chars().take(8).collect::<String>();
+    format!(
+        "{}..{}",
+        short(&record.old_commit),
+        short(&record.new_commit)
+    )
+}
+
+// Reviewers of the deployed pull requests, each named once
+fn approvers(record: &SyncRecord) -> Vec<String> {
+    let mut approvers: Vec<String> = Vec::new();
+    for reviewer in record
+        .pull_requests
+        .iter()
+        .flat_map(|pull_request| &pull_request.reviewers)
+    {
+        if !approvers.contains(reviewer) {
+            approvers.push(reviewer.clone());
+        }
+    }
+    approvers
+}
+
+// Creates a record and returns its ID
+async fn create(
+    client: &Client,
+    system: &ChangeRecordConfig,
+    fields: &BTreeMap<String, String>,
+    repo: &RepoRef<'_>,
+    record: &SyncRecord,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let request = client
+        .post(&system.url)
+        .json(&render_fields(fields, repo, record));
+    let response = system.authorize(request).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!("{} {}", status, text.trim()).into());
+    }
+
+    let body: Value = response.json().await?;
+    record_id(&body, &system.id_field)
+        .ok_or_else(|| format!("the response has no '{}'", system.id_field).into())
+}
+
+async fn update(
+    client: &Client,
+    system: &ChangeRecordConfig,
+    id: &str,
+    repo: &RepoRef<'_>,
+    record: &SyncRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let request = client
+        .patch(format!("{}/{}", system.url.trim_end_matches('/'), id))
+        .json(&render_fields(&system.fields, repo, record));
+    let response = system.authorize(request).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!("{} {}", status, text.trim()).into());
+    }
+    Ok(())
+}
+
+// Follows a dotted path such as "result.sys_id" to a string or number
+fn record_id(body: &Value, path: &str) -> Option<String> {
+    let value = path
+        .split('.')
+        .try_fold(body, |value, key| value.get(key))?;
+    match value {
+        Value::String(id) if !id.is_empty() => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn record_ids_are_found_by_path() {
+        let body = json!({ "result": { "sys_id": "a1b2", "number": 42 } });
+        assert_eq!(record_id(&body, "result.sys_id").as_deref(), Some("a1b2"));
+        assert_eq!(record_id(&body, "result.number").as_deref(), Some("42"));
+        assert_eq!(record_id(&body, "result.missing"), None);
+        assert_eq!(record_id(&body, "result"), None);
+    }
+}
@@ -0,0 +1,477 @@
+// The command line: every subcommand with its flags. Argument parsing goes by this table and the
+// completion scripts are generated from it, so a flag can't be accepted without being completed.
+use crate::completions;
+
+// A flag, with the value it takes if any
+pub struct Flag {
+    pub long: &'static str,
+    pub short: &'static str,
+    pub about: &'static str,
+    pub value: Option<Value>,
+}
+
+// What a flag's value is, with how it is described when it is missing
+#[derive(Clone, Copy, PartialEq)]
+pub enum Value {
+    File(&'static str),
+    Text(&'static str),
+}
+
+pub struct Subcommand {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub flags: &'static [Flag],
+    // Fixed words accepted as the first argument
+    pub words: &'static [&'static str],
+    // Placeholder of the free argument it takes, if any
+    pub operand: Option<&'static str>,
+}
+
+// The arguments given to a subcommand
+pub struct Args {
+    command: &'static Subcommand,
+    // Long names of the flags given, in order, with their values
+    given: Vec<(&'static str, Option<String>)>,
+    pub operand: Option<String>,
+}
+
+impl Args {
+    // Whether the flag was given, by its long name
+    pub fn has(&self, long: &str) -> bool {
+        self.check(long);
+        self.given.iter().any(|(given, _)| *given == long)
+    }
+
+    // The value of the flag, the last one when it was given more than once
+    pub fn value(&self, long: &str) -> Option<&str> {
+        self.check(long);
+        self.given
+            .iter()
+            .rev()
+            .find(|(given, _)| *given == long)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    // A flag missing from the table would never be given
+    fn check(&self, long: &str) {
+        debug_assert!(
+            self.command.flags.iter().any(|flag| flag.long == long),
+            "{} is not a flag of {}",
+            long,
+            self.command.name
+        );
+    }
+}
+
+// Reads the arguments of a subcommand by its entry in SUBCOMMANDS
+pub fn parse(command: &str, args: &[String]) -> Result<Args, String> {
+    let command = SUBCOMMANDS
+        .iter()
+        .find(|subcommand| subcommand.name == command)
+        .ok_or_else(|| format!("Unknown command: {}", command))?;
+    let mut parsed = Args {
+        command,
+        given: Vec::new(),
+        operand: None,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let flag = command
+            .flags
+            .iter()
+            .find(|flag| arg == flag.long || arg == flag.short);
+        match flag {
+            Some(flag) => {
+                let value = match flag.value {
+                    Some(Value::File(what) | Value::Text(what)) => Some(
+                        iter.next()
+                            .cloned()
+                            .ok_or_else(|| format!("{} needs {}", flag.long, what))?,
+                    ),
+                    None => None,
+                };
+                parsed.given.push((flag.long, value));
+            }
+            None if command.operand.is_some()
+                && parsed.operand.is_none()
+                && !arg.starts_with('-') =>
+            {
+                parsed.operand = Some(arg.clone())
+            }
+            None => return Err(format!("Unknown argument for {}: {}", command.name, arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+// Flags accepted when running the sync loop
+pub const GLOBAL_FLAGS: &[Flag] = &[
+    Flag {
+        long: "--quiet",
+        short: "-q",
+        about: "Only print errors to the console",
+        value: None,
+    },
+    Flag {
+        long: "--verbose",
+        short: "-v",
+        about: "Mirror the log file to the console",
+        value: None,
+    },
+    Flag {
+        long: "--version",
+        short: "-V",
+        about: "Print version and build information",
+        value: None,
+    },
+];
+
+pub const SUBCOMMANDS: &[Subcommand] = &[
+    Subcommand {
+        name: "encrypt-secret",
+        about: "Encrypt a secret for config.toml with age",
+        flags: &[
+            Flag {
+                long: "--identity",
+                short: "-i",
+                about: "age identity file to encrypt for",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--recipient",
+                short: "-r",
+                about: "age recipient to encrypt for",
+                value: Some(Value::Text("a recipient")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "store-identity",
+        about: "Keep an age identity in the OS keyring",
+        flags: &[Flag {
+            long: "--file",
+            short: "-f",
+            about: "age identity file to store",
+            value: Some(Value::File("a path")),
+        }],
+        words: &[],
+        operand: Some("<name>"),
+    },
+    Subcommand {
+        name: "reload-credentials",
+        about: "Ask the running agent to re-read its PAT",
+        flags: &[],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "approve-force-push",
+        about: "Let the running agent sync a force-pushed branch it is holding",
+        flags: &[
+            Flag {
+                long: "--repository",
+                short: "-r",
+                about: "Repository to approve, when several are configured",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--project",
+                short: "-p",
+                about: "Project of the repository, when several have its name",
+                value: Some(Value::Text("a name")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "approve-terraform",
+        about: "Let the running agent apply the Terraform plan it is holding",
+        flags: &[
+            Flag {
+                long: "--repository",
+                short: "-r",
+                about: "Repository to approve, when several are configured",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--project",
+                short: "-p",
+                about: "Project of the repository, when several have its name",
+                value: Some(Value::Text("a name")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "simulate",
+        about: "Rehearse the config against a simulated remote in a scratch directory",
+        flags: &[
+            Flag {
+                long: "--repository",
+                short: "-r",
+                about: "Repository to simulate, when several are configured",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--timeout",
+                short: "-t",
+                about: "Seconds each scenario may take",
+                value: Some(Value::Text("a number of seconds")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "version",
+        about: "Print version and build information",
+        flags: &[],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "completions",
+        about: "Print a shell completion script",
+        flags: &[],
+        words: completions::SHELLS,
+        operand: None,
+    },
+    Subcommand {
+        name: "init",
+        about: "Write a starting config.toml",
+        flags: &[
+            Flag {
+                long: "--example",
+                short: "-e",
+                about: "Write every option with its default",
+                value: None,
+            },
+            Flag {
+                long: "--from-project",
+                short: "-P",
+                about: "Mirror every repository of <org>/<project>",
+                value: Some(Value::Text("<org>/<project>")),
+            },
+            Flag {
+                long: "--base-dir",
+                short: "-d",
+                about: "Directory the repositories are cloned under",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--pat-env",
+                short: "-t",
+                about: "Environment variable holding the PAT",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--output",
+                short: "-o",
+                about: "File to write, or - for stdout",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--force",
+                short: "-f",
+                about: "Overwrite an existing file",
+                value: None,
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "list-repos",
+        about: "List the projects, repositories and branches the PAT can see",
+        flags: &[
+            Flag {
+                long: "--project",
+                short: "-p",
+                about: "Only list this project",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--branches",
+                short: "-b",
+                about: "Also list every branch",
+                value: None,
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "discover",
+        about: "List the agents announcing themselves on the LAN",
+        flags: &[Flag {
+            long: "--timeout",
+            short: "-t",
+            about: "Seconds to wait for answers",
+            value: Some(Value::Text("a number of seconds")),
+        }],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "register-webhook",
+        about: "Subscribe the repositories' pushes to the agent's webhook",
+        flags: &[Flag {
+            long: "--repository",
+            short: "-r",
+            about: "Only subscribe this repository",
+            value: Some(Value::Text("a name")),
+        }],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "rollback",
+        about: "Point the current link back at an earlier kept release",
+        flags: &[
+            Flag {
+                long: "--repository",
+                short: "-r",
+                about: "Repository to roll back, when several keep releases",
+                value: Some(Value::Text("a name")),
+            },
+            Flag {
+                long: "--to",
+                short: "-t",
+                about: "Commit (or prefix) of the kept release to switch to",
+                value: Some(Value::Text("a commit")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "audit",
+        about: "Check the audit log for tampering",
+        flags: &[
+            Flag {
+                long: "--file",
+                short: "-f",
+                about: "Audit log to check instead of those in config.toml",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--key-file",
+                short: "-k",
+                about: "Key the entries were signed with",
+                value: Some(Value::File("a path")),
+            },
+        ],
+        words: &["verify"],
+        operand: None,
+    },
+    Subcommand {
+        name: "history",
+        about: "Export the sync history as CSV or JSON",
+        flags: &[
+            Flag {
+                long: "--format",
+                short: "-F",
+                about: "csv (the default) or json",
+                value: Some(Value::Text("csv or json")),
+            },
+            Flag {
+                long: "--since",
+                short: "-s",
+                about: "Only syncs from this date, time or duration ago on",
+                value: Some(Value::Text("a date or a duration")),
+            },
+            Flag {
+                long: "--file",
+                short: "-f",
+                about: "History file to read instead of those in config.toml",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--output",
+                short: "-o",
+                about: "File to write, or - for stdout",
+                value: Some(Value::File("a path")),
+            },
+        ],
+        words: &["export"],
+        operand: None,
+    },
+    Subcommand {
+        name: "stats",
+        about: "Summarize the sync history: syncs per day, failures, time to sync, busy hours",
+        flags: &[
+            Flag {
+                long: "--file",
+                short: "-f",
+                about: "History file to read instead of those in config.toml",
+                value: Some(Value::File("a path")),
+            },
+            Flag {
+                long: "--days",
+                short: "-d",
+                about: "Only count the syncs of the last number of days",
+                value: Some(Value::Text("a number of days")),
+            },
+        ],
+        words: &[],
+        operand: None,
+    },
+    Subcommand {
+        name: "config-schema",
+        about: "Print the config.toml reference",
+        flags: &[],
+        words: &[],
+        operand: None,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn flags_are_read_by_either_name() {
+        let parsed = parse("init", &args(&["-P", "contoso/web", "--force", "-o", "-"])).unwrap();
+        assert_eq!(parsed.value("--from-project"), Some("contoso/web"));
+        assert_eq!(parsed.value("--output"), Some("-"));
+        assert!(parsed.has("--force"));
+        assert!(!parsed.has("--example"));
+        assert_eq!(parsed.value("--base-dir"), None);
+    }
+
+    #[test]
+    fn only_the_flags_in_the_table_are_accepted() {
+        let unknown = parse("stats", &args(&["--since", "7d"])).err().unwrap();
+        assert_eq!(unknown, "Unknown argument for stats: --since");
+        let missing = parse("stats", &args(&["--days"])).err().unwrap();
+        assert_eq!(missing, "--days needs a number of days");
+        // Only store-identity takes a free argument
+        assert!(parse("rollback", &args(&["abc123"])).is_err());
+        let parsed = parse("store-identity", &args(&["deploy", "-f", "key.txt"])).unwrap();
+        assert_eq!(parsed.operand.as_deref(), Some("deploy"));
+        assert!(parse("store-identity", &args(&["deploy", "again"])).is_err());
+    }
+
+    #[test]
+    fn names_are_unique_within_a_subcommand() {
+        for command in SUBCOMMANDS {
+            let mut names: Vec<&str> = command
+                .flags
+                .iter()
+                .flat_map(|flag| [flag.long, flag.short])
+                .collect();
+            let count = names.len();
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), count, "{} repeats a flag", command.name);
+        }
+    }
+}
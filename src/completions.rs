@@ -0,0 +1,285 @@
+// Tab completion scripts for the subcommands and flags, printed by `completions <shell>`. They are
+// generated from the table in cli.rs the arguments are parsed by.
+use crate::cli::{Flag, Value, GLOBAL_FLAGS, SUBCOMMANDS};
+use std::fmt::Write;
+
+const BIN: &str = "DevOps_Repository_Sync";
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+// Prints the completion script for the shell named in the arguments
+pub fn completions_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let script = match args.first().map(String::as_str) {
+        Some("bash") => bash(),
+        Some("zsh") => zsh(),
+        Some("fish") => fish(),
+        Some("powershell") | Some("pwsh") => powershell(),
+        Some(other) => {
+            return Err(format!(
+                "Unknown shell '{}', expected one of: {}",
+                other,
+                SHELLS.join(", ")
+            )
+            .into())
+        }
+        None => return Err(format!("Usage: {} completions <{}>", BIN, SHELLS.join("|")).into()),
+    };
+    print!("{}", script);
+    Ok(())
+}
+
+// Everything that can be typed first: subcommands and global flags
+fn top_level_words() -> Vec<&'static str> {
+    SUBCOMMANDS
+        .iter()
+        .map(|command| command.name)
+        .chain(GLOBAL_FLAGS.iter().flat_map(|flag| [flag.long, flag.short]))
+        .collect()
+}
+
+fn bash() -> String {
+    let function = format!("_{}", BIN);
+    let mut out = format!(
+        "{}() {{\n    local cur prev\n    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n\n    case \"${{COMP_WORDS[1]}}\" in\n",
+        function
+    );
+    for command in SUBCOMMANDS {
+        if command.flags.is_empty() && command.words.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "        {})", command.name);
+        let with_values: Vec<&Flag> = command
+            .flags
+            .iter()
+            .filter(|flag| flag.value.is_some())
+            .collect();
+        if !with_values.is_empty() {
+            out.push_str("            case \"$prev\" in\n");
+            for flag in with_values {
+                let reply = match flag.value {
+                    Some(Value::File(_)) => "COMPREPLY=($(compgen -f -- \"$cur\"))",
+                    _ => "COMPREPLY=()",
+                };
+                let _ = writeln!(
+                    out,
+                    "                {}|{}) {}; return ;;",
+                    flag.long, flag.short, reply
+                );
+            }
+            out.push_str("            esac\n");
+        }
+        let words: Vec<&str> = command
+            .flags
+            .iter()
+            .flat_map(|flag| [flag.long, flag.short])
+            .chain(command.words.iter().copied())
+            .collect();
+        let _ = writeln!(
+            out,
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return ;;",
+            words.join(" ")
+        );
+    }
+    let _ = write!(
+        out,
+        "    esac\n\n    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n}}\n\ncomplete -F {} {} {}.exe\n",
+        top_level_words().join(" "),
+        function,
+        BIN,
+        BIN
+    );
+    out
+}
+
+fn zsh() -> String {
+    let mut out = format!(
+        "#compdef {}\n\n_{}() {{\n    local -a commands\n    commands=(\n",
+        BIN, BIN
+    );
+    for command in SUBCOMMANDS {
+        let _ = writeln!(out, "        '{}:{}'", command.name, command.about);
+    }
+    out.push_str("    )\n\n    _arguments -C \\\n");
+    for flag in GLOBAL_FLAGS {
+        let _ = writeln!(
+            out,
+            "        '({} {})'{{{},{}}}'[{}]' \\",
+            flag.short, flag.long, flag.short, flag.long, flag.about
+        );
+    }
+    out.push_str(
+        "        '1: :->command' \\\n        '*:: :->args'\n\n    case $state in\n        command) _describe 'command' commands ;;\n        args)\n            case $words[1] in\n",
+    );
+    for command in SUBCOMMANDS {
+        if !command.words.is_empty() || !command.flags.is_empty() {
+            let _ = write!(
+                out,
+                "                {})\n                    _arguments",
+                command.name
+            );
+            if !command.words.is_empty() {
+                let _ = write!(
+                    out,
+                    " \\\n                        '1:{}:({})'",
+                    command.name,
+                    command.words.join(" ")
+                );
+            }
+            for flag in command.flags {
+                let action = match flag.value {
+                    Some(Value::File(_)) => ":file:_files",
+                    Some(Value::Text(_)) => ":value:",
+                    None => "",
+                };
+                let _ = write!(
+                    out,
+                    " \\\n                        '({} {})'{{{},{}}}'[{}]{}'",
+                    flag.short, flag.long, flag.short, flag.long, flag.about, action
+                );
+            }
+            out.push_str(" ;;\n");
+        }
+    }
+    let _ = write!(
+        out,
+        "            esac ;;\n    esac\n}}\n\n_{} \"$@\"\n",
+        BIN
+    );
+    out
+}
+
+fn fish() -> String {
+    let mut out = format!("complete -c {} -f\n", BIN);
+    for flag in GLOBAL_FLAGS {
+        let _ = writeln!(
+            out,
+            "complete -c {} -n __fish_use_subcommand -s {} -l {} -d '{}'",
+            BIN,
+            flag.short.trim_start_matches('-'),
+            flag.long.trim_start_matches('-'),
+            flag.about
+        );
+    }
+    for command in SUBCOMMANDS {
+        let _ = writeln!(
+            out,
+            "complete -c {} -n __fish_use_subcommand -a {} -d '{}'",
+            BIN, command.name, command.about
+        );
+        let condition = format!("__fish_seen_subcommand_from {}", command.name);
+        for flag in command.flags {
+            let value = match flag.value {
+                Some(Value::File(_)) => " -r -F",
+                Some(Value::Text(_)) => " -r",
+                None => "",
+            };
+            let _ = writeln!(
+                out,
+                "complete -c {} -n '{}' -s {} -l {}{} -d '{}'",
+                BIN,
+                condition,
+                flag.short.trim_start_matches('-'),
+                flag.long.trim_start_matches('-'),
+                value,
+                flag.about
+            );
+        }
+        if !command.words.is_empty() {
+            let _ = writeln!(
+                out,
+                "complete -c {} -n '{}' -a '{}'",
+                BIN,
+                condition,
+                command.words.join(" ")
+            );
+        }
+    }
+    out
+}
+
+fn powershell() -> String {
+    let quoted = |words: Vec<&str>| {
+        words
+            .iter()
+            .map(|word| format!("'{}'", word))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut out = format!(
+        "Register-ArgumentCompleter -Native -CommandName '{}', '{}.exe' -ScriptBlock {{\n    param($wordToComplete, $commandAst, $cursorPosition)\n    $words = $commandAst.CommandElements | Select-Object -Skip 1 | ForEach-Object {{ $_.ToString() }} | Where-Object {{ $_ -ne $wordToComplete }}\n    $command = $words | Where-Object {{ $_ -notlike '-*' }} | Select-Object -First 1\n    $candidates = switch ($command) {{\n",
+        BIN, BIN
+    );
+    for command in SUBCOMMANDS {
+        let words: Vec<&str> = command
+            .flags
+            .iter()
+            .map(|flag| flag.long)
+            .chain(command.words.iter().copied())
+            .collect();
+        if !words.is_empty() {
+            let _ = writeln!(out, "        '{}' {{ @({}) }}", command.name, quoted(words));
+        }
+    }
+    let _ = write!(
+        out,
+        "        default {{ @({}) }}\n    }}\n    $candidates | Where-Object {{ $_ -like \"$wordToComplete*\" }} | ForEach-Object {{\n        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n    }}\n}}\n",
+        quoted(top_level_words())
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subcommand_and_flag_is_completed() {
+        for (shell, script) in [
+            ("bash", bash()),
+            ("zsh", zsh()),
+            ("fish", fish()),
+            ("powershell", powershell()),
+        ] {
+            // fish names flags without their dashes
+            let flag = |flag: &Flag| match shell {
+                "fish" => format!("-l {}", flag.long.trim_start_matches('-')),
+                _ => flag.long.to_string(),
+            };
+            for global in GLOBAL_FLAGS {
+                assert!(
+                    script.contains(&flag(global)),
+                    "{} misses {}",
+                    shell,
+                    global.long
+                );
+            }
+            for command in SUBCOMMANDS {
+                assert!(
+                    script.contains(command.name),
+                    "{} misses {}",
+                    shell,
+                    command.name
+                );
+                for word in command.words {
+                    assert!(
+                        script.contains(word),
+                        "{} misses {} {}",
+                        shell,
+                        command.name,
+                        word
+                    );
+                }
+                for given in command.flags {
+                    assert!(
+                        script.contains(&flag(given)),
+                        "{} misses {} {}",
+                        shell,
+                        command.name,
+                        given.long
+                    );
+                }
+            }
+        }
+    }
+}
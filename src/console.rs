@@ -0,0 +1,67 @@
+// What the sync loop writes to the console, chosen with --quiet or --verbose.
+use std::io::{self, Write};
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    // The status ticker and sync summaries
+    Normal,
+    // Nothing but errors, for supervisors that capture the output
+    Quiet,
+    // Everything written to app.log, one line per entry
+    Verbose,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+// Takes --quiet / -q or --verbose / -v out of the arguments, returning the mode they pick
+pub fn take_mode_flag(args: &mut Vec<String>) -> Result<Mode, String> {
+    let quiet = take_flag(args, "--quiet", "-q");
+    let verbose = take_flag(args, "--verbose", "-v");
+    match (quiet, verbose) {
+        (true, true) => Err("--quiet and --verbose can't be used together".to_string()),
+        (true, false) => Ok(Mode::Quiet),
+        (false, true) => Ok(Mode::Verbose),
+        (false, false) => Ok(Mode::Normal),
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, long: &str, short: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != long && arg != short);
+    args.len() != before
+}
+
+pub fn init(mode: Mode) {
+    let _ = MODE.set(mode);
+}
+
+pub fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Normal)
+}
+
+// Rewrites the single status line in place. The log already carries the same information,
+// so it only shows in normal mode.
+pub fn ticker(text: &str) -> io::Result<()> {
+    if mode() != Mode::Normal {
+        return Ok(());
+    }
+    print!("\r{}", text);
+    io::stdout().flush()
+}
+
+// Prints a line of its own below the ticker, in normal mode
+pub fn line(text: &str) {
+    if mode() == Mode::Normal {
+        println!("\n{}", text);
+    }
+}
+
+// Prints a block of lines as they are, in normal mode
+pub fn block(lines: &[String]) {
+    if mode() == Mode::Normal {
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+}
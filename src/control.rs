@@ -0,0 +1,728 @@
+use crate::azure;
+use crate::metrics;
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use crate::webhook;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{error, info, warn};
+use percent_encoding::percent_decode_str;
+use reqwest::Client;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::Sender;
+
+// Commands the control endpoint hands over to every repo's sync loop
+#[derive(Clone)]
+pub enum ControlCommand {
+    ReloadCredentials,
+    // A push to the repository came in through the webhook
+    CheckNow(RepoKey),
+    // An operator approved syncing the repository to its force-pushed branch
+    ApproveForcePush(RepoKey),
+    // An operator approved applying the repository's Terraform plan
+    ApproveTerraform(RepoKey),
+}
+
+// A repository as commands name it. Repository names are only unique within a project, so the
+// project is part of the key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepoKey {
+    pub project: String,
+    pub repository: String,
+}
+
+impl RepoKey {
+    pub fn new(project: &str, repository: &str) -> Self {
+        RepoKey {
+            project: project.to_string(),
+            repository: repository.to_string(),
+        }
+    }
+
+    // Azure DevOps names ignore case
+    pub fn is(&self, other: &RepoKey) -> bool {
+        self.project.eq_ignore_ascii_case(&other.project)
+            && self.repository.eq_ignore_ascii_case(&other.repository)
+    }
+
+    // The route of a command for the repository, e.g. "/approve-terraform/Web%20Team/site"
+    pub fn route(&self, command: &str) -> String {
+        format!(
+            "/{}/{}/{}",
+            command,
+            azure::segment(&self.project),
+            azure::segment(&self.repository)
+        )
+    }
+
+    // Reads the "<project>/<repository>" a route ends in, each part percent-encoded
+    fn from_route(rest: &str) -> Option<Self> {
+        let decode = |part: &str| {
+            percent_decode_str(part)
+                .decode_utf8()
+                .ok()
+                .map(|part| part.into_owned())
+                .filter(|part| !part.is_empty())
+        };
+        let (project, repository) = rest.split_once('/')?;
+        Some(RepoKey {
+            project: decode(project)?,
+            repository: decode(repository)?,
+        })
+    }
+}
+
+impl fmt::Display for RepoKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.project, self.repository)
+    }
+}
+
+// Largest webhook body read; push notifications are a few kilobytes
+const MAX_WEBHOOK_BODY: usize = 1024 * 1024;
+
+// Environment variable the control commands take their token from
+pub const TOKEN_ENV: &str = "SYNC_CONTROL_TOKEN";
+
+// What a token may do on the control endpoint
+#[derive(Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // Read status and metrics, e.g. for monitoring
+    Read,
+    // Also send commands that change what the agent does
+    Operator,
+}
+
+// One [[control_tokens]] entry
+#[derive(Deserialize, Clone, Debug)]
+pub struct ControlToken {
+    // Who the token belongs to, logged with the commands it sends
+    pub name: String,
+    // The bearer token itself, plain or enc: encrypted
+    pub token: String,
+    pub scope: Scope,
+}
+
+impl Documented for ControlToken {
+    const SECTION: &'static str = "[[control_tokens]]";
+    const ABOUT: &'static str =
+        "Bearer tokens for the control endpoint; once any is set, every request needs one.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "name",
+            "string",
+            r#""monitoring""#,
+            "Who the token belongs to, logged with the commands it sends",
+        ),
+        schema::required(
+            "token",
+            "string",
+            r#""enc:...""#,
+            "The token, sent as `Authorization: Bearer <token>`; plain or enc: encrypted",
+        ),
+        schema::required(
+            "scope",
+            "\"read\" or \"operator\"",
+            r#""read""#,
+            "read allows GET /metrics; operator also allows commands such as reload-credentials",
+        ),
+    ];
+}
+
+impl ControlToken {
+    // Decrypts an enc: token, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.token = secrets::resolve_secret(&self.token, secrets_identity)?;
+        if self.token.is_empty() {
+            return Err(format!("control_tokens '{}' has an empty token", self.name).into());
+        }
+        Ok(())
+    }
+}
+
+// Compares in time independent of where the values differ, so a token can't be guessed
+// byte by byte from response times
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+// Listens for control requests on the configured local address and forwards them to the loop.
+// With no tokens configured the endpoint is open to anyone who can reach the address. With a
+// webhook secret, POST /webhook takes push notifications authenticated by it instead. Commands
+// for a repository other than the configured `repos` are answered with 404.
+pub async fn serve(
+    listen: String,
+    commands: Sender<ControlCommand>,
+    tokens: Vec<ControlToken>,
+    webhook_secret: Option<String>,
+    repos: Vec<RepoKey>,
+) {
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind control endpoint on {}: {}", listen, e);
+            return;
+        }
+    };
+    info!("Control endpoint listening on {}", listen);
+    if tokens.is_empty() {
+        warn!("No control_tokens are configured, the control endpoint accepts every request.");
+    }
+    let tokens = Arc::new(tokens);
+    let webhook_secret = Arc::new(webhook_secret);
+    let repos = Arc::new(repos);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let commands = commands.clone();
+                let tokens = tokens.clone();
+                let webhook_secret = webhook_secret.clone();
+                let repos = repos.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(
+                        stream,
+                        commands,
+                        &tokens,
+                        webhook_secret.as_deref(),
+                        &repos,
+                    )
+                    .await
+                    {
+                        error!("Control request failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept control connection: {}", e),
+        }
+    }
+}
+
+// Reads a single HTTP request and answers it
+async fn handle_connection(
+    mut stream: TcpStream,
+    commands: Sender<ControlCommand>,
+    tokens: &[ControlToken],
+    webhook_secret: Option<&str>,
+    repos: &[RepoKey],
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    // Of the headers, only the credentials and the body's length are needed
+    let mut bearer = None;
+    let mut basic_password = None;
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("authorization") {
+                let value = value.trim();
+                bearer = value
+                    .strip_prefix("Bearer ")
+                    .map(|token| token.trim().to_string());
+                basic_password = value.strip_prefix("Basic ").and_then(basic_auth_password);
+            } else if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    // Azure DevOps can't send a bearer token, so the webhook is checked against its own secret
+    if let (Some(secret), ("POST", "/webhook")) = (webhook_secret, (method, path)) {
+        if !basic_password.is_some_and(|given| same_token(&given, secret)) {
+            warn!("Rejected a webhook request without the webhook secret.");
+            return respond(
+                &mut stream,
+                "401 Unauthorized",
+                "WWW-Authenticate: Basic\r\n",
+                r#"{"error":"the webhook secret is required"}"#,
+            )
+            .await;
+        }
+        if content_length > MAX_WEBHOOK_BODY {
+            return respond(
+                &mut stream,
+                "413 Payload Too Large",
+                "",
+                r#"{"error":"the body is too large"}"#,
+            )
+            .await;
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+        let body = match webhook::pushed_repository(&body) {
+            Some(pushed) if repos.iter().any(|repo| repo.is(&pushed)) => {
+                info!("Push to {} announced through the webhook.", pushed);
+                let _ = commands.send(ControlCommand::CheckNow(pushed));
+                r#"{"status":"checking"}"#
+            }
+            // Test notifications, other events and pushes to repositories this agent doesn't
+            // sync are acknowledged and otherwise ignored
+            _ => r#"{"status":"ignored"}"#,
+        };
+        return respond(&mut stream, "200 OK", "", body).await;
+    }
+
+    // Reading needs the read scope, anything that changes the agent needs operator
+    let needed = if method == "GET" {
+        Scope::Read
+    } else {
+        Scope::Operator
+    };
+    let caller = if tokens.is_empty() {
+        None
+    } else {
+        match bearer.and_then(|given| tokens.iter().find(|t| same_token(&given, &t.token))) {
+            Some(token) if token.scope >= needed => Some(token.name.as_str()),
+            Some(token) => {
+                warn!(
+                    "Control token '{}' ({:?}) may not {} {}.",
+                    token.name, token.scope, method, path
+                );
+                return respond(
+                    &mut stream,
+                    "403 Forbidden",
+                    "",
+                    r#"{"error":"the token's scope doesn't allow this request"}"#,
+                )
+                .await;
+            }
+            None => {
+                warn!(
+                    "Rejected control request {} {} without a valid token.",
+                    method, path
+                );
+                return respond(
+                    &mut stream,
+                    "401 Unauthorized",
+                    "WWW-Authenticate: Bearer\r\n",
+                    r#"{"error":"a valid bearer token is required"}"#,
+                )
+                .await;
+            }
+        }
+    };
+
+    let json = "application/json";
+    let (status, content_type, body) = match (method, path, approval(path)) {
+        ("POST", "/reload-credentials", _) => {
+            info!(
+                "Credential reload requested through the control endpoint{}.",
+                caller
+                    .map(|name| format!(" by '{}'", name))
+                    .unwrap_or_default()
+            );
+            let _ = commands.send(ControlCommand::ReloadCredentials);
+            (
+                "200 OK",
+                json,
+                r#"{"status":"reloading credentials"}"#.to_string(),
+            )
+        }
+        ("POST", _, Some((command, rest))) => match RepoKey::from_route(rest) {
+            Some(repo) if repos.iter().any(|configured| configured.is(&repo)) => {
+                info!(
+                    "{} of {} approved through the control endpoint{}.",
+                    if command == "approve-terraform" {
+                        "Terraform plan"
+                    } else {
+                        "Force push"
+                    },
+                    repo,
+                    caller
+                        .map(|name| format!(" by '{}'", name))
+                        .unwrap_or_default()
+                );
+                let _ = commands.send(if command == "approve-terraform" {
+                    ControlCommand::ApproveTerraform(repo)
+                } else {
+                    ControlCommand::ApproveForcePush(repo)
+                });
+                ("200 OK", json, r#"{"status":"approved"}"#.to_string())
+            }
+            Some(repo) => (
+                "404 Not Found",
+                json,
+                serde_json::json!({
+                    "error": format!("no configured repository is {}", repo)
+                })
+                .to_string(),
+            ),
+            None => (
+                "400 Bad Request",
+                json,
+                format!(
+                    r#"{{"error":"expected /{}/<project>/<repository>"}}"#,
+                    command
+                ),
+            ),
+        },
+        ("GET", "/metrics", _) => ("200 OK", "text/plain; version=0.0.4", metrics::render()),
+        _ => (
+            "404 Not Found",
+            json,
+            r#"{"error":"unknown control command"}"#.to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// The approval command a path names, with the rest of the path after it
+fn approval(path: &str) -> Option<(&str, &str)> {
+    ["approve-force-push", "approve-terraform"]
+        .into_iter()
+        .find_map(|command| {
+            path.strip_prefix('/')?
+                .strip_prefix(command)?
+                .strip_prefix('/')
+                .map(|rest| (command, rest))
+        })
+}
+
+// The password of a Basic authorization value
+fn basic_auth_password(encoded: &str) -> Option<String> {
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
+}
+
+// Answers a request with a JSON body
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// Sends a control command to a running agent and returns its reply. The token comes from
+// SYNC_CONTROL_TOKEN, or else the first operator token in the config.
+pub async fn send_command(
+    client: &Client,
+    listen: &str,
+    path: &str,
+    tokens: &[ControlToken],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let token = env::var(TOKEN_ENV).ok().or_else(|| {
+        tokens
+            .iter()
+            .find(|token| token.scope == Scope::Operator)
+            .map(|token| token.token.clone())
+    });
+
+    let mut request = client.post(format!("http://{}{}", listen, path));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+
+    let status = response.status();
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("Control endpoint returned {}: {}", status, body).into());
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast;
+
+    fn tokens() -> Vec<ControlToken> {
+        [
+            ("monitoring", "read-token-1234", Scope::Read),
+            ("ops", "operator-token-5678", Scope::Operator),
+        ]
+        .into_iter()
+        .map(|(name, token, scope)| ControlToken {
+            name: name.to_string(),
+            token: token.to_string(),
+            scope,
+        })
+        .collect()
+    }
+
+    // Sends one raw request to a control endpoint, returning the status line and whether a
+    // command reached the loops
+    async fn send(
+        tokens: &[ControlToken],
+        webhook_secret: Option<&str>,
+        request: &str,
+    ) -> (String, bool) {
+        let (status, command) = send_to(tokens, webhook_secret, request).await;
+        (status, command.is_some())
+    }
+
+    // Like send(), returning the command that reached the loops
+    async fn send_to(
+        tokens: &[ControlToken],
+        webhook_secret: Option<&str>,
+        request: &str,
+    ) -> (String, Option<ControlCommand>) {
+        let repos = [
+            RepoKey::new("Web Team", "site"),
+            RepoKey::new("Infra", "site"),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, mut receiver) = broadcast::channel(4);
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle_connection(stream, sender, tokens, webhook_secret, &repos)
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let status = response.lines().next().unwrap_or_default().to_string();
+        (status, receiver.try_recv().ok())
+    }
+
+    fn post(path: &str, authorization: &str) -> String {
+        format!(
+            "POST {} HTTP/1.1\r\nAuthorization: {}\r\nContent-Length: 2\r\n\r\n{{}}",
+            path, authorization
+        )
+    }
+
+    #[test]
+    fn tokens_compare_equal_only_when_identical() {
+        assert!(same_token("operator-token-5678", "operator-token-5678"));
+        assert!(!same_token("operator-token-5679", "operator-token-5678"));
+        assert!(!same_token("operator-token-567", "operator-token-5678"));
+        assert!(!same_token("", "operator-token-5678"));
+        assert!(same_token("", ""));
+    }
+
+    #[test]
+    fn basic_credentials_yield_the_password() {
+        let encoded = BASE64.encode("azure:hook-secret:with-colon");
+        assert_eq!(
+            basic_auth_password(&encoded).as_deref(),
+            Some("hook-secret:with-colon")
+        );
+        assert_eq!(basic_auth_password(&BASE64.encode("no-colon")), None);
+        assert_eq!(basic_auth_password("not base64!"), None);
+    }
+
+    #[tokio::test]
+    async fn commands_need_an_operator_token() {
+        let tokens = tokens();
+        let (status, sent) = send(
+            &tokens,
+            None,
+            &post("/reload-credentials", "Bearer operator-token-5678"),
+        )
+        .await;
+        assert_eq!((status.as_str(), sent), ("HTTP/1.1 200 OK", true));
+
+        let (status, sent) = send(
+            &tokens,
+            None,
+            &post("/reload-credentials", "Bearer read-token-1234"),
+        )
+        .await;
+        assert_eq!((status.as_str(), sent), ("HTTP/1.1 403 Forbidden", false));
+
+        for authorization in [
+            "Bearer operator-token-0000",
+            "Bearer ",
+            "Basic b3BzOm9wZXJhdG9yLXRva2VuLTU2Nzg=",
+        ] {
+            let (status, sent) = send(
+                &tokens,
+                None,
+                &post("/approve-terraform/Infra/site", authorization),
+            )
+            .await;
+            assert_eq!(
+                (status.as_str(), sent),
+                ("HTTP/1.1 401 Unauthorized", false),
+                "{}",
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reading_needs_any_valid_token() {
+        let tokens = tokens();
+        let get = |token: &str| {
+            format!(
+                "GET /metrics HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+                token
+            )
+        };
+        for token in ["read-token-1234", "operator-token-5678"] {
+            let (status, _) = send(&tokens, None, &get(token)).await;
+            assert_eq!(status, "HTTP/1.1 200 OK", "{}", token);
+        }
+        let (status, _) = send(&tokens, None, "GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        // Without tokens the endpoint is open
+        let (status, sent) = send(&[], None, &post("/reload-credentials", "Bearer anything")).await;
+        assert_eq!((status.as_str(), sent), ("HTTP/1.1 200 OK", true));
+    }
+
+    #[tokio::test]
+    async fn the_webhook_needs_its_secret() {
+        let tokens = tokens();
+        let basic =
+            |password: &str| format!("Basic {}", BASE64.encode(format!("azure:{}", password)));
+
+        let (status, _) = send(
+            &tokens,
+            Some("hook-secret"),
+            &post("/webhook", &basic("hook-secret")),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        for authorization in [
+            basic("hook-secre"),
+            basic(""),
+            "Bearer operator-token-5678".to_string(),
+        ] {
+            let (status, sent) = send(
+                &tokens,
+                Some("hook-secret"),
+                &post("/webhook", &authorization),
+            )
+            .await;
+            assert_eq!(
+                (status.as_str(), sent),
+                ("HTTP/1.1 401 Unauthorized", false),
+                "{}",
+                authorization
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn approvals_name_the_project_and_repository() {
+        let approve = |path: &str| post(path, "Bearer operator-token-5678");
+        let route = RepoKey::new("Web Team", "site").route("approve-terraform");
+        assert_eq!(route, "/approve-terraform/Web%20Team/site");
+
+        let (status, command) = send_to(&tokens(), None, &approve(&route)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        match command {
+            Some(ControlCommand::ApproveTerraform(repo)) => {
+                assert_eq!(repo, RepoKey::new("Web Team", "site"))
+            }
+            _ => panic!("no approval was sent"),
+        }
+
+        // Reserved characters other than spaces decode too, and names ignore case
+        let (status, command) = send_to(
+            &tokens(),
+            None,
+            &approve("/approve-force-push/web%20team/SITE"),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(matches!(command, Some(ControlCommand::ApproveForcePush(_))));
+
+        for (path, expected) in [
+            (
+                "/approve-terraform/Web%20Team/blog",
+                "HTTP/1.1 404 Not Found",
+            ),
+            ("/approve-terraform/Sales/site", "HTTP/1.1 404 Not Found"),
+            ("/approve-terraform/site", "HTTP/1.1 400 Bad Request"),
+            ("/approve-terraform/Web%20Team/", "HTTP/1.1 400 Bad Request"),
+            ("/approve-terraform/Infra/%FF", "HTTP/1.1 400 Bad Request"),
+        ] {
+            let (status, command) = send_to(&tokens(), None, &approve(path)).await;
+            assert_eq!(status, expected, "{}", path);
+            assert!(command.is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn routes_decode_to_the_names_they_were_made_from() {
+        for (project, repository) in [("Web Team", "site"), ("R&D", "a/b?c#d"), ("Über", "100%")] {
+            let repo = RepoKey::new(project, repository);
+            let route = repo.route("approve-force-push");
+            let (command, rest) = approval(&route).unwrap();
+            assert_eq!(command, "approve-force-push");
+            assert_eq!(RepoKey::from_route(rest), Some(repo));
+        }
+    }
+
+    #[tokio::test]
+    async fn the_webhook_wakes_only_configured_repositories() {
+        let request = |project: &str, repository: &str| {
+            let body = serde_json::json!({
+                "eventType": "git.push",
+                "resource": {
+                    "repository": {"name": repository, "project": {"name": project}}
+                }
+            })
+            .to_string();
+            format!(
+                "POST /webhook HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{}",
+                BASE64.encode("azure:hook-secret"),
+                body.len(),
+                body
+            )
+        };
+        let (_, command) = send_to(&tokens(), Some("hook-secret"), &request("Infra", "site")).await;
+        match command {
+            Some(ControlCommand::CheckNow(repo)) => assert_eq!(repo, RepoKey::new("Infra", "site")),
+            _ => panic!("no check was requested"),
+        }
+        let (status, command) =
+            send_to(&tokens(), Some("hook-secret"), &request("Sales", "site")).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(command.is_none());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::git::{self, Change};
+use crate::glob;
+use crate::post_sync;
+use crate::schema::{self, Documented, Field};
+use log::info;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// A directory the synced files are copied to after every successful pull, for targets that
+// can't hold the checkout itself (e.g. a web root owned by another process), on this machine
+// or on a remote host reached over SSH
+#[derive(Deserialize)]
+pub struct CopyConfig {
+    // Local directory, or the directory on the host
+    pub destination: String,
+    // Directory of the repo to copy, defaults to all of it
+    #[serde(default)]
+    pub source: String,
+    // Copy only the files the sync changed instead of comparing the whole tree
+    #[serde(default)]
+    pub changed_only: bool,
+    // Remove files from the destination that aren't in the source (any more)
+    #[serde(default)]
+    pub delete: bool,
+    // Globs relative to the source that are neither copied nor deleted
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    // Only copy when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Remote host the files are pushed to over SSH, with its own login
+    pub host: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    // Private key to log in with; the ssh agent and default keys are used when unset
+    pub identity_file: Option<String>,
+    // known_hosts file the host key is checked against, instead of the user's own
+    pub known_hosts_file: Option<String>,
+    #[serde(default)]
+    pub method: RemoteMethod,
+}
+
+// How files get to a remote host
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteMethod {
+    // OpenSSH's sftp, uploading the files to copy
+    #[default]
+    Sftp,
+    // rsync over ssh, comparing the trees like a local copy does
+    Rsync,
+}
+
+impl Documented for CopyConfig {
+    const SECTION: &'static str = "[[post_sync.copy]]";
+    const ABOUT: &'static str =
+        "Directories the synced files are copied to after every successful pull, e.g. a web root.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "destination",
+            "string",
+            r#""/var/www/shop""#,
+            "Directory the files are copied to, outside the repo",
+        ),
+        schema::defaulted(
+            "source",
+            "string",
+            r#""""#,
+            "Directory of the repo to copy, e.g. \"public\" (empty means the whole repo)",
+        ),
+        schema::defaulted(
+            "changed_only",
+            "boolean",
+            "false",
+            "Copy only the files the sync changed in git, instead of every file that differs",
+        ),
+        schema::defaulted(
+            "delete",
+            "boolean",
+            "false",
+            "Remove files from the destination that aren't in the source",
+        ),
+        schema::defaulted(
+            "exclude",
+            "list of globs",
+            "[]",
+            "Paths relative to the source that are neither copied nor deleted, e.g. [\"uploads/**\"]",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only copy when a changed file matches one of these (empty means always)",
+        ),
+        schema::optional(
+            "host",
+            "string",
+            r#""kiosk-07.lan""#,
+            "Push to this host over SSH; destination is then a path on it",
+        ),
+        schema::defaulted("user", "string", "the ssh default", "SSH user on the host"),
+        schema::defaulted("port", "integer", "22", "SSH port of the host"),
+        schema::optional(
+            "identity_file",
+            "string",
+            r#""/etc/devops-sync/kiosk_ed25519""#,
+            "Private key for the host (key authentication only, no passwords)",
+        ),
+        schema::optional(
+            "known_hosts_file",
+            "string",
+            r#""/etc/devops-sync/known_hosts""#,
+            "known_hosts file the host key must be in",
+        ),
+        schema::defaulted(
+            "method",
+            "\"sftp\" or \"rsync\"",
+            r#""sftp""#,
+            "sftp uploads the files to copy; rsync over ssh only sends what differs",
+        ),
+    ];
+}
+
+// What a copy did
+#[derive(Default, Debug, PartialEq)]
+pub struct Copied {
+    pub copied: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+}
+
+impl CopyConfig {
+    // Refuses destinations that overlap the repo, which a delete could empty
+    pub fn check(&self, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.host.is_some() {
+            if self.destination.trim().is_empty() {
+                return Err("[[post_sync.copy]] to a host needs a destination".into());
+            }
+            return Ok(());
+        }
+        let absolute = |path: &str| {
+            std::path::absolute(path).map_err(|e| format!("Invalid path '{}': {}", path, e))
+        };
+        let destination = absolute(&self.destination)?;
+        let repo = absolute(repo_path)?;
+        if self.destination.trim().is_empty()
+            || destination.starts_with(&repo)
+            || repo.starts_with(&destination)
+        {
+            return Err(format!(
+                "[[post_sync.copy]] destination '{}' overlaps repo_path '{}'",
+                self.destination, repo_path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn excluded(&self, path: &str) -> bool {
+        // A pattern naming a directory covers everything inside it
+        let mut prefix = path;
+        loop {
+            if glob::matches_any(&self.exclude, prefix) {
+                return true;
+            }
+            match prefix.rfind('/') {
+                Some(at) => prefix = &prefix[..at],
+                None => return false,
+            }
+        }
+    }
+
+    // "user@host", or just the host to log in as ssh's default user
+    fn login(&self) -> String {
+        let host = self.host.as_deref().unwrap_or_default();
+        match &self.user {
+            Some(user) => format!("{}@{}", user, host),
+            None => host.to_string(),
+        }
+    }
+
+    // Where the files go, for logs and the startup summary
+    pub fn describe(&self) -> String {
+        match &self.host {
+            Some(_) => format!("{}:{}", self.login(), self.destination),
+            None => self.destination.clone(),
+        }
+    }
+
+    fn source_path(&self, repo_path: &str) -> PathBuf {
+        Path::new(repo_path).join(self.source.trim_matches('/'))
+    }
+}
+
+// Copies the synced files to the destination
+pub async fn run(
+    copy: &CopyConfig,
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Copied, Box<dyn std::error::Error>> {
+    let source = copy.source_path(repo_path);
+    if copy.host.is_some() {
+        return match copy.method {
+            RemoteMethod::Sftp => sftp(copy, &source, repo_path, old_commit, new_commit).await,
+            RemoteMethod::Rsync => rsync(copy, &source).await,
+        };
+    }
+    let destination = Path::new(&copy.destination);
+    let empty = fs::read_dir(destination).map_or(true, |mut entries| entries.next().is_none());
+
+    // A new destination has none of the files yet, so it gets all of them
+    if !copy.changed_only || empty {
+        if copy.changed_only {
+            info!("'{}' is empty, copying every file.", copy.destination);
+        }
+        return Ok(mirror(copy, &source, destination)?);
+    }
+
+    let mut copied = Copied::default();
+    for (path, deleted) in changes(copy, repo_path, old_commit, new_commit).await? {
+        let target = destination.join(&path);
+        if !deleted {
+            copy_file(&source.join(&path), &target)?;
+            copied.copied += 1;
+        } else if copy.delete && remove(&target, destination)? {
+            copied.deleted += 1;
+        }
+    }
+    Ok(copied)
+}
+
+// The files the sync changed below the source, relative to it, and whether each was deleted
+async fn changes(
+    copy: &CopyConfig,
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Vec<(String, bool)>, Box<dyn std::error::Error>> {
+    let prefix = match copy.source.trim_matches('/') {
+        "" => String::new(),
+        source => format!("{}/", source),
+    };
+    Ok(git::file_changes(repo_path, old_commit, new_commit)
+        .await?
+        .into_iter()
+        .filter_map(|change| {
+            let path = change.path.strip_prefix(&prefix)?.to_string();
+            (!copy.excluded(&path)).then_some((path, change.change == Change::Deleted))
+        })
+        .collect())
+}
+
+// Uploads the files with one sftp batch: every file of the source, or with changed_only the
+// files the sync changed, removing those it deleted when delete is set
+async fn sftp(
+    copy: &CopyConfig,
+    source: &Path,
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Copied, Box<dyn std::error::Error>> {
+    let (files, deleted) = if copy.changed_only {
+        let (deleted, changed): (Vec<_>, Vec<_>) = changes(copy, repo_path, old_commit, new_commit)
+            .await?
+            .into_iter()
+            .partition(|(_, deleted)| *deleted);
+        let deleted = if copy.delete { deleted } else { Vec::new() };
+        (
+            changed.into_iter().map(|(path, _)| path).collect(),
+            deleted.into_iter().map(|(path, _)| path).collect(),
+        )
+    } else {
+        (walk(copy, source)?.0, Vec::<String>::new())
+    };
+    if files.is_empty() && deleted.is_empty() {
+        return Ok(Copied::default());
+    }
+
+    let destination = copy.destination.trim_end_matches('/');
+    let batch = sftp_batch(destination, source, &files, &deleted);
+    let batch_file = post_sync::ScratchFile::create("sftp", repo_path, batch.as_bytes())?;
+
+    let mut args = vec![
+        "-b".to_string(),
+        batch_file.path().to_string_lossy().to_string(),
+    ];
+    if let Some(port) = copy.port {
+        args.extend(["-P".to_string(), port.to_string()]);
+    }
+    args.extend(ssh_options(copy));
+    args.push(copy.login());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    post_sync::run_command("sftp", &args, None).await?;
+
+    Ok(Copied {
+        copied: files.len(),
+        deleted: deleted.len(),
+        unchanged: 0,
+    })
+}
+
+// The sftp commands creating the directories (errors ignored with "-", as they may exist),
+// uploading the files and removing the deleted ones
+fn sftp_batch(destination: &str, source: &Path, files: &[String], deleted: &[String]) -> String {
+    let mut directories: Vec<&str> = files
+        .iter()
+        .flat_map(|file| file.match_indices('/').map(move |(at, _)| &file[..at]))
+        .collect();
+    directories.sort_unstable();
+    directories.dedup();
+
+    let mut batch = format!("-mkdir {}\n", sftp_quote(destination));
+    for directory in directories {
+        batch.push_str(&format!(
+            "-mkdir {}\n",
+            sftp_quote(&format!("{}/{}", destination, directory))
+        ));
+    }
+    for file in files {
+        batch.push_str(&format!(
+            "put -p {} {}\n",
+            sftp_quote(&source.join(file).to_string_lossy()),
+            sftp_quote(&format!("{}/{}", destination, file))
+        ));
+    }
+    for file in deleted {
+        batch.push_str(&format!(
+            "-rm {}\n",
+            sftp_quote(&format!("{}/{}", destination, file))
+        ));
+    }
+    batch
+}
+
+// Quotes a path for an sftp batch file
+fn sftp_quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Mirrors the source to the host with rsync over ssh, which compares the trees itself
+async fn rsync(copy: &CopyConfig, source: &Path) -> Result<Copied, Box<dyn std::error::Error>> {
+    let mut ssh = vec!["ssh".to_string()];
+    if let Some(port) = copy.port {
+        ssh.extend(["-p".to_string(), port.to_string()]);
+    }
+    ssh.extend(ssh_options(copy));
+
+    let mut args = vec![
+        "-rlt".to_string(),
+        "--stats".to_string(),
+        "--exclude=/.git".to_string(),
+        "-e".to_string(),
+        ssh.join(" "),
+    ];
+    args.extend(
+        copy.exclude
+            .iter()
+            .map(|pattern| format!("--exclude={}", pattern)),
+    );
+    if copy.delete {
+        args.push("--delete".to_string());
+    }
+    // The trailing slash copies the source's contents rather than the directory itself
+    args.push(format!(
+        "{}/",
+        source.to_string_lossy().trim_end_matches('/')
+    ));
+    args.push(format!(
+        "{}:{}/",
+        copy.login(),
+        copy.destination.trim_end_matches('/')
+    ));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let stats = post_sync::run_command("rsync", &args, None).await?;
+
+    let count = |label: &str| {
+        stats
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(label))
+            // e.g. "Number of files: 1,204 (reg: 1,180, dir: 24)"
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.replace(',', "").parse().ok())
+            .unwrap_or(0)
+    };
+    let files: usize = count("Number of files:");
+    let copied = count("Number of regular files transferred:");
+    Ok(Copied {
+        copied,
+        deleted: count("Number of deleted files:"),
+        unchanged: files.saturating_sub(copied),
+    })
+}
+
+// Options shared by ssh, sftp and rsync's ssh: never prompt, and the configured key and
+// known_hosts file
+fn ssh_options(copy: &CopyConfig) -> Vec<String> {
+    let mut options = vec!["-o".to_string(), "BatchMode=yes".to_string()];
+    if let Some(identity) = &copy.identity_file {
+        options.extend(["-i".to_string(), identity.clone()]);
+    }
+    if let Some(known_hosts) = &copy.known_hosts_file {
+        options.extend([
+            "-o".to_string(),
+            format!("UserKnownHostsFile={}", known_hosts),
+        ]);
+    }
+    options
+}
+
+// Makes the destination match the source, copying only the files whose size or modification
+// time differ
+fn mirror(copy: &CopyConfig, source: &Path, destination: &Path) -> io::Result<Copied> {
+    let mut copied = Copied::default();
+    let (files, _) = walk(copy, source)?;
+    for path in &files {
+        let target = destination.join(path);
+        if same_file(&source.join(path), &target) {
+            copied.unchanged += 1;
+        } else {
+            copy_file(&source.join(path), &target)?;
+            copied.copied += 1;
+        }
+    }
+
+    if copy.delete && destination.exists() {
+        let keep: HashSet<&String> = files.iter().collect();
+        let (present, directories) = walk(copy, destination)?;
+        for path in present.iter().filter(|path| !keep.contains(path)) {
+            fs::remove_file(destination.join(path))?;
+            copied.deleted += 1;
+        }
+        // Deepest first, so emptied parents go too; directories still holding files stay
+        for directory in directories.iter().rev() {
+            let _ = fs::remove_dir(destination.join(directory));
+        }
+    }
+    Ok(copied)
+}
+
+// Every file and directory below the root, as "/"-separated relative paths, skipping .git and
+// excluded paths. Directories are listed parents first.
+fn walk(copy: &CopyConfig, root: &Path) -> io::Result<(Vec<String>, Vec<String>)> {
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(directory) = pending.pop() {
+        for entry in fs::read_dir(root.join(&directory))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = if directory.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", directory, name)
+            };
+            if name == ".git" || copy.excluded(&path) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(path.clone());
+                pending.push(path);
+            } else if !(file_type.is_symlink() && entry.path().is_dir()) {
+                files.push(path);
+            }
+        }
+    }
+    directories.sort();
+    Ok((files, directories))
+}
+
+// Whether the target already has the file, judged like rsync by size and modification time
+fn same_file(source: &Path, target: &Path) -> bool {
+    match (fs::metadata(source), fs::metadata(target)) {
+        (Ok(source), Ok(target)) => {
+            source.len() == target.len()
+                && matches!((source.modified(), target.modified()), (Ok(a), Ok(b)) if a == b)
+        }
+        _ => false,
+    }
+}
+
+// Copies a file, carrying its modification time over so the next comparison can skip it
+fn copy_file(source: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(source, target)?;
+    let modified = fs::metadata(source)?.modified()?;
+    File::options()
+        .write(true)
+        .open(target)?
+        .set_modified(modified)
+}
+
+// Removes a file and the directories it leaves empty, up to the destination
+fn remove(target: &Path, destination: &Path) -> io::Result<bool> {
+    match fs::remove_file(target) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    let mut parent = target.parent();
+    while let Some(directory) = parent.filter(|directory| *directory != destination) {
+        if fs::remove_dir(directory).is_err() {
+            break;
+        }
+        parent = directory.parent();
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_copies_differences_and_keeps_excluded_files() {
+        let root = std::env::temp_dir().join(format!("sync-copy-test-{}", std::process::id()));
+        let (source, destination) = (root.join("repo"), root.join("www"));
+        for (path, content) in [
+            ("index.html", "home"),
+            ("css/site.css", "body {}"),
+            (".git/HEAD", "ref"),
+        ] {
+            fs::create_dir_all(source.join(path).parent().unwrap()).unwrap();
+            fs::write(source.join(path), content).unwrap();
+        }
+        for (path, content) in [("old/gone.html", "old"), ("uploads/photo.jpg", "jpg")] {
+            fs::create_dir_all(destination.join(path).parent().unwrap()).unwrap();
+            fs::write(destination.join(path), content).unwrap();
+        }
+        let copy = CopyConfig {
+            destination: destination.to_string_lossy().to_string(),
+            source: String::new(),
+            changed_only: false,
+            delete: true,
+            exclude: vec!["uploads".to_string()],
+            paths: Vec::new(),
+            host: None,
+            user: None,
+            port: None,
+            identity_file: None,
+            known_hosts_file: None,
+            method: RemoteMethod::Sftp,
+        };
+
+        let first = mirror(&copy, &source, &destination).unwrap();
+        let second = mirror(&copy, &source, &destination).unwrap();
+        let kept = destination.join("uploads/photo.jpg").exists();
+        let gone = destination.join("old").exists();
+        let git = destination.join(".git").exists();
+        let css = fs::read_to_string(destination.join("css/site.css")).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(
+            first,
+            Copied {
+                copied: 2,
+                deleted: 1,
+                unchanged: 0
+            }
+        );
+        assert_eq!(
+            second,
+            Copied {
+                copied: 0,
+                deleted: 0,
+                unchanged: 2
+            }
+        );
+        assert!(kept && !gone && !git);
+        assert_eq!(css, "body {}");
+    }
+
+    #[test]
+    fn sftp_batch_creates_directories_before_uploading() {
+        let batch = sftp_batch(
+            "/srv/kiosk",
+            Path::new("/repo"),
+            &["css/site.css".to_string(), "say \"hi\".txt".to_string()],
+            &["old.html".to_string()],
+        );
+        assert_eq!(
+            batch,
+            "-mkdir \"/srv/kiosk\"\n\
+             -mkdir \"/srv/kiosk/css\"\n\
+             put -p \"/repo/css/site.css\" \"/srv/kiosk/css/site.css\"\n\
+             put -p \"/repo/say \\\"hi\\\".txt\" \"/srv/kiosk/say \\\"hi\\\".txt\"\n\
+             -rm \"/srv/kiosk/old.html\"\n"
+        );
+    }
+}
@@ -0,0 +1,1970 @@
+// One repository's sync loop, stage by stage. Each check follows the default branch and the
+// repository's location, looks for drift, probes the provider and reads the manifest. It then
+// finds the remote commit, decides whether it may be pulled, and pulls and deploys it. Finally it
+// reports the cycle and waits for the next one, handling the control commands that end the wait.
+use crate::events::{self, Directive, SyncEvent};
+use crate::history::{PullRequestRef, SyncRecord, SyncStatus};
+use crate::{
+    alert, audit, audit_key, azure, batch, change, check_drift, console, control, delay_remaining,
+    deployment, describe_assignment, fallback_branch, fetch_changes, force_pushed_from,
+    get_latest_commit, get_local_commit, git, halt_reason, history, ignore, is_ancestor, jira,
+    logging, manifest, metrics, monorepo, network, notify, ordering, paths, pipelines, plugins,
+    policy, post_sync, pull_in_batch, read_assignment, releases, reload_credentials, rules,
+    secrets, settle_remaining, startup_summary, state, terraform, verify_checkout,
+    wait_for_next_check, write_state, AppConfig, CheckoutMode, Compare, ForcePush, SyncMode,
+    SyncOn, Wake, AUTO_BRANCH, BUILD_POLL_INTERVAL, OFFLINE_PROBE_INTERVAL, REMOTE_REFRESH,
+};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use reqwest::Client;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex};
+
+// What the loop keeps from one check to the next
+pub struct RepoSync {
+    config: AppConfig,
+    client: Client,
+    // Concurrency group the repo takes turns syncing in, if any
+    group: Option<(String, Rc<Mutex<()>>)>,
+    links: ordering::Links,
+    batch: Option<batch::Batch>,
+    // History, notifications, alerts, change records, Jira, deployment statuses, policies, rules
+    // and plugins all follow the sync through its events
+    events: events::EventBus,
+    virtual_repos: Vec<monorepo::VirtualRepo>,
+    // Hooks and restarts held back by their min_interval_seconds
+    cooldowns: post_sync::Cooldowns,
+    // Probed the way the API client connects, so a broken address family shows up as offline
+    resolution: network::Resolution,
+    interval_script: Option<rules::IntervalScript>,
+    // Whether the rules need the incoming commits listed before the pull
+    list_incoming: bool,
+    // With several repos sharing the console, the status line says which one it is about
+    ticker_prefix: String,
+    // "auto" follows the repository's default branch, looked up now and re-checked now and then
+    auto_branch: bool,
+    branch_resolved: Instant,
+    // Branch configured (or the default one), which the manifest is read from and which
+    // agents without an assignment follow
+    home_branch: String,
+    // When the name of a repository known by its ID was last looked up, so a rename or a move to
+    // another project is followed. A failed pull or a 404 from the API clears it, since either
+    // is what a move leads to.
+    name_resolved: Option<Instant>,
+    // When the last change was pulled. Time spans are measured on the monotonic clock, so NTP
+    // corrections and manual clock changes can't make them negative or jump; the wall-clock
+    // time is only kept for display.
+    last_change: Instant,
+    last_change_at: DateTime<Utc>,
+    // Remote commit whose deployment was rolled back, held off until the remote moves on
+    rolled_back_commit: Option<String>,
+    // Remote commit a plugin or policy aborted before the pull, not tried again until the remote
+    // moves on, so one abort is recorded and notified once instead of every interval
+    aborted_commit: Option<String>,
+    // Whether the target branch has had a commit, so a later "not found" means it was deleted
+    branch_seen: bool,
+    // Remote commit the repo was last confirmed to be at, so unchanged cycles can skip git
+    in_sync_with: Option<String>,
+    // Commit the checkout was left at by the last sync or check, what drift is measured against
+    synced_commit: Option<String>,
+    drift_checked: Instant,
+    // Differences already reported, so lasting drift isn't reported every time it's seen
+    reported_drift: Vec<String>,
+    // Remote commit already reported as not pulled in observe mode
+    reported_behind: Option<String>,
+    // Since when the provider has been unreachable, with check_connectivity
+    offline_since: Option<(Instant, DateTime<Utc>)>,
+    // Commit of home_branch the manifest was last read at, and what it assigned this agent
+    manifest_read_at: Option<String>,
+    assignment: Option<manifest::Assignment>,
+    // Remote commit last checked for the halt file, with the halt reason if it had the file
+    halt_checked: Option<(String, Option<String>)>,
+    // Local and remote commit last compared with compare = "ancestry", and whether the local one
+    // was ahead
+    ancestry_checked: Option<(String, String, bool)>,
+    // Remote commit last checked for a force push, with the tip it replaced if it was one
+    force_push_checked: Option<(String, Option<String>)>,
+    // Force-pushed remote commit an operator approved syncing to, with force_push = "hold"
+    force_push_approved: Option<String>,
+    // Remote commit last checked against ignore_authors and ignore_paths, and why its new
+    // commits can be ignored if they can
+    ignore_checked: Option<(String, Option<String>)>,
+    // Remote commit whose builds finished, and whether they passed (None) or why not. Failed
+    // builds are looked up again every cycle, since one may be retried.
+    build_checked: Option<(String, Option<String>)>,
+    // Remote commit waiting out sync_delay_seconds, and when it was first seen
+    delayed: Option<(String, Instant)>,
+    // Remote commit the branch was last seen moving to, and when, for settle_seconds
+    settling: Option<(String, Instant)>,
+}
+
+// What one check found, for the state file and the wait after it
+#[derive(Default)]
+struct Cycle {
+    timings: metrics::Timings,
+    // Commit the manifest or a completed pull request pins the checkout to
+    pin: Option<String>,
+    // Pull requests completed into the branch, when syncing on them
+    completed_pull_requests: Vec<PullRequestRef>,
+    // Whether the cycle ends with the checkout at the remote commit, for repos syncing after
+    settled: bool,
+    // Local and remote commits seen and what went wrong, for the state file
+    local_seen: Option<String>,
+    remote_seen: Option<String>,
+    failure: Option<String>,
+    // Set when the next check is due before the interval is up
+    recheck_in: Option<Duration>,
+}
+
+// Why the remote commit may not be pulled yet. Each hold is only looked into while the ones
+// before it don't apply.
+#[derive(Default)]
+struct Holds {
+    rolled_back: bool,
+    aborted: bool,
+    halt: Option<String>,
+    // The tip a force push replaced
+    force_pushed: Option<String>,
+    force_push_held: bool,
+    ignored: Option<String>,
+    delay: Option<Duration>,
+    unsettled: Option<Duration>,
+    build: Option<String>,
+    waiting_on: Option<String>,
+}
+
+// What a check does about the remote commit
+#[derive(Debug, PartialEq)]
+enum Decision {
+    // Behind in observe mode, reported but not pulled
+    Observe,
+    RolledBack,
+    Aborted,
+    Halted(String),
+    // Force-pushed over this commit, waiting for approve-force-push
+    ForcePushHeld(String),
+    // Nothing worth deploying is missing
+    Ignored(String),
+    Delayed(Duration),
+    Unsettled(Duration),
+    BuildHeld(String),
+    WaitingOn(String),
+    Pull,
+    UpToDate { ahead: bool },
+}
+
+// The first hold that applies, or whether to pull
+fn decide(mode: SyncMode, behind: bool, ahead: bool, holds: &Holds) -> Decision {
+    if mode == SyncMode::Observe && behind {
+        Decision::Observe
+    } else if holds.rolled_back {
+        Decision::RolledBack
+    } else if behind && holds.aborted {
+        Decision::Aborted
+    } else if let Some(reason) = &holds.halt {
+        Decision::Halted(reason.clone())
+    } else if let (true, Some(previous)) = (holds.force_push_held, &holds.force_pushed) {
+        Decision::ForcePushHeld(previous.clone())
+    } else if let Some(reason) = &holds.ignored {
+        Decision::Ignored(reason.clone())
+    } else if let Some(remaining) = holds.delay {
+        Decision::Delayed(remaining)
+    } else if let Some(remaining) = holds.unsettled {
+        Decision::Unsettled(remaining)
+    } else if let Some(reason) = &holds.build {
+        Decision::BuildHeld(reason.clone())
+    } else if let Some(reason) = &holds.waiting_on {
+        Decision::WaitingOn(reason.clone())
+    } else if behind {
+        Decision::Pull
+    } else {
+        Decision::UpToDate { ahead }
+    }
+}
+
+impl Decision {
+    // The status line for a commit that isn't pulled; pulls and up-to-date checks say more
+    fn ticker(&self, local: &str, remote: &str) -> Option<String> {
+        Some(match self {
+            Decision::Observe => format!(
+                "Behind the remote: local {}, remote {} (observe mode, not pulling).",
+                local, remote
+            ),
+            Decision::RolledBack => format!(
+                "Holding at {} because deploying {} was rolled back.",
+                local, remote
+            ),
+            Decision::Aborted => format!(
+                "Holding at {} because syncing {} was aborted, waiting for a new commit.",
+                local, remote
+            ),
+            Decision::Halted(reason) => {
+                format!("Halted at {}, not pulling {}: {}", local, remote, reason)
+            }
+            Decision::ForcePushHeld(previous) => format!(
+                "Holding at {}: {} was force-pushed over {}, waiting for approve-force-push.",
+                local, remote, previous
+            ),
+            Decision::Ignored(reason) => format!(
+                "Staying at {}, ignoring {}: only {}.",
+                local, remote, reason
+            ),
+            Decision::Delayed(remaining) => format!(
+                "Holding {} for another {}s before syncing (sync_delay_seconds).",
+                remote,
+                remaining.as_secs()
+            ),
+            Decision::Unsettled(remaining) => format!(
+                "Waiting for the remote to settle at {}, {}s to go (settle_seconds).",
+                remote,
+                remaining.as_secs()
+            ),
+            Decision::BuildHeld(reason) => format!("Holding {}, {}.", remote, reason),
+            Decision::WaitingOn(reason) => format!(
+                "Holding {} until the repos it syncs after are ready: {}.",
+                remote, reason
+            ),
+            Decision::Pull | Decision::UpToDate { .. } => return None,
+        })
+    }
+}
+
+impl RepoSync {
+    // Prepares the checkout and the event subscribers, and looks up the branch to follow
+    pub async fn start(
+        mut config: AppConfig,
+        client: Client,
+        group: Option<(String, Rc<Mutex<()>>)>,
+        links: ordering::Links,
+        batch: Option<batch::Batch>,
+        several: bool,
+    ) -> Result<RepoSync, Box<dyn std::error::Error>> {
+        config.repo_path = paths::normalize_repo_path(&config.repo_path);
+        if paths::is_unc(&config.repo_path) {
+            info!("Repo is on a network share: {}", config.repo_path);
+        }
+
+        // Fail now with a clear message rather than with opaque git errors every cycle
+        git::ensure_safe_directory(&config.repo_path, config.add_safe_directory).await?;
+        // Observing leaves even the repo's git config alone
+        if config.mode == SyncMode::Sync {
+            if let Err(e) = git::enable_long_paths(&config.repo_path).await {
+                error!("Failed to enable core.longpaths: {}", e);
+            }
+        }
+
+        let auto_branch = config.target_branch == AUTO_BRANCH;
+        if auto_branch {
+            config.target_branch = config.default_branch(&client).await?;
+            logging::set_branch(&config.target_branch);
+            info!("Following default branch '{}'.", config.target_branch);
+        }
+        metrics::add_agent_labels(&config.agent_labels);
+
+        let summary = startup_summary(&config, auto_branch);
+        for line in &summary {
+            info!("{}", line);
+        }
+        console::block(&summary);
+
+        let ticker_prefix = if several {
+            format!("[{}] ", config.repository)
+        } else {
+            String::new()
+        };
+        let virtual_repos = std::mem::take(&mut config.virtual_repos)
+            .into_iter()
+            .map(|virtual_repo| monorepo::VirtualRepo::new(virtual_repo, &client))
+            .collect();
+        let resolution = config.resolution()?;
+        // Rules decide before the pull, on the commits it would bring in
+        let list_incoming = !config.rules.is_empty();
+        let interval_script = config
+            .interval_script
+            .as_deref()
+            .map(rules::IntervalScript::new)
+            .transpose()?;
+        let events = subscribe(&mut config, &client)?;
+
+        Ok(RepoSync {
+            home_branch: config.target_branch.clone(),
+            config,
+            client,
+            group,
+            links,
+            batch,
+            events,
+            virtual_repos,
+            cooldowns: post_sync::Cooldowns::default(),
+            resolution,
+            interval_script,
+            list_incoming,
+            ticker_prefix,
+            auto_branch,
+            branch_resolved: Instant::now(),
+            name_resolved: Some(Instant::now()),
+            last_change: Instant::now(),
+            last_change_at: Utc::now(),
+            rolled_back_commit: None,
+            aborted_commit: None,
+            branch_seen: false,
+            in_sync_with: None,
+            synced_commit: None,
+            drift_checked: Instant::now(),
+            reported_drift: Vec::new(),
+            reported_behind: None,
+            offline_since: None,
+            manifest_read_at: None,
+            assignment: None,
+            halt_checked: None,
+            ancestry_checked: None,
+            force_push_checked: None,
+            force_push_approved: None,
+            ignore_checked: None,
+            build_checked: None,
+            delayed: None,
+            settling: None,
+        })
+    }
+
+    // One check of the remote, then the wait for the next one
+    pub async fn run_cycle(
+        &mut self,
+        control_rx: &mut broadcast::Receiver<control::ControlCommand>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        logging::start_cycle();
+        self.follow_default_branch().await;
+        self.follow_location().await;
+        self.watch_drift().await;
+
+        if !self.probe().await? {
+            if let Wake::Command(control::ControlCommand::ReloadCredentials) =
+                self.wait(OFFLINE_PROBE_INTERVAL, control_rx).await
+            {
+                reload_credentials(&mut self.config);
+            }
+            return Ok(());
+        }
+
+        self.read_manifest().await;
+        logging::set_branch(&self.config.target_branch);
+        let mut cycle = Cycle {
+            pin: self
+                .assignment
+                .as_ref()
+                .and_then(|assignment| assignment.commit.clone()),
+            ..Cycle::default()
+        };
+        self.events
+            .publish(SyncEvent::SyncStarted, &self.config.repo_ref())
+            .await;
+
+        let remote_commit = self.remote_commit(&mut cycle).await;
+        self.check_remote(remote_commit, &mut cycle).await?;
+        self.run_deferred().await;
+        self.report(&cycle);
+
+        let wake = self.wait(self.next_wait(&cycle), control_rx).await;
+        self.handle(wake).await;
+        Ok(())
+    }
+
+    async fn follow_default_branch(&mut self) {
+        if !self.auto_branch || self.branch_resolved.elapsed() < REMOTE_REFRESH {
+            return;
+        }
+        self.branch_resolved = Instant::now();
+        match self.config.default_branch(&self.client).await {
+            Ok(branch) if branch != self.home_branch => {
+                info!(
+                    "Default branch changed from '{}' to '{}', following it.",
+                    self.home_branch, branch
+                );
+                self.home_branch = branch.clone();
+                self.config.target_branch = branch;
+                self.in_sync_with = None;
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to re-check the default branch: {}", e),
+        }
+    }
+
+    // Follows a rename or a move to another project of a repository known by its ID
+    async fn follow_location(&mut self) {
+        if self.config.repository_id.is_none()
+            || self
+                .name_resolved
+                .is_some_and(|resolved| resolved.elapsed() < REMOTE_REFRESH)
+        {
+            return;
+        }
+        self.name_resolved = Some(Instant::now());
+        match self.config.resolve_repository(&self.client).await {
+            Ok(Some(previous)) => {
+                if self.config.update_remote_url {
+                    let url = azure::repository_url(
+                        &self.config.organization,
+                        &self.config.project,
+                        &self.config.repository,
+                    );
+                    match git::set_origin_url(&self.config.repo_path, &url).await {
+                        Ok(true) => info!("Pointed origin at {}.", url),
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to point origin at {}: {}", url, e),
+                    }
+                }
+                self.events
+                    .publish(
+                        SyncEvent::RepositoryMoved {
+                            old_location: &previous,
+                            new_location: &self.config.location(),
+                        },
+                        &self.config.repo_ref(),
+                    )
+                    .await;
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to re-check the repository's location: {}", e),
+        }
+    }
+
+    // Compares the checkout with the commit it was left at, every drift_check_seconds
+    async fn watch_drift(&mut self) {
+        let (Some(seconds), Some(commit)) = (self.config.drift_check_seconds, &self.synced_commit)
+        else {
+            return;
+        };
+        if self.drift_checked.elapsed() < Duration::from_secs(seconds) {
+            return;
+        }
+        self.drift_checked = Instant::now();
+        let commit = commit.clone();
+        let drift = check_drift(&self.config, &commit).await;
+        metrics::set_gauge(
+            "devops_sync_drifted_files",
+            &self.config.repository,
+            drift.as_ref().map_or(0, Vec::len) as f64,
+        );
+        match drift {
+            Ok(differences) if differences.is_empty() => self.reported_drift.clear(),
+            Ok(differences) => {
+                let repaired = self.config.reset_on_conflict && self.config.mode == SyncMode::Sync;
+                if differences != self.reported_drift || repaired {
+                    self.events
+                        .publish(
+                            SyncEvent::DriftDetected {
+                                commit: &commit,
+                                differences: &differences,
+                                repaired,
+                            },
+                            &self.config.repo_ref(),
+                        )
+                        .await;
+                }
+                self.reported_drift = if repaired { Vec::new() } else { differences };
+                // Left alone, HEAD may have moved, so the next check has to look at git
+                self.in_sync_with = None;
+            }
+            Err(e) => error!("Failed to check for drift: {}", e),
+        }
+    }
+
+    // Whether the provider can be reached, with check_connectivity. While it can't, the cycle is
+    // reported as offline and goes no further.
+    async fn probe(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.config.check_connectivity {
+            return Ok(true);
+        }
+        let (host, port) = azure::server_host();
+        let probe = network::probe(&host, port, &self.resolution).await;
+        metrics::set_gauge(
+            "devops_sync_online",
+            &self.config.repository,
+            f64::from(u8::from(probe.is_ok())),
+        );
+        let e = match probe {
+            Ok(()) => {
+                if let Some((since, _)) = self.offline_since.take() {
+                    info!(
+                        "{} is reachable again after {} seconds offline, checking now.",
+                        host,
+                        since.elapsed().as_secs()
+                    );
+                }
+                return Ok(true);
+            }
+            Err(e) => e,
+        };
+        // Said once when going offline, the status line covers the rest
+        let (_, since_at) = *self.offline_since.get_or_insert_with(|| {
+            warn!(
+                "Offline, {} is unreachable ({}). Waiting for the network.",
+                host, e
+            );
+            (Instant::now(), Utc::now())
+        });
+        console::ticker(&format!(
+            "{}Offline since {}: {}.",
+            self.ticker_prefix,
+            since_at.format("%Y-%m-%d %H:%M:%S"),
+            e
+        ))?;
+        self.links.report(false);
+        if let Some(batch) = &self.batch {
+            batch.report(Err("is offline".to_string()));
+        }
+        write_state(
+            &self.config,
+            self.synced_commit.as_deref(),
+            None,
+            state::Status::Offline,
+            Some(&e.to_string()),
+            self.last_change_at,
+        );
+        Ok(false)
+    }
+
+    // Reads what the manifest assigns this agent, whenever home_branch has moved, and follows the
+    // branch it names
+    async fn read_manifest(&mut self) {
+        let Some(path) = self.config.manifest_file.clone() else {
+            return;
+        };
+        match get_latest_commit(&self.client, &self.config, &self.home_branch).await {
+            Ok(commit) if self.manifest_read_at.as_deref() != Some(commit.as_str()) => {
+                match read_assignment(&self.client, &self.config, &path, &commit).await {
+                    Ok(found) => {
+                        if found != self.assignment {
+                            info!("{}", describe_assignment(&path, found.as_ref()));
+                            self.in_sync_with = None;
+                        }
+                        self.assignment = found;
+                        self.manifest_read_at = Some(commit);
+                    }
+                    // The last good assignment stays in force until the manifest is fixed
+                    Err(e) => error!("Failed to read the manifest {}: {}", path, e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!(
+                "Failed to check '{}' for the manifest: {}",
+                self.home_branch, e
+            ),
+        }
+        self.config.target_branch = self
+            .assignment
+            .as_ref()
+            .and_then(|assignment| assignment.branch.clone())
+            .unwrap_or_else(|| self.home_branch.clone());
+    }
+
+    // The commit to converge to: the pinned one, the merge commit of the latest completed pull
+    // request, or the tip of the branch
+    async fn remote_commit(
+        &mut self,
+        cycle: &mut Cycle,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        match &cycle.pin {
+            // Pinned agents converge to the commit, wherever the branch has moved to
+            Some(commit) => Ok(commit.clone()),
+            None if self.config.sync_on == SyncOn::PullRequests => {
+                let started = Instant::now();
+                let completed = azure::completed_pull_requests(
+                    &self.client,
+                    &self.config.repo_ref(),
+                    &self.config.api_version,
+                )
+                .await;
+                metrics::record(&mut cycle.timings, "api_check", started);
+                let completed = completed?;
+                match completed.first() {
+                    // The checkout goes to the pull request's merge commit, like a pin
+                    Some(latest) => {
+                        let commit = latest.merge_commit.clone();
+                        cycle.pin = Some(commit.clone());
+                        cycle.completed_pull_requests = completed;
+                        Ok(commit)
+                    }
+                    None => Err(format!(
+                        "no pull request into '{}' has been completed yet",
+                        self.config.target_branch
+                    )
+                    .into()),
+                }
+            }
+            None => {
+                let started = Instant::now();
+                let latest_commit =
+                    get_latest_commit(&self.client, &self.config, &self.config.target_branch).await;
+                metrics::record(&mut cycle.timings, "api_check", started);
+                latest_commit
+            }
+        }
+    }
+
+    // Compares the remote commit with the checkout, or handles why there is none
+    async fn check_remote(
+        &mut self,
+        remote_commit: Result<String, Box<dyn std::error::Error>>,
+        cycle: &mut Cycle,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match remote_commit {
+            Ok(remote_commit) => {
+                self.branch_seen = true;
+                cycle.remote_seen = Some(remote_commit.clone());
+                // Quiet repos cost a single API request, git only runs once the remote has moved
+                let local_commit = match &self.in_sync_with {
+                    Some(commit) if *commit == remote_commit => Ok(commit.clone()),
+                    _ => get_local_commit(&self.config).await,
+                };
+                match local_commit {
+                    Ok(local_commit) => {
+                        self.check_commits(&local_commit, &remote_commit, cycle)
+                            .await?
+                    }
+                    Err(e) => {
+                        error!("Failed to get local commit: {}", e);
+                        self.check_failed(&e.to_string(), cycle).await;
+                    }
+                }
+            }
+            Err(e) if self.branch_seen && e.is::<azure::BranchError>() => {
+                error!("Target branch is gone from the remote: {}", e);
+                cycle.failure = Some(e.to_string());
+                self.follow_fallback().await;
+            }
+            // A brand new repo or branch isn't an outage, keep checking until it has a commit
+            Err(e) if self.config.wait_for_first_commit && e.is::<azure::BranchError>() => {
+                info!("Waiting for the first commit: {}", e);
+                console::ticker(&format!(
+                    "{}Waiting for the first commit: {}.",
+                    self.ticker_prefix, e
+                ))?;
+            }
+            Err(e) => {
+                error!("Failed to get latest commit from remote: {}", e);
+                self.check_failed(&e.to_string(), cycle).await;
+                if e.is::<secrets::AuthError>() {
+                    reload_credentials(&mut self.config);
+                }
+                if e.is::<azure::RepositoryNotFound>() {
+                    self.name_resolved = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_failed(&mut self, error: &str, cycle: &mut Cycle) {
+        cycle.failure = Some(error.to_string());
+        self.events
+            .publish(SyncEvent::CheckFailed { error }, &self.config.repo_ref())
+            .await;
+    }
+
+    // The target branch was deleted: follows the default branch, or the branch the fallback
+    // settings name, if there is one
+    async fn follow_fallback(&mut self) {
+        let missing = self.config.target_branch.clone();
+        let fallback = fallback_branch(&self.client, &self.config).await;
+        self.events
+            .publish(
+                SyncEvent::BranchMissing {
+                    branch: &missing,
+                    fallback: fallback.as_deref(),
+                },
+                &self.config.repo_ref(),
+            )
+            .await;
+        match fallback {
+            Some(branch) => {
+                warn!("Following branch '{}' instead of '{}'.", branch, missing);
+                self.home_branch = branch.clone();
+                self.config.target_branch = branch;
+                self.in_sync_with = None;
+            }
+            // Reported once, from here on it is an ordinary failed check
+            None => self.branch_seen = false,
+        }
+    }
+
+    // Decides what to do about the remote commit and does it
+    async fn check_commits(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        cycle: &mut Cycle,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        cycle.local_seen = Some(local_commit.to_string());
+        let ahead = self.is_ahead(local_commit, remote_commit).await;
+        let behind = remote_commit != local_commit && !ahead;
+        metrics::set_gauge(
+            "devops_sync_behind",
+            &self.config.repository,
+            f64::from(u8::from(behind)),
+        );
+        let holds = self.holds(local_commit, remote_commit, behind, cycle).await;
+        let decision = decide(self.config.mode, behind, ahead, &holds);
+        if let Some(line) = decision.ticker(local_commit, remote_commit) {
+            console::ticker(&format!("{}{}", self.ticker_prefix, line))?;
+        }
+        match decision {
+            Decision::Observe => self.report_behind(local_commit, remote_commit).await,
+            // Repos waiting on this one may go ahead
+            Decision::Ignored(_) => cycle.settled = true,
+            // Checked again as soon as the branch could have settled
+            Decision::Unsettled(remaining) => cycle.recheck_in = Some(remaining),
+            Decision::Pull => {
+                let force =
+                    holds.force_pushed.is_some() && self.config.force_push != ForcePush::Merge;
+                self.sync(local_commit, remote_commit, force, cycle).await
+            }
+            Decision::UpToDate { ahead } => {
+                self.up_to_date(local_commit, remote_commit, ahead, cycle)
+                    .await?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Whether the checkout is ahead of the remote commit, with compare = "ancestry"
+    async fn is_ahead(&mut self, local_commit: &str, remote_commit: &str) -> bool {
+        if self.config.compare != Compare::Ancestry || remote_commit == local_commit {
+            return false;
+        }
+        match &self.ancestry_checked {
+            Some((local, remote, ahead)) if local == local_commit && remote == remote_commit => {
+                *ahead
+            }
+            _ => {
+                let ahead = is_ancestor(&self.config, remote_commit, local_commit).await;
+                if ahead {
+                    info!(
+                        "Local {} is ahead of the remote {}, nothing to pull.",
+                        local_commit, remote_commit
+                    );
+                }
+                self.ancestry_checked =
+                    Some((local_commit.to_string(), remote_commit.to_string(), ahead));
+                ahead
+            }
+        }
+    }
+
+    // Looks into what could hold the commit back, in order, stopping at the first that does
+    async fn holds(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        behind: bool,
+        cycle: &mut Cycle,
+    ) -> Holds {
+        let syncing = self.config.mode == SyncMode::Sync && behind;
+        let mut holds = Holds {
+            rolled_back: self.rolled_back_commit.as_deref() == Some(remote_commit),
+            aborted: self.aborted_commit.as_deref() == Some(remote_commit),
+            ..Holds::default()
+        };
+        if syncing {
+            holds.halt = self.halt(local_commit, remote_commit).await;
+        }
+        if syncing && cycle.pin.is_none() && holds.halt.is_none() {
+            holds.force_pushed = self.force_push(remote_commit).await;
+        }
+        holds.force_push_held = holds.force_pushed.is_some()
+            && self.config.force_push == ForcePush::Hold
+            && self.force_push_approved.as_deref() != Some(remote_commit);
+
+        let open = syncing && holds.halt.is_none() && !holds.force_push_held;
+        if open && (!self.config.ignore_authors.is_empty() || !self.config.ignore_paths.is_empty())
+        {
+            holds.ignored = self
+                .ignored(local_commit, remote_commit, &mut cycle.timings)
+                .await;
+        }
+        let open = open && holds.ignored.is_none();
+        if open {
+            holds.unsettled = settle_remaining(
+                &mut self.settling,
+                remote_commit,
+                self.config.settle_seconds,
+            );
+        }
+        if open && !holds.rolled_back && !holds.aborted {
+            holds.delay = delay_remaining(
+                &mut self.delayed,
+                remote_commit,
+                self.config.sync_delay_seconds,
+            );
+        }
+        let open = open && holds.unsettled.is_none();
+        if open && self.config.wait_for_build {
+            holds.build = self.build_hold(remote_commit, cycle).await;
+        }
+        // Only asked when this repo would otherwise pull now
+        if open
+            && holds.build.is_none()
+            && !holds.rolled_back
+            && !holds.aborted
+            && self.links.has_dependencies()
+        {
+            holds.waiting_on = self.links.wait_for_dependencies().await.err();
+        }
+        holds
+    }
+
+    // The halt reason, if the remote has the halt file. Checked once per remote commit.
+    async fn halt(&mut self, local_commit: &str, remote_commit: &str) -> Option<String> {
+        if let Some((commit, reason)) = &self.halt_checked {
+            if commit == remote_commit {
+                return reason.clone();
+            }
+        }
+        match halt_reason(&self.client, &self.config, remote_commit).await {
+            Ok(reason) => {
+                if let Some(reason) = &reason {
+                    warn!(
+                        "Halt file found on the remote at {}, holding at {}: {}",
+                        remote_commit, local_commit, reason
+                    );
+                    self.events
+                        .publish(
+                            SyncEvent::Halted {
+                                local_commit,
+                                remote_commit,
+                                reason,
+                            },
+                            &self.config.repo_ref(),
+                        )
+                        .await;
+                }
+                self.halt_checked = Some((remote_commit.to_string(), reason.clone()));
+                reason
+            }
+            // Not knowing is treated as halted, and asked again next cycle
+            Err(e) => {
+                error!("Failed to look for the halt file: {}", e);
+                Some(format!("the halt file couldn't be checked: {}", e))
+            }
+        }
+    }
+
+    // The tip the remote commit replaced, if it was force-pushed. Checked once per remote commit,
+    // and reported when it rewrote the branch.
+    async fn force_push(&mut self, remote_commit: &str) -> Option<String> {
+        if let Some((commit, previous)) = &self.force_push_checked {
+            if commit == remote_commit {
+                return previous.clone();
+            }
+        }
+        match force_pushed_from(&self.client, &self.config, remote_commit).await {
+            Ok(previous) => {
+                if let Some(previous) = &previous {
+                    let held = self.config.force_push == ForcePush::Hold;
+                    warn!(
+                        "'{}' was force-pushed: {} replaced {}.{}",
+                        self.config.target_branch,
+                        remote_commit,
+                        previous,
+                        if held {
+                            " Holding until the sync is approved."
+                        } else {
+                            ""
+                        }
+                    );
+                    self.events
+                        .publish(
+                            SyncEvent::ForcePushed {
+                                old_commit: previous,
+                                new_commit: remote_commit,
+                                held,
+                            },
+                            &self.config.repo_ref(),
+                        )
+                        .await;
+                }
+                self.force_push_checked = Some((remote_commit.to_string(), previous.clone()));
+                previous
+            }
+            Err(e) => {
+                warn!("Failed to check {} for a force push: {}", remote_commit, e);
+                None
+            }
+        }
+    }
+
+    // Why the new commits can be ignored, if ignore_authors and ignore_paths cover all of them.
+    // Fetched and checked once per remote commit.
+    async fn ignored(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        timings: &mut metrics::Timings,
+    ) -> Option<String> {
+        if let Some((commit, reason)) = &self.ignore_checked {
+            if commit == remote_commit {
+                return reason.clone();
+            }
+        }
+        let reason = match fetch_changes(&self.config, timings).await {
+            Ok(()) => {
+                ignore::noise(
+                    &self.config.repo_path,
+                    local_commit,
+                    remote_commit,
+                    &self.config.ignore_authors,
+                    &self.config.ignore_paths,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match reason {
+            Ok(reason) => {
+                if let Some(reason) = &reason {
+                    info!(
+                        "Ignoring {}, only {}. Staying at {}.",
+                        remote_commit, reason, local_commit
+                    );
+                }
+                self.ignore_checked = Some((remote_commit.to_string(), reason.clone()));
+                reason
+            }
+            // Pulled as usual, the pull reports what went wrong
+            Err(e) => {
+                error!(
+                    "Failed to check the new commits against the ignore rules: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    // Why the commit isn't pulled yet, while its builds haven't passed. Asked every cycle until
+    // the builds finish, then remembered.
+    async fn build_hold(&mut self, remote_commit: &str, cycle: &mut Cycle) -> Option<String> {
+        if let Some((commit, None)) = &self.build_checked {
+            if commit == remote_commit {
+                return None;
+            }
+        }
+        let started = Instant::now();
+        let state = pipelines::build_state(
+            &self.client,
+            &self.config.repo_ref(),
+            remote_commit,
+            &self.config.build_definitions,
+            &self.config.api_version,
+        )
+        .await;
+        metrics::record(&mut cycle.timings, "build_check", started);
+        match state {
+            Ok(pipelines::BuildState::Passed) => {
+                info!("Builds of {} passed.", remote_commit);
+                self.build_checked = Some((remote_commit.to_string(), None));
+                None
+            }
+            Ok(pipelines::BuildState::Failed(reason)) => {
+                let hold = format!("its {}", reason);
+                let known = self.build_checked.as_ref().is_some_and(|(commit, known)| {
+                    commit == remote_commit && known.as_ref() == Some(&hold)
+                });
+                if !known {
+                    warn!(
+                        "Not pulling {}, {}. Waiting for a retry or a newer commit.",
+                        remote_commit, hold
+                    );
+                }
+                self.build_checked = Some((remote_commit.to_string(), Some(hold.clone())));
+                Some(hold)
+            }
+            Ok(pipelines::BuildState::Pending(reason)) => {
+                cycle.recheck_in = Some(BUILD_POLL_INTERVAL);
+                Some(format!("waiting for its build, {}", reason))
+            }
+            // Not knowing is treated as not built, and asked again
+            Err(e) => {
+                error!("Failed to look up the builds: {}", e);
+                cycle.recheck_in = Some(BUILD_POLL_INTERVAL);
+                Some(format!("its builds couldn't be checked: {}", e))
+            }
+        }
+    }
+
+    // Observe mode: the checkout stays where it is, and each new remote commit is reported once
+    async fn report_behind(&mut self, local_commit: &str, remote_commit: &str) {
+        // Drift is still measured against where the checkout is
+        self.synced_commit = Some(local_commit.to_string());
+        if self.reported_behind.as_deref() == Some(remote_commit) {
+            return;
+        }
+        info!(
+            "Behind the remote: local {}, remote {}. Observe mode, not pulling.",
+            local_commit, remote_commit
+        );
+        self.events
+            .publish(
+                SyncEvent::Behind {
+                    local_commit,
+                    remote_commit,
+                },
+                &self.config.repo_ref(),
+            )
+            .await;
+        self.reported_behind = Some(remote_commit.to_string());
+    }
+
+    async fn up_to_date(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        ahead: bool,
+        cycle: &mut Cycle,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.in_sync_with = Some(local_commit.to_string());
+        self.synced_commit = Some(local_commit.to_string());
+        cycle.settled = true;
+        if ahead {
+            console::ticker(&format!(
+                "{}Local {} is ahead of the remote {}, nothing to pull.",
+                self.ticker_prefix, local_commit, remote_commit
+            ))?;
+        } else {
+            console::ticker(&format!(
+                "{}No new changes since {}. Elapsed time: {} seconds.",
+                self.ticker_prefix,
+                self.last_change_at.format("%Y-%m-%d %H:%M:%S"),
+                self.last_change.elapsed().as_secs()
+            ))?;
+        }
+        self.events
+            .publish(
+                SyncEvent::UpToDate {
+                    commit: local_commit,
+                },
+                &self.config.repo_ref(),
+            )
+            .await;
+        Ok(())
+    }
+
+    // Pulls the remote commit and deploys it, recording the sync
+    async fn sync(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        force: bool,
+        cycle: &mut Cycle,
+    ) {
+        info!("New changes detected. Pulling updates...");
+        // Held until the sync is recorded, so the group's repos take turns
+        let group = self.group.clone();
+        let _turn = match &group {
+            Some((name, lock)) => Some(match lock.try_lock() {
+                Ok(turn) => turn,
+                Err(_) => {
+                    info!(
+                        "Waiting for another repo in concurrency group '{}' to finish syncing.",
+                        name
+                    );
+                    lock.lock().await
+                }
+            }),
+            None => None,
+        };
+        self.in_sync_with = None;
+        let sync_started = Instant::now();
+        let mut record = SyncRecord::new(local_commit, remote_commit);
+        let (directive, pulled) = self
+            .pull(local_commit, remote_commit, force, &mut record, cycle)
+            .await;
+        if pulled {
+            let diff = git::diff_stat(&self.config.repo_path, local_commit, remote_commit)
+                .await
+                .map_err(|e| error!("Failed to count changed lines: {}", e))
+                .ok();
+            self.deploy(
+                local_commit,
+                remote_commit,
+                directive.hooks,
+                &mut record,
+                cycle,
+            )
+            .await;
+            if record.status == SyncStatus::Success {
+                let summary = record.summary(diff.as_ref(), sync_started.elapsed());
+                info!("{}", summary);
+                console::line(&summary);
+                cycle.settled = true;
+                self.links.wake_dependents();
+            }
+        }
+        self.finish(record, directive.skip, cycle).await;
+    }
+
+    // Lets the subscribers look at the incoming commits, then pulls them. Returns what the
+    // subscribers asked for, and whether the checkout now holds the remote commit.
+    async fn pull(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        force: bool,
+        record: &mut SyncRecord,
+        cycle: &mut Cycle,
+    ) -> (Directive, bool) {
+        let incoming = if self.list_incoming {
+            match fetch_changes(&self.config, &mut cycle.timings).await {
+                Ok(()) => {
+                    git::commit_log(&self.config.repo_path, local_commit, remote_commit).await
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            Ok(Vec::new())
+        };
+        let mut directive = match &incoming {
+            Ok(commits) => {
+                self.events
+                    .publish(
+                        SyncEvent::ChangesDetected {
+                            old_commit: local_commit,
+                            new_commit: remote_commit,
+                            commits,
+                        },
+                        &self.config.repo_ref(),
+                    )
+                    .await
+            }
+            // Checked at the next check instead, once the commits list
+            Err(_) => Directive::default(),
+        };
+        record
+            .annotations
+            .extend(std::mem::take(&mut directive.annotations));
+
+        if directive.skip {
+            info!("Sync of {} skipped by a plugin.", remote_commit);
+        } else if let Err(e) = &incoming {
+            // The rules can't pass commits they can't see
+            error!(
+                "Not syncing {}, the new commits couldn't be listed for the rules: {}",
+                remote_commit, e
+            );
+            record.status = SyncStatus::Aborted;
+            record.error = Some(format!(
+                "the new commits couldn't be listed for the rules: {}",
+                e
+            ));
+        } else if let Some(reason) = directive.abort.take() {
+            record.status = SyncStatus::Aborted;
+            record.error = Some(reason);
+            self.aborted_commit = Some(remote_commit.to_string());
+        } else if let Err(e) = pull_in_batch(
+            &self.config,
+            match self.config.checkout_mode {
+                CheckoutMode::Branch => cycle.pin.as_deref(),
+                // Detached checkouts go to exactly the commit that was checked
+                CheckoutMode::Detached => Some(remote_commit),
+            },
+            force,
+            self.batch.as_ref(),
+            &mut cycle.timings,
+        )
+        .await
+        {
+            error!("Failed to pull changes: {}", e);
+            record.status = SyncStatus::PullFailed;
+            record.error = Some(e.to_string());
+            self.synced_commit = None;
+            self.name_resolved = None;
+            self.events
+                .publish(
+                    SyncEvent::PullFailed {
+                        error: &e.to_string(),
+                    },
+                    &self.config.repo_ref(),
+                )
+                .await;
+            if e.is::<secrets::AuthError>() {
+                reload_credentials(&mut self.config);
+            }
+        } else {
+            return (directive, true);
+        }
+        (directive, false)
+    }
+
+    // Everything after the pull: the record of what came in, the verification, the post-sync
+    // actions and the virtual repos. `hooks` are the hooks a subscriber picked before the pull.
+    async fn deploy(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        hooks: Option<Vec<String>>,
+        record: &mut SyncRecord,
+        cycle: &mut Cycle,
+    ) {
+        self.last_change = Instant::now();
+        self.last_change_at = Utc::now();
+        self.synced_commit = Some(remote_commit.to_string());
+        self.describe_pull(
+            local_commit,
+            remote_commit,
+            &cycle.completed_pull_requests,
+            record,
+        )
+        .await;
+
+        let unverified = match self.config.verify {
+            Some(verification) => {
+                let started = Instant::now();
+                let unverified = verify_checkout(&self.config, remote_commit, verification).await;
+                metrics::record(&mut cycle.timings, "verify", started);
+                unverified
+            }
+            None => None,
+        };
+        let pulled = self
+            .events
+            .publish(
+                SyncEvent::PullCompleted {
+                    old_commit: local_commit,
+                    new_commit: remote_commit,
+                    commits: &record.commits,
+                },
+                &self.config.repo_ref(),
+            )
+            .await;
+        record.annotations.extend(pulled.annotations);
+        if let Some(reason) = pulled.abort {
+            // Pulled already, so only the post-sync actions are held back
+            record.status = SyncStatus::Aborted;
+            record.error = Some(reason);
+        } else if let Some(error) = unverified {
+            // Nothing is deployed from a tree that isn't what was pulled
+            error!("Skipping post-sync actions: {}", error);
+            record.status = SyncStatus::VerificationFailed;
+            record.error = Some(error);
+        } else {
+            self.post_sync_actions(
+                local_commit,
+                remote_commit,
+                pulled.hooks.or(hooks).as_deref(),
+                record,
+                &mut cycle.timings,
+            )
+            .await;
+        }
+        // Virtual repos deploy whatever the repo's own actions did, as long as the checkout
+        // holds the pulled commit
+        if !self.virtual_repos.is_empty()
+            && matches!(
+                record.status,
+                SyncStatus::Success | SyncStatus::PostSyncFailed
+            )
+        {
+            let started = Instant::now();
+            self.deploy_virtual_repos(local_commit, remote_commit, record)
+                .await;
+            metrics::record(&mut cycle.timings, "virtual_repos", started);
+        }
+        for hook in record.hooks.iter().filter(|hook| !hook.succeeded()) {
+            self.events
+                .publish(SyncEvent::HookFailed { hook }, &self.config.repo_ref())
+                .await;
+        }
+    }
+
+    // Fills the record with the pulled commits and the pull requests, work items and changed
+    // files that came with them
+    async fn describe_pull(
+        &self,
+        local_commit: &str,
+        remote_commit: &str,
+        completed_pull_requests: &[PullRequestRef],
+        record: &mut SyncRecord,
+    ) {
+        let repo_path = &self.config.repo_path;
+        match git::commit_log(repo_path, local_commit, remote_commit).await {
+            Ok(commits) => record.commits = commits,
+            Err(e) => error!("Failed to list pulled commits: {}", e),
+        }
+        record.pull_requests = completed_pull_requests
+            .iter()
+            .filter(|pull_request| {
+                record
+                    .commits
+                    .iter()
+                    .any(|commit| commit.id == pull_request.merge_commit)
+            })
+            .cloned()
+            .collect();
+        for pull_request in &record.pull_requests {
+            info!(
+                "Deployed pull request !{} '{}' by {}.",
+                pull_request.id, pull_request.title, pull_request.author
+            );
+        }
+        // Written before the post-sync actions, so hooks can read it too
+        if let Some(path) = &self.config.changed_files_file {
+            let written = match git::file_changes(repo_path, local_commit, remote_commit).await {
+                Ok(files) => state::write_changed_files(path, local_commit, remote_commit, &files),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                error!("Failed to write the changed files to {}: {}", path, e);
+            }
+        }
+        if self.config.link_work_items {
+            match azure::link_work_items(
+                &self.client,
+                &self.config.organization,
+                &self.config.project,
+                &self.config.repository,
+                &self.config.pat,
+                &self.config.api_version,
+                &mut record.commits,
+            )
+            .await
+            {
+                Ok(work_items) => record.work_items = work_items,
+                Err(e) => error!("Failed to resolve work items: {}", e),
+            }
+        }
+    }
+
+    // The artifact, the release, the post-sync actions and the trigger, each only once the one
+    // before it succeeded
+    async fn post_sync_actions(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        hooks: Option<&[String]>,
+        record: &mut SyncRecord,
+        timings: &mut metrics::Timings,
+    ) {
+        let downloaded = match &self.config.artifact {
+            Some(artifact) => {
+                let started = Instant::now();
+                let downloaded = pipelines::download_artifact(
+                    &self.client,
+                    &self.config.repo_ref(),
+                    remote_commit,
+                    artifact,
+                    &self.config.api_version,
+                )
+                .await;
+                metrics::record(timings, "artifact", started);
+                downloaded
+            }
+            None => Ok(()),
+        };
+        // Hooks restarting services from the current link need it moved first
+        let released = match (&downloaded, &self.config.releases) {
+            (Ok(()), Some(releases)) => {
+                let started = Instant::now();
+                let released =
+                    releases::publish(&self.config.repo_path, releases, remote_commit).await;
+                metrics::record(timings, "release", started);
+                released
+            }
+            _ => Ok(()),
+        };
+        // The actions would deploy the previous build's output
+        if let Err(e) = downloaded {
+            error!(
+                "Skipping post-sync actions, the artifact couldn't be downloaded: {}",
+                e
+            );
+            record.error = Some(format!("artifact download failed: {}", e));
+            record.status = SyncStatus::PostSyncFailed;
+            return;
+        }
+        if let Err(e) = released {
+            error!(
+                "Skipping post-sync actions, the release couldn't be published: {}",
+                e
+            );
+            record.error = Some(format!("release failed: {}", e));
+            record.status = SyncStatus::PostSyncFailed;
+            return;
+        }
+
+        let context = post_sync::SyncContext {
+            client: &self.client,
+            repo_path: &self.config.repo_path,
+            branch: &self.config.target_branch,
+            old_commit: local_commit,
+            new_commit: remote_commit,
+            hooks,
+        };
+        let started = Instant::now();
+        let result = post_sync::run(
+            &self.config.post_sync,
+            &context,
+            &mut self.cooldowns,
+            &mut record.hooks,
+        )
+        .await;
+        metrics::record(timings, "hooks", started);
+        if let Err(e) = result {
+            error!("Post-sync actions failed: {}", e);
+            record.error = Some(e.to_string());
+            record.status = SyncStatus::PostSyncFailed;
+            if e.is::<post_sync::RolledBack>() {
+                record.status = SyncStatus::RolledBack;
+                self.rolled_back_commit = Some(remote_commit.to_string());
+                self.synced_commit = Some(local_commit.to_string());
+                if let Some(releases) = &self.config.releases {
+                    if let Err(e) = releases::activate(releases, local_commit) {
+                        warn!("Couldn't point the current link back: {}", e);
+                    }
+                }
+            }
+        } else if let Some(trigger) = &self.config.trigger {
+            // Downstream automation only hears of a sync that fully succeeded
+            let started = Instant::now();
+            let triggered = pipelines::trigger(
+                &self.client,
+                &self.config.repo_ref(),
+                trigger,
+                local_commit,
+                remote_commit,
+                &self.config.api_version,
+            )
+            .await;
+            metrics::record(timings, "trigger", started);
+            match triggered {
+                Ok(run) => info!("Started {}.", run),
+                Err(e) => {
+                    error!("Failed to start the trigger: {}", e);
+                    record.error = Some(format!("trigger failed: {}", e));
+                    record.status = SyncStatus::PostSyncFailed;
+                }
+            }
+        }
+    }
+
+    async fn deploy_virtual_repos(
+        &mut self,
+        local_commit: &str,
+        remote_commit: &str,
+        record: &SyncRecord,
+    ) {
+        let changed =
+            match git::changed_files(&self.config.repo_path, local_commit, remote_commit).await {
+                Ok(changed) => changed,
+                Err(e) => {
+                    error!("Failed to list changed files for the virtual repos: {}", e);
+                    return;
+                }
+            };
+        let context = post_sync::SyncContext {
+            client: &self.client,
+            repo_path: &self.config.repo_path,
+            branch: &self.config.target_branch,
+            old_commit: local_commit,
+            new_commit: remote_commit,
+            hooks: None,
+        };
+        for virtual_repo in &mut self.virtual_repos {
+            virtual_repo
+                .deploy(&self.config.repo_ref(), &context, &changed, &record.commits)
+                .await;
+        }
+    }
+
+    // Records the sync with its timings, unless a plugin skipped it
+    async fn finish(&mut self, mut record: SyncRecord, skipped: bool, cycle: &mut Cycle) {
+        record.timings = cycle.timings.clone();
+        if !matches!(record.status, SyncStatus::Success | SyncStatus::Aborted) {
+            cycle.failure = Some(
+                record
+                    .error
+                    .clone()
+                    .unwrap_or_else(|| format!("{:?}", record.status)),
+            );
+        }
+        if !skipped {
+            self.events
+                .publish(
+                    SyncEvent::SyncFinished { record: &record },
+                    &self.config.repo_ref(),
+                )
+                .await;
+        }
+    }
+
+    // Deferred hooks and restarts catch up once their wait is over, whether or not a new commit
+    // came in meanwhile
+    async fn run_deferred(&mut self) {
+        let Some(commit) = &self.synced_commit else {
+            return;
+        };
+        if self
+            .cooldowns
+            .next_due(&self.config.post_sync)
+            .is_some_and(|wait| wait.is_zero())
+        {
+            let mut results = Vec::new();
+            if let Err(e) = post_sync::run_deferred(
+                &self.config.post_sync,
+                &self.client,
+                &self.config.repo_path,
+                &self.config.target_branch,
+                commit,
+                &mut self.cooldowns,
+                &mut results,
+            )
+            .await
+            {
+                error!("Deferred post-sync actions failed: {}", e);
+            }
+            for hook in results.iter().filter(|hook| !hook.succeeded()) {
+                self.events
+                    .publish(SyncEvent::HookFailed { hook }, &self.config.repo_ref())
+                    .await;
+            }
+        }
+        for virtual_repo in &mut self.virtual_repos {
+            virtual_repo
+                .run_deferred(&self.config.repo_ref(), &self.config.repo_path, commit)
+                .await;
+        }
+    }
+
+    // Writes the state file and tells the linked repos and the batch how the cycle ended
+    fn report(&self, cycle: &Cycle) {
+        info!("Cycle timings: {}", metrics::summary(&cycle.timings));
+        write_state(
+            &self.config,
+            self.synced_commit
+                .as_deref()
+                .or(cycle.local_seen.as_deref()),
+            cycle.remote_seen.as_deref(),
+            cycle_status(cycle),
+            cycle.failure.as_deref(),
+            self.last_change_at,
+        );
+        self.links.report(cycle.settled);
+        if let Some(batch) = &self.batch {
+            batch.report(if cycle.settled {
+                Ok(())
+            } else {
+                Err("isn't in sync with its remote".to_string())
+            });
+        }
+    }
+
+    // The interval, or less when a hold or a deferred action is due sooner
+    fn next_wait(&self, cycle: &Cycle) -> Duration {
+        let since_change = self.last_change.elapsed().as_secs();
+        let interval = rules::check_interval(
+            self.interval_script.as_ref(),
+            self.config.intervals().at(since_change),
+            since_change,
+        );
+        let virtual_due = self
+            .virtual_repos
+            .iter()
+            .filter_map(|virtual_repo| virtual_repo.next_due());
+        self.cooldowns
+            .next_due(&self.config.post_sync)
+            .into_iter()
+            .chain(virtual_due)
+            .chain(cycle.recheck_in)
+            .fold(Duration::from_secs(interval), Duration::min)
+    }
+
+    async fn wait(
+        &self,
+        interval: Duration,
+        control_rx: &mut broadcast::Receiver<control::ControlCommand>,
+    ) -> Wake {
+        wait_for_next_check(
+            interval,
+            &control::RepoKey::new(&self.config.project, &self.config.repository),
+            control_rx,
+            &self.links,
+            self.batch.as_ref(),
+        )
+        .await
+    }
+
+    // Handles what ended the wait for the next check
+    async fn handle(&mut self, wake: Wake) {
+        match wake {
+            Wake::Due => {}
+            Wake::Resumed(gap) => info!(
+                "Resumed after a suspected suspend of about {} seconds, checking now.",
+                gap.as_secs()
+            ),
+            Wake::Command(control::ControlCommand::ReloadCredentials) => {
+                reload_credentials(&mut self.config)
+            }
+            Wake::Command(control::ControlCommand::CheckNow(_)) => {
+                info!("Checking now for the push announced through the webhook.")
+            }
+            Wake::Command(control::ControlCommand::ApproveForcePush(_)) => {
+                match &self.force_push_checked {
+                    Some((commit, Some(_))) if self.config.force_push == ForcePush::Hold => {
+                        info!("Force push to {} approved, syncing now.", commit);
+                        self.force_push_approved = Some(commit.clone());
+                    }
+                    _ => warn!("Force push approved, but no sync is held for one."),
+                }
+            }
+            Wake::Command(control::ControlCommand::ApproveTerraform(_)) => {
+                self.approve_terraform().await
+            }
+        }
+    }
+
+    // Applies the Terraform plans held for approval, of the repo and its virtual repos
+    async fn approve_terraform(&mut self) {
+        let modules = self.config.post_sync.terraform.iter().chain(
+            self.virtual_repos
+                .iter()
+                .filter_map(|virtual_repo| virtual_repo.post_sync().terraform.as_ref()),
+        );
+        let mut results = Vec::new();
+        let mut failed = false;
+        match get_local_commit(&self.config).await {
+            Ok(commit) => {
+                for module in modules {
+                    if let Err(e) =
+                        terraform::apply(module, &self.config.repo_path, &commit, &mut results)
+                            .await
+                    {
+                        error!("Approved Terraform apply failed: {}", e);
+                        failed = true;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Approved Terraform apply failed: {}", e);
+                failed = true;
+            }
+        }
+        if results.is_empty() && !failed {
+            warn!("Terraform plan approved, but none is waiting.");
+        }
+        for hook in results.iter().filter(|hook| !hook.succeeded()) {
+            self.events
+                .publish(SyncEvent::HookFailed { hook }, &self.config.repo_ref())
+                .await;
+        }
+    }
+}
+
+// The event subscribers of a repo, taking their settings out of the config
+fn subscribe(
+    config: &mut AppConfig,
+    client: &Client,
+) -> Result<events::EventBus, Box<dyn std::error::Error>> {
+    let mut events = events::EventBus::default();
+    events.subscribe(policy::Policies::new(
+        std::mem::take(&mut config.policies),
+        config
+            .post_sync
+            .hooks
+            .iter()
+            .map(|hook| hook.label().to_string())
+            .collect(),
+    ));
+    events.subscribe(rules::Rules::new(std::mem::take(&mut config.rules))?);
+    events.subscribe(plugins::Plugins::new(std::mem::take(&mut config.plugins)));
+    events.subscribe(history::HistoryLog::new(config.history_file.clone()));
+    if let Some(path) = &config.audit_file {
+        events.subscribe(audit::AuditLog::open(
+            path.clone(),
+            audit_key(config)?,
+            config.audit_allow_broken_chain,
+        )?);
+    }
+    events.subscribe(notify::Notifier::new(
+        std::mem::take(&mut config.notifications),
+        client.clone(),
+    ));
+    events.subscribe(alert::Alerts::new(
+        std::mem::take(&mut config.alerts),
+        client.clone(),
+    ));
+    events.subscribe(change::ChangeRecords::new(
+        std::mem::take(&mut config.change_records),
+        client.clone(),
+    ));
+    if let Some(jira) = config.jira.take() {
+        events.subscribe(jira::Jira::new(jira, client.clone()));
+    }
+    if let Some(status) = config.deployment_status.take() {
+        events.subscribe(deployment::DeploymentStatus::new(
+            status,
+            client.clone(),
+            config.api_version.clone(),
+        ));
+    }
+    if let Some(github) = config.github_deployment.take() {
+        events.subscribe(deployment::GithubDeployment::new(github, client.clone()));
+    }
+    Ok(events)
+}
+
+// How the cycle ended, for the state file
+fn cycle_status(cycle: &Cycle) -> state::Status {
+    if cycle.failure.is_some() {
+        state::Status::Failed
+    } else if cycle.settled {
+        state::Status::InSync
+    } else {
+        state::Status::Behind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Command as StdCommand;
+
+    fn git(repo: &str, args: &[&str]) -> String {
+        let output = StdCommand::new("git")
+            .args([
+                "-C",
+                repo,
+                "-c",
+                "user.name=Ann",
+                "-c",
+                "user.email=ann@example.com",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    fn commit(repo: &str, file: &str, message: &str) -> String {
+        std::fs::write(PathBuf::from(repo).join(file), message).unwrap();
+        git(repo, &["add", "-A"]);
+        git(repo, &["commit", "-qm", message]);
+        git(repo, &["rev-parse", "HEAD"])
+    }
+
+    // A repo with one commit, and the loop of a config for it with the given extra keys
+    async fn repo_sync(name: &str, extra: &str) -> (RepoSync, String) {
+        let repo = std::env::temp_dir().join(format!("cycle-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo);
+        std::fs::create_dir_all(&repo).unwrap();
+        let repo_path = repo.to_string_lossy().to_string();
+        git(&repo_path, &["init", "-q"]);
+        commit(&repo_path, "readme.md", "one");
+        let text = format!(
+            "repo_path = {:?}\norganization = \"contoso\"\nproject = \"Shop\"\nrepository = \"web\"\ntarget_branch = \"main\"\ncheck_interval_seconds = 60\npat = \"secret\"\n{}",
+            repo_path, extra
+        );
+        let config = crate::parse_config_text(&text).unwrap().remove(0);
+        let repo_sync = RepoSync::start(
+            config,
+            Client::new(),
+            None,
+            ordering::Links::default(),
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+        (repo_sync, repo_path)
+    }
+
+    #[test]
+    fn the_first_hold_that_applies_decides() {
+        let behind = |holds: &Holds| decide(SyncMode::Sync, true, false, holds);
+        assert_eq!(behind(&Holds::default()), Decision::Pull);
+        assert_eq!(
+            decide(SyncMode::Sync, false, true, &Holds::default()),
+            Decision::UpToDate { ahead: true }
+        );
+        assert_eq!(
+            decide(SyncMode::Observe, true, false, &Holds::default()),
+            Decision::Observe
+        );
+
+        let mut holds = Holds {
+            waiting_on: Some("api isn't ready".to_string()),
+            ..Holds::default()
+        };
+        assert_eq!(
+            behind(&holds),
+            Decision::WaitingOn("api isn't ready".to_string())
+        );
+        holds.build = Some("its build failed".to_string());
+        assert_eq!(
+            behind(&holds),
+            Decision::BuildHeld("its build failed".to_string())
+        );
+        holds.unsettled = Some(Duration::from_secs(5));
+        assert_eq!(behind(&holds), Decision::Unsettled(Duration::from_secs(5)));
+        holds.delay = Some(Duration::from_secs(9));
+        assert_eq!(behind(&holds), Decision::Delayed(Duration::from_secs(9)));
+        holds.ignored = Some("docs".to_string());
+        assert_eq!(behind(&holds), Decision::Ignored("docs".to_string()));
+        // A force push only holds the commit until it is approved
+        holds.force_pushed = Some("0ld".to_string());
+        assert_eq!(behind(&holds), Decision::Ignored("docs".to_string()));
+        holds.force_push_held = true;
+        assert_eq!(behind(&holds), Decision::ForcePushHeld("0ld".to_string()));
+        holds.halt = Some("freeze".to_string());
+        assert_eq!(behind(&holds), Decision::Halted("freeze".to_string()));
+        holds.aborted = true;
+        assert_eq!(behind(&holds), Decision::Aborted);
+        holds.rolled_back = true;
+        assert_eq!(behind(&holds), Decision::RolledBack);
+        // Aborted commits are only held while they are ahead
+        assert_eq!(
+            decide(
+                SyncMode::Sync,
+                false,
+                false,
+                &Holds {
+                    aborted: true,
+                    ..Holds::default()
+                }
+            ),
+            Decision::UpToDate { ahead: false }
+        );
+    }
+
+    #[test]
+    fn every_hold_has_a_status_line() {
+        let ticker = |decision: Decision| decision.ticker("aaa", "bbb");
+        assert_eq!(ticker(Decision::Pull), None);
+        assert_eq!(ticker(Decision::UpToDate { ahead: false }), None);
+        assert_eq!(
+            ticker(Decision::Halted("freeze".to_string())).as_deref(),
+            Some("Halted at aaa, not pulling bbb: freeze")
+        );
+        assert_eq!(
+            ticker(Decision::Delayed(Duration::from_secs(30))).as_deref(),
+            Some("Holding bbb for another 30s before syncing (sync_delay_seconds).")
+        );
+    }
+
+    #[test]
+    fn the_state_file_says_failed_before_in_sync() {
+        let mut cycle = Cycle::default();
+        assert_eq!(cycle_status(&cycle), state::Status::Behind);
+        cycle.settled = true;
+        assert_eq!(cycle_status(&cycle), state::Status::InSync);
+        cycle.failure = Some("pull failed".to_string());
+        assert_eq!(cycle_status(&cycle), state::Status::Failed);
+    }
+
+    #[tokio::test]
+    async fn a_checkout_ahead_of_the_remote_is_left_alone() {
+        let (mut repo_sync, repo_path) = repo_sync("ahead", "compare = \"ancestry\"\n").await;
+        let remote = git(&repo_path, &["rev-parse", "HEAD"]);
+        let local = commit(&repo_path, "hotfix.txt", "hotfix");
+        assert!(repo_sync.is_ahead(&local, &remote).await);
+        assert!(!repo_sync.is_ahead(&remote, &local).await);
+        assert!(!repo_sync.is_ahead(&local, &local).await);
+
+        let mut cycle = Cycle::default();
+        repo_sync
+            .check_commits(&local, &remote, &mut cycle)
+            .await
+            .unwrap();
+        assert!(cycle.settled);
+        assert_eq!(repo_sync.synced_commit.as_deref(), Some(local.as_str()));
+        std::fs::remove_dir_all(&repo_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pulled_commits_are_recorded_with_their_pull_requests_and_files() {
+        let changed_files =
+            std::env::temp_dir().join(format!("cycle-changed-{}.txt", std::process::id()));
+        let (repo_sync, repo_path) = repo_sync(
+            "describe",
+            &format!("changed_files_file = {:?}\n", changed_files),
+        )
+        .await;
+        let old = git(&repo_path, &["rev-parse", "HEAD"]);
+        let merged = commit(&repo_path, "api.rs", "two");
+        let new = commit(&repo_path, "web.rs", "three");
+        let pull_request = |id, merge_commit: &str| PullRequestRef {
+            id,
+            title: format!("PR {}", id),
+            author: "Ann".to_string(),
+            reviewers: Vec::new(),
+            merge_commit: merge_commit.to_string(),
+            url: String::new(),
+        };
+
+        let mut record = SyncRecord::new(&old, &new);
+        repo_sync
+            .describe_pull(
+                &old,
+                &new,
+                &[pull_request(7, &merged), pull_request(8, "elsewhere")],
+                &mut record,
+            )
+            .await;
+        let ids: Vec<&str> = record.commits.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, [new.as_str(), merged.as_str()]);
+        let pull_requests: Vec<u64> = record.pull_requests.iter().map(|pr| pr.id).collect();
+        assert_eq!(pull_requests, [7]);
+        assert_eq!(
+            std::fs::read_to_string(&changed_files).unwrap(),
+            "api.rs\nweb.rs\n"
+        );
+        std::fs::remove_file(&changed_files).unwrap();
+        std::fs::remove_dir_all(&repo_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failing_hook_fails_the_sync() {
+        let (mut repo_sync, repo_path) =
+            repo_sync("hooks", "[[post_sync.hooks]]\ncommand = \"exit 3\"\n").await;
+        let old = git(&repo_path, &["rev-parse", "HEAD"]);
+        let new = commit(&repo_path, "app.txt", "two");
+
+        let mut record = SyncRecord::new(&old, &new);
+        let mut timings = metrics::Timings::new();
+        repo_sync
+            .post_sync_actions(&old, &new, None, &mut record, &mut timings)
+            .await;
+        assert_eq!(record.status, SyncStatus::PostSyncFailed);
+        assert_eq!(record.hooks.len(), 1);
+        assert!(!record.hooks[0].succeeded());
+        assert!(timings.contains_key("hooks"));
+
+        // Picking no hooks leaves nothing to fail
+        let mut record = SyncRecord::new(&old, &new);
+        repo_sync
+            .post_sync_actions(&old, &new, Some(&[]), &mut record, &mut timings)
+            .await;
+        assert_eq!(record.status, SyncStatus::Success);
+        std::fs::remove_dir_all(&repo_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_force_push_is_only_approved_while_one_is_held() {
+        let (mut repo_sync, repo_path) = repo_sync("approve", "force_push = \"hold\"\n").await;
+        let approve = || {
+            Wake::Command(control::ControlCommand::ApproveForcePush(
+                control::RepoKey::new("Shop", "web"),
+            ))
+        };
+        repo_sync.force_push_checked = Some(("new".to_string(), None));
+        repo_sync.handle(approve()).await;
+        assert_eq!(repo_sync.force_push_approved, None);
+
+        repo_sync.force_push_checked = Some(("new".to_string(), Some("old".to_string())));
+        repo_sync.handle(approve()).await;
+        assert_eq!(repo_sync.force_push_approved.as_deref(), Some("new"));
+        std::fs::remove_dir_all(&repo_path).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+use crate::azure;
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::{SyncRecord, SyncStatus};
+use crate::notify::{self, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use crate::template;
+use log::{error, info};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// Longest status description sent, the rest of an error message is cut off
+const MAX_DESCRIPTION: usize = 400;
+
+// The [deployment_status] table: every sync is reported back to Azure DevOps as a status on
+// the synced commit, so the UI shows where each commit runs
+#[derive(Deserialize)]
+pub struct DeploymentStatusConfig {
+    // Where the commit was deployed, e.g. "production/web-01"; defaults to the host name
+    pub environment: Option<String>,
+    // Groups the statuses of every agent, shown as "<genre>/<environment>"
+    #[serde(default = "default_genre")]
+    pub genre: String,
+    // Link on the status, with {{placeholders}}, e.g. the site the commit now serves
+    pub url: Option<String>,
+    // Whether failed syncs are reported too
+    #[serde(default = "default_true")]
+    pub failures: bool,
+}
+
+fn default_genre() -> String {
+    "deployed".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Documented for DeploymentStatusConfig {
+    const SECTION: &'static str = "[deployment_status]";
+    const ABOUT: &'static str =
+        "Commit statuses posted back to Azure DevOps after every sync, showing where each commit runs.";
+    const FIELDS: &'static [Field] = &[
+        schema::defaulted(
+            "environment",
+            "string",
+            "the host name",
+            "Where the commit was deployed, the status name",
+        ),
+        schema::defaulted(
+            "genre",
+            "string",
+            r#""deployed""#,
+            "Status genre, shown as <genre>/<environment>",
+        ),
+        schema::optional(
+            "url",
+            "string",
+            r#""https://{{host}}.acme.com""#,
+            "Link on the status; notification placeholders are substituted",
+        ),
+        schema::defaulted(
+            "failures",
+            "bool",
+            "true",
+            "Also report failed syncs, as failed statuses",
+        ),
+    ];
+}
+
+impl DeploymentStatusConfig {
+    pub fn environment(&self) -> String {
+        self.environment.clone().unwrap_or_else(notify::host_name)
+    }
+}
+
+// Posts the statuses
+pub struct DeploymentStatus {
+    config: DeploymentStatusConfig,
+    client: Client,
+    api_version: String,
+}
+
+impl DeploymentStatus {
+    pub fn new(config: DeploymentStatusConfig, client: Client, api_version: String) -> Self {
+        DeploymentStatus {
+            config,
+            client,
+            api_version,
+        }
+    }
+
+    async fn report(&self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        let environment = self.config.environment();
+        let labels = if repo.labels.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", repo.labels.join(", "))
+        };
+        let (state, description) = match &record.error {
+            None => (
+                "succeeded",
+                format!(
+                    "Running on {}{} since {}",
+                    environment, labels, record.timestamp
+                ),
+            ),
+            Some(error) => (
+                "failed",
+                format!("Sync to {}{} failed: {}", environment, labels, error),
+            ),
+        };
+        let mut status = json!({
+            "state": state,
+            "description": description.chars().take(MAX_DESCRIPTION).collect::<String>(),
+            "context": { "genre": self.config.genre, "name": environment },
+        });
+        if let Some(url) = &self.config.url {
+            let (vars, lists) = notify::template_values(repo, record);
+            status["targetUrl"] = template::render_with_lists(url, &vars, &lists).into();
+        }
+
+        match self.post(repo, &record.new_commit, &status).await {
+            Ok(()) => info!(
+                "Reported {} {} on {} to Azure DevOps.",
+                self.config.genre, state, environment
+            ),
+            Err(e) => error!("Failed to report the deployment status: {}", e),
+        }
+
+        // The commit that was running here no longer is
+        if record.error.is_none() && !record.old_commit.is_empty() {
+            let replaced = json!({
+                "state": "notApplicable",
+                "description": format!(
+                    "Replaced on {}{} by {} at {}",
+                    environment,
+                    labels,
+                    record.new_commit.chars().take(8).collect::<String>(),
+                    record.timestamp
+                ),
+                "context": { "genre": self.config.genre, "name": environment },
+            });
+            if let Err(e) = self.post(repo, &record.old_commit, &replaced).await {
+                error!("Failed to mark the previous commit as replaced: {}", e);
+            }
+        }
+    }
+
+    async fn post(
+        &self,
+        repo: &RepoRef<'_>,
+        commit: &str,
+        status: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        azure::post_commit_status(&self.client, repo, commit, status, &self.api_version).await
+    }
+}
+
+impl Subscriber for DeploymentStatus {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            if let SyncEvent::SyncFinished { record } = event {
+                let wanted = match record.status {
+                    SyncStatus::Success => true,
+                    // A plugin stopping the sync leaves the commit undeployed, but nothing failed
+                    SyncStatus::Aborted => false,
+                    _ => self.config.failures,
+                };
+                if wanted && record.old_commit != record.new_commit {
+                    self.report(repo, record).await;
+                }
+            }
+            Directive::default()
+        })
+    }
+}
+
+// The [github_deployment] table: for repositories mirrored to GitHub, every sync is also a
+// GitHub deployment of the same commit, so the environment view shows what each agent runs
+#[derive(Deserialize)]
+pub struct GithubDeploymentConfig {
+    // The GitHub mirror, "owner/name"
+    pub repository: String,
+    // Token with the deployments permission (repo_deployment scope for classic tokens)
+    pub token: String,
+    // GitHub environment; defaults to the host name
+    pub environment: Option<String>,
+    // Link to the deployed environment, with {{placeholders}}
+    pub url: Option<String>,
+    // API base URL, e.g. https://github.acme.com/api/v3 for GitHub Enterprise Server
+    #[serde(default = "default_github_api")]
+    pub api_url: String,
+}
+
+fn default_github_api() -> String {
+    "https://api.github.com".to_string()
+}
+
+impl Documented for GithubDeploymentConfig {
+    const SECTION: &'static str = "[github_deployment]";
+    const ABOUT: &'static str =
+        "GitHub deployments of every synced commit, for repositories mirrored to GitHub.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "repository",
+            "string",
+            r#""acme/web-shop""#,
+            "The GitHub mirror, owner/name",
+        ),
+        schema::required(
+            "token",
+            "string",
+            r#""enc:...""#,
+            "Token allowed to write deployments; plain or enc: encrypted",
+        ),
+        schema::defaulted(
+            "environment",
+            "string",
+            "the host name",
+            "GitHub environment deployed to",
+        ),
+        schema::optional(
+            "url",
+            "string",
+            r#""https://shop.acme.com""#,
+            "Environment URL shown on the deployment; notification placeholders are substituted",
+        ),
+        schema::defaulted(
+            "api_url",
+            "string",
+            r#""https://api.github.com""#,
+            "API base URL, e.g. https://github.acme.com/api/v3 for GitHub Enterprise Server",
+        ),
+    ];
+}
+
+impl GithubDeploymentConfig {
+    // Decrypts an enc: token, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.token = secrets::resolve_secret(&self.token, secrets_identity)?;
+        if self.token.is_empty() {
+            return Err("[github_deployment] needs a token".into());
+        }
+        Ok(())
+    }
+
+    pub fn environment(&self) -> String {
+        self.environment.clone().unwrap_or_else(notify::host_name)
+    }
+
+    fn post(&self, client: &Client, path: &str) -> RequestBuilder {
+        client
+            .post(format!(
+                "{}/repos/{}/{}",
+                self.api_url.trim_end_matches('/'),
+                self.repository,
+                path
+            ))
+            .bearer_auth(&self.token)
+            .header("Accept", "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", GITHUB_API_VERSION)
+    }
+}
+
+const GITHUB_API_VERSION: &str = "2022-11-28";
+
+#[derive(Deserialize)]
+struct Created {
+    id: u64,
+}
+
+// Opens a GitHub deployment when changes are detected and gives it the sync's outcome
+pub struct GithubDeployment {
+    config: GithubDeploymentConfig,
+    client: Client,
+    // The deployment of the sync in progress
+    open: Option<u64>,
+}
+
+impl GithubDeployment {
+    pub fn new(config: GithubDeploymentConfig, client: Client) -> Self {
+        GithubDeployment {
+            config,
+            client,
+            open: None,
+        }
+    }
+
+    // Creates the deployment of a commit and marks it in progress
+    async fn start(&mut self, repo: &RepoRef<'_>, commit: &str) {
+        if self.open.is_some() {
+            return;
+        }
+        let description = format!(
+            "{} ({}) synced by {}",
+            repo.repository,
+            repo.branch,
+            notify::host_name()
+        );
+        match self.create(commit, &description).await {
+            Ok(id) => {
+                info!(
+                    "Started GitHub deployment {} of {} to {}.",
+                    id,
+                    commit,
+                    self.config.environment()
+                );
+                self.open = Some(id);
+                let status = json!({ "state": "in_progress", "description": description });
+                if let Err(e) = self.status(id, &status).await {
+                    error!("Failed to mark GitHub deployment {} in progress: {}", id, e);
+                }
+            }
+            Err(e) => error!("Failed to start the GitHub deployment: {}", e),
+        }
+    }
+
+    // Gives the deployment the outcome of the sync
+    async fn finish(&mut self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        let id = match self.open.take() {
+            Some(id) => id,
+            None => {
+                self.start(repo, &record.new_commit).await;
+                match self.open.take() {
+                    Some(id) => id,
+                    None => return,
+                }
+            }
+        };
+
+        let (state, description) = match (record.status, &record.error) {
+            (SyncStatus::Aborted, error) => (
+                "inactive",
+                format!(
+                    "Stopped by a plugin: {}",
+                    error.as_deref().unwrap_or_default()
+                ),
+            ),
+            (_, None) => (
+                "success",
+                format!(
+                    "Running on {} since {}",
+                    notify::host_name(),
+                    record.timestamp
+                ),
+            ),
+            (_, Some(error)) => ("failure", format!("Sync failed: {}", error)),
+        };
+        // GitHub cuts descriptions off at 140 characters
+        let mut status = json!({
+            "state": state,
+            "description": description.chars().take(140).collect::<String>(),
+            "auto_inactive": true,
+        });
+        if let Some(url) = &self.config.url {
+            let (vars, lists) = notify::template_values(repo, record);
+            status["environment_url"] = template::render_with_lists(url, &vars, &lists).into();
+        }
+
+        match self.status(id, &status).await {
+            Ok(()) => info!("GitHub deployment {} is now {}.", id, state),
+            Err(e) => error!(
+                "Failed to report GitHub deployment {} as {}: {}",
+                id, state, e
+            ),
+        }
+    }
+
+    async fn create(
+        &self,
+        commit: &str,
+        description: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let environment = self.config.environment();
+        let response = self
+            .config
+            .post(&self.client, "deployments")
+            .json(&json!({
+                "ref": commit,
+                "environment": environment,
+                "description": description,
+                // The sync already happened or is happening, GitHub has nothing to merge or wait for
+                "auto_merge": false,
+                "required_contexts": [],
+            }))
+            .send()
+            .await?;
+        let status = response.status();
+        if status == StatusCode::UNPROCESSABLE_ENTITY {
+            return Err(format!(
+                "{} is not in {}, is it a mirror of the synced repository?",
+                commit, self.config.repository
+            )
+            .into());
+        }
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("{} {}", status, text.trim()).into());
+        }
+        let created: Created = response.json().await?;
+        Ok(created.id)
+    }
+
+    async fn status(&self, id: u64, status: &Value) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self
+            .config
+            .post(&self.client, &format!("deployments/{}/statuses", id))
+            .json(status)
+            .send()
+            .await?;
+        let code = response.status();
+        if !code.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(format!("{} {}", code, text.trim()).into());
+        }
+        Ok(())
+    }
+}
+
+impl Subscriber for GithubDeployment {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            match event {
+                SyncEvent::ChangesDetected { new_commit, .. } => self.start(repo, new_commit).await,
+                SyncEvent::SyncFinished { record } if record.old_commit != record.new_commit => {
+                    self.finish(repo, record).await
+                }
+                _ => {}
+            }
+            Directive::default()
+        })
+    }
+}
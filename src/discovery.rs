@@ -0,0 +1,387 @@
+// Finding agents on the local network without any central service, for plant floors and other
+// sites where the agents are the only infrastructure. With `announce = true` the agent answers
+// mDNS (DNS-SD) queries for _devops-sync._tcp.local with its name, control port and status, and
+// `discover` sends such a query and lists whoever answers.
+use crate::metrics;
+use log::{error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout_at, Instant};
+
+// Service type the agents announce themselves under
+const SERVICE: &str = "_devops-sync._tcp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+// How long answers may be cached by other hosts, in seconds
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+// Largest mDNS packet read
+const MAX_PACKET: usize = 9000;
+
+// What this agent tells the LAN about itself
+pub struct Announcement {
+    // Instance name, normally the host name
+    pub name: String,
+    // Port of the control endpoint, 0 when there is none
+    pub port: u16,
+    // Fixed TXT entries such as the version and labels; the status is added when answering
+    pub details: Vec<(String, String)>,
+}
+
+// An agent that answered `discover`
+pub struct Agent {
+    pub name: String,
+    pub address: IpAddr,
+    pub port: u16,
+    pub details: BTreeMap<String, String>,
+}
+
+// Answers queries for the service until the process stops. Failing to join the multicast group
+// only costs the announcement, so it is logged rather than stopping the agent.
+pub async fn announce(announcement: Announcement) {
+    let socket = match join_group() {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to start the mDNS announcement: {}", e);
+            return;
+        }
+    };
+    info!(
+        "Announcing this agent on the LAN as '{}' ({}).",
+        announcement.name, SERVICE
+    );
+    let group = SocketAddr::from((MDNS_GROUP, MDNS_PORT));
+
+    // Unsolicited announcements, so browsers already running see the agent come up
+    for _ in 0..2 {
+        if let Err(e) = socket
+            .send_to(&response(&announcement, 0, None), group)
+            .await
+        {
+            warn!("Failed to send the mDNS announcement: {}", e);
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+
+    let mut buffer = vec![0; MAX_PACKET];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to read an mDNS packet: {}", e);
+                continue;
+            }
+        };
+        let Some(id) = asks_for_service(&buffer[..length]) else {
+            continue;
+        };
+        // A query from another port is a one-shot query, answered to the sender directly
+        let (id, question, to) = if from.port() == MDNS_PORT {
+            (0, None, group)
+        } else {
+            (id, Some(SERVICE), from)
+        };
+        if let Err(e) = socket
+            .send_to(&response(&announcement, id, question), to)
+            .await
+        {
+            warn!("Failed to answer an mDNS query from {}: {}", from, e);
+        }
+    }
+}
+
+// Asks the LAN for agents and lists the ones that answer within `wait`
+pub async fn discover(wait: Duration) -> Result<Vec<Agent>, Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let id = std::process::id() as u16;
+    socket.send_to(&query(id), (MDNS_GROUP, MDNS_PORT)).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut agents: Vec<Agent> = Vec::new();
+    let mut buffer = vec![0; MAX_PACKET];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (length, from) = received?;
+        for agent in parse_response(&buffer[..length], from.ip()) {
+            // Agents answering twice, e.g. on two interfaces, are listed once
+            if !agents
+                .iter()
+                .any(|known| known.name.eq_ignore_ascii_case(&agent.name))
+            {
+                agents.push(agent);
+            }
+        }
+    }
+    agents.sort_by_key(|agent| agent.name.to_lowercase());
+    Ok(agents)
+}
+
+// A socket on the mDNS port, in the multicast group. Other responders (the OS's own, or more
+// agents on the host) share the port, so it is bound for reuse.
+fn join_group() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// The address other hosts reach this one on, as picked for multicast traffic
+fn local_address() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(address) if !address.is_unspecified() => Some(address),
+        _ => None,
+    }
+}
+
+// DNS labels can't hold dots, and are at most 63 bytes
+fn instance_label(name: &str) -> String {
+    let mut label: String = name
+        .chars()
+        .map(|c| if c == '.' { '-' } else { c })
+        .collect();
+    while label.len() > 63 {
+        label.pop();
+    }
+    label
+}
+
+fn header(packet: &mut Vec<u8>, id: u16, flags: u16, counts: [u16; 4]) {
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        packet.extend_from_slice(&count.to_be_bytes());
+    }
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn write_record(packet: &mut Vec<u8>, name: &str, record_type: u16, data: &[u8]) {
+    write_name(packet, name);
+    packet.extend_from_slice(&record_type.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+fn query(id: u16) -> Vec<u8> {
+    let mut packet = Vec::new();
+    header(&mut packet, id, 0, [1, 0, 0, 0]);
+    write_name(&mut packet, SERVICE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+// The PTR answer pointing at this agent, with its SRV, TXT and A records alongside
+fn response(announcement: &Announcement, id: u16, question: Option<&str>) -> Vec<u8> {
+    let label = instance_label(&announcement.name);
+    let instance = format!("{}.{}", label, SERVICE);
+    let host = format!("{}.local", label);
+    let address = local_address();
+
+    let mut packet = Vec::new();
+    let additional = 2 + u16::from(address.is_some());
+    header(
+        &mut packet,
+        id,
+        0x8400,
+        [u16::from(question.is_some()), 1, 0, additional],
+    );
+    if let Some(question) = question {
+        write_name(&mut packet, question);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+
+    let mut data = Vec::new();
+    write_name(&mut data, &instance);
+    write_record(&mut packet, SERVICE, TYPE_PTR, &data);
+
+    let mut data = vec![0, 0, 0, 0];
+    data.extend_from_slice(&announcement.port.to_be_bytes());
+    write_name(&mut data, &host);
+    write_record(&mut packet, &instance, TYPE_SRV, &data);
+
+    let mut data = Vec::new();
+    let status = ("status".to_string(), metrics::agent_status().to_string());
+    for (key, value) in announcement.details.iter().chain([&status]) {
+        let mut entry = format!("{}={}", key, value).into_bytes();
+        entry.truncate(255);
+        data.push(entry.len() as u8);
+        data.extend_from_slice(&entry);
+    }
+    write_record(&mut packet, &instance, TYPE_TXT, &data);
+
+    if let Some(address) = address {
+        write_record(&mut packet, &host, TYPE_A, &address.octets());
+    }
+    packet
+}
+
+fn read_u16(packet: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(at)?, *packet.get(at + 1)?]))
+}
+
+// Reads a possibly compressed name, returning it and the position after it
+fn read_name(packet: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let length = *packet.get(at)? as usize;
+        if length & 0xC0 == 0xC0 {
+            end.get_or_insert(at + 2);
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            at = ((length & 0x3F) << 8) | *packet.get(at + 1)? as usize;
+            continue;
+        }
+        if length == 0 {
+            end.get_or_insert(at + 1);
+            break;
+        }
+        let label = packet.get(at + 1..at + 1 + length)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        at += 1 + length;
+    }
+    Some((labels.join("."), end?))
+}
+
+// The id of a query asking for the service, None for anything else
+fn asks_for_service(packet: &[u8]) -> Option<u16> {
+    let id = read_u16(packet, 0)?;
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let questions = read_u16(packet, 4)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, at)?;
+        let question_type = read_u16(packet, next)?;
+        at = next + 4;
+        if name.eq_ignore_ascii_case(SERVICE)
+            && (question_type == TYPE_PTR || question_type == TYPE_ANY)
+        {
+            return Some(id);
+        }
+    }
+    None
+}
+
+// The agents a response describes
+fn parse_response(packet: &[u8], from: IpAddr) -> Vec<Agent> {
+    let mut agents = Vec::new();
+    let Some(flags) = read_u16(packet, 2) else {
+        return agents;
+    };
+    if flags & 0x8000 == 0 {
+        return agents;
+    }
+    let (Some(questions), Some(answers), Some(authority), Some(additional)) = (
+        read_u16(packet, 4),
+        read_u16(packet, 6),
+        read_u16(packet, 8),
+        read_u16(packet, 10),
+    ) else {
+        return agents;
+    };
+
+    let mut at = 12;
+    for _ in 0..questions {
+        let Some((_, next)) = read_name(packet, at) else {
+            return agents;
+        };
+        at = next + 4;
+    }
+
+    let mut instances = Vec::new();
+    let mut ports = BTreeMap::new();
+    let mut details: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    for _ in 0..answers as usize + authority as usize + additional as usize {
+        let Some((name, next)) = read_name(packet, at) else {
+            break;
+        };
+        let (Some(record_type), Some(length)) =
+            (read_u16(packet, next), read_u16(packet, next + 8))
+        else {
+            break;
+        };
+        let data_at = next + 10;
+        let Some(data) = packet.get(data_at..data_at + length as usize) else {
+            break;
+        };
+        let name = name.to_lowercase();
+        match record_type {
+            TYPE_PTR if name == SERVICE => {
+                if let Some((instance, _)) = read_name(packet, data_at) {
+                    instances.push(instance);
+                }
+            }
+            TYPE_SRV => {
+                if let Some(port) = read_u16(data, 4) {
+                    ports.insert(name, port);
+                }
+            }
+            TYPE_TXT => {
+                let entries = details.entry(name).or_default();
+                let mut entry_at = 0;
+                while let Some(&entry_length) = data.get(entry_at) {
+                    let Some(entry) = data.get(entry_at + 1..entry_at + 1 + entry_length as usize)
+                    else {
+                        break;
+                    };
+                    let entry = String::from_utf8_lossy(entry);
+                    if let Some((key, value)) = entry.split_once('=') {
+                        entries.insert(key.to_string(), value.to_string());
+                    }
+                    entry_at += 1 + entry_length as usize;
+                }
+            }
+            _ => {}
+        }
+        at = data_at + length as usize;
+    }
+
+    for instance in instances {
+        let key = instance.to_lowercase();
+        let name = instance
+            .strip_suffix(&format!(".{}", SERVICE))
+            .unwrap_or(&instance)
+            .to_string();
+        agents.push(Agent {
+            name,
+            address: from,
+            port: ports.get(&key).copied().unwrap_or(0),
+            details: details.remove(&key).unwrap_or_default(),
+        });
+    }
+    agents
+}
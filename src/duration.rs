@@ -0,0 +1,118 @@
+// Durations in config.toml, written as whole seconds (90) or as text ("30s", "5m", "1h30m",
+// "2 days"), the way humantime reads them.
+use serde::{Deserialize, Deserializer};
+
+// Units a number can be followed by, with their length in seconds
+const UNITS: &[(&[&str], u64)] = &[
+    (&["s", "sec", "secs", "second", "seconds"], 1),
+    (&["m", "min", "mins", "minute", "minutes"], 60),
+    (&["h", "hr", "hrs", "hour", "hours"], 3600),
+    (&["d", "day", "days"], 86400),
+];
+
+// Seconds in a duration such as "90", "45s", "5m" or "1h 30m"
+pub fn parse(text: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid duration '{}', expected e.g. \"30s\", \"5m\" or \"1h\"",
+            text
+        )
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = rest[digits..].trim_start();
+        let letters = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = rest[..letters].to_ascii_lowercase();
+        let (_, seconds) = UNITS
+            .iter()
+            .find(|(names, _)| names.contains(&unit.as_str()))
+            .ok_or_else(invalid)?;
+        total = number
+            .checked_mul(*seconds)
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(invalid)?;
+        rest = rest[letters..].trim_start();
+    }
+    Ok(total)
+}
+
+// A duration the way parse() reads it, e.g. "1h30m" for 5400
+pub fn format(seconds: u64) -> String {
+    if seconds == 0 {
+        return "0s".to_string();
+    }
+    let mut text = String::new();
+    let mut left = seconds;
+    for (unit, length) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if left >= length {
+            text.push_str(&format!("{}{}", left / length, unit));
+            left %= length;
+        }
+    }
+    text
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Written {
+    Seconds(u64),
+    Text(String),
+}
+
+// Reads a duration key as seconds, from a number or from text
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match Written::deserialize(deserializer)? {
+        Written::Seconds(seconds) => Ok(seconds),
+        Written::Text(text) => parse(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+// seconds() for a key that may be left out
+pub fn optional_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    seconds(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn reads_units_and_combinations() {
+        assert_eq!(parse("90"), Ok(90));
+        assert_eq!(parse("30s"), Ok(30));
+        assert_eq!(parse("5m"), Ok(300));
+        assert_eq!(parse("1h30m"), Ok(5400));
+        assert_eq!(parse(" 2 days 1 hour "), Ok(176400));
+        assert!(parse("5 fortnights").is_err());
+        assert!(parse("m5").is_err());
+        assert!(parse("").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_durations_read_back(
+            seconds in prop_oneof![0..120u64, 0..86400u64, 0..u64::MAX / 86400],
+        ) {
+            let text = format(seconds);
+            prop_assert_eq!(parse(&text), Ok(seconds), "written as {:?}", text);
+        }
+    }
+}
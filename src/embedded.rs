@@ -0,0 +1,778 @@
+// The git operations of the sync path on the libgit2 built into the binary (the embedded-git
+// feature), used when no git program can be found. Each runs on the blocking thread pool.
+// Verification, drift checks, releases, archives and simulate still run the git program.
+use crate::git::{Change, CommitFiles, DiffStat, DubiousOwnership, FileChange};
+use crate::history::CommitSummary;
+use crate::verify::Verification;
+use crate::{glob, secrets};
+use chrono::{DateTime, FixedOffset, SecondsFormat};
+use git2::build::CheckoutBuilder;
+use git2::{
+    BranchType, Commit, Config, ConfigLevel, Diff, ErrorClass, ErrorCode, FetchOptions, FetchPrune,
+    MergeAnalysis, Oid, Repository, ResetType, Signature, Sort, Status, StatusOptions,
+};
+use log::{info, warn};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Merges need an author, and an appliance without git has no git identity configured either
+const MERGE_NAME: &str = "DevOps_Repository_Sync";
+const MERGE_EMAIL: &str = "devops-sync@localhost";
+
+pub fn version() -> String {
+    let (major, minor, patch) = git2::Version::get().libgit2_version();
+    format!("libgit2 {}.{}.{}", major, minor, patch)
+}
+
+// Runs a libgit2 operation without blocking the runtime
+async fn blocking<T: Send + 'static>(
+    operation: impl FnOnce() -> Result<T> + Send + 'static,
+) -> std::result::Result<T, Box<dyn std::error::Error>> {
+    tokio::task::spawn_blocking(operation)
+        .await?
+        .map_err(|e| e as Box<dyn std::error::Error>)
+}
+
+fn open(repo_path: &str) -> Result<Repository> {
+    Repository::open(repo_path).map_err(|e| match e.code() {
+        ErrorCode::Owner => Box::new(DubiousOwnership(repo_path.to_string())) as _,
+        _ => e.into(),
+    })
+}
+
+fn commit<'repo>(repo: &'repo Repository, revision: &str) -> Result<Commit<'repo>> {
+    Ok(repo.revparse_single(revision)?.peel_to_commit()?)
+}
+
+// The commit HEAD is at, or nothing in a fresh clone whose branch has no commit yet
+pub async fn head(repo_path: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let repo_path = repo_path.to_string();
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let head = match repo.head() {
+            Ok(head) => head,
+            Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound) => {
+                return Ok(String::new())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let commit = head.peel_to_commit()?;
+        Ok(commit.id().to_string())
+    })
+    .await
+}
+
+pub async fn ensure_safe_directory(
+    repo_path: &str,
+    add: bool,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let repo_path = repo_path.to_string();
+    blocking(move || {
+        match Repository::open(&repo_path) {
+            Err(e) if e.code() == ErrorCode::Owner => {}
+            _ => return Ok(()),
+        }
+        if !add {
+            return Err(Box::new(DubiousOwnership(repo_path)) as _);
+        }
+        warn!(
+            "'{}' is owned by another user, adding it to git's safe.directory list.",
+            repo_path
+        );
+        // "^$" matches none of the existing values, so the path is added to them
+        Config::open_default()?
+            .open_level(ConfigLevel::Global)?
+            .set_multivar("safe.directory", "^$", &repo_path)?;
+        info!("Added '{}' to safe.directory.", repo_path);
+        Ok(())
+    })
+    .await
+}
+
+pub async fn enable_long_paths(
+    repo_path: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let repo_path = repo_path.to_string();
+    blocking(move || {
+        Ok(open(&repo_path)?
+            .config()?
+            .set_bool("core.longpaths", true)?)
+    })
+    .await
+}
+
+// Fetches the remote's branches into origin/*, with the PAT in the URL
+pub async fn fetch(
+    repo_path: &str,
+    url: &str,
+    refspec: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, url, refspec) = (repo_path.to_string(), url.to_string(), refspec.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let mut options = FetchOptions::new();
+        options.prune(FetchPrune::On);
+        let fetched =
+            repo.remote_anonymous(&url)?
+                .fetch(&[refspec.as_str()], Some(&mut options), None);
+        match fetched {
+            Err(e)
+                if e.code() == ErrorCode::Auth
+                    || (e.class() == ErrorClass::Http
+                        && e.message().contains("authentication")) =>
+            {
+                Err(Box::new(secrets::AuthError(format!("git fetch was rejected: {}", e))) as _)
+            }
+            fetched => Ok(fetched?),
+        }
+    })
+    .await
+}
+
+// Switches to branch, creating it from origin/<branch> when it doesn't exist yet. Local changes
+// are kept, and the switch fails rather than overwrite the ones it would touch.
+pub async fn switch_branch(
+    repo_path: &str,
+    branch: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, branch) = (repo_path.to_string(), branch.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let local = match repo.find_branch(&branch, BranchType::Local) {
+            Ok(local) => local,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                let remote = format!("origin/{}", branch);
+                let mut local = repo.branch(&branch, &commit(&repo, &remote)?, false)?;
+                local.set_upstream(Some(&remote))?;
+                info!("Created branch '{}' tracking {}.", branch, remote);
+                local
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let name = local
+            .get()
+            .name()
+            .ok_or("the branch name isn't UTF-8")?
+            .to_string();
+        let target = local.get().peel_to_commit()?;
+        repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+        repo.set_head(&name)?;
+        Ok(())
+    })
+    .await
+}
+
+// Switches to branch, throwing away local changes that would stop the switch
+pub async fn checkout_force(
+    repo_path: &str,
+    branch: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, branch) = (repo_path.to_string(), branch.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        repo.set_head(&format!("refs/heads/{}", branch))?;
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn checkout_detached(
+    repo_path: &str,
+    target: &str,
+    force: bool,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, target) = (repo_path.to_string(), target.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let target = commit(&repo, &target)?;
+        let mut checkout = CheckoutBuilder::new();
+        if force {
+            checkout.force();
+        } else {
+            checkout.safe();
+        }
+        repo.checkout_tree(target.as_object(), Some(&mut checkout))?;
+        repo.set_head_detached(target.id())?;
+        Ok(())
+    })
+    .await
+}
+
+// Merges origin/<branch> into the checked-out branch: a fast-forward when possible, otherwise a
+// merge commit. A conflict leaves the merge in progress, as git does.
+pub async fn merge(
+    repo_path: &str,
+    remote_branch: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, remote_branch) = (repo_path.to_string(), remote_branch.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let theirs = repo.reference_to_annotated_commit(
+            &repo.find_reference(&format!("refs/remotes/{}", remote_branch))?,
+        )?;
+        let (analysis, _) = repo.merge_analysis(&[&theirs])?;
+        if analysis.contains(MergeAnalysis::ANALYSIS_UP_TO_DATE) {
+            return Ok(());
+        }
+        let target = repo.find_commit(theirs.id())?;
+        if analysis.contains(MergeAnalysis::ANALYSIS_FASTFORWARD) {
+            repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+            repo.head()?.set_target(
+                target.id(),
+                &format!("merge {}: Fast-forward", remote_branch),
+            )?;
+            return Ok(());
+        }
+
+        repo.merge(&[&theirs], None, Some(CheckoutBuilder::new().safe()))?;
+        let mut index = repo.index()?;
+        if index.has_conflicts() {
+            return Err("Automatic merge failed, the changes conflict".into());
+        }
+        let tree = repo.find_tree(index.write_tree()?)?;
+        let signature = repo
+            .signature()
+            .or_else(|_| Signature::now(MERGE_NAME, MERGE_EMAIL))?;
+        let ours = repo.head()?.peel_to_commit()?;
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &format!("Merge remote-tracking branch '{}'", remote_branch),
+            &tree,
+            &[&ours, &target],
+        )?;
+        repo.cleanup_state()?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn remote_tip(repo_path: &str, branch: &str) -> Option<String> {
+    let (repo_path, branch) = (repo_path.to_string(), branch.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let tip = commit(&repo, &format!("refs/remotes/origin/{}", branch))?;
+        Ok(tip.id().to_string())
+    })
+    .await
+    .ok()
+}
+
+pub async fn set_origin_url(
+    repo_path: &str,
+    url: &str,
+) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+    let (repo_path, url) = (repo_path.to_string(), url.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let has_origin = match repo.find_remote("origin") {
+            Ok(_) => true,
+            Err(e) if e.code() == ErrorCode::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        if has_origin {
+            repo.remote_set_url("origin", &url)?;
+        }
+        Ok(has_origin)
+    })
+    .await
+}
+
+pub async fn is_ancestor(repo_path: &str, ancestor: &str, descendant: &str) -> bool {
+    let (repo_path, ancestor, descendant) = (
+        repo_path.to_string(),
+        ancestor.to_string(),
+        descendant.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let ancestor = commit(&repo, &ancestor)?.id();
+        let descendant = commit(&repo, &descendant)?.id();
+        Ok(ancestor == descendant || repo.graph_descendant_of(descendant, ancestor)?)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+pub async fn reset_hard(
+    repo_path: &str,
+    target: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, target) = (repo_path.to_string(), target.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        // A merge left half-way would otherwise survive as MERGE_HEAD
+        repo.cleanup_state()?;
+        let target = commit(&repo, &target)?;
+        repo.reset(target.as_object(), ResetType::Hard, None)?;
+        Ok(())
+    })
+    .await
+}
+
+// Like `git reset --keep`: the safe checkout refuses to overwrite local changes to files the move
+// touches and keeps the others, then the branch and the index follow
+pub async fn reset_keep(
+    repo_path: &str,
+    target: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, target) = (repo_path.to_string(), target.to_string());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let target = commit(&repo, &target)?;
+        repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().safe()))?;
+        repo.reset(target.as_object(), ResetType::Mixed, None)?;
+        Ok(())
+    })
+    .await
+}
+
+// Removes untracked files, and gitignored ones when asked to, except the preserved ones. The
+// directories they leave empty go too. Nested repositories are left alone.
+pub async fn clean(
+    repo_path: &str,
+    ignored: bool,
+    preserve: &[String],
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (repo_path, preserve) = (repo_path.to_string(), preserve.to_vec());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true)
+            .include_ignored(ignored)
+            .recurse_ignored_dirs(true);
+        let removable = Status::WT_NEW | Status::IGNORED;
+        let paths: Vec<String> = repo
+            .statuses(Some(&mut options))?
+            .iter()
+            .filter(|entry| entry.status().intersects(removable))
+            .filter_map(|entry| entry.path().map(str::to_string))
+            .filter(|path| !preserved(&preserve, path))
+            .collect();
+
+        let root = Path::new(&repo_path);
+        for path in paths {
+            let full = root.join(&path);
+            // libgit2 lists a nested repository as a directory instead of looking inside
+            if full.join(".git").exists() {
+                continue;
+            }
+            info!("Removing {}", path);
+            if full.is_dir() {
+                std::fs::remove_dir_all(&full)?;
+            } else {
+                std::fs::remove_file(&full)?;
+            }
+            let mut parent = full.parent();
+            while let Some(directory) = parent.filter(|directory| *directory != root) {
+                if std::fs::remove_dir(directory).is_err() {
+                    break;
+                }
+                parent = directory.parent();
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+// Whether a pattern, as in .gitignore, matches the path or a directory it is in. Patterns
+// without a slash, other than a trailing one, match a name at any depth.
+fn preserved(patterns: &[String], path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    (1..=segments.len()).any(|depth| {
+        let within = segments[..depth].join("/");
+        patterns.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.contains('/') {
+                glob::matches(pattern, &within)
+            } else {
+                glob::matches(pattern, segments[depth - 1])
+            }
+        })
+    })
+}
+
+// Like verify::differences in status mode: HEAD and the porcelain status lines, leaving out
+// gitignored files and preserved paths. Hashing the tree again is left to the git program.
+pub async fn differences(
+    repo_path: &str,
+    commit: &str,
+    verification: Verification,
+    preserve: &[String],
+) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
+    if verification == Verification::Hashes {
+        return Err(
+            "verify = \"hashes\" needs a git program, the embedded libgit2 only compares statuses"
+                .into(),
+        );
+    }
+    let (repo_path, commit, preserve) =
+        (repo_path.to_string(), commit.to_string(), preserve.to_vec());
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let mut found = Vec::new();
+        let head = repo.head()?.peel_to_commit()?.id().to_string();
+        if head != commit {
+            found.push(format!("HEAD is at {} instead of {}", head, commit));
+        }
+        let mut options = StatusOptions::new();
+        // Listed file by file so a preserved path inside an untracked directory can be left out
+        options.include_untracked(true).recurse_untracked_dirs(true);
+        for entry in repo.statuses(Some(&mut options))?.iter() {
+            let Some(path) = entry.path() else {
+                continue;
+            };
+            if preserved(&preserve, path) {
+                continue;
+            }
+            found.push(format!("status: {} {}", status_code(entry.status()), path));
+        }
+        Ok(found)
+    })
+    .await
+}
+
+// The two letters `git status --porcelain` shows for the index and the working tree
+fn status_code(status: Status) -> String {
+    if status.contains(Status::WT_NEW) {
+        return "??".to_string();
+    }
+    if status.contains(Status::CONFLICTED) {
+        return "UU".to_string();
+    }
+    let index = [
+        (Status::INDEX_NEW, 'A'),
+        (Status::INDEX_MODIFIED, 'M'),
+        (Status::INDEX_DELETED, 'D'),
+        (Status::INDEX_RENAMED, 'R'),
+        (Status::INDEX_TYPECHANGE, 'T'),
+    ];
+    let worktree = [
+        (Status::WT_MODIFIED, 'M'),
+        (Status::WT_DELETED, 'D'),
+        (Status::WT_RENAMED, 'R'),
+        (Status::WT_TYPECHANGE, 'T'),
+    ];
+    let letter = |flags: &[(Status, char)]| {
+        flags
+            .iter()
+            .find(|(flag, _)| status.contains(*flag))
+            .map_or(' ', |(_, letter)| *letter)
+    };
+    format!("{}{}", letter(&index), letter(&worktree))
+}
+
+fn diff<'repo>(repo: &'repo Repository, old_commit: &str, new_commit: &str) -> Result<Diff<'repo>> {
+    let old_tree = commit(repo, old_commit)?.tree()?;
+    let new_tree = commit(repo, new_commit)?.tree()?;
+    Ok(repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?)
+}
+
+fn delta_path(delta: &git2::DiffDelta<'_>) -> Option<String> {
+    delta
+        .new_file()
+        .path()
+        .or(delta.old_file().path())
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+}
+
+pub async fn changed_files(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (repo_path, old_commit, new_commit) = (
+        repo_path.to_string(),
+        old_commit.to_string(),
+        new_commit.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let diff = diff(&repo, &old_commit, &new_commit)?;
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| delta_path(&delta))
+            .collect())
+    })
+    .await
+}
+
+pub async fn file_changes(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> std::result::Result<Vec<FileChange>, Box<dyn std::error::Error>> {
+    let (repo_path, old_commit, new_commit) = (
+        repo_path.to_string(),
+        old_commit.to_string(),
+        new_commit.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let diff = diff(&repo, &old_commit, &new_commit)?;
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| {
+                let change = match delta.status() {
+                    git2::Delta::Added => Change::Added,
+                    git2::Delta::Deleted => Change::Deleted,
+                    git2::Delta::Typechange => Change::Changed,
+                    _ => Change::Modified,
+                };
+                Some(FileChange {
+                    path: delta_path(&delta)?,
+                    change,
+                })
+            })
+            .collect())
+    })
+    .await
+}
+
+pub async fn diff_stat(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> std::result::Result<DiffStat, Box<dyn std::error::Error>> {
+    let (repo_path, old_commit, new_commit) = (
+        repo_path.to_string(),
+        old_commit.to_string(),
+        new_commit.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        let stats = diff(&repo, &old_commit, &new_commit)?.stats()?;
+        Ok(DiffStat {
+            files_changed: stats.files_changed() as u64,
+            insertions: stats.insertions() as u64,
+            deletions: stats.deletions() as u64,
+        })
+    })
+    .await
+}
+
+// The commits reachable from new_commit but not old_commit, newest first
+fn walk(repo: &Repository, old_commit: &str, new_commit: &str) -> Result<Vec<Oid>> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TIME)?;
+    walk.push(commit(repo, new_commit)?.id())?;
+    // A checkout without a commit yet has nothing to leave out
+    if !old_commit.is_empty() {
+        walk.hide(commit(repo, old_commit)?.id())?;
+    }
+    Ok(walk.collect::<std::result::Result<_, _>>()?)
+}
+
+pub async fn commit_log(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> std::result::Result<Vec<CommitSummary>, Box<dyn std::error::Error>> {
+    let (repo_path, old_commit, new_commit) = (
+        repo_path.to_string(),
+        old_commit.to_string(),
+        new_commit.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        walk(&repo, &old_commit, &new_commit)?
+            .into_iter()
+            .map(|id| {
+                let commit = repo.find_commit(id)?;
+                let author = commit.author();
+                let message = commit.summary().unwrap_or_default().to_string();
+                let body = commit.body().unwrap_or_default();
+                let time = commit.time();
+                let committed_at = FixedOffset::east_opt(time.offset_minutes() * 60)
+                    .zip(DateTime::from_timestamp(time.seconds(), 0))
+                    .map(|(offset, at)| {
+                        at.with_timezone(&offset)
+                            .to_rfc3339_opts(SecondsFormat::Secs, false)
+                    });
+                Ok(CommitSummary {
+                    id: id.to_string(),
+                    short_id: commit
+                        .as_object()
+                        .short_id()?
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    author: author.name().unwrap_or_default().to_string(),
+                    author_email: author.email().unwrap_or_default().to_string(),
+                    work_item_ids: crate::git::work_item_mentions(&format!(
+                        "{}\n{}",
+                        message, body
+                    )),
+                    message,
+                    committed_at,
+                })
+            })
+            .collect()
+    })
+    .await
+}
+
+pub async fn commit_files(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> std::result::Result<Vec<CommitFiles>, Box<dyn std::error::Error>> {
+    let (repo_path, old_commit, new_commit) = (
+        repo_path.to_string(),
+        old_commit.to_string(),
+        new_commit.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path)?;
+        walk(&repo, &old_commit, &new_commit)?
+            .into_iter()
+            .map(|id| {
+                let commit = repo.find_commit(id)?;
+                let author = commit.author();
+                // Like git log, merge commits list no files of their own
+                let files = match commit.parent_count() {
+                    0 | 1 => {
+                        let parent = commit.parents().next().map(|parent| parent.tree());
+                        let diff = repo.diff_tree_to_tree(
+                            parent.transpose()?.as_ref(),
+                            Some(&commit.tree()?),
+                            None,
+                        )?;
+                        diff.deltas()
+                            .filter_map(|delta| delta_path(&delta))
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+                Ok(CommitFiles {
+                    id: id.to_string(),
+                    author: author.name().unwrap_or_default().to_string(),
+                    author_email: author.email().unwrap_or_default().to_string(),
+                    files,
+                })
+            })
+            .collect()
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(repo: &str, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-C",
+                repo,
+                "-c",
+                "user.name=Ann",
+                "-c",
+                "user.email=ann@example.com",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn a_fresh_checkout_follows_the_remote_like_the_git_program() {
+        let root = std::env::temp_dir().join(format!("embedded-git-test-{}", std::process::id()));
+        let (remote, local) = (root.join("remote"), root.join("local"));
+        std::fs::create_dir_all(&remote).unwrap();
+        std::fs::create_dir_all(&local).unwrap();
+        let remote_path = remote.to_string_lossy().to_string();
+        let local_path = local.to_string_lossy().to_string();
+        git(&remote_path, &["init", "-q"]);
+        git(&remote_path, &["checkout", "-qb", "main"]);
+        std::fs::write(remote.join("readme.md"), "one\n").unwrap();
+        git(&remote_path, &["add", "-A"]);
+        git(&remote_path, &["commit", "-qm", "one"]);
+        let first = git(&remote_path, &["rev-parse", "HEAD"]);
+        git(&local_path, &["init", "-q"]);
+        git(&local_path, &["remote", "add", "origin", &remote_path]);
+
+        // Nothing is checked out before the first sync
+        assert_eq!(head(&local_path).await.unwrap(), "");
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        fetch(&local_path, &remote_path, refspec).await.unwrap();
+        assert_eq!(remote_tip(&local_path, "main").await, Some(first.clone()));
+        switch_branch(&local_path, "main").await.unwrap();
+        assert_eq!(head(&local_path).await.unwrap(), first);
+
+        std::fs::write(remote.join("readme.md"), "one\ntwo\n").unwrap();
+        std::fs::write(remote.join("notes.txt"), "AB#42\n").unwrap();
+        git(&remote_path, &["add", "-A"]);
+        git(&remote_path, &["commit", "-qm", "two", "-m", "Fixes AB#42"]);
+        let second = git(&remote_path, &["rev-parse", "HEAD"]);
+        fetch(&local_path, &remote_path, refspec).await.unwrap();
+        assert!(is_ancestor(&local_path, &first, &second).await);
+        assert!(!is_ancestor(&local_path, &second, &first).await);
+
+        // A local edit the merge doesn't touch survives the fast-forward
+        std::fs::create_dir_all(local.join("cache/keep")).unwrap();
+        std::fs::write(local.join("cache/keep/data"), "kept").unwrap();
+        std::fs::write(local.join("cache/stale"), "stale").unwrap();
+        merge(&local_path, "origin/main").await.unwrap();
+        assert_eq!(head(&local_path).await.unwrap(), second);
+
+        let commits = commit_log(&local_path, &first, &second).await.unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].message, "two");
+        assert_eq!(commits[0].author, "Ann");
+        assert_eq!(commits[0].work_item_ids, vec![42]);
+        assert_eq!(
+            commits[0].committed_at,
+            Some(git(&remote_path, &["log", "-1", "--format=%cI"]))
+        );
+        let mut changed = changed_files(&local_path, &first, &second).await.unwrap();
+        changed.sort();
+        assert_eq!(changed, ["notes.txt", "readme.md"]);
+        let stat = diff_stat(&local_path, &first, &second).await.unwrap();
+        assert_eq!((stat.files_changed, stat.insertions), (2, 2));
+
+        std::fs::write(local.join("readme.md"), "edited\n").unwrap();
+        let preserve = ["cache/keep".to_string()];
+        assert_eq!(
+            differences(&local_path, &first, Verification::Status, &preserve)
+                .await
+                .unwrap(),
+            [
+                format!("HEAD is at {} instead of {}", second, first),
+                "status: ?? cache/stale".to_string(),
+                "status:  M readme.md".to_string(),
+            ]
+        );
+        git(&local_path, &["checkout", "--", "readme.md"]);
+
+        clean(&local_path, false, &preserve).await.unwrap();
+        assert!(local.join("cache/keep/data").exists());
+        assert!(!local.join("cache/stale").exists());
+
+        // A pin moves the branch back without touching the untracked files
+        reset_keep(&local_path, &first).await.unwrap();
+        assert_eq!(head(&local_path).await.unwrap(), first);
+        assert!(!local.join("notes.txt").exists());
+        assert!(local.join("cache/keep/data").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn preserved_paths_match_like_gitignore_patterns() {
+        let patterns = ["/data".to_string(), "*.env".to_string()];
+        assert!(preserved(&patterns, "data/a/b.txt"));
+        assert!(preserved(&patterns, "deep/dir/prod.env"));
+        assert!(!preserved(&patterns, "other/data/b.txt"));
+        assert!(!preserved(&patterns, "envs/readme"));
+    }
+}
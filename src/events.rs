@@ -0,0 +1,232 @@
+use crate::history::{CommitSummary, SyncRecord};
+use crate::hooks::HookResult;
+use crate::notify::RepoRef;
+use log::debug;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+// Something that happened while checking or syncing the repo
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncEvent<'a> {
+    // A check cycle began
+    SyncStarted,
+    // The remote branch moved past the local commit. The incoming commits are fetched and
+    // listed beforehand only when something needs them, [[rules]] for now.
+    ChangesDetected {
+        old_commit: &'a str,
+        new_commit: &'a str,
+        #[serde(skip_serializing_if = "<[_]>::is_empty")]
+        commits: &'a [CommitSummary],
+    },
+    PullCompleted {
+        old_commit: &'a str,
+        new_commit: &'a str,
+        commits: &'a [CommitSummary],
+    },
+    PullFailed {
+        error: &'a str,
+    },
+    // A post-sync hook exited non-zero, timed out or could not be started
+    HookFailed {
+        hook: &'a HookResult,
+    },
+    // A sync attempt is over, whatever its outcome
+    SyncFinished {
+        record: &'a SyncRecord,
+    },
+    // Nothing to pull, the local commit matches the remote
+    UpToDate {
+        commit: &'a str,
+    },
+    // The target branch was deleted or renamed on the remote
+    BranchMissing {
+        branch: &'a str,
+        // Branch followed from now on, if one is configured and exists
+        fallback: Option<&'a str>,
+    },
+    // The remote or local commit could not be read
+    CheckFailed {
+        error: &'a str,
+    },
+    // The remote branch carries the halt file, so the checkout stays where it is
+    Halted {
+        local_commit: &'a str,
+        remote_commit: &'a str,
+        reason: &'a str,
+    },
+    // The remote branch was rewritten: its previous tip is no longer in its history
+    ForcePushed {
+        old_commit: &'a str,
+        new_commit: &'a str,
+        // Whether the sync waits for an operator to approve it (force_push = "hold")
+        held: bool,
+    },
+    // The repository was renamed or moved to another project, and is followed by its ID
+    RepositoryMoved {
+        // "project/repository" before and after
+        old_location: &'a str,
+        new_location: &'a str,
+    },
+    // The remote moved on and, in observe mode, the checkout is left behind
+    Behind {
+        local_commit: &'a str,
+        remote_commit: &'a str,
+    },
+    // The checkout was changed locally since the last sync
+    DriftDetected {
+        // Commit the checkout should be at
+        commit: &'a str,
+        differences: &'a [String],
+        // Whether it was reset back to the commit
+        repaired: bool,
+    },
+}
+
+impl SyncEvent<'_> {
+    // Name of the event as it appears in JSON and in subscriber filters
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncEvent::SyncStarted => "sync_started",
+            SyncEvent::ChangesDetected { .. } => "changes_detected",
+            SyncEvent::PullCompleted { .. } => "pull_completed",
+            SyncEvent::PullFailed { .. } => "pull_failed",
+            SyncEvent::HookFailed { .. } => "hook_failed",
+            SyncEvent::SyncFinished { .. } => "sync_finished",
+            SyncEvent::UpToDate { .. } => "up_to_date",
+            SyncEvent::BranchMissing { .. } => "branch_missing",
+            SyncEvent::CheckFailed { .. } => "check_failed",
+            SyncEvent::Halted { .. } => "halted",
+            SyncEvent::ForcePushed { .. } => "force_pushed",
+            SyncEvent::RepositoryMoved { .. } => "repository_moved",
+            SyncEvent::Behind { .. } => "behind",
+            SyncEvent::DriftDetected { .. } => "drift_detected",
+        }
+    }
+}
+
+impl fmt::Display for SyncEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncEvent::SyncStarted => write!(f, "sync started"),
+            SyncEvent::ChangesDetected {
+                old_commit,
+                new_commit,
+                ..
+            } => write!(f, "changes detected ({} -> {})", old_commit, new_commit),
+            SyncEvent::PullCompleted {
+                old_commit,
+                new_commit,
+                ..
+            } => write!(f, "pull completed ({} -> {})", old_commit, new_commit),
+            SyncEvent::PullFailed { error } => write!(f, "pull failed: {}", error),
+            SyncEvent::HookFailed { hook } => write!(f, "hook '{}' failed", hook.name),
+            SyncEvent::SyncFinished { record } => {
+                write!(f, "sync finished ({:?})", record.status)
+            }
+            SyncEvent::UpToDate { commit } => write!(f, "up to date at {}", commit),
+            SyncEvent::BranchMissing { branch, fallback } => match fallback {
+                Some(fallback) => write!(f, "branch {} missing, following {}", branch, fallback),
+                None => write!(f, "branch {} missing", branch),
+            },
+            SyncEvent::CheckFailed { error } => write!(f, "check failed: {}", error),
+            SyncEvent::Halted {
+                local_commit,
+                remote_commit,
+                reason,
+            } => write!(
+                f,
+                "halted at {}, not pulling {}: {}",
+                local_commit, remote_commit, reason
+            ),
+            SyncEvent::ForcePushed {
+                old_commit,
+                new_commit,
+                held,
+            } => write!(
+                f,
+                "force-pushed ({} -> {}){}",
+                old_commit,
+                new_commit,
+                if *held { ", held" } else { "" }
+            ),
+            SyncEvent::RepositoryMoved {
+                old_location,
+                new_location,
+            } => write!(f, "moved from {} to {}", old_location, new_location),
+            SyncEvent::Behind {
+                local_commit,
+                remote_commit,
+            } => write!(f, "behind ({} -> {})", local_commit, remote_commit),
+            SyncEvent::DriftDetected {
+                commit,
+                differences,
+                repaired,
+            } => write!(
+                f,
+                "drift from {} ({} difference(s){})",
+                commit,
+                differences.len(),
+                if *repaired { ", repaired" } else { "" }
+            ),
+        }
+    }
+}
+
+// What subscribers asked for in response to an event
+#[derive(Default)]
+pub struct Directive {
+    // Leave the changes alone until the next cycle
+    pub skip: bool,
+    // Stop the sync, with the reason why
+    pub abort: Option<String>,
+    // Notes to keep with the sync record
+    pub annotations: BTreeMap<String, String>,
+    // Names of the post-sync hooks to run, in order, instead of the configured list
+    pub hooks: Option<Vec<String>>,
+}
+
+impl Directive {
+    fn merge(&mut self, other: Directive) {
+        self.skip |= other.skip;
+        if self.abort.is_none() {
+            self.abort = other.abort;
+        }
+        self.annotations.extend(other.annotations);
+        if self.hooks.is_none() {
+            self.hooks = other.hooks;
+        }
+    }
+}
+
+pub type Handled<'a> = Pin<Box<dyn Future<Output = Directive> + 'a>>;
+
+// Receives every published event, ignoring the ones it has no use for
+pub trait Subscriber {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a>;
+}
+
+// Fans sync events out to the registered subscribers
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<Box<dyn Subscriber>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&mut self, subscriber: impl Subscriber + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    // Hands the event to each subscriber in registration order, combining what they ask for
+    pub async fn publish(&mut self, event: SyncEvent<'_>, repo: &RepoRef<'_>) -> Directive {
+        debug!("Event: {}", event);
+        let mut directive = Directive::default();
+        for subscriber in &mut self.subscribers {
+            directive.merge(subscriber.handle(&event, repo).await);
+        }
+        directive
+    }
+}
@@ -0,0 +1,323 @@
+// Azure Artifacts feeds watched for new package versions, for deployments that ship as NuGet or
+// universal packages instead of a repository. Each [[feeds]] entry is checked on the same
+// schedule as the repos, unpacked into its directory, and reported through the same history,
+// notifications and hooks.
+use crate::alert::AlertConfig;
+use crate::azure;
+use crate::duration;
+use crate::hooks::HookConfig;
+use crate::notify::{NotificationConfig, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::secrets::AuthError;
+use crate::unpack;
+use log::info;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tokio::process::Command;
+
+// Packaging APIs are only published as previews
+const PACKAGING_API_VERSION: &str = "7.1-preview.1";
+
+// File in the directory recording which version it holds
+const VERSION_FILE: &str = ".package-version";
+
+// Environment variable the Azure CLI reads a PAT from
+const AZ_PAT_ENV: &str = "AZURE_DEVOPS_EXT_PAT";
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Nuget,
+    // Universal packages, downloaded with the Azure CLI
+    Universal,
+}
+
+// One [[feeds]] entry, merged over the top-level keys like a [[repos]] entry
+#[derive(Deserialize)]
+pub struct FeedConfig {
+    pub organization: String,
+    // Project of a project-scoped feed; empty for a feed scoped to the organization
+    #[serde(default)]
+    pub project: String,
+    pub feed: String,
+    pub package: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    // Directory the package's contents replace whenever a new version comes out
+    pub directory: String,
+    #[serde(default)]
+    pub pat: String,
+    pub pat_env: Option<String>,
+    pub pat_file: Option<String>,
+    pub secrets_identity: Option<String>,
+    #[serde(alias = "check_interval", deserialize_with = "duration::seconds")]
+    pub check_interval_seconds: u64,
+    #[serde(default = "default_history_file")]
+    pub history_file: String,
+    #[serde(default)]
+    pub notifications: Vec<NotificationConfig>,
+    #[serde(default)]
+    pub alerts: Vec<AlertConfig>,
+    #[serde(default)]
+    pub agent_labels: Vec<String>,
+    // Commands run after each new version is in place
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    // File this feed's log records are also written to
+    pub log_file: Option<String>,
+}
+
+fn default_history_file() -> String {
+    "sync_history.jsonl".to_string()
+}
+
+impl Documented for FeedConfig {
+    const SECTION: &'static str = "[[feeds]]";
+    const ABOUT: &'static str = "Azure Artifacts packages deployed whenever a new version is published. Top-level keys such as organization, project, the PAT, check_interval_seconds, history_file and notifications apply unless the entry sets its own.";
+    const FIELDS: &'static [Field] = &[
+        schema::required("feed", "string", r#""releases""#, "Feed name"),
+        schema::required("package", "string", r#""Contoso.Service""#, "Package name"),
+        schema::defaulted(
+            "protocol",
+            "\"nuget\" or \"universal\"",
+            r#""nuget""#,
+            "Package type; universal packages are downloaded with the Azure CLI (az)",
+        ),
+        schema::required(
+            "directory",
+            "string",
+            r#""C:\\Deploy\\service""#,
+            "Directory the package's contents replace when a new version comes out",
+        ),
+        schema::defaulted(
+            "project",
+            "string",
+            "top-level project",
+            "Project of a project-scoped feed, \"\" for an organization-scoped feed",
+        ),
+        schema::defaulted(
+            "hooks",
+            "list of [[post_sync.hooks]] tables",
+            "[]",
+            "Commands run in the directory once a new version is in place, with {{old_version}}, {{new_version}}, {{package}}, {{feed}} and {{directory}}; paths and min_interval_seconds don't apply",
+        ),
+    ];
+}
+
+#[derive(Deserialize)]
+struct PackageList {
+    value: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    #[serde(default)]
+    versions: Vec<PackageVersion>,
+}
+
+#[derive(Deserialize)]
+struct PackageVersion {
+    version: String,
+    #[serde(rename = "isLatest", default)]
+    is_latest: bool,
+}
+
+impl FeedConfig {
+    // How the feed appears to history, notifications and alerts: the package in place of the
+    // repository and the feed in place of the branch
+    pub fn repo_ref(&self) -> RepoRef<'_> {
+        RepoRef {
+            organization: &self.organization,
+            project: &self.project,
+            repository: &self.package,
+            branch: &self.feed,
+            pat: &self.pat,
+            labels: &self.agent_labels,
+        }
+    }
+
+    // "{org}/{project}/" or "{org}/" for organization-scoped feeds
+    fn scope(&self) -> String {
+        if self.project.is_empty() {
+            format!("{}/", azure::segment(&self.organization))
+        } else {
+            format!(
+                "{}/{}/",
+                azure::segment(&self.organization),
+                azure::segment(&self.project)
+            )
+        }
+    }
+
+    // The version the directory holds, as recorded when it was unpacked
+    pub fn installed_version(&self) -> Option<String> {
+        fs::read_to_string(Path::new(&self.directory).join(VERSION_FILE))
+            .ok()
+            .map(|version| version.trim().to_string())
+            .filter(|version| !version.is_empty())
+    }
+
+    // The newest version of the package in the feed
+    pub async fn latest_version(
+        &self,
+        client: &Client,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let protocol = match self.protocol {
+            Protocol::Nuget => "NuGet",
+            Protocol::Universal => "UPack",
+        };
+        let response = client
+            .get(format!(
+                "{}/{}_apis/packaging/Feeds/{}/packages",
+                azure::service_url("feeds"),
+                self.scope(),
+                azure::segment(&self.feed)
+            ))
+            .query(&[
+                ("packageNameQuery", self.package.as_str()),
+                ("protocolType", protocol),
+                ("api-version", PACKAGING_API_VERSION),
+            ])
+            .basic_auth("", Some(&self.pat))
+            .send()
+            .await?;
+        // A bad or expired PAT is answered with 401/403 or a 203 sign-in page
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED
+            || status == StatusCode::FORBIDDEN
+            || status == StatusCode::NON_AUTHORITATIVE_INFORMATION
+        {
+            return Err(Box::new(AuthError(format!("feed API returned {}", status))));
+        }
+        let list: PackageList = response.error_for_status()?.json().await?;
+
+        // The name query matches substrings, so the exact package is picked out here
+        let package = list
+            .value
+            .into_iter()
+            .find(|package| package.name.eq_ignore_ascii_case(&self.package))
+            .ok_or_else(|| {
+                format!(
+                    "package '{}' not found in feed '{}'",
+                    self.package, self.feed
+                )
+            })?;
+        package
+            .versions
+            .into_iter()
+            .find(|version| version.is_latest)
+            .map(|version| version.version)
+            .ok_or_else(|| format!("package '{}' has no released version", self.package).into())
+    }
+
+    // Downloads the version and swaps it into the directory, which keeps the previous version
+    // if anything goes wrong
+    pub async fn install(
+        &self,
+        client: &Client,
+        version: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let directory = Path::new(&self.directory);
+        info!("Downloading {} {}.", self.package, version);
+        match self.protocol {
+            Protocol::Nuget => {
+                let download = client
+                    .get(format!(
+                        "{}/{}_apis/packaging/feeds/{}/nuget/packages/{}/versions/{}/content",
+                        azure::service_url("pkgs"),
+                        self.scope(),
+                        azure::segment(&self.feed),
+                        azure::segment(&self.package),
+                        azure::segment(version)
+                    ))
+                    .query(&[("api-version", PACKAGING_API_VERSION)])
+                    .basic_auth("", Some(&self.pat))
+                    .timeout(azure::DOWNLOAD_TIMEOUT)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                unpack::unzip_into(download, directory, None).await?;
+            }
+            Protocol::Universal => {
+                let staging = unpack::fresh_staging(directory)?;
+                let result = match self.az_download(version, &staging).await {
+                    Ok(()) => unpack::swap_into(&staging, directory, None),
+                    Err(e) => Err(e),
+                };
+                unpack::discard(&staging);
+                result?;
+            }
+        }
+        fs::write(directory.join(VERSION_FILE), version)?;
+        info!(
+            "{} {} is in {}.",
+            self.package,
+            version,
+            directory.display()
+        );
+        Ok(())
+    }
+
+    // Universal packages are stored deduplicated and have no plain download URL, so the Azure
+    // CLI (with the azure-devops extension) fetches them
+    async fn az_download(
+        &self,
+        version: &str,
+        into: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut command = Command::new(if cfg!(windows) { "az.cmd" } else { "az" });
+        command
+            .args(["artifacts", "universal", "download"])
+            .arg("--organization")
+            .arg(format!(
+                "{}/{}",
+                azure::base_url(),
+                azure::segment(&self.organization)
+            ))
+            .args([
+                "--feed",
+                &self.feed,
+                "--name",
+                &self.package,
+                "--version",
+                version,
+            ])
+            .arg("--path")
+            .arg(into)
+            .env(AZ_PAT_ENV, &self.pat)
+            .kill_on_drop(true);
+        if self.project.is_empty() {
+            command.args(["--scope", "organization"]);
+        } else {
+            command.args(["--scope", "project", "--project", &self.project]);
+        }
+
+        let output = command
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run the Azure CLI (az): {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "az artifacts universal download failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // What hooks get about the new version, as {{name}} and SYNC_<NAME>
+    pub fn hook_vars(&self, old_version: &str, new_version: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("old_version", old_version.to_string()),
+            ("new_version", new_version.to_string()),
+            ("package", self.package.clone()),
+            ("feed", self.feed.clone()),
+            ("directory", self.directory.clone()),
+        ]
+    }
+}
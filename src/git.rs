@@ -0,0 +1,771 @@
+use crate::history::CommitSummary;
+use crate::post_sync::run_command;
+use log::{info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::sync::OnceLock;
+use tokio::process::Command;
+
+// Oldest git release the tool is known to work with
+const MIN_VERSION: (u32, u32) = (2, 20);
+
+// The git executable: git_path, a bundled git next to the executable, or "git" from the PATH
+static PROGRAM: OnceLock<String> = OnceLock::new();
+
+// Set when no git was found and the libgit2 built in with the embedded-git feature is used instead
+#[cfg(feature = "embedded-git")]
+static EMBEDDED: OnceLock<()> = OnceLock::new();
+
+// Separate commits and their fields in the git log output
+const FIELD_SEPARATOR: char = '\u{1f}';
+const RECORD_SEPARATOR: char = '\u{1e}';
+
+// Path of the git executable every git command runs
+pub fn program() -> &'static str {
+    PROGRAM.get().map(String::as_str).unwrap_or("git")
+}
+
+// Whether the sync goes through the embedded libgit2 rather than a git program
+#[cfg(feature = "embedded-git")]
+pub fn embedded() -> bool {
+    EMBEDDED.get().is_some()
+}
+
+// What runs git, for the startup summary
+pub fn describe() -> String {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return format!("embedded {}", crate::embedded::version());
+    }
+    program().to_string()
+}
+
+// A git command that runs without blocking the runtime and is killed if the sync is cancelled
+pub fn command() -> Command {
+    let mut command = Command::new(program());
+    command.kill_on_drop(true);
+    command
+}
+
+// Checks that git can be run and is recent enough, then uses it for every git command
+pub async fn init(path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let program = match path {
+        Some(path) => path.to_string(),
+        None => bundled_git().unwrap_or_else(|| "git".to_string()),
+    };
+    let program = program.as_str();
+    let output = match Command::new(program).arg("--version").output().await {
+        Ok(output) => output,
+        // Without a git_path that must be used, a binary built with embedded-git doesn't need one
+        #[cfg(feature = "embedded-git")]
+        Err(e) if path.is_none() && e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "git was not found, using the embedded {}.",
+                crate::embedded::version()
+            );
+            let _ = EMBEDDED.set(());
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(match e.kind() {
+                std::io::ErrorKind::NotFound => format!(
+                    "git was not found at '{}'. Install git, ship a git bundle in a 'git' folder next to the executable, or point git_path at it.",
+                    program
+                ),
+                _ => format!("Failed to run git at '{}': {}", program, e),
+            }
+            .into())
+        }
+    };
+
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let (major, minor) = parse_version(&version).ok_or_else(|| {
+        format!(
+            "Unrecognised output from '{} --version': {}",
+            program, version
+        )
+    })?;
+    if (major, minor) < MIN_VERSION {
+        return Err(format!(
+            "{} is too old, {}.{} or newer is required.",
+            version, MIN_VERSION.0, MIN_VERSION.1
+        )
+        .into());
+    }
+
+    info!("Using {} ({}).", version, program);
+    let _ = PROGRAM.set(program.to_string());
+    Ok(())
+}
+
+// A portable git (e.g. MinGit) shipped in a "git" folder next to the executable, for hosts
+// where git can't be installed
+fn bundled_git() -> Option<String> {
+    let exe = std::env::current_exe().ok()?;
+    let bundle = exe.parent()?.join("git");
+    let candidates = if cfg!(windows) {
+        ["cmd/git.exe", "bin/git.exe"]
+    } else {
+        ["bin/git", "libexec/git-core/git"]
+    };
+
+    candidates
+        .iter()
+        .map(|candidate| bundle.join(candidate))
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| candidate.to_string_lossy().to_string())
+}
+
+// Reads the major and minor version from e.g. "git version 2.43.0.windows.1"
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let mut numbers = output
+        .strip_prefix("git version ")?
+        .split('.')
+        .map(|part| part.parse::<u32>());
+    Some((numbers.next()?.ok()?, numbers.next()?.ok()?))
+}
+
+// Returned when git refuses to work in a repo owned by another user
+#[derive(Debug)]
+pub struct DubiousOwnership(pub String);
+
+impl fmt::Display for DubiousOwnership {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "git refuses to use '{}' because it is owned by another user. Set add_safe_directory = true to trust it, or run: git config --global --add safe.directory {}",
+            self.0, self.0
+        )
+    }
+}
+
+impl std::error::Error for DubiousOwnership {}
+
+// Whether git's error output is the "dubious ownership" refusal
+pub fn is_dubious_ownership(stderr: &str) -> bool {
+    stderr.contains("dubious ownership")
+}
+
+// Makes sure git will work in the repo, adding it to safe.directory when allowed to
+pub async fn ensure_safe_directory(
+    repo_path: &str,
+    add: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::ensure_safe_directory(repo_path, add).await;
+    }
+    let output = command()
+        .args(["-C", repo_path, "rev-parse", "--git-dir"])
+        .output()
+        .await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() || !is_dubious_ownership(&stderr) {
+        return Ok(());
+    }
+
+    // git suggests the exact value to trust, which differs from repo_path for UNC and Windows paths
+    let directory = stderr
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("git config --global --add safe.directory ")
+        })
+        .map(|value| value.trim().trim_matches('\'').to_string())
+        .unwrap_or_else(|| repo_path.to_string());
+
+    if !add {
+        return Err(Box::new(DubiousOwnership(directory)));
+    }
+
+    warn!(
+        "'{}' is owned by another user, adding it to git's safe.directory list.",
+        directory
+    );
+    run_command(
+        program(),
+        &["config", "--global", "--add", "safe.directory", &directory],
+        None,
+    )
+    .await?;
+    info!("Added '{}' to safe.directory.", directory);
+    Ok(())
+}
+
+// Lets git on Windows handle paths beyond 260 characters in this repo
+pub async fn enable_long_paths(repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if cfg!(windows) {
+        #[cfg(feature = "embedded-git")]
+        if embedded() {
+            return crate::embedded::enable_long_paths(repo_path).await;
+        }
+        run_command(
+            program(),
+            &["-C", repo_path, "config", "core.longpaths", "true"],
+            None,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// Switches to branch, creating it to track origin/<branch> when it doesn't exist locally yet.
+// Local changes are carried over, and the switch fails rather than overwrite them.
+pub async fn switch_branch(
+    repo_path: &str,
+    branch: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::switch_branch(repo_path, branch).await;
+    }
+    let exists = command()
+        .args(["-C", repo_path, "rev-parse", "--verify", "--quiet", branch])
+        .output()
+        .await?
+        .status
+        .success();
+    if exists {
+        run_command(program(), &["-C", repo_path, "checkout", branch], None).await?;
+    } else {
+        let remote_branch = format!("origin/{}", branch);
+        run_command(
+            program(),
+            &[
+                "-C",
+                repo_path,
+                "checkout",
+                "-b",
+                branch,
+                "--track",
+                &remote_branch,
+            ],
+            None,
+        )
+        .await?;
+        info!("Created branch '{}' tracking {}.", branch, remote_branch);
+    }
+    Ok(())
+}
+
+// Switches to branch, throwing away local changes that would stop the switch
+pub async fn checkout_force(
+    repo_path: &str,
+    branch: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::checkout_force(repo_path, branch).await;
+    }
+    run_command(
+        program(),
+        &["-C", repo_path, "checkout", "-f", branch],
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+// Checks out target with HEAD detached from any branch. Local changes to files the move touches
+// make it fail, unless forced, which throws them away.
+pub async fn checkout_detached(
+    repo_path: &str,
+    target: &str,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::checkout_detached(repo_path, target, force).await;
+    }
+    let mut args = vec!["-C", repo_path, "checkout", "--detach"];
+    if force {
+        args.push("-f");
+    }
+    args.push(target);
+    run_command(program(), &args, None).await?;
+    Ok(())
+}
+
+// Where origin/<branch> was left by the last fetch, if it has been fetched
+pub async fn remote_tip(repo_path: &str, branch: &str) -> Option<String> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::remote_tip(repo_path, branch).await;
+    }
+    let output = command()
+        .args([
+            "-C",
+            repo_path,
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("refs/remotes/origin/{}^{{commit}}", branch),
+        ])
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Points the origin remote at url, if the checkout has an origin. Returns whether it had one.
+pub async fn set_origin_url(
+    repo_path: &str,
+    url: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::set_origin_url(repo_path, url).await;
+    }
+    let has_origin = command()
+        .args(["-C", repo_path, "remote", "get-url", "origin"])
+        .output()
+        .await?
+        .status
+        .success();
+    if has_origin {
+        run_command(
+            program(),
+            &["-C", repo_path, "remote", "set-url", "origin", url],
+            None,
+        )
+        .await?;
+    }
+    Ok(has_origin)
+}
+
+// Whether ancestor is in the history of descendant. A commit that isn't in the repo yet, such as a
+// remote commit not fetched, is never an ancestor.
+pub async fn is_ancestor(repo_path: &str, ancestor: &str, descendant: &str) -> bool {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::is_ancestor(repo_path, ancestor, descendant).await;
+    }
+    command()
+        .args([
+            "-C",
+            repo_path,
+            "merge-base",
+            "--is-ancestor",
+            ancestor,
+            descendant,
+        ])
+        .output()
+        .await
+        .is_ok_and(|output| output.status.success())
+}
+
+// Points the checked-out branch at target, discarding local commits and changes to tracked files
+pub async fn reset_hard(repo_path: &str, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::reset_hard(repo_path, target).await;
+    }
+    // A merge left half-way would otherwise survive as MERGE_HEAD
+    let _ = command()
+        .args(["-C", repo_path, "merge", "--abort"])
+        .output()
+        .await;
+    run_command(
+        program(),
+        &["-C", repo_path, "reset", "--hard", target],
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+// Moves the checked-out branch to target, keeping local changes to files the move doesn't touch
+// and failing instead of overwriting the ones it does
+pub async fn reset_keep(repo_path: &str, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::reset_keep(repo_path, target).await;
+    }
+    run_command(
+        program(),
+        &["-C", repo_path, "reset", "--keep", target],
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+// Merges remote_branch (e.g. origin/main) into the checked-out branch. A failure, such as a
+// conflict, comes back as git's explanation and leaves the merge for reset_hard to abort.
+pub async fn merge(repo_path: &str, remote_branch: &str) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::merge(repo_path, remote_branch).await;
+    }
+    let output = command()
+        .args(["-C", repo_path, "merge", "--no-edit", remote_branch])
+        .output()
+        .await?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Conflicts are reported on stdout, other refusals on stderr
+    Err(format!("{} {}", stdout.trim(), stderr.trim()).trim().into())
+}
+
+// Removes untracked files and directories, except the preserved ones. Gitignored files, such as
+// build outputs and virtualenvs next to the checkout, are only removed when asked to. Nested
+// repositories are always left alone, since a single -f doesn't remove them.
+pub async fn clean(
+    repo_path: &str,
+    ignored: bool,
+    preserve: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::clean(repo_path, ignored, preserve).await;
+    }
+    let mut args = vec!["-C", repo_path, "clean", "-fd"];
+    if ignored {
+        args.push("-x");
+    }
+    for path in preserve {
+        args.extend(["-e", path.as_str()]);
+    }
+    let stdout = run_command(program(), &args, None).await?;
+    for removed in stdout.lines() {
+        info!("{}", removed);
+    }
+    Ok(())
+}
+
+// Lists the files that differ between two commits
+pub async fn changed_files(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::changed_files(repo_path, old_commit, new_commit).await;
+    }
+    let range = format!("{}..{}", old_commit, new_commit);
+    let stdout = run_command(
+        program(),
+        &["-C", repo_path, "diff", "--name-only", "-z", &range],
+        None,
+    )
+    .await?;
+
+    // -z leaves paths unquoted, so non-ASCII names match path filters as written
+    Ok(stdout
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+// How a file changed between two commits
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+    // Type changes, e.g. a file replaced by a symbolic link
+    Changed,
+}
+
+#[derive(Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub change: Change,
+}
+
+// Lists the files that differ between two commits with how each changed. Renames are listed as
+// the old path deleted and the new one added, so every path a cache or watcher knows is covered.
+pub async fn file_changes(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Vec<FileChange>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::file_changes(repo_path, old_commit, new_commit).await;
+    }
+    let range = format!("{}..{}", old_commit, new_commit);
+    let stdout = run_command(
+        program(),
+        &[
+            "-C",
+            repo_path,
+            "diff",
+            "--name-status",
+            "--no-renames",
+            "-z",
+            &range,
+        ],
+        None,
+    )
+    .await?;
+
+    // -z gives status and path as separate fields, and leaves paths unquoted
+    let mut fields = stdout.split('\0').filter(|field| !field.is_empty());
+    let mut changes = Vec::new();
+    while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+        let change = match status {
+            "A" => Change::Added,
+            "D" => Change::Deleted,
+            "T" => Change::Changed,
+            _ => Change::Modified,
+        };
+        changes.push(FileChange {
+            path: path.to_string(),
+            change,
+        });
+    }
+    Ok(changes)
+}
+
+// Files changed and lines added and removed between two commits
+#[derive(Default)]
+pub struct DiffStat {
+    pub files_changed: u64,
+    pub insertions: u64,
+    pub deletions: u64,
+}
+
+pub async fn diff_stat(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<DiffStat, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::diff_stat(repo_path, old_commit, new_commit).await;
+    }
+    let range = format!("{}..{}", old_commit, new_commit);
+    let stdout = run_command(
+        program(),
+        &["-C", repo_path, "diff", "--shortstat", &range],
+        None,
+    )
+    .await?;
+
+    Ok(parse_shortstat(&stdout))
+}
+
+// Reads e.g. " 3 files changed, 10 insertions(+), 2 deletions(-)", where any part may be missing
+fn parse_shortstat(output: &str) -> DiffStat {
+    let mut stat = DiffStat::default();
+    for part in output.trim().split(", ") {
+        let mut words = part.split_whitespace();
+        let (Some(Ok(count)), Some(what)) = (words.next().map(str::parse), words.next()) else {
+            continue;
+        };
+        if what.starts_with("file") {
+            stat.files_changed = count;
+        } else if what.starts_with("insertion") {
+            stat.insertions = count;
+        } else if what.starts_with("deletion") {
+            stat.deletions = count;
+        }
+    }
+    stat
+}
+
+// Lists the commits reachable from new_commit but not old_commit, newest first
+pub async fn commit_log(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Vec<CommitSummary>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::commit_log(repo_path, old_commit, new_commit).await;
+    }
+    let range = format!("{}..{}", old_commit, new_commit);
+    let stdout = run_command(
+        program(),
+        &[
+            "-C",
+            repo_path,
+            "log",
+            "--format=%H%x1f%h%x1f%an%x1f%ae%x1f%cI%x1f%s%x1f%b%x1e",
+            &range,
+        ],
+        None,
+    )
+    .await?;
+
+    Ok(stdout
+        .split(RECORD_SEPARATOR)
+        .filter_map(|record| {
+            let mut fields = record.trim_start().split(FIELD_SEPARATOR);
+            let id = fields.next()?.to_string();
+            let short_id = fields.next()?.to_string();
+            let author = fields.next()?.to_string();
+            let author_email = fields.next()?.to_string();
+            let committed_at = Some(fields.next()?.to_string());
+            let message = fields.next()?.to_string();
+            let body = fields.next().unwrap_or_default();
+            let work_item_ids = work_item_mentions(&format!("{}\n{}", message, body));
+
+            Some(CommitSummary {
+                id,
+                short_id,
+                author,
+                author_email,
+                message,
+                committed_at,
+                work_item_ids,
+            })
+        })
+        .collect())
+}
+
+// Who wrote each commit reachable from new_commit but not old_commit, and the files it touched
+pub struct CommitFiles {
+    pub id: String,
+    pub author: String,
+    pub author_email: String,
+    pub files: Vec<String>,
+}
+
+pub async fn commit_files(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+) -> Result<Vec<CommitFiles>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if embedded() {
+        return crate::embedded::commit_files(repo_path, old_commit, new_commit).await;
+    }
+    let range = format!("{}..{}", old_commit, new_commit);
+    let stdout = run_command(
+        program(),
+        &[
+            "-C",
+            repo_path,
+            "log",
+            "--format=%x1e%H%x1f%an%x1f%ae",
+            "--name-only",
+            "-z",
+            &range,
+        ],
+        None,
+    )
+    .await?;
+
+    Ok(stdout
+        .split(RECORD_SEPARATOR)
+        .filter_map(|record| {
+            // With -z the header ends in a NUL and the file names follow, each ending in a NUL,
+            // the first one after a newline
+            let mut lines = record.split('\0');
+            let mut fields = lines.next()?.split(FIELD_SEPARATOR);
+            let id = fields.next()?.to_string();
+            let author = fields.next()?.to_string();
+            let author_email = fields.next()?.to_string();
+            Some(CommitFiles {
+                id,
+                author,
+                author_email,
+                files: lines
+                    .enumerate()
+                    .map(|(index, path)| match index {
+                        0 => path.strip_prefix('\n').unwrap_or(path),
+                        _ => path,
+                    })
+                    .filter(|path| !path.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect())
+}
+
+// Finds work item mentions such as "#1234" or "AB#1234" in a commit message
+pub fn work_item_mentions(message: &str) -> Vec<u64> {
+    let mut ids = Vec::new();
+
+    for (index, _) in message.match_indices('#') {
+        let before = &message[..index];
+        let standalone = before
+            .chars()
+            .next_back()
+            .is_none_or(|c| !c.is_alphanumeric());
+        if !standalone && !before.ends_with("AB") {
+            continue;
+        }
+
+        let digits: String = message[index + 1..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        if let Ok(id) = digits.parse() {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command as StdCommand;
+
+    fn git(repo: &str, args: &[&str]) -> String {
+        let output = StdCommand::new("git")
+            .args([
+                "-C",
+                repo,
+                "-c",
+                "user.name=Ann",
+                "-c",
+                "user.email=ann@example.com",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn unusual_paths_are_listed_as_written() {
+        let repo = std::env::temp_dir().join(format!("git-paths-test-{}", std::process::id()));
+        std::fs::create_dir_all(&repo).unwrap();
+        let repo_path = repo.to_string_lossy().to_string();
+        git(&repo_path, &["init", "-q"]);
+        std::fs::write(repo.join("readme.md"), "one").unwrap();
+        git(&repo_path, &["add", "-A"]);
+        git(&repo_path, &["commit", "-qm", "one"]);
+        let old_commit = git(&repo_path, &["rev-parse", "HEAD"]);
+
+        std::fs::create_dir_all(repo.join("docs")).unwrap();
+        std::fs::write(repo.join("docs/größe ä.md"), "two").unwrap();
+        std::fs::write(repo.join("say \"hi\".txt"), "two").unwrap();
+        git(&repo_path, &["add", "-A"]);
+        git(&repo_path, &["commit", "-qm", "two"]);
+        let new_commit = git(&repo_path, &["rev-parse", "HEAD"]);
+
+        let expected = ["docs/größe ä.md", "say \"hi\".txt"];
+        let changed = changed_files(&repo_path, &old_commit, &new_commit)
+            .await
+            .unwrap();
+        assert_eq!(changed, expected);
+
+        let commits = commit_files(&repo_path, &old_commit, &new_commit)
+            .await
+            .unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].id, new_commit);
+        assert_eq!(commits[0].author, "Ann");
+        assert_eq!(commits[0].files, expected);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+// git_backend = "gitoxide": fetching and the reads every cycle makes run in-process on gix (the
+// gitoxide feature), instead of starting a git process for each. The checkout, merge and clean
+// that follow a fetch stay with git.
+use crate::secrets;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use gix::protocol::transport::client::http;
+use gix::remote::Direction;
+use reqwest::header::{HeaderValue, AUTHORIZATION, USER_AGENT};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+// Used for reflog entries when git has no identity configured
+const REFLOG_NAME: &str = "DevOps_Repository_Sync";
+const REFLOG_EMAIL: &str = "devops-sync@localhost";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Runs a gix operation without blocking the runtime
+async fn blocking<T: Send + 'static>(
+    operation: impl FnOnce() -> Result<T> + Send + 'static,
+) -> std::result::Result<T, Box<dyn std::error::Error>> {
+    tokio::task::spawn_blocking(operation)
+        .await?
+        .map_err(|e| e as Box<dyn std::error::Error>)
+}
+
+fn open(repo_path: &str, overrides: Vec<String>) -> Result<gix::Repository> {
+    Ok(gix::open_opts(
+        repo_path,
+        gix::open::Options::default().config_overrides(overrides),
+    )?)
+}
+
+// The commit HEAD is at, or nothing in a fresh clone whose branch has no commit yet
+pub async fn head(repo_path: &str) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let repo_path = repo_path.to_string();
+    blocking(move || {
+        let repo = open(&repo_path, Vec::new())?;
+        let head = repo.head()?;
+        if head.is_unborn() {
+            return Ok(String::new());
+        }
+        Ok(head.into_peeled_id()?.to_string())
+    })
+    .await
+}
+
+pub async fn remote_tip(repo_path: &str, branch: &str) -> Option<String> {
+    let (repo_path, branch) = (repo_path.to_string(), branch.to_string());
+    blocking(move || {
+        let repo = open(&repo_path, Vec::new())?;
+        let spec = format!("refs/remotes/origin/{}^{{commit}}", branch);
+        Ok(repo.rev_parse_single(spec.as_str())?.to_string())
+    })
+    .await
+    .ok()
+}
+
+pub async fn is_ancestor(repo_path: &str, ancestor: &str, descendant: &str) -> bool {
+    let (repo_path, ancestor, descendant) = (
+        repo_path.to_string(),
+        ancestor.to_string(),
+        descendant.to_string(),
+    );
+    blocking(move || {
+        let repo = open(&repo_path, Vec::new())?;
+        let ancestor = repo.rev_parse_single(ancestor.as_str())?.detach();
+        let descendant = repo.rev_parse_single(descendant.as_str())?.detach();
+        Ok(ancestor == descendant || repo.merge_base(ancestor, descendant)?.detach() == ancestor)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// Fetches the remote's branches into origin/* like `git fetch --prune`. The PAT goes in an
+// Authorization header, as for the API requests, rather than in the URL.
+pub async fn fetch(
+    repo_path: &str,
+    url: &str,
+    pat: &str,
+    user_agent: &str,
+    refspec: &str,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let overrides = vec![
+        // The header carries the PAT, so a rejected one fails rather than asking a credential
+        // helper or the terminal
+        "credential.helper=".to_string(),
+        "gitoxide.credentials.terminalPrompt=false".to_string(),
+        // The reflogs of updated refs need a committer, and agents rarely have a git identity
+        format!("gitoxide.committer.nameFallback={}", REFLOG_NAME),
+        format!("gitoxide.committer.emailFallback={}", REFLOG_EMAIL),
+    ];
+    let mut authorization =
+        HeaderValue::from_str(&format!("Basic {}", BASE64.encode(format!(":{}", pat))))?;
+    authorization.set_sensitive(true);
+    let user_agent = HeaderValue::from_str(user_agent)?;
+    let (repo_path, url, refspec) = (repo_path.to_string(), url.to_string(), refspec.to_string());
+    blocking(move || {
+        let repo = open(&repo_path, overrides)?;
+        let remote = repo
+            .remote_at(url.as_str())?
+            .with_refspecs([refspec.as_str()], Direction::Fetch)?;
+        // gix's reqwest transport ignores http.extraHeader and http.userAgent, so both headers
+        // are set on each request it makes. Local paths in tests have no http options.
+        let transport_options = repo
+            .transport_options(url.as_str(), None)?
+            .and_then(|options| options.downcast::<http::Options>().ok())
+            .map(|mut options| {
+                let configure: Box<http::reqwest::ConfigureRequestFn> = Box::new(move |request| {
+                    let headers = request.headers_mut();
+                    headers.insert(AUTHORIZATION, authorization.clone());
+                    headers.insert(USER_AGENT, user_agent.clone());
+                    Ok(())
+                });
+                options.backend = Some(Arc::new(Mutex::new(http::reqwest::Options {
+                    configure_request: Some(configure),
+                })));
+                options
+            });
+        let fetched = (|| -> Result<_> {
+            let mut connection = remote.connect(Direction::Fetch)?;
+            if let Some(options) = transport_options {
+                connection.set_transport_options(options);
+            }
+            let prepared = connection.prepare_fetch(gix::progress::Discard, Default::default())?;
+            Ok(prepared.receive(gix::progress::Discard, &AtomicBool::new(false))?)
+        })();
+        let outcome = match fetched {
+            Ok(outcome) => outcome,
+            // With the helpers cleared, a 401 surfaces as gix failing to obtain credentials
+            Err(e)
+                if e.to_string().contains("401")
+                    || e.to_string().to_lowercase().contains("authentication")
+                    || e.to_string().contains("obtain credentials") =>
+            {
+                return Err(Box::new(secrets::AuthError(format!(
+                    "git fetch was rejected: {}",
+                    e
+                ))))
+            }
+            Err(e) => return Err(e),
+        };
+
+        // gix has no --prune, so the tracking refs of branches the remote no longer has go here
+        let fetched: Vec<_> = outcome
+            .ref_map
+            .mappings
+            .iter()
+            .filter_map(|mapping| mapping.local.clone())
+            .collect();
+        let references = repo.references()?;
+        for reference in references.remote_branches()? {
+            let reference = reference.map_err(|e| e.to_string())?;
+            let name = reference.name().as_bstr().to_owned();
+            if name.starts_with(b"refs/remotes/origin/") && !fetched.contains(&name) {
+                reference.delete()?;
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(repo: &str, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-C",
+                repo,
+                "-c",
+                "user.name=Ann",
+                "-c",
+                "user.email=ann@example.com",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[tokio::test]
+    async fn fetch_sends_the_pat_and_user_agent_and_reports_a_rejection() {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+        let local = std::env::temp_dir().join(format!("gitoxide-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&local).unwrap();
+        let local_path = local.to_string_lossy().to_string();
+        git(&local_path, &["init", "-q"]);
+
+        let url = format!("http://127.0.0.1:{}/org/project/_git/repo", port);
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        let error = fetch(&local_path, &url, "secret", "sync/1.0", refspec)
+            .await
+            .unwrap_err();
+        assert!(error.is::<secrets::AuthError>(), "{}", error);
+        let request = server.join().unwrap();
+        assert!(
+            request.contains("authorization: basic onnly3jlda=="),
+            "{}",
+            request
+        );
+        assert!(request.contains("user-agent: sync/1.0"), "{}", request);
+        std::fs::remove_dir_all(&local).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fetches_prune_branches_the_remote_deleted() {
+        let root = std::env::temp_dir().join(format!("gitoxide-test-{}", std::process::id()));
+        let (remote, local) = (root.join("remote"), root.join("local"));
+        std::fs::create_dir_all(&remote).unwrap();
+        std::fs::create_dir_all(&local).unwrap();
+        let remote_path = remote.to_string_lossy().to_string();
+        let local_path = local.to_string_lossy().to_string();
+        git(&remote_path, &["init", "-q"]);
+        git(&remote_path, &["checkout", "-qb", "main"]);
+        git(
+            &remote_path,
+            &["commit", "-q", "--allow-empty", "-m", "one"],
+        );
+        let first = git(&remote_path, &["rev-parse", "HEAD"]);
+        git(&remote_path, &["branch", "feature"]);
+        git(&local_path, &["init", "-q"]);
+        assert_eq!(head(&local_path).await.unwrap(), "");
+
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        fetch(&local_path, &remote_path, "pat", "agent", refspec)
+            .await
+            .unwrap();
+        assert_eq!(remote_tip(&local_path, "main").await, Some(first.clone()));
+        assert_eq!(
+            remote_tip(&local_path, "feature").await,
+            Some(first.clone())
+        );
+        git(
+            &local_path,
+            &["checkout", "-q", "-b", "main", "origin/main"],
+        );
+        assert_eq!(head(&local_path).await.unwrap(), first);
+
+        git(
+            &remote_path,
+            &["commit", "-q", "--allow-empty", "-m", "two"],
+        );
+        git(&remote_path, &["branch", "-D", "feature"]);
+        let second = git(&remote_path, &["rev-parse", "HEAD"]);
+        fetch(&local_path, &remote_path, "pat", "agent", refspec)
+            .await
+            .unwrap();
+        assert_eq!(remote_tip(&local_path, "main").await, Some(second.clone()));
+        assert_eq!(remote_tip(&local_path, "feature").await, None);
+        assert!(is_ancestor(&local_path, &first, &second).await);
+        assert!(!is_ancestor(&local_path, &second, &first).await);
+        assert!(
+            !is_ancestor(
+                &local_path,
+                "0123456789012345678901234567890123456789",
+                &first
+            )
+            .await
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+// Minimal glob matching for repo-relative paths.
+// `**` matches any number of directories, `*` anything within one path segment and `?` a single character.
+
+// Checks whether the path matches any of the patterns
+pub fn matches_any(patterns: &[String], path: &str) -> bool {
+    patterns.iter().any(|pattern| matches(pattern, path))
+}
+
+// Checks whether a repo-relative path matches a single pattern
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.replace('\\', "/");
+    let path = path.replace('\\', "/");
+    let pattern: Vec<&str> = pattern.trim_start_matches('/').split('/').collect();
+    let path: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                let pattern: Vec<char> = first.chars().collect();
+                let text: Vec<char> = segment.chars().collect();
+                match_segment(&pattern, &text) && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+// Compares characters rather than bytes, so `?` stands for one letter of a non-ASCII name too
+fn match_segment(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| match_segment(rest, &text[skip..])),
+        Some(('?', rest)) => !text.is_empty() && match_segment(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && match_segment(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(matches("docs/**", "docs/a.md"));
+        assert!(matches("docs/**", "docs/guide/deep/a.md"));
+        assert!(matches("**/*.rs", "main.rs"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**/mod.rs", "src/mod.rs"));
+        assert!(matches("src/**/mod.rs", "src/a/b/mod.rs"));
+        assert!(!matches("docs/**", "src/docs/a.md"));
+    }
+
+    #[test]
+    fn single_star_and_question_mark_stay_within_a_segment() {
+        assert!(matches("*.md", "readme.md"));
+        assert!(!matches("*.md", "docs/readme.md"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(matches("v?.txt", "v1.txt"));
+        assert!(!matches("v?.txt", "v10.txt"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(matches("gr??e.md", "größe.md"));
+    }
+
+    #[test]
+    fn patterns_are_anchored_at_the_repo_root() {
+        assert!(matches("config.toml", "config.toml"));
+        assert!(!matches("config.toml", "deploy/config.toml"));
+        assert!(matches("/deploy/*.yaml", "deploy/app.yaml"));
+        assert!(!matches("deploy", "deploy/app.yaml"));
+        assert!(matches("deploy\\*.yaml", "deploy/app.yaml"));
+        assert!(matches_any(
+            &["*.md".to_string(), "src/**".to_string()],
+            "src/lib.rs"
+        ));
+        assert!(!matches_any(&[], "src/lib.rs"));
+    }
+}
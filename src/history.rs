@@ -0,0 +1,277 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::git::DiffStat;
+use crate::hooks::HookResult;
+use crate::metrics::Timings;
+use crate::notify::RepoRef;
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+// Outcome of one sync attempt
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncStatus {
+    Success,
+    PullFailed,
+    PostSyncFailed,
+    RolledBack,
+    // A plugin stopped the sync
+    Aborted,
+    // The working tree didn't match the pulled commit afterwards
+    VerificationFailed,
+}
+
+// One line of the sync history file
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SyncRecord {
+    pub timestamp: String,
+    pub old_commit: String,
+    pub new_commit: String,
+    pub status: SyncStatus,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub commits: Vec<CommitSummary>,
+    #[serde(default)]
+    pub work_items: Vec<WorkItemRef>,
+    // Pull requests the sync deployed, when syncing on pull requests
+    #[serde(default)]
+    pub pull_requests: Vec<PullRequestRef>,
+    #[serde(default)]
+    pub hooks: Vec<HookResult>,
+    // Notes added by plugins
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    // Seconds spent in each phase of the cycle
+    #[serde(default)]
+    pub timings: Timings,
+}
+
+// A commit applied by a sync
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CommitSummary {
+    pub id: String,
+    pub short_id: String,
+    pub author: String,
+    pub author_email: String,
+    pub message: String,
+    // Committer date, RFC 3339; missing from entries written before it was recorded
+    #[serde(default)]
+    pub committed_at: Option<String>,
+    // Work items mentioned in the message or linked to the commit in Azure DevOps
+    #[serde(default)]
+    pub work_item_ids: Vec<u64>,
+}
+
+// A work item referenced by the commits of a sync
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorkItemRef {
+    pub id: u64,
+    pub title: String,
+    pub work_item_type: String,
+    pub state: String,
+    pub url: String,
+}
+
+// A completed pull request into the target branch
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PullRequestRef {
+    pub id: u64,
+    pub title: String,
+    pub author: String,
+    // Everyone who reviewed it, groups left out
+    pub reviewers: Vec<String>,
+    // Commit the pull request was merged (or squashed) as
+    pub merge_commit: String,
+    pub url: String,
+}
+
+impl SyncRecord {
+    pub fn new(old_commit: &str, new_commit: &str) -> Self {
+        SyncRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            old_commit: old_commit.to_string(),
+            new_commit: new_commit.to_string(),
+            status: SyncStatus::Success,
+            error: None,
+            commits: Vec::new(),
+            work_items: Vec::new(),
+            pull_requests: Vec::new(),
+            hooks: Vec::new(),
+            annotations: BTreeMap::new(),
+            timings: Timings::new(),
+        }
+    }
+
+    // One line describing a successful sync, e.g.
+    // "Synced 1a2b3c4d: 3 commit(s), 5 file(s) changed, +120 -14 in 4.2s."
+    pub fn summary(&self, diff: Option<&DiffStat>, elapsed: Duration) -> String {
+        let short_commit: String = self.new_commit.chars().take(8).collect();
+        let mut summary = format!("Synced {}: {} commit(s)", short_commit, self.commits.len());
+        if let Some(diff) = diff {
+            summary.push_str(&format!(
+                ", {} file(s) changed, +{} -{}",
+                diff.files_changed, diff.insertions, diff.deletions
+            ));
+        }
+        summary.push_str(&format!(" in {:.1}s.", elapsed.as_secs_f64()));
+        summary
+    }
+}
+
+// Appends the record as a JSON line to the history file
+pub fn append(path: &str, record: &SyncRecord) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+// Every record of a history file, with the number of lines that couldn't be read
+pub fn read(path: &str) -> Result<(Vec<SyncRecord>, usize), Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    let mut unreadable = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(_) => unreadable += 1,
+        }
+    }
+    Ok((records, unreadable))
+}
+
+// A record as `history export` writes it, with the history file it came from
+#[derive(Serialize)]
+pub struct Exported<'a> {
+    pub source: &'a str,
+    #[serde(flatten)]
+    pub record: &'a SyncRecord,
+}
+
+// Columns of the CSV export, one row per sync
+const CSV_COLUMNS: &[&str] = &[
+    "timestamp",
+    "source",
+    "status",
+    "old_commit",
+    "new_commit",
+    "commits",
+    "authors",
+    "work_items",
+    "pull_requests",
+    "failed_hooks",
+    "duration_seconds",
+    "error",
+];
+
+// The records as CSV (RFC 4180), lists within a cell separated by "; "
+pub fn to_csv(records: &[Exported<'_>]) -> String {
+    let mut csv = CSV_COLUMNS.join(",") + "\r\n";
+    for Exported { source, record } in records {
+        let mut authors: Vec<&str> = Vec::new();
+        for commit in &record.commits {
+            if !authors.contains(&commit.author.as_str()) {
+                authors.push(&commit.author);
+            }
+        }
+        let joined = |ids: Vec<String>| ids.join("; ");
+        let cells = [
+            record.timestamp.clone(),
+            source.to_string(),
+            crate::notify::status_name(record.status).to_string(),
+            record.old_commit.clone(),
+            record.new_commit.clone(),
+            record.commits.len().to_string(),
+            authors.join("; "),
+            joined(
+                record
+                    .work_items
+                    .iter()
+                    .map(|item| item.id.to_string())
+                    .collect(),
+            ),
+            joined(
+                record
+                    .pull_requests
+                    .iter()
+                    .map(|pr| pr.id.to_string())
+                    .collect(),
+            ),
+            joined(
+                record
+                    .hooks
+                    .iter()
+                    .filter(|hook| !hook.succeeded())
+                    .map(|hook| hook.name.clone())
+                    .collect(),
+            ),
+            format!(
+                "{:.1}",
+                record
+                    .timings
+                    .values()
+                    .fold(0.0, |sum, seconds| sum + seconds)
+            ),
+            record.error.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = cells.iter().map(|cell| csv_cell(cell)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Quotes a cell holding a separator, a quote or a line break, doubling its quotes. A leading
+// =, +, - or @ is prefixed with ' so spreadsheets don't run commit messages as formulas.
+fn csv_cell(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+// Writes every finished sync to the history file
+pub struct HistoryLog {
+    path: String,
+}
+
+impl HistoryLog {
+    pub fn new(path: String) -> Self {
+        HistoryLog { path }
+    }
+}
+
+impl Subscriber for HistoryLog {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, _repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            if let SyncEvent::SyncFinished { record } = event {
+                if let Err(e) = append(&self.path, record) {
+                    error!("Failed to write sync history: {}", e);
+                }
+            }
+            Directive::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_cells_are_quoted_and_defused() {
+        assert_eq!(csv_cell("plain"), "plain");
+        assert_eq!(csv_cell("a, b"), "\"a, b\"");
+        assert_eq!(csv_cell("say \"hi\"\nbye"), "\"say \"\"hi\"\"\nbye\"");
+        assert_eq!(csv_cell("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+    }
+}
@@ -0,0 +1,572 @@
+use crate::schema::{self, Documented, Field};
+use crate::template;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::time::timeout;
+
+// A shell command to run after a successful pull
+#[derive(Deserialize)]
+pub struct HookConfig {
+    // Label used in logs, defaults to the command itself
+    pub name: Option<String>,
+    pub command: String,
+    // Only run when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // The hook and everything it started are killed after this long
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    // Directory to run in, relative to the repo unless absolute (defaults to the repo)
+    pub working_dir: Option<String>,
+    // When set, only these environment variables are passed to the hook
+    pub env_allowlist: Option<Vec<String>>,
+    // Output kept per stream; anything beyond is discarded
+    #[serde(default = "default_max_output")]
+    pub max_output_bytes: usize,
+    // Shortest time between two runs; a sync within it defers the hook until it has passed
+    #[serde(default)]
+    pub min_interval_seconds: u64,
+}
+
+impl Documented for HookConfig {
+    const SECTION: &'static str = "[[post_sync.hooks]]";
+    const ABOUT: &'static str = "Shell commands run after every successful pull, in order.";
+    const FIELDS: &'static [Field] = &[
+        schema::optional("name", "string", r#""build""#, "Label used in the log, defaults to the command"),
+        schema::required(
+            "command",
+            "string",
+            r#""cargo build --release""#,
+            "Run with sh -c (cmd /C on Windows); {{old_commit}}, {{new_commit}}, {{branch}}, {{repo_path}} and {{changed_files}} are substituted, shell-quoted",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only run when a changed file matches one of these (empty means always)",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "300",
+            "The hook and everything it started are killed after this long",
+        ),
+        schema::optional(
+            "working_dir",
+            "string",
+            r#""build""#,
+            "Directory to run in, relative to the repo unless absolute",
+        ),
+        schema::optional(
+            "env_allowlist",
+            "list of strings",
+            r#"["PATH", "HOME"]"#,
+            "Only pass these environment variables to the hook",
+        ),
+        schema::defaulted(
+            "max_output_bytes",
+            "integer",
+            "65536",
+            "Output kept per stream for the log, the rest is discarded",
+        ),
+        schema::defaulted(
+            "min_interval_seconds",
+            "integer",
+            "0",
+            "Shortest time between two runs; syncs within it defer the hook, which then runs once for the latest commit",
+        ),
+    ];
+}
+
+// What happened when a hook ran, kept in the sync history
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HookResult {
+    pub name: String,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub timed_out: bool,
+    pub output_truncated: bool,
+    pub error: Option<String>,
+    // The end of the output, kept for tools run by other post-sync actions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl HookResult {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0) && !self.timed_out && self.error.is_none()
+    }
+}
+
+impl HookConfig {
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.command)
+    }
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+fn default_max_output() -> usize {
+    64 * 1024
+}
+
+// Output kept in the history per tool run; build and deploy logs end with what went wrong
+const OUTPUT_TAIL: usize = 16 * 1024;
+
+// Runs the hook through the platform shell, enforcing its timeout, environment and output limits.
+// Each var is substituted for {{name}} in the command and exported as SYNC_<NAME>.
+pub async fn run_hook(hook: &HookConfig, repo_path: &str, vars: &[(&str, String)]) -> HookResult {
+    let started = Instant::now();
+    let mut result = HookResult {
+        name: hook.label().to_string(),
+        exit_code: None,
+        duration_ms: 0,
+        timed_out: false,
+        output_truncated: false,
+        error: None,
+        output: None,
+    };
+
+    info!("Running hook '{}'", hook.label());
+    match execute(hook, repo_path, vars, &mut result).await {
+        Ok(()) if result.succeeded() => info!(
+            "Hook '{}' finished in {} ms.",
+            hook.label(),
+            started.elapsed().as_millis()
+        ),
+        Ok(()) => error!(
+            "Hook '{}' failed (exit code: {:?}, timed out: {})",
+            hook.label(),
+            result.exit_code,
+            result.timed_out
+        ),
+        Err(e) => {
+            error!("Hook '{}' could not be run: {}", hook.label(), e);
+            result.error = Some(e.to_string());
+        }
+    }
+
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    result
+}
+
+async fn execute(
+    hook: &HookConfig,
+    repo_path: &str,
+    vars: &[(&str, String)],
+    result: &mut HookResult,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let working_dir = match &hook.working_dir {
+        Some(dir) => Path::new(repo_path).join(dir),
+        None => Path::new(repo_path).to_path_buf(),
+    };
+
+    let script =
+        template::render_escaped(&hook.command, vars, shell_quote).map_err(|e| match e {
+            template::EscapeError::Value(name) => format!(
+                "the value of {{{{{}}}}} can't be quoted safely for the shell, read SYNC_{} from a script instead",
+                name,
+                name.to_uppercase()
+            ),
+            template::EscapeError::Template(e) => format!("command doesn't render: {}", e),
+        })?;
+
+    let mut command = std::process::Command::new(shell);
+    command
+        .arg(flag)
+        .arg(script)
+        .current_dir(&working_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(allowlist) = &hook.env_allowlist {
+        command.env_clear();
+        for name in allowlist {
+            if let Ok(value) = env::var(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    // The sync context is always passed, even with an allowlist
+    for (name, value) in vars {
+        command.env(format!("SYNC_{}", name.to_uppercase()), value);
+    }
+
+    // Run the hook in its own process group so a timeout can take down everything it started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+    let mut child = Command::from(command).kill_on_drop(true).spawn()?;
+    let stdout = tokio::spawn(read_capped(
+        child.stdout.take(),
+        hook.max_output_bytes,
+        Keep::Start,
+    ));
+    let stderr = tokio::spawn(read_capped(
+        child.stderr.take(),
+        hook.max_output_bytes,
+        Keep::Start,
+    ));
+
+    match timeout(Duration::from_secs(hook.timeout_seconds), child.wait()).await {
+        Ok(status) => result.exit_code = status?.code(),
+        Err(_) => {
+            warn!(
+                "Hook '{}' exceeded its {} second timeout, killing it.",
+                hook.label(),
+                hook.timeout_seconds
+            );
+            result.timed_out = true;
+            if let Some(pid) = child.id() {
+                kill_tree(pid);
+            }
+            let _ = child.kill().await;
+        }
+    }
+
+    // Give the output readers a moment to drain, leftover grandchildren may still hold the pipes
+    let mut output = Vec::new();
+    for (stream, reader) in [("stdout", stdout), ("stderr", stderr)] {
+        let abort = reader.abort_handle();
+        match timeout(Duration::from_secs(2), reader).await {
+            Ok(Ok((text, truncated))) => {
+                result.output_truncated |= truncated;
+                output.push(format!(
+                    "{}: {}",
+                    stream,
+                    String::from_utf8_lossy(&text).trim()
+                ));
+            }
+            _ => abort.abort(),
+        }
+    }
+    info!("Hook '{}' output. {}", hook.label(), output.join(", "));
+
+    Ok(())
+}
+
+// Quotes a value substituted into a hook command, so branch names and file paths from the remote
+// are passed as one argument and never run as shell syntax. sh gets it in single quotes. cmd has
+// no escape that works inside quotes, so values with a quote, % or a line break are refused.
+fn shell_quote(value: &str) -> Option<String> {
+    if cfg!(windows) {
+        if value.contains(['"', '%', '\r', '\n']) {
+            return None;
+        }
+        Some(format!("\"{}\"", value))
+    } else {
+        Some(format!("'{}'", value.replace('\'', r"'\''")))
+    }
+}
+
+// Which end of a stream read_capped keeps
+#[derive(Clone, Copy)]
+enum Keep {
+    Start,
+    End,
+}
+
+// Reads a stream to the end, keeping at most max bytes from its start or its end
+async fn read_capped<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    max: usize,
+    keep: Keep,
+) -> (Vec<u8>, bool) {
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    let Some(mut reader) = reader else {
+        return (kept, truncated);
+    };
+
+    let mut buffer = [0u8; 8192];
+    while let Ok(read) = reader.read(&mut buffer).await {
+        if read == 0 {
+            break;
+        }
+        match keep {
+            Keep::Start => {
+                let room = max.saturating_sub(kept.len());
+                if read > room {
+                    truncated = true;
+                }
+                kept.extend_from_slice(&buffer[..read.min(room)]);
+            }
+            Keep::End => {
+                kept.extend_from_slice(&buffer[..read]);
+                if kept.len() > max {
+                    truncated = true;
+                    kept.drain(..kept.len() - max);
+                }
+            }
+        }
+    }
+
+    (kept, truncated)
+}
+
+// Runs a tool a post-sync action needs (docker, kubectl, ...) within the timeout, adding how
+// it went to the results with the end of its output. A failure is also returned as an error.
+pub async fn run_tool(
+    name: &str,
+    program: &str,
+    args: &[String],
+    stdin: Option<&str>,
+    timeout_seconds: u64,
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    run_tool_accepting(name, program, args, stdin, timeout_seconds, &[], results)
+        .await
+        .map(|_| ())
+}
+
+// Like run_tool, for a tool that also exits with the `accepted` codes when it succeeds, e.g.
+// terraform plan -detailed-exitcode. The code is returned; the results record it as 0, since
+// the history takes any other code for a failure.
+pub async fn run_tool_accepting(
+    name: &str,
+    program: &str,
+    args: &[String],
+    stdin: Option<&str>,
+    timeout_seconds: u64,
+    accepted: &[i32],
+    results: &mut Vec<HookResult>,
+) -> Result<i32, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut result = HookResult {
+        name: name.to_string(),
+        exit_code: None,
+        duration_ms: 0,
+        timed_out: false,
+        output_truncated: false,
+        error: None,
+        output: None,
+    };
+
+    info!("Running {}", name);
+    let mut code = 0;
+    match run_captured(program, args, stdin, timeout_seconds).await {
+        Ok(Some((exit_code, output, truncated))) => {
+            result.exit_code = match exit_code {
+                Some(exit_code) if accepted.contains(&exit_code) => {
+                    code = exit_code;
+                    Some(0)
+                }
+                other => other,
+            };
+            result.output_truncated = truncated || output.len() > OUTPUT_TAIL;
+            result.output = Some(tail(&output));
+        }
+        Ok(None) => result.timed_out = true,
+        Err(e) => result.error = Some(e.to_string()),
+    }
+    result.duration_ms = started.elapsed().as_millis() as u64;
+
+    let succeeded = result.succeeded();
+    if succeeded {
+        info!("{} finished in {} ms.", name, result.duration_ms);
+    } else {
+        error!(
+            "{} failed (exit code: {:?}, timed out: {}, error: {}). Output: {}",
+            name,
+            result.exit_code,
+            result.timed_out,
+            result.error.as_deref().unwrap_or("none"),
+            result.output.as_deref().unwrap_or_default()
+        );
+    }
+    results.push(result);
+    if !succeeded {
+        return Err(format!("{} failed", name).into());
+    }
+    Ok(code)
+}
+
+// The exit code, the end of stdout and stderr and whether any of them was cut, or None after a
+// timeout. Only the last OUTPUT_TAIL bytes of each stream are kept in memory. The tool is killed
+// when the timeout drops it.
+async fn run_captured(
+    program: &str,
+    args: &[String],
+    stdin: Option<&str>,
+    timeout_seconds: u64,
+) -> Result<Option<(Option<i32>, String, bool)>, Box<dyn std::error::Error>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run '{}': {}", program, e))?;
+    let readers = [
+        tokio::spawn(read_capped(child.stdout.take(), OUTPUT_TAIL, Keep::End)),
+        tokio::spawn(read_capped(child.stderr.take(), OUTPUT_TAIL, Keep::End)),
+    ];
+
+    let limit = Duration::from_secs(timeout_seconds);
+    let finished = timeout(limit, async {
+        if let (Some(text), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(text.as_bytes()).await?;
+        }
+        child.wait().await
+    })
+    .await;
+    let status = match finished {
+        Ok(status) => status?,
+        Err(_) => {
+            readers.iter().for_each(|reader| reader.abort());
+            return Ok(None);
+        }
+    };
+
+    // Leftover child processes may still hold the pipes, so the readers only get a moment
+    let mut text = String::new();
+    let mut truncated = false;
+    for reader in readers {
+        let abort = reader.abort_handle();
+        match timeout(Duration::from_secs(2), reader).await {
+            Ok(Ok((output, cut))) => {
+                text.push_str(&String::from_utf8_lossy(&output));
+                truncated |= cut;
+            }
+            _ => abort.abort(),
+        }
+    }
+    Ok(Some((status.code(), text, truncated)))
+}
+
+// The last OUTPUT_TAIL bytes of the output, cut at a character boundary
+fn tail(output: &str) -> String {
+    let mut start = output.len().saturating_sub(OUTPUT_TAIL);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    output[start..].trim().to_string()
+}
+
+// Kills the hook's whole process tree
+fn kill_tree(pid: u32) {
+    let status = if cfg!(windows) {
+        std::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .status()
+    } else {
+        std::process::Command::new("kill")
+            .args(["-s", "KILL", "--", &format!("-{}", pid)])
+            .status()
+    };
+
+    if let Err(e) = status {
+        error!("Failed to kill hook process tree {}: {}", pid, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn substituted_values_cannot_break_out_of_their_quotes() {
+        assert_eq!(shell_quote("main").unwrap(), "'main'");
+        assert_eq!(
+            shell_quote("a'; rm -rf ~; '").unwrap(),
+            r"'a'\''; rm -rf ~; '\'''"
+        );
+        let vars = [("branch", "x$(touch pwned)'`id`".to_string())];
+        let script = template::render_escaped("echo {{branch}}", &vars, shell_quote).unwrap();
+        let output = std::process::Command::new("sh")
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "x$(touch pwned)'`id`\n"
+        );
+    }
+
+    #[test]
+    fn output_tail_keeps_the_end_at_a_character_boundary() {
+        assert_eq!(tail("  short  "), "short");
+        let output = format!("{}{}", "é".repeat(OUTPUT_TAIL), "the error");
+        let kept = tail(&output);
+        assert!(kept.ends_with("the error"));
+        assert!(kept.len() <= OUTPUT_TAIL);
+    }
+
+    #[tokio::test]
+    async fn capped_reads_keep_the_chosen_end() {
+        let text = b"0123456789".repeat(3000);
+        let (start, cut) = read_capped(Some(&text[..]), 15, Keep::Start).await;
+        assert_eq!((&start[..], cut), (&b"012345678901234"[..], true));
+        let (end, cut) = read_capped(Some(&text[..]), 15, Keep::End).await;
+        assert_eq!((&end[..], cut), (&b"567890123456789"[..], true));
+        let (all, cut) = read_capped(Some(&b"short"[..]), 15, Keep::End).await;
+        assert_eq!((&all[..], cut), (&b"short"[..], false));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn accepted_exit_codes_count_as_success() {
+        let exit = |code: i32| ["-c".to_string(), format!("exit {}", code)];
+        let mut results = Vec::new();
+        let code = run_tool_accepting("plan", "sh", &exit(2), None, 30, &[2], &mut results)
+            .await
+            .unwrap();
+        assert_eq!(code, 2);
+        assert!(results[0].succeeded());
+
+        assert!(
+            run_tool_accepting("plan", "sh", &exit(1), None, 30, &[2], &mut results)
+                .await
+                .is_err()
+        );
+        assert_eq!(results[1].exit_code, Some(1));
+        assert!(run_tool("plan", "sh", &exit(2), None, 30, &mut results)
+            .await
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn tools_are_stopped_at_their_timeout() {
+        let args = ["-c".to_string(), "sleep 30".to_string()];
+        let mut results = Vec::new();
+        let started = Instant::now();
+        assert!(run_tool("sleep", "sh", &args, None, 1, &mut results)
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(results[0].timed_out);
+
+        let args = [
+            "-c".to_string(),
+            "head -c 100000 /dev/zero | tr '\\0' x; echo; echo done".to_string(),
+        ];
+        run_tool("noisy", "sh", &args, None, 30, &mut results)
+            .await
+            .unwrap();
+        assert!(results[1].output_truncated);
+        assert!(results[1].output.as_deref().unwrap().ends_with("done"));
+    }
+}
@@ -0,0 +1,57 @@
+// Remote changes not worth a pull: commits by ignored authors, such as a CI bot bumping version
+// files, and commits touching only ignored paths. When every new commit is one of those, the
+// checkout stays where it is and nothing restarts; the commits come along with the next real
+// change.
+use crate::git::{self, CommitFiles};
+use crate::glob;
+
+// Author patterns match the name or email, ignoring case, with `*` and `?` wildcards
+fn ignored_author(patterns: &[String], commit: &CommitFiles) -> bool {
+    let name = commit.author.to_lowercase();
+    let email = commit.author_email.to_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        glob::matches(&pattern, &name) || glob::matches(&pattern, &email)
+    })
+}
+
+// A commit that changes no files, such as a merge, is judged by the commits it brings in
+fn only_ignored_paths(patterns: &[String], commit: &CommitFiles) -> bool {
+    !patterns.is_empty()
+        && commit
+            .files
+            .iter()
+            .all(|file| glob::matches_any(patterns, file))
+}
+
+// Why the commits between two fetched commits can be ignored, or None if any of them counts
+pub async fn noise(
+    repo_path: &str,
+    old_commit: &str,
+    new_commit: &str,
+    authors: &[String],
+    paths: &[String],
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let commits = git::commit_files(repo_path, old_commit, new_commit).await?;
+    if commits.is_empty() {
+        return Ok(None);
+    }
+
+    let mut by_author = 0;
+    for commit in &commits {
+        if ignored_author(authors, commit) {
+            by_author += 1;
+        } else if !only_ignored_paths(paths, commit) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(match by_author {
+        0 => format!("{} commit(s) touching only ignore_paths", commits.len()),
+        n if n == commits.len() => format!("{} commit(s) by ignore_authors", n),
+        n => format!(
+            "{} commit(s) by ignore_authors and {} touching only ignore_paths",
+            n,
+            commits.len() - n
+        ),
+    }))
+}
@@ -0,0 +1,229 @@
+// Container images built from the synced repo, and optionally pushed to a registry
+use crate::hooks::{self, HookResult};
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use crate::template;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// One [[post_sync.images]] entry
+#[derive(Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub tool: ImageTool,
+    // Tags with {{placeholders}}, e.g. "registry.acme.com/shop:{{short_commit}}"
+    pub tags: Vec<String>,
+    // Build context relative to the repo
+    #[serde(default = "default_context")]
+    pub context: String,
+    // Dockerfile or Containerfile relative to the repo, the tool's default when unset
+    pub file: Option<String>,
+    // --build-arg values, with {{placeholders}}
+    #[serde(default)]
+    pub build_args: BTreeMap<String, String>,
+    // Push every tag once the build succeeded
+    #[serde(default)]
+    pub push: bool,
+    // Registry login before pushing, when a username is set
+    pub registry: Option<String>,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    // The build or a push is stopped after this long
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    // Only build when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageTool {
+    #[default]
+    Docker,
+    Podman,
+    Buildah,
+}
+
+impl ImageTool {
+    fn program(self) -> &'static str {
+        match self {
+            ImageTool::Docker => "docker",
+            ImageTool::Podman => "podman",
+            ImageTool::Buildah => "buildah",
+        }
+    }
+}
+
+fn default_context() -> String {
+    ".".to_string()
+}
+
+fn default_timeout() -> u64 {
+    1800
+}
+
+impl Documented for ImageConfig {
+    const SECTION: &'static str = "[[post_sync.images]]";
+    const ABOUT: &'static str =
+        "Container images built after every successful pull, optionally pushed to a registry.";
+    const FIELDS: &'static [Field] = &[
+        schema::defaulted(
+            "tool",
+            "\"docker\", \"podman\" or \"buildah\"",
+            r#""docker""#,
+            "Tool that builds and pushes the image",
+        ),
+        schema::required(
+            "tags",
+            "list of strings",
+            r#"["registry.acme.com/shop:{{short_commit}}"]"#,
+            "Image tags; {{new_commit}}, {{short_commit}}, {{old_commit}} and {{branch}} are substituted",
+        ),
+        schema::defaulted("context", "string", r#"".""#, "Build context, relative to the repo"),
+        schema::optional(
+            "file",
+            "string",
+            r#""docker/Dockerfile""#,
+            "Dockerfile or Containerfile, relative to the repo",
+        ),
+        schema::defaulted(
+            "build_args",
+            "table of strings",
+            "{}",
+            "--build-arg values, with the same placeholders as tags",
+        ),
+        schema::defaulted("push", "boolean", "false", "Push every tag after a successful build"),
+        schema::optional(
+            "registry",
+            "string",
+            r#""registry.acme.com""#,
+            "Registry to log in to before pushing",
+        ),
+        schema::optional("username", "string", r#""ci-bot""#, "Registry user; no login when unset"),
+        schema::optional(
+            "password",
+            "string",
+            r#""enc:...""#,
+            "Registry password or token, passed on stdin; plain or enc: encrypted",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "1800",
+            "The build, and each push, is stopped after this long",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only build when a changed file matches one of these (empty means always)",
+        ),
+    ];
+}
+
+impl ImageConfig {
+    // Decrypts an enc: password, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.tags.is_empty() {
+            return Err("[[post_sync.images]] needs at least one tag".into());
+        }
+        self.password = secrets::resolve_secret(&self.password, secrets_identity)?;
+        Ok(())
+    }
+
+    // The first tag, naming the image in logs
+    pub fn label(&self) -> &str {
+        self.tags.first().map(String::as_str).unwrap_or_default()
+    }
+}
+
+// Builds the image, then logs in and pushes it if configured. Every step is added to the
+// results, which end up in the sync history with the end of their output.
+pub async fn build(
+    image: &ImageConfig,
+    repo_path: &str,
+    vars: &[(&str, String)],
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tags: Vec<String> = image
+        .tags
+        .iter()
+        .map(|tag| template::render(tag, vars))
+        .collect();
+
+    let mut args = vec!["build".to_string()];
+    for tag in &tags {
+        args.extend(["-t".to_string(), tag.clone()]);
+    }
+    if let Some(file) = &image.file {
+        args.extend([
+            "-f".to_string(),
+            Path::new(repo_path)
+                .join(file)
+                .to_string_lossy()
+                .to_string(),
+        ]);
+    }
+    for (name, value) in &image.build_args {
+        args.extend([
+            "--build-arg".to_string(),
+            format!("{}={}", name, template::render(value, vars)),
+        ]);
+    }
+    args.push(
+        Path::new(repo_path)
+            .join(&image.context)
+            .to_string_lossy()
+            .to_string(),
+    );
+    let build = format!("image build {}", tags[0]);
+    run_step(image, &build, &args, None, results).await?;
+
+    if !image.push {
+        return Ok(());
+    }
+    if let Some(username) = &image.username {
+        let mut args = vec![
+            "login".to_string(),
+            "-u".to_string(),
+            username.clone(),
+            "--password-stdin".to_string(),
+        ];
+        args.extend(image.registry.clone());
+        let name = format!(
+            "image login {}",
+            image.registry.as_deref().unwrap_or_default()
+        );
+        run_step(
+            image,
+            name.trim_end(),
+            &args,
+            Some(&image.password),
+            results,
+        )
+        .await?;
+    }
+    for tag in &tags {
+        let args = ["push".to_string(), tag.clone()];
+        run_step(image, &format!("image push {}", tag), &args, None, results).await?;
+    }
+    Ok(())
+}
+
+// Runs the image tool within the image's timeout
+async fn run_step(
+    image: &ImageConfig,
+    name: &str,
+    args: &[String],
+    stdin: Option<&str>,
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program = image.tool.program();
+    hooks::run_tool(name, program, args, stdin, image.timeout_seconds, results).await
+}
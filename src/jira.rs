@@ -0,0 +1,310 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::{SyncRecord, SyncStatus};
+use crate::notify::{self, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use crate::template;
+use log::{error, info, warn};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+// The [jira] table: Jira issues referenced by synced commits are told where they were deployed
+#[derive(Deserialize)]
+pub struct JiraConfig {
+    // Site URL, e.g. https://acme.atlassian.net
+    pub url: String,
+    // Account email for Jira Cloud API tokens; without it the token is sent as a bearer token
+    // (Jira Data Center personal access tokens)
+    pub username: Option<String>,
+    pub api_token: String,
+    // Project keys whose issues are updated; empty means any key-like reference
+    #[serde(default)]
+    pub projects: Vec<String>,
+    // Comment added to every referenced issue, with {{placeholders}}; empty adds none
+    #[serde(default = "default_comment")]
+    pub comment: String,
+    // Transition applied to every referenced issue, by name, when the issue offers it
+    pub transition: Option<String>,
+}
+
+fn default_comment() -> String {
+    "Deployed to {{host}}{% if labels %} [{{labels}}]{% endif %}: {{repo}} ({{branch}}) synced to {{short_commit}}. {{commit_url}}".to_string()
+}
+
+impl Documented for JiraConfig {
+    const SECTION: &'static str = "[jira]";
+    const ABOUT: &'static str =
+        "Jira issues referenced by synced commits get a deployment comment or a transition.";
+    const FIELDS: &'static [Field] = &[
+        schema::required("url", "string", r#""https://acme.atlassian.net""#, "Jira site URL"),
+        schema::optional(
+            "username",
+            "string",
+            r#""deploy-bot@acme.com""#,
+            "Account email for a Jira Cloud API token; unset, the token is sent as a bearer token (Data Center PAT)",
+        ),
+        schema::required(
+            "api_token",
+            "string",
+            r#""enc:...""#,
+            "API token or personal access token; plain or enc: encrypted",
+        ),
+        schema::defaulted(
+            "projects",
+            "list of strings",
+            "[]",
+            "Project keys whose issues are updated, e.g. [\"SHOP\", \"OPS\"] (empty means any key-like reference)",
+        ),
+        schema::defaulted(
+            "comment",
+            "string",
+            r#""Deployed to {{host}}: {{repo}} synced to {{short_commit}}.""#,
+            "Comment added to each issue; notification placeholders plus {{issue}}, empty adds none",
+        ),
+        schema::optional(
+            "transition",
+            "string",
+            r#""Deployed""#,
+            "Transition applied to each issue that offers it, by name",
+        ),
+    ];
+}
+
+impl JiraConfig {
+    // Decrypts an enc: token, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.api_token = secrets::resolve_secret(&self.api_token, secrets_identity)?;
+        if self.api_token.is_empty() {
+            return Err("[jira] needs an api_token".into());
+        }
+        Ok(())
+    }
+
+    // What is done to the issues, for the startup summary
+    pub fn describe(&self) -> String {
+        let action = match (&self.transition, self.comment.is_empty()) {
+            (Some(transition), false) => format!("comment and transition to '{}'", transition),
+            (Some(transition), true) => format!("transition to '{}'", transition),
+            (None, false) => "comment".to_string(),
+            (None, true) => "nothing".to_string(),
+        };
+        let projects = if self.projects.is_empty() {
+            "any project".to_string()
+        } else {
+            self.projects.join(", ")
+        };
+        format!("{} on {} issues at {}", action, projects, self.url)
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.username {
+            Some(username) => request.basic_auth(username, Some(&self.api_token)),
+            None => request.bearer_auth(&self.api_token),
+        }
+    }
+
+    fn issue_url(&self, key: &str, path: &str) -> String {
+        format!(
+            "{}/rest/api/2/issue/{}{}",
+            self.url.trim_end_matches('/'),
+            key,
+            path
+        )
+    }
+}
+
+// Updates the issues referenced by every successful sync
+pub struct Jira {
+    config: JiraConfig,
+    client: Client,
+}
+
+impl Jira {
+    pub fn new(config: JiraConfig, client: Client) -> Self {
+        Jira { config, client }
+    }
+
+    async fn deployed(&self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        let texts = record
+            .commits
+            .iter()
+            .map(|commit| commit.message.as_str())
+            .chain(record.pull_requests.iter().map(|pr| pr.title.as_str()));
+        let mut keys: Vec<String> = Vec::new();
+        for key in texts.flat_map(|text| issue_keys(text, &self.config.projects)) {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        let (mut vars, lists) = notify::template_values(repo, record);
+        for key in keys {
+            vars.retain(|(name, _)| *name != "issue");
+            vars.push(("issue", key.clone()));
+            let comment = template::render_with_lists(&self.config.comment, &vars, &lists);
+            match self.update(&key, &comment).await {
+                Ok(done) => info!("Jira issue {}: {}.", key, done),
+                Err(e) => error!("Failed to update Jira issue {}: {}", key, e),
+            }
+        }
+    }
+
+    // Comments on and transitions one issue, saying what was done
+    async fn update(&self, key: &str, comment: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let mut done = Vec::new();
+        if !comment.is_empty() {
+            let request = self
+                .client
+                .post(self.config.issue_url(key, "/comment"))
+                .json(&json!({ "body": comment }));
+            let response = self.config.request(request).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                warn!("{} is not a Jira issue, or not one the token can see.", key);
+                return Ok("skipped".to_string());
+            }
+            response.error_for_status()?;
+            done.push("commented".to_string());
+        }
+
+        if let Some(name) = &self.config.transition {
+            done.push(match self.transition(key, name).await? {
+                true => format!("moved through '{}'", name),
+                false => format!("'{}' not available, left as is", name),
+            });
+        }
+        Ok(done.join(", "))
+    }
+
+    // Applies the named transition if the issue offers it in its current state
+    async fn transition(&self, key: &str, name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let url = self.config.issue_url(key, "/transitions");
+        let available: Transitions = self
+            .config
+            .request(self.client.get(&url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let Some(transition) = available.transitions.iter().find(|transition| {
+            transition.name.eq_ignore_ascii_case(name)
+                || transition
+                    .to
+                    .as_ref()
+                    .is_some_and(|to| to.name.eq_ignore_ascii_case(name))
+        }) else {
+            return Ok(false);
+        };
+
+        let request = self
+            .client
+            .post(&url)
+            .json(&json!({ "transition": { "id": transition.id } }));
+        self.config
+            .request(request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(true)
+    }
+}
+
+impl Subscriber for Jira {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            if let SyncEvent::SyncFinished { record } = event {
+                if record.status == SyncStatus::Success {
+                    self.deployed(repo, record).await;
+                }
+            }
+            Directive::default()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Transitions {
+    transitions: Vec<Transition>,
+}
+
+#[derive(Deserialize)]
+struct Transition {
+    id: String,
+    name: String,
+    to: Option<Status>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    name: String,
+}
+
+// Finds issue keys such as "SHOP-123" in a commit message, limited to the given projects if any
+pub fn issue_keys(message: &str, projects: &[String]) -> Vec<String> {
+    let mut keys = Vec::new();
+
+    for (index, _) in message.match_indices('-') {
+        let before = &message[..index];
+        let project_start = before
+            .rfind(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .map_or(0, |at| at + 1);
+        let project = &before[project_start..];
+        // Keys start with a letter and stand on their own, not the end of a longer word
+        if !project.starts_with(|c: char| c.is_ascii_uppercase())
+            || before[..project_start]
+                .chars()
+                .next_back()
+                .is_some_and(char::is_alphanumeric)
+        {
+            continue;
+        }
+        if !projects.is_empty() && !projects.iter().any(|allowed| allowed == project) {
+            continue;
+        }
+
+        let digits: String = message[index + 1..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        let followed_by_word = message[index + 1 + digits.len()..]
+            .chars()
+            .next()
+            .is_some_and(char::is_alphanumeric);
+        if digits.is_empty() || digits.starts_with('0') || followed_by_word {
+            continue;
+        }
+
+        let key = format!("{}-{}", project, digits);
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issue_keys_are_found_in_messages() {
+        assert_eq!(
+            issue_keys("SHOP-12: fix cart, see OPS-7 and SHOP-12", &[]),
+            vec!["SHOP-12", "OPS-7"]
+        );
+        assert_eq!(
+            issue_keys("[SHOP-3] (OPS-44)", &[]),
+            vec!["SHOP-3", "OPS-44"]
+        );
+        assert!(issue_keys("bump to v1-2, my-branch, 9-5, aSHOP-1, SHOP-1x", &[]).is_empty());
+        assert_eq!(
+            issue_keys("UTF-8 output for SHOP-5", &["SHOP".to_string()]),
+            vec!["SHOP-5"]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+// Kubernetes manifests applied from the synced repo with kubectl, a minimal GitOps agent for
+// clusters without Argo CD or Flux
+use crate::hooks::{self, HookResult};
+use crate::schema::{self, Documented, Field};
+use serde::Deserialize;
+use std::path::Path;
+
+// [post_sync.kubernetes]
+#[derive(Deserialize)]
+pub struct KubernetesConfig {
+    // Manifest file or directory, relative to the repo
+    pub path: String,
+    // Apply with -k; defaults to whether the directory has a kustomization file
+    pub kustomize: Option<bool>,
+    pub kubeconfig: Option<String>,
+    pub context: Option<String>,
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub dry_run: DryRun,
+    #[serde(default = "default_kubectl")]
+    pub kubectl: String,
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    // Only apply when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DryRun {
+    #[default]
+    Off,
+    Client,
+    Server,
+}
+
+fn default_kubectl() -> String {
+    "kubectl".to_string()
+}
+
+fn default_timeout() -> u64 {
+    300
+}
+
+// File names kubectl kustomize looks for in a directory
+const KUSTOMIZATION_FILES: [&str; 3] = ["kustomization.yaml", "kustomization.yml", "Kustomization"];
+
+impl Documented for KubernetesConfig {
+    const SECTION: &'static str = "[post_sync.kubernetes]";
+    const ABOUT: &'static str =
+        "Applies Kubernetes manifests from the repo with kubectl after every successful pull.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "path",
+            "string",
+            r#""deploy/overlays/production""#,
+            "Manifest file or directory, relative to the repo",
+        ),
+        schema::optional(
+            "kustomize",
+            "boolean",
+            "true",
+            "Apply with -k rather than -f; defaults to whether path has a kustomization file",
+        ),
+        schema::optional(
+            "kubeconfig",
+            "string",
+            r#""/etc/devops-sync/kubeconfig""#,
+            "kubeconfig file, kubectl's default when unset",
+        ),
+        schema::optional("context", "string", r#""production""#, "kubeconfig context"),
+        schema::optional(
+            "namespace",
+            "string",
+            r#""shop""#,
+            "Namespace for manifests that don't set one",
+        ),
+        schema::defaulted(
+            "dry_run",
+            "\"off\", \"client\" or \"server\"",
+            r#""off""#,
+            "Only show what would change, checked locally or by the API server",
+        ),
+        schema::defaulted("kubectl", "string", r#""kubectl""#, "kubectl executable"),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "300",
+            "The apply is stopped after this long",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only apply when a changed file matches one of these (empty means always)",
+        ),
+    ];
+}
+
+impl KubernetesConfig {
+    pub fn describe(&self) -> String {
+        let mut description = self.path.clone();
+        if let Some(context) = &self.context {
+            description.push_str(&format!(" to {}", context));
+        }
+        if self.dry_run != DryRun::Off {
+            description.push_str(" (dry run)");
+        }
+        description
+    }
+
+    // The kubectl arguments applying the manifests in the checkout
+    fn apply_args(&self, repo_path: &str) -> Vec<String> {
+        let manifests = Path::new(repo_path).join(&self.path);
+        let kustomize = self.kustomize.unwrap_or_else(|| {
+            KUSTOMIZATION_FILES
+                .iter()
+                .any(|name| manifests.join(name).is_file())
+        });
+
+        let mut args = Vec::new();
+        if let Some(kubeconfig) = &self.kubeconfig {
+            args.extend(["--kubeconfig".to_string(), kubeconfig.clone()]);
+        }
+        if let Some(context) = &self.context {
+            args.extend(["--context".to_string(), context.clone()]);
+        }
+        if let Some(namespace) = &self.namespace {
+            args.extend(["--namespace".to_string(), namespace.clone()]);
+        }
+        args.push("apply".to_string());
+        if kustomize {
+            args.push("-k".to_string());
+        } else {
+            args.extend(["--recursive".to_string(), "-f".to_string()]);
+        }
+        args.push(manifests.to_string_lossy().to_string());
+        match self.dry_run {
+            DryRun::Off => {}
+            DryRun::Client => args.push("--dry-run=client".to_string()),
+            DryRun::Server => args.push("--dry-run=server".to_string()),
+        }
+        args
+    }
+}
+
+// Applies the manifests, adding the run and kubectl's output to the results
+pub async fn apply(
+    config: &KubernetesConfig,
+    repo_path: &str,
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = format!("kubectl apply {}", config.describe());
+    let args = config.apply_args(repo_path);
+    hooks::run_tool(
+        &name,
+        &config.kubectl,
+        &args,
+        None,
+        config.timeout_seconds,
+        results,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kustomization_directories_are_applied_with_k() {
+        let repo = std::env::temp_dir().join(format!("kubernetes-test-{}", std::process::id()));
+        std::fs::create_dir_all(repo.join("overlay")).unwrap();
+        std::fs::write(repo.join("overlay/kustomization.yaml"), "resources: []\n").unwrap();
+        let mut config: KubernetesConfig =
+            toml::from_str("path = \"overlay\"\ncontext = \"prod\"\ndry_run = \"server\"").unwrap();
+        let repo_path = repo.to_string_lossy().to_string();
+
+        let args = config.apply_args(&repo_path);
+        assert_eq!(&args[..4], ["--context", "prod", "apply", "-k"]);
+        assert_eq!(args.last().unwrap(), "--dry-run=server");
+
+        config.kustomize = Some(false);
+        assert!(config.apply_args(&repo_path).contains(&"-f".to_string()));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+// The process-wide logger. Every record goes to app.log and, as --quiet / --verbose ask, to the
+// console. Records logged while a repo's loop runs carry that repo's name, branch and cycle
+// number as fields, and also go to the repo's own log_file when it has one.
+use crate::console;
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use simplelog::{ColorChoice, SharedLogger, TermLogger, TerminalMode};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+const MAIN_LOG: &str = "app.log";
+
+// How lines are written to app.log and the repo log files
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    // `12:00:00 [INFO] repo=website branch=main cycle=3 message`
+    #[default]
+    Text,
+    // One JSON object per line with time, level, repo, branch, cycle and message
+    Json,
+}
+
+// Which repo loop a record was logged from
+struct Context {
+    repository: String,
+    branch: String,
+    cycle: u64,
+    // Path of the repo's own log file, if it has one
+    log_file: Option<String>,
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<Context>;
+}
+
+// Runs a repo's loop with its name, branch and log file attached to everything it logs
+pub async fn in_repo<F: Future>(
+    repository: String,
+    branch: String,
+    log_file: Option<String>,
+    run: F,
+) -> F::Output {
+    let context = Context {
+        repository,
+        branch,
+        cycle: 0,
+        log_file,
+    };
+    CONTEXT.scope(RefCell::new(context), run).await
+}
+
+// Numbers the cycle that is starting, for the records logged during it
+pub fn start_cycle() {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().cycle += 1);
+}
+
+// Records the branch the cycle follows, once the manifest or a fallback has settled it
+pub fn set_branch(branch: &str) {
+    let _ = CONTEXT.try_with(|context| context.borrow_mut().branch = branch.to_string());
+}
+
+// The files records are written to
+struct Files {
+    format: Format,
+    main: File,
+    // Repo log files by path; repos may share one
+    repos: HashMap<String, File>,
+}
+
+static FILES: Mutex<Option<Files>> = Mutex::new(None);
+
+struct Logger {
+    level: LevelFilter,
+    console: Option<Box<dyn SharedLogger>>,
+}
+
+// Starts logging to a fresh app.log, mirrored to the console as the mode asks
+pub fn init(mode: console::Mode) -> Result<(), Box<dyn std::error::Error>> {
+    init_at(Path::new(MAIN_LOG), mode)
+}
+
+// Starts logging to a fresh main log at `path`, e.g. in the scratch directory of `simulate`
+pub fn init_at(path: &Path, mode: console::Mode) -> Result<(), Box<dyn std::error::Error>> {
+    let main =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    *FILES.lock().unwrap() = Some(Files {
+        format: Format::Text,
+        main,
+        repos: HashMap::new(),
+    });
+
+    let console: Option<Box<dyn SharedLogger>> = match mode {
+        console::Mode::Normal => None,
+        console::Mode::Quiet => Some(TermLogger::new(
+            LevelFilter::Error,
+            simplelog::Config::default(),
+            TerminalMode::Stderr,
+            ColorChoice::Auto,
+        )),
+        console::Mode::Verbose => Some(TermLogger::new(
+            LevelFilter::Info,
+            simplelog::Config::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        )),
+    };
+    log::set_boxed_logger(Box::new(Logger {
+        level: LevelFilter::Info,
+        console,
+    }))?;
+    log::set_max_level(LevelFilter::Info);
+    Ok(())
+}
+
+// Applies the configured format and opens the repo log files, appending to what earlier runs
+// wrote there
+pub fn configure(format: Format, log_files: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut repos = HashMap::new();
+    for &path in log_files {
+        if repos.contains_key(path) {
+            continue;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open log file '{}': {}", path, e))?;
+        repos.insert(path.to_string(), file);
+    }
+
+    let mut files = FILES.lock().unwrap();
+    if let Some(files) = files.as_mut() {
+        files.format = format;
+        files.repos = repos;
+    }
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let (fields, log_file) = CONTEXT
+            .try_with(|context| {
+                let context = context.borrow();
+                (
+                    Some((
+                        context.repository.clone(),
+                        context.branch.clone(),
+                        context.cycle,
+                    )),
+                    context.log_file.clone(),
+                )
+            })
+            .unwrap_or((None, None));
+
+        if let Some(files) = FILES.lock().unwrap().as_mut() {
+            let line = match files.format {
+                Format::Text => text_line(record, fields.as_ref()),
+                Format::Json => json_line(record, fields.as_ref()),
+            };
+            let _ = writeln!(files.main, "{}", line);
+            if let Some(file) = log_file.and_then(|path| files.repos.get_mut(&path)) {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+
+        if let Some(console) = &self.console {
+            match &fields {
+                Some(fields) => console.log(
+                    &Record::builder()
+                        .args(format_args!("{} {}", text_fields(fields), record.args()))
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                ),
+                None => console.log(record),
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(files) = FILES.lock().unwrap().as_mut() {
+            let _ = files.main.flush();
+            for file in files.repos.values_mut() {
+                let _ = file.flush();
+            }
+        }
+        if let Some(console) = &self.console {
+            console.flush();
+        }
+    }
+}
+
+fn text_fields((repository, branch, cycle): &(String, String, u64)) -> String {
+    format!("repo={} branch={} cycle={}", repository, branch, cycle)
+}
+
+fn text_line(record: &Record, fields: Option<&(String, String, u64)>) -> String {
+    let time = Utc::now().format("%H:%M:%S");
+    match fields {
+        Some(fields) => format!(
+            "{} [{}] {} {}",
+            time,
+            record.level(),
+            text_fields(fields),
+            record.args()
+        ),
+        None => format!("{} [{}] {}", time, record.level(), record.args()),
+    }
+}
+
+fn json_line(record: &Record, fields: Option<&(String, String, u64)>) -> String {
+    let mut line = serde_json::json!({
+        "time": Utc::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "message": record.args().to_string(),
+    });
+    if let Some((repository, branch, cycle)) = fields {
+        line["repo"] = repository.as_str().into();
+        line["branch"] = branch.as_str().into();
+        line["cycle"] = (*cycle).into();
+    }
+    line.to_string()
+}
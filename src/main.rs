@@ -1,68 +1,2590 @@
-use chrono::{DateTime, Utc};
-use log::{error, info};
-use reqwest::Client;
+use chrono::{DateTime, Datelike, Local, Utc};
+use events::SyncEvent;
+use log::{error, info, warn};
+use reqwest::{Client, StatusCode};
 use serde::Deserialize;
-use serde_json;
-use simplelog::*;
+use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
 use std::io::{self, Write};
-use std::path::Path;
-use std::process::Command;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::LocalSet;
 use tokio::time::sleep;
 
+mod alert;
+mod archive;
+mod audit;
+mod azure;
+mod batch;
+mod change;
+mod cli;
+mod completions;
+mod console;
+mod control;
+mod copy;
+mod cycle;
+mod deployment;
+mod discovery;
+mod duration;
+#[cfg(feature = "embedded-git")]
+mod embedded;
+mod events;
+mod feeds;
+mod git;
+#[cfg(feature = "gitoxide")]
+mod gitoxide;
+mod glob;
+mod history;
+mod hooks;
+mod ignore;
+mod image;
+mod jira;
+mod kubernetes;
+mod logging;
+mod manifest;
+mod metrics;
+mod monorepo;
+mod network;
+mod notify;
+mod ordering;
+mod paths;
+mod pipelines;
+mod plugins;
+mod policy;
+mod polling;
+mod post_sync;
+mod releases;
+mod rules;
+mod schema;
+mod script;
+mod secrets;
+mod simulate;
+mod state;
+mod stats;
+#[cfg(test)]
+mod strategies;
+mod template;
+mod terraform;
+mod tls;
+mod unpack;
+mod verify;
+mod version;
+mod webhook;
+
 // Struct to hold the configuration
 #[derive(Deserialize)]
 struct AppConfig {
     repo_path: String,
     organization: String,
     project: String,
+    // Repository name, looked up from repository_id when that's set instead
+    #[serde(default)]
     repository: String,
+    // Repository GUID, which tells same-named repositories apart and survives renames
+    repository_id: Option<String>,
     target_branch: String,
+    // Inline PAT, ignored when pat_env or pat_file is set
+    #[serde(default)]
     pat: String,
+    // Name of an environment variable holding the PAT
+    pat_env: Option<String>,
+    // Path to a file holding the PAT
+    pat_file: Option<String>,
+    // Written as seconds or as a duration, under this name or as check_interval
+    #[serde(alias = "check_interval", deserialize_with = "duration::seconds")]
     check_interval_seconds: u64,
+    // Interval used instead while the last pulled change is recent
+    #[serde(default, deserialize_with = "duration::optional_seconds")]
+    active_interval: Option<u64>,
+    // How long a pulled change counts as recent for active_interval
+    #[serde(
+        default = "default_active_window",
+        deserialize_with = "duration::seconds"
+    )]
+    active_window: u64,
+    // Slowest interval, which checks slow down to while the repository stays quiet
+    #[serde(default, deserialize_with = "duration::optional_seconds")]
+    quiet_interval: Option<u64>,
+    // How long after the last pulled change quiet_interval is reached
+    #[serde(
+        default = "default_quiet_after",
+        deserialize_with = "duration::seconds"
+    )]
+    quiet_after: u64,
+    // Check intervals by day and time of day, replacing check_interval_seconds where they apply
+    #[serde(default)]
+    schedule: Vec<polling::ScheduleEntry>,
+    // Path to the age identity used to decrypt "enc:" values (SYNC_AGE_IDENTITY overrides it)
+    secrets_identity: Option<String>,
+    // Local address for the control endpoint, e.g. "127.0.0.1:7878"
+    control_listen: Option<String>,
+    // Bearer tokens the control endpoint requires, each with a read or operator scope
+    #[serde(default)]
+    control_tokens: Vec<control::ControlToken>,
+    // Service hook that posts pushes to the control endpoint's /webhook route
+    webhook: Option<webhook::WebhookConfig>,
+    // Actions to run after a successful pull
+    #[serde(default)]
+    post_sync: post_sync::PostSyncConfig,
+    // Path subsets of the repo deployed independently, with their own actions and notifications
+    #[serde(default)]
+    virtual_repos: Vec<monorepo::VirtualRepoConfig>,
+    // JSON lines file every sync attempt is appended to
+    #[serde(default = "default_history_file")]
+    history_file: String,
+    // Hash-chained copy of the history that `audit verify` checks for tampering
+    audit_file: Option<String>,
+    // Key the audit entries are signed with (HMAC-SHA256)
+    audit_key_file: Option<String>,
+    // Keep appending to an audit_file that fails verification instead of refusing to start
+    #[serde(default)]
+    audit_allow_broken_chain: bool,
+    // Where to send sync notifications
+    #[serde(default)]
+    notifications: Vec<notify::NotificationConfig>,
+    // Resolve work items referenced by pulled commits for notifications and history
+    #[serde(default = "default_true")]
+    link_work_items: bool,
+    // Incident services to page after repeated failures
+    #[serde(default)]
+    alerts: Vec<alert::AlertConfig>,
+    // Change-management systems that get a change record for every sync that applies changes
+    #[serde(default)]
+    change_records: Vec<change::ChangeRecordConfig>,
+    // Jira issues referenced by synced commits, told where they were deployed
+    jira: Option<jira::JiraConfig>,
+    // Commit statuses posted back to Azure DevOps, showing where each commit runs
+    deployment_status: Option<deployment::DeploymentStatusConfig>,
+    // GitHub deployments of every synced commit, for repositories mirrored to GitHub
+    github_deployment: Option<deployment::GithubDeploymentConfig>,
+    // External programs that receive sync events as JSON and can steer the sync
+    #[serde(default)]
+    plugins: Vec<plugins::PluginConfig>,
+    // WebAssembly modules that approve or deny each sync
+    #[serde(default)]
+    policies: Vec<policy::PolicyConfig>,
+    // Inline expressions that skip the post-sync actions when they match
+    #[serde(default)]
+    rules: Vec<rules::RuleConfig>,
+    // Expression computing the seconds until the next check, overriding check_interval_seconds
+    interval_script: Option<String>,
+    // git executable to use instead of the one on the PATH, e.g. a portable git bundle
+    git_path: Option<String>,
+    // "gitoxide" fetches and reads the checkout in-process with gix instead of running git
+    #[serde(default)]
+    git_backend: GitBackend,
+    // Trust repo_path in git's safe.directory list when it is owned by another user
+    #[serde(default)]
+    add_safe_directory: bool,
+    // Treat a missing or empty target branch as "not yet" instead of a failure
+    #[serde(default)]
+    wait_for_first_commit: bool,
+    // Branch to follow if the target branch is deleted or renamed ("default" for the repo's default branch)
+    fallback_branch: Option<String>,
+    // Reset the branch to the remote when local changes or history block the merge
+    #[serde(default)]
+    reset_on_conflict: bool,
+    // Remove untracked files after every sync so the checkout mirrors the repo exactly
+    #[serde(default)]
+    clean_untracked: bool,
+    // Also remove gitignored files (build outputs, virtualenvs) when the tree is cleaned
+    #[serde(default)]
+    clean_ignored: bool,
+    // Untracked paths (git pathspec patterns) that cleaning never removes
+    #[serde(default)]
+    preserve_paths: Vec<String>,
+    // Compare the working tree with the pulled commit after each sync ("status" or "hashes")
+    verify: Option<verify::Verification>,
+    // Seconds between checks for local changes to the checkout, off when unset
+    drift_check_seconds: Option<u64>,
+    // Labels naming this agent's groups, for the manifest, metrics and notifications
+    #[serde(default)]
+    agent_labels: Vec<String>,
+    // Manifest in the repo assigning agents to branches or pinned commits
+    manifest_file: Option<String>,
+    // File in the repo that, once on the remote branch, stops the agent from pulling
+    halt_file: Option<String>,
+    // Probe the provider before each check and wait quietly while it can't be reached
+    #[serde(default)]
+    check_connectivity: bool,
+    // Repos in the same group never pull or run post-sync actions at the same time
+    concurrency_group: Option<String>,
+    // Repos (by repository name) that must be in sync before this one syncs
+    #[serde(default)]
+    after: Vec<String>,
+    // Repos in the same batch only apply their changes once all of them have fetched
+    batch: Option<String>,
+    // Remote commits by these authors (name or email, wildcards allowed) don't trigger a pull
+    #[serde(default)]
+    ignore_authors: Vec<String>,
+    // Remote commits touching only these paths (globs) don't trigger a pull
+    #[serde(default)]
+    ignore_paths: Vec<String>,
+    // File this repo's log records are also written to
+    log_file: Option<String>,
+    // JSON file rewritten after every check with the commit, branch and status
+    state_file: Option<String>,
+    // File listing what each sync changed, JSON when it ends in .json
+    changed_files_file: Option<String>,
+    // How app.log and the repo log files are written: "text" or "json"
+    #[serde(default)]
+    log_format: logging::Format,
+    // Address family for every connection: "any", "ipv4" or "ipv6"
+    #[serde(default)]
+    ip_version: network::IpVersion,
+    // DNS servers to resolve names with instead of the system's
+    #[serde(default)]
+    dns_servers: Vec<String>,
+    // Certificate presented to servers that require one (mTLS)
+    client_certificate: Option<tls::ClientCertificate>,
+    // Seconds a new remote commit waits before it is pulled, so later rings trail the canaries
+    #[serde(default)]
+    sync_delay_seconds: u64,
+    // Seconds the remote branch must stay at one commit before it is pulled, so a burst of
+    // pushes syncs once
+    #[serde(default)]
+    settle_seconds: u64,
+    // Only pull commits whose Azure Pipelines builds completed successfully
+    #[serde(default)]
+    wait_for_build: bool,
+    // Pipeline (definition) ids whose builds must pass; empty means every pipeline building
+    // the commit
+    #[serde(default)]
+    build_definitions: Vec<u64>,
+    // Pipeline artifact downloaded for every synced commit, before the post-sync actions
+    artifact: Option<pipelines::ArtifactConfig>,
+    // Pipeline or release started after every successful sync
+    trigger: Option<pipelines::TriggerConfig>,
+    // Synced commits kept as worktrees behind a `current` link, for an offline rollback
+    releases: Option<releases::ReleasesConfig>,
+    // "observe" only reports how the checkout compares to the remote, without changing it
+    #[serde(default)]
+    mode: SyncMode,
+    // "pull_requests" syncs to the latest pull request completed into the branch, not its head
+    #[serde(default)]
+    sync_on: SyncOn,
+    // "detached" checks out the exact remote commit with no local branch
+    #[serde(default)]
+    checkout_mode: CheckoutMode,
+    // "ancestry" leaves a checkout alone when it already contains the remote commit
+    #[serde(default)]
+    compare: Compare,
+    // What a force push to the target branch leads to: "merge", "reset" or "hold"
+    #[serde(default)]
+    force_push: ForcePush,
+    // Whether the checkout's origin remote is pointed at the repository's new URL when it moves
+    #[serde(default)]
+    update_remote_url: bool,
+    // Azure DevOps REST API version, for servers that need a specific one
+    #[serde(default = "default_api_version")]
+    api_version: String,
+    // Where Azure DevOps is reached, an Azure DevOps Server's URL for on-premises installs
+    #[serde(default = "default_server_url")]
+    server_url: String,
+    // User-Agent sent with every Azure DevOps request
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+    // Directory for files kept between syncs, such as Terraform plans waiting for approval
+    #[serde(default = "default_state_dir")]
+    state_dir: String,
+    // Longest an HTTP request may take, so a stalled server can't hold up the loop
+    #[serde(default = "default_request_timeout")]
+    request_timeout_seconds: u64,
+    // Look for newer releases at startup and once a day, logging when one exists
+    #[serde(default)]
+    check_for_updates: bool,
+    // Answer mDNS queries so `discover` finds this agent on the LAN
+    #[serde(default)]
+    announce: bool,
+    // Release feed to check instead of the GitHub releases of this project
+    update_feed_url: Option<String>,
+    // Position of the entry in config.toml, which still finds it after the project and
+    // repository have been replaced by the remote's names
+    #[serde(skip)]
+    entry: usize,
+}
+
+// Whether the agent keeps the checkout in sync or only watches it
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum SyncMode {
+    #[default]
+    Sync,
+    // Read-only: report being behind or drifted through logs, metrics and notifications
+    Observe,
+}
+
+// What moves the checkout forward
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum SyncOn {
+    // Every commit on the branch
+    #[default]
+    Commits,
+    // Only completed pull requests; commits pushed straight to the branch wait for the next one
+    PullRequests,
+}
+
+// How the checkout follows the remote
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum CheckoutMode {
+    // A local target_branch that origin/<target_branch> is merged into
+    #[default]
+    Branch,
+    // HEAD detached at the remote commit, so there is no local branch to diverge
+    Detached,
+}
+
+// What fetches and reads the checkout
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum GitBackend {
+    #[default]
+    Git,
+    // gix, built in with the gitoxide feature; checkouts and merges still go through git
+    Gitoxide,
+}
+
+// How the local commit is compared with the remote one
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum Compare {
+    // Any difference is new changes to pull
+    #[default]
+    Exact,
+    // Only a remote commit missing from the local history is; a checkout ahead of the remote,
+    // with a hotfix committed on it, is left as it is
+    Ancestry,
+}
+
+// How a remote commit that rewrote the branch's history is synced
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum ForcePush {
+    // Like any other commit, merged into the local branch
+    #[default]
+    Merge,
+    // The local branch is reset to the remote and cleaned, dropping the replaced commits
+    Reset,
+    // Nothing is synced until an operator approves it, then the branch is reset
+    Hold,
+}
+
+impl AppConfig {
+    // Looks up the repository's default branch in Azure DevOps
+    async fn default_branch(&self, client: &Client) -> Result<String, Box<dyn std::error::Error>> {
+        azure::default_branch(
+            client,
+            &self.organization,
+            &self.project,
+            self.api_repository(),
+            &self.pat,
+            &self.api_version,
+        )
+        .await
+    }
+
+    // The check intervals as they are now, which interval_script can override. The [[schedule]]
+    // entry covering the current local time replaces check_interval_seconds.
+    fn intervals(&self) -> polling::Intervals {
+        let now = Local::now();
+        polling::Intervals {
+            check: polling::scheduled(&self.schedule, now.weekday(), now.time())
+                .unwrap_or(self.check_interval_seconds),
+            active: self.active_interval,
+            active_window: self.active_window,
+            quiet: self.quiet_interval,
+            quiet_after: self.quiet_after,
+        }
+    }
+
+    // Fails unless the repository is named or identified, when a rule or interval_script
+    // doesn't compile, or when git_backend needs a feature the binary wasn't built with
+    fn check_repository(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.repository.is_empty() && self.repository_id.is_none() {
+            return Err("set repository or repository_id".into());
+        }
+        if self.git_backend == GitBackend::Gitoxide && !cfg!(feature = "gitoxide") {
+            return Err(
+                "git_backend = \"gitoxide\" needs a binary built with the gitoxide feature".into(),
+            );
+        }
+        azure::check_server_url(&self.server_url)?;
+        Ok(rules::check(&self.rules, self.interval_script.as_deref())?)
+    }
+
+    // The repository as API URLs name it: its ID once known, which stays valid across renames
+    fn api_repository(&self) -> &str {
+        self.repository_id.as_deref().unwrap_or(&self.repository)
+    }
+
+    // Looks the repository up by its ID, or by its name until the ID is known, and keeps both the
+    // ID and its current project and name, which git needs for the clone URL. Returns where it
+    // was before if it has been renamed or moved since.
+    async fn resolve_repository(
+        &mut self,
+        client: &Client,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let remote = match &self.repository_id {
+            Some(id) => {
+                azure::repository_by_id(
+                    client,
+                    &self.organization,
+                    id,
+                    &self.pat,
+                    &self.api_version,
+                )
+                .await?
+            }
+            None => {
+                azure::repository(
+                    client,
+                    &self.organization,
+                    &self.project,
+                    &self.repository,
+                    &self.pat,
+                    &self.api_version,
+                )
+                .await?
+            }
+        };
+        let moved = !self.repository.is_empty()
+            && (!remote.name.eq_ignore_ascii_case(&self.repository)
+                || !remote.project.name.eq_ignore_ascii_case(&self.project));
+        let previous = self.location();
+        self.project = remote.project.name;
+        self.repository = remote.name;
+        self.repository_id = Some(remote.id);
+        if !moved {
+            return Ok(None);
+        }
+        warn!(
+            "Repository {} is {} on the remote now, following it.",
+            previous,
+            self.location()
+        );
+        Ok(Some(previous))
+    }
+
+    // Where the repository is in the organization, as "project/repository"
+    fn location(&self) -> String {
+        format!("{}/{}", self.project, self.repository)
+    }
+
+    // How names are resolved and which address family is used, from ip_version and dns_servers
+    fn resolution(&self) -> Result<network::Resolution, Box<dyn std::error::Error>> {
+        network::Resolution::new(self.ip_version, &self.dns_servers)
+    }
+
+    // The client_certificate for the API client, if one is configured
+    fn client_identity(&self) -> Result<Option<reqwest::Identity>, Box<dyn std::error::Error>> {
+        self.client_certificate
+            .as_ref()
+            .map(|certificate| certificate.identity())
+            .transpose()
+    }
+
+    // Identifies the synced repository for notifications and alerts
+    fn repo_ref(&self) -> notify::RepoRef<'_> {
+        notify::RepoRef {
+            organization: &self.organization,
+            project: &self.project,
+            repository: &self.repository,
+            branch: &self.target_branch,
+            pat: &self.pat,
+            labels: &self.agent_labels,
+        }
+    }
+}
+
+impl schema::Documented for AppConfig {
+    const SECTION: &'static str = "";
+    const ABOUT: &'static str = "The repository to sync and how the agent runs.";
+    const FIELDS: &'static [schema::Field] = &[
+        schema::required(
+            "repo_path",
+            "string",
+            r#""C:\\deploy\\repo""#,
+            "Local git repository kept in sync",
+        ),
+        schema::required("organization", "string", r#""contoso""#, "Azure DevOps organization"),
+        schema::required("project", "string", r#""platform""#, "Azure DevOps project"),
+        schema::optional(
+            "repository",
+            "string",
+            r#""deploy-config""#,
+            "Azure DevOps repository name; required unless repository_id is set",
+        ),
+        schema::optional(
+            "repository_id",
+            "string",
+            r#""3f2a1c9e-5b7d-4e8f-9a6b-0c1d2e3f4a5b""#,
+            "Repository GUID, as `list-repos` prints it; the name is looked up from it at startup",
+        ),
+        schema::required(
+            "target_branch",
+            "string",
+            r#""main""#,
+            "Remote branch to follow, or \"auto\" for the repository's default branch",
+        ),
+        schema::optional(
+            "pat",
+            "string",
+            r#""<TOKEN GOES HERE>""#,
+            "Personal Access Token, may be an \"enc:\" value; ignored when pat_env or pat_file is set",
+        ),
+        schema::optional(
+            "pat_env",
+            "string",
+            r#""AZURE_DEVOPS_PAT""#,
+            "Environment variable holding the PAT",
+        ),
+        schema::optional(
+            "pat_file",
+            "string",
+            r#""C:\\secrets\\pat.txt""#,
+            "File holding the PAT, takes precedence over pat_env",
+        ),
+        schema::required(
+            "check_interval_seconds",
+            "integer or duration",
+            "20",
+            "Seconds between checks of the remote, or a duration such as \"30s\", \"5m\" or \"1h\"; may be written check_interval",
+        ),
+        schema::optional(
+            "active_interval",
+            "integer or duration",
+            r#""15s""#,
+            "Interval between checks for active_window after a pulled change",
+        ),
+        schema::defaulted(
+            "active_window",
+            "integer or duration",
+            r#""1h""#,
+            "How long after a pulled change active_interval is used",
+        ),
+        schema::optional(
+            "quiet_interval",
+            "integer or duration",
+            r#""15m""#,
+            "Slowest interval, which checks slow down to after active_window while no change is pulled",
+        ),
+        schema::defaulted(
+            "quiet_after",
+            "integer or duration",
+            r#""8h""#,
+            "How long after a pulled change quiet_interval is reached",
+        ),
+        schema::optional(
+            "interval_script",
+            "string",
+            r#""if hour >= 22 || hour < 6 { 600 } else { default_interval }""#,
+            "Rhai expression computing the seconds until the next check, compiled at startup",
+        ),
+        schema::optional(
+            "secrets_identity",
+            "string",
+            r#""C:\\Users\\me\\.age\\key.txt""#,
+            "age identity used to decrypt \"enc:\" values, a file or \"keyring:<name>\" (SYNC_AGE_IDENTITY overrides it)",
+        ),
+        schema::optional(
+            "control_listen",
+            "string",
+            r#""127.0.0.1:7878""#,
+            "Local address of the control endpoint (reload-credentials, approve-force-push, approve-terraform, /metrics)",
+        ),
+        schema::defaulted(
+            "history_file",
+            "string",
+            r#""sync_history.jsonl""#,
+            "JSON lines file every sync attempt is appended to",
+        ),
+        schema::optional(
+            "audit_file",
+            "string",
+            r#""sync_audit.jsonl""#,
+            "Append-only, hash-chained log of every sync attempt, checked with `audit verify`",
+        ),
+        schema::optional(
+            "audit_key_file",
+            "string",
+            r#""C:\\secrets\\audit.key""#,
+            "File holding the key audit entries are signed with (HMAC-SHA256), plain or enc: encrypted",
+        ),
+        schema::defaulted(
+            "audit_allow_broken_chain",
+            "boolean",
+            "false",
+            "Keep appending after the last entry of an audit_file that fails verification, instead of refusing to start",
+        ),
+        schema::defaulted(
+            "link_work_items",
+            "boolean",
+            "true",
+            "Resolve work items referenced by pulled commits",
+        ),
+        schema::optional(
+            "git_path",
+            "string",
+            r#""C:\\Tools\\PortableGit\\cmd\\git.exe""#,
+            "git executable to use instead of the one on the PATH",
+        ),
+        schema::defaulted(
+            "git_backend",
+            "\"git\" or \"gitoxide\"",
+            r#""git""#,
+            "\"gitoxide\" fetches and reads the checkout in-process with gix (needs the gitoxide build feature); checkouts and merges still run git",
+        ),
+        schema::defaulted(
+            "add_safe_directory",
+            "boolean",
+            "false",
+            "Add repo_path to git's safe.directory if it is owned by another user",
+        ),
+        schema::defaulted(
+            "wait_for_first_commit",
+            "boolean",
+            "false",
+            "Keep polling quietly while the target branch is missing or empty",
+        ),
+        schema::optional(
+            "fallback_branch",
+            "string",
+            r#""default""#,
+            "Branch to follow if target_branch is deleted or renamed (\"default\" for the default branch)",
+        ),
+        schema::defaulted(
+            "reset_on_conflict",
+            "boolean",
+            "false",
+            "Discard local changes and commits when they block the merge, then remove untracked files",
+        ),
+        schema::defaulted(
+            "clean_untracked",
+            "boolean",
+            "false",
+            "Remove untracked files after every sync so the checkout mirrors the repo exactly",
+        ),
+        schema::defaulted(
+            "clean_ignored",
+            "boolean",
+            "false",
+            "Also remove gitignored files when the tree is cleaned",
+        ),
+        schema::optional(
+            "preserve_paths",
+            "array of strings",
+            r#"["config/local.json", "data/"]"#,
+            "Untracked files and directories that cleaning never removes",
+        ),
+        schema::optional(
+            "verify",
+            "\"status\" or \"hashes\"",
+            r#""status""#,
+            "Check the working tree matches the pulled commit after each sync; \"hashes\" also rehashes every file",
+        ),
+        schema::optional(
+            "agent_labels",
+            "array of strings",
+            r#"["edge", "store-042"]"#,
+            "Groups this agent belongs to, matched by the manifest and shown in metrics and notifications",
+        ),
+        schema::optional(
+            "manifest_file",
+            "string",
+            r#"".sync/agents.toml""#,
+            "Manifest on target_branch assigning hosts to a branch or pinned commit",
+        ),
+        schema::optional(
+            "halt_file",
+            "string",
+            r#"".sync/halt""#,
+            "File that, once pushed to the target branch, stops agents from pulling until it is removed",
+        ),
+        schema::defaulted(
+            "check_connectivity",
+            "boolean",
+            "false",
+            "Probe the Azure DevOps server before each check; while it's unreachable, wait quietly and check as soon as it's back",
+        ),
+        schema::optional(
+            "concurrency_group",
+            "string",
+            r#""website""#,
+            "Repos naming the same group take turns to pull and run post-sync actions, e.g. two repos deploying one service",
+        ),
+        schema::defaulted(
+            "after",
+            "array of strings",
+            "[]",
+            "Repositories (by name) that must be checked and in sync before this one syncs, e.g. a shared library before the app",
+        ),
+        schema::optional(
+            "batch",
+            "string",
+            r#""release""#,
+            "Repos in the same batch fetch first and only check out and run hooks once every one of them fetched",
+        ),
+        schema::defaulted(
+            "ignore_authors",
+            "array of strings",
+            "[]",
+            "New commits only by these authors (name or email, * and ? wildcards, any case) are not pulled, e.g. a CI bot",
+        ),
+        schema::defaulted(
+            "ignore_paths",
+            "list of globs",
+            "[]",
+            "New commits touching only these paths are not pulled, e.g. [\"VERSION\", \"docs/**\"]",
+        ),
+        schema::optional(
+            "log_file",
+            "string",
+            r#""website.log""#,
+            "File this repo's log records are also written to, appended to across runs",
+        ),
+        schema::optional(
+            "state_file",
+            "string",
+            r#""/var/lib/devops-sync/website.json""#,
+            "JSON file replaced after every check with the commit, remote commit, branch, status and times, for scripts on the machine",
+        ),
+        schema::optional(
+            "changed_files_file",
+            "string",
+            r#""/var/lib/devops-sync/website-changes.json""#,
+            "File replaced after every sync with the files it changed; a .json path adds the commits and whether each file was added, modified or deleted",
+        ),
+        schema::defaulted(
+            "log_format",
+            "\"text\" or \"json\"",
+            r#""text""#,
+            "How app.log and log_file lines are written; json gives one object per line with repo, branch and cycle fields",
+        ),
+        schema::defaulted(
+            "ip_version",
+            "\"any\", \"ipv4\" or \"ipv6\"",
+            r#""any""#,
+            "Connect to the API, webhooks and git remote over this address family only, e.g. to avoid a broken IPv6 path",
+        ),
+        schema::optional(
+            "dns_servers",
+            "array of strings",
+            r#"["10.0.0.53", "10.0.1.53:53"]"#,
+            "DNS servers, asked in order, to resolve names with instead of the system resolver",
+        ),
+        schema::defaulted(
+            "sync_delay_seconds",
+            "integer",
+            "0",
+            "Seconds a new remote commit waits before it is pulled, e.g. 0 on canaries and 3600 on production",
+        ),
+        schema::defaulted(
+            "settle_seconds",
+            "integer",
+            "0",
+            "Seconds the remote branch must stay at one commit before it is pulled, so a burst of pushes syncs once",
+        ),
+        schema::defaulted(
+            "wait_for_build",
+            "boolean",
+            "false",
+            "Only pull a commit once its Azure Pipelines builds have completed successfully",
+        ),
+        schema::defaulted(
+            "build_definitions",
+            "list of integers",
+            "[]",
+            "Pipeline ids whose builds must pass with wait_for_build; empty means every pipeline that built the commit",
+        ),
+        schema::defaulted(
+            "mode",
+            "\"sync\" or \"observe\"",
+            r#""sync""#,
+            "\"observe\" never touches the checkout, it only reports being behind or drifted",
+        ),
+        schema::defaulted(
+            "sync_on",
+            "\"commits\" or \"pull_requests\"",
+            r#""commits""#,
+            "\"pull_requests\" syncs to the latest pull request completed into the branch and records its title, id and reviewers",
+        ),
+        schema::defaulted(
+            "checkout_mode",
+            "\"branch\" or \"detached\"",
+            r#""branch""#,
+            "\"detached\" checks out the exact remote commit each sync instead of merging into a local branch, so local commits can't diverge",
+        ),
+        schema::defaulted(
+            "compare",
+            "\"exact\" or \"ancestry\"",
+            r#""exact""#,
+            "\"ancestry\" only pulls when the remote commit is missing from the local history, leaving a checkout that is ahead of the remote alone",
+        ),
+        schema::defaulted(
+            "force_push",
+            "\"merge\", \"reset\" or \"hold\"",
+            r#""merge""#,
+            "What a force push to the branch leads to, always logged and reported as force_pushed: merged as usual, a hard reset to the remote, or holding until `approve-force-push`",
+        ),
+        schema::defaulted(
+            "update_remote_url",
+            "bool",
+            "false",
+            "Point the checkout's origin remote at the repository's new URL when it is renamed or moved on the remote",
+        ),
+        schema::optional(
+            "drift_check_seconds",
+            "integer",
+            "300",
+            "Seconds between checks for local edits to the checkout, repaired when reset_on_conflict is set",
+        ),
+        schema::defaulted(
+            "server_url",
+            "string",
+            r#""https://dev.azure.com""#,
+            "Azure DevOps Server URL for on-premises installs, e.g. \"https://devops.contoso.com/tfs\" (organization is then the collection)",
+        ),
+        schema::defaulted(
+            "api_version",
+            "string",
+            r#""7.0""#,
+            "Azure DevOps REST API version sent with every request",
+        ),
+        schema::defaulted(
+            "user_agent",
+            "string",
+            concat!("\"DevOps_Repository_Sync/", env!("CARGO_PKG_VERSION"), "\""),
+            "User-Agent sent to Azure DevOps by API requests and git",
+        ),
+        schema::defaulted(
+            "request_timeout_seconds",
+            "integer",
+            "60",
+            "Longest an HTTP request may take (artifact and package downloads: while no data arrives)",
+        ),
+        schema::defaulted(
+            "state_dir",
+            "string",
+            r#""state""#,
+            "Directory for files kept between syncs, such as Terraform plans waiting for approve-terraform; top level only",
+        ),
+        schema::defaulted(
+            "check_for_updates",
+            "boolean",
+            "false",
+            "Log when a newer release is published (checked at startup and daily)",
+        ),
+        schema::defaulted(
+            "announce",
+            "boolean",
+            "false",
+            "Announce the agent on the LAN over mDNS so `discover` can find it",
+        ),
+        schema::optional(
+            "update_feed_url",
+            "string",
+            r#""https://mirror.example.com/releases/latest.json""#,
+            "Release feed to check instead of GitHub",
+        ),
+    ];
+}
+
+// Writes a starting config.toml:
+// `init (--example | --from-project <org>/<project> [--base-dir <dir>] [--pat-env <var>]) [--output <path>|-] [--force]`
+async fn init_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("init", args)?;
+    let example = args.has("--example");
+    let from_project = args.value("--from-project");
+    let base_dir = args.value("--base-dir").unwrap_or(".");
+    let pat_env = args.value("--pat-env").unwrap_or("AZURE_DEVOPS_PAT");
+    let output = args.value("--output").unwrap_or("config.toml");
+    let force = args.has("--force");
+
+    let config = match (example, from_project) {
+        (true, None) => schema::example_config(&config_sections()),
+        (false, Some(from_project)) => {
+            let (organization, project) = from_project
+                .split_once('/')
+                .filter(|(organization, project)| !organization.is_empty() && !project.is_empty())
+                .ok_or("--from-project expects <org>/<project>")?;
+            project_config(organization, project, base_dir, pat_env).await?
+        }
+        _ => return Err("Usage: init (--example | --from-project <org>/<project> [--base-dir <dir>] [--pat-env <var>]) [--output <path>|-] [--force]".into()),
+    };
+
+    if output == "-" {
+        print!("{}", config);
+        return Ok(());
+    }
+    if Path::new(&output).exists() && !force {
+        return Err(format!("{} already exists, use --force to overwrite it", output).into());
+    }
+    fs::write(output, config)?;
+    println!("Wrote {}", output);
+    Ok(())
+}
+
+// A config with one [[repos]] entry per repository of the project, each cloned under base_dir
+// and following its current default branch. The PAT is read from pat_env, both now and when
+// the config is used, so it never ends up in the file.
+async fn project_config(
+    organization: &str,
+    project: &str,
+    base_dir: &str,
+    pat_env: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let pat = std::env::var(pat_env)
+        .map_err(|_| format!("Set {} to a PAT that can read the project", pat_env))?;
+    let client = azure::client(
+        azure::DEFAULT_USER_AGENT,
+        &network::Resolution::default(),
+        None,
+        Duration::from_secs(azure::DEFAULT_REQUEST_TIMEOUT),
+    )?;
+    let repositories = azure::list_repositories(
+        &client,
+        organization,
+        project,
+        &pat,
+        azure::DEFAULT_API_VERSION,
+    )
+    .await
+    .map_err(|e| {
+        format!(
+            "Failed to list repositories in {}/{}: {}",
+            organization, project, e
+        )
+    })?;
+    if repositories.is_empty() {
+        return Err(format!("{}/{} has no repositories", organization, project).into());
+    }
+
+    let quote = |value: &str| toml::Value::String(value.to_string()).to_string();
+    let mut config = format!(
+        "# Mirrors every repository of {}/{}, generated by `init --from-project`.\n\
+         # Keys set here apply to every [[repos]] entry unless the entry sets them too.\n\
+         organization = {}\nproject = {}\npat_env = {}\ncheck_interval_seconds = 60\n",
+        organization,
+        project,
+        quote(organization),
+        quote(project),
+        quote(pat_env)
+    );
+    for repo in &repositories {
+        let path = Path::new(base_dir).join(&repo.name);
+        config.push_str(&format!(
+            "\n[[repos]]\nrepo_path = {}\nrepository = {}\nrepository_id = {}\n",
+            quote(&path.to_string_lossy()),
+            quote(&repo.name),
+            quote(&repo.id)
+        ));
+        match &repo.default_branch {
+            Some(branch) => config.push_str(&format!("target_branch = {}\n", quote(branch))),
+            // Nothing pushed yet, so there's no branch to pick: follow whichever becomes the default
+            None => config.push_str("target_branch = \"auto\"\nwait_for_first_commit = true\n"),
+        }
+        config.push_str(&format!(
+            "history_file = {}\n",
+            quote(&format!("{}_history.jsonl", repo.name))
+        ));
+    }
+    Ok(config)
+}
+
+// The client every Azure DevOps request goes through, set up from the top-level user_agent,
+// ip_version, dns_servers and client_certificate. The top-level server_url decides where those
+// requests go.
+fn shared_client(config: &AppConfig) -> Result<Client, Box<dyn std::error::Error>> {
+    azure::set_base_url(config.server_url.clone());
+    Ok(azure::client(
+        &config.user_agent,
+        &config.resolution()?,
+        config.client_identity()?,
+        Duration::from_secs(config.request_timeout_seconds),
+    )?)
+}
+
+// The client commands talking to a running agent's control endpoint use. The endpoint is a
+// local address, so names resolve the system's way.
+fn control_client(config: &AppConfig) -> Result<Client, Box<dyn std::error::Error>> {
+    Ok(azure::client(
+        &config.user_agent,
+        &network::Resolution::default(),
+        None,
+        Duration::from_secs(config.request_timeout_seconds),
+    )?)
+}
+
+// Decrypts any enc: control tokens
+fn resolve_control_tokens(config: &mut AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let identity = secrets::identity_path(config.secrets_identity.as_deref());
+    for token in &mut config.control_tokens {
+        token.resolve(identity.as_deref())?;
+    }
+    Ok(())
+}
+
+// The audit_key_file contents, if one is configured
+fn audit_key(config: &AppConfig) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let identity = secrets::identity_path(config.secrets_identity.as_deref());
+    config
+        .audit_key_file
+        .as_deref()
+        .map(|path| audit::read_key(path, identity.as_deref()))
+        .transpose()
+}
+
+// Checks audit logs for tampering: `audit verify [--file <path> [--key-file <path>]]`, by
+// default every audit_file in the config
+fn audit_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.first().map(String::as_str) != Some("verify") {
+        return Err(
+            "Usage: DevOps_Repository_Sync audit verify [--file <path>] [--key-file <path>]".into(),
+        );
+    }
+    let args = cli::parse("audit", &args[1..])?;
+    let file = args.value("--file").map(str::to_string);
+    let key_file = args.value("--key-file");
+
+    let logs: Vec<(String, Option<Vec<u8>>)> = match file {
+        Some(file) => {
+            let identity = secrets::identity_path(None);
+            let key = key_file
+                .map(|path| audit::read_key(path, identity.as_deref()))
+                .transpose()?;
+            vec![(file, key)]
+        }
+        None => {
+            let configs = parse_configs(Path::new("config.toml"))?;
+            let mut logs = Vec::new();
+            for config in &configs {
+                if let Some(path) = &config.audit_file {
+                    if !logs.iter().any(|(listed, _)| listed == path) {
+                        logs.push((path.clone(), audit_key(config)?));
+                    }
+                }
+            }
+            if logs.is_empty() {
+                return Err("No audit_file is configured in config.toml; pass --file".into());
+            }
+            logs
+        }
+    };
+
+    let mut tampered = 0;
+    for (path, key) in &logs {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read audit log '{}': {}", path, e))?;
+        match audit::verify(&text, key.as_deref()) {
+            Ok(verified) => println!(
+                "{}: OK, {} entries, {}, last hash {}",
+                path,
+                verified.entries,
+                if key.is_some() {
+                    "all signatures valid".to_string()
+                } else {
+                    format!("{} signed (not checked without a key)", verified.signed)
+                },
+                verified.head
+            ),
+            Err(e) => {
+                println!("{}: TAMPERED, {}", path, e);
+                tampered += 1;
+            }
+        }
+    }
+    if tampered > 0 {
+        return Err(format!(
+            "{} of {} audit log(s) failed verification",
+            tampered,
+            logs.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Every history_file config.toml names, for repos, their virtual repos and feeds
+fn history_files() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let config_path = Path::new("config.toml");
+    let mut files: Vec<String> = Vec::new();
+    for config in parse_configs(config_path)? {
+        files.push(config.history_file);
+        files.extend(
+            config
+                .virtual_repos
+                .into_iter()
+                .filter_map(|virtual_repo| virtual_repo.history_file),
+        );
+    }
+    files.extend(
+        parse_feeds(config_path)?
+            .into_iter()
+            .map(|feed| feed.history_file),
+    );
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+// Writes the sync history as CSV or JSON for reporting and change management, oldest first
+fn history_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let usage = "Usage: DevOps_Repository_Sync history export [--format csv|json] [--since <date>|<duration>] [--file <path>] [--output <path>|-]";
+    if args.first().map(String::as_str) != Some("export") {
+        return Err(usage.into());
+    }
+    let args = cli::parse("history", &args[1..])?;
+    let format = args.value("--format").unwrap_or("csv");
+    let since = args.value("--since").map(parse_since).transpose()?;
+    let file = args.value("--file").map(str::to_string);
+    let output = args.value("--output").unwrap_or("-");
+
+    if !["csv", "json"].contains(&format) {
+        return Err(format!("Unknown --format '{}', expected csv or json", format).into());
+    }
+
+    let files = match file {
+        Some(file) => vec![file],
+        None => history_files()?,
+    };
+    let mut read = Vec::new();
+    for path in &files {
+        let (records, unreadable) =
+            history::read(path).map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        if unreadable > 0 {
+            eprintln!(
+                "{}: {} line(s) couldn't be read and were left out",
+                path, unreadable
+            );
+        }
+        read.push((path, records));
+    }
+    let mut exported: Vec<(DateTime<chrono::FixedOffset>, history::Exported<'_>)> = read
+        .iter()
+        .flat_map(|(path, records)| {
+            records.iter().filter_map(|record| {
+                let time = DateTime::parse_from_rfc3339(&record.timestamp).ok()?;
+                since.is_none_or(|since| time >= since).then_some((
+                    time,
+                    history::Exported {
+                        source: path.as_str(),
+                        record,
+                    },
+                ))
+            })
+        })
+        .collect();
+    exported.sort_by_key(|(time, _)| *time);
+    let exported: Vec<history::Exported<'_>> =
+        exported.into_iter().map(|(_, record)| record).collect();
+
+    let text = if format == "csv" {
+        history::to_csv(&exported)
+    } else {
+        serde_json::to_string_pretty(&exported)? + "\n"
+    };
+    if output == "-" {
+        print!("{}", text);
+    } else {
+        fs::write(output, text)?;
+        eprintln!("Wrote {} sync(s) to {}", exported.len(), output);
+    }
+    Ok(())
+}
+
+// The start of an export: a date ("2024-05-01", from local midnight), a time in RFC 3339, or a
+// duration back from now ("30d")
+fn parse_since(value: &str) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+            .map(|midnight| midnight.with_timezone(&Utc))
+            .ok_or_else(|| format!("Invalid --since: {}", value).into());
+    }
+    let seconds = duration::parse(value).map_err(|_| {
+        format!(
+            "Invalid --since '{}', expected a date (2024-05-01), a time or a duration (30d)",
+            value
+        )
+    })?;
+    Ok(Utc::now() - chrono::Duration::seconds(seconds as i64))
+}
+
+// Prints aggregates of the sync history: syncs per day, failure rate, how long commits wait to be
+// synced and the busiest hours
+fn stats_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("stats", args)?;
+    let file = args.value("--file").map(str::to_string);
+    let days = args
+        .value("--days")
+        .map(|value| {
+            value
+                .parse::<i64>()
+                .map_err(|_| format!("Invalid --days: {}", value))
+        })
+        .transpose()?;
+
+    let files = match file {
+        Some(file) => vec![file],
+        None => history_files()?,
+    };
+
+    let since = days.map(|days| Utc::now() - chrono::Duration::days(days));
+    for path in &files {
+        let (mut records, unreadable) = match history::read(path) {
+            Ok(read) => read,
+            Err(e) if files.len() > 1 => {
+                println!("{}: not read, {}", path, e);
+                continue;
+            }
+            Err(e) => return Err(format!("Failed to read '{}': {}", path, e).into()),
+        };
+        if let Some(since) = since {
+            records.retain(|record| {
+                DateTime::parse_from_rfc3339(&record.timestamp).is_ok_and(|time| time >= since)
+            });
+        }
+        println!("{}:", path);
+        for line in stats::summarize(&records) {
+            println!("  {}", line);
+        }
+        if unreadable > 0 {
+            println!(
+                "  ({} line(s) couldn't be read and were left out)",
+                unreadable
+            );
+        }
+    }
+    Ok(())
+}
+
+// Prints the projects, repositories and (with --branches) branches the PAT can see in each
+// configured organization: `list-repos [--project <name>] [--branches]`
+async fn list_repos_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("list-repos", args)?;
+    let project_filter = args.value("--project").map(str::to_string);
+    let branches = args.has("--branches");
+
+    let configs = parse_configs(Path::new("config.toml"))?;
+    let client = shared_client(&configs[0])?;
+    let mut listed: Vec<(String, String)> = Vec::new();
+
+    for config in &configs {
+        // Repos sharing an organization and token only need listing once
+        let pat = load_pat(config)?;
+        let key = (config.organization.to_lowercase(), pat.clone());
+        if listed.contains(&key) {
+            continue;
+        }
+        listed.push(key);
+
+        let organization = &config.organization;
+        let api_version = &config.api_version;
+        let projects = match &project_filter {
+            Some(project) => vec![project.clone()],
+            None => azure::list_projects(&client, organization, &pat, api_version)
+                .await
+                .map_err(|e| format!("Failed to list projects in '{}': {}", organization, e))?,
+        };
+
+        println!("{}", organization);
+        for project in projects {
+            println!("  {}", project);
+            let repositories =
+                match azure::list_repositories(&client, organization, &project, &pat, api_version)
+                    .await
+                {
+                    Ok(repositories) => repositories,
+                    Err(e) => {
+                        println!("    (failed to list repositories: {})", e);
+                        continue;
+                    }
+                };
+            for repo in repositories {
+                match &repo.default_branch {
+                    Some(branch) => println!(
+                        "    {} (default branch: {}, ID {})",
+                        repo.name, branch, repo.id
+                    ),
+                    None => println!("    {} (empty, ID {})", repo.name, repo.id),
+                }
+                if !branches || repo.default_branch.is_none() {
+                    continue;
+                }
+                match azure::list_branches(
+                    &client,
+                    organization,
+                    &project,
+                    &repo.name,
+                    &pat,
+                    api_version,
+                )
+                .await
+                {
+                    Ok(names) => names.iter().for_each(|name| println!("      {}", name)),
+                    Err(e) => println!("      (failed to list branches: {})", e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Every documented config section, in the order config.toml lists them
+fn config_sections() -> Vec<schema::Section> {
+    vec![
+        schema::Section::of::<AppConfig>(),
+        schema::Section::of::<hooks::HookConfig>(),
+        schema::Section::of::<post_sync::ServiceRestart>(),
+        schema::Section::of::<post_sync::ComposeConfig>(),
+        schema::Section::of::<copy::CopyConfig>(),
+        schema::Section::of::<archive::ArchiveConfig>(),
+        schema::Section::of::<image::ImageConfig>(),
+        schema::Section::of::<kubernetes::KubernetesConfig>(),
+        schema::Section::of::<terraform::TerraformConfig>(),
+        schema::Section::of::<monorepo::VirtualRepoConfig>(),
+        schema::Section::of::<pipelines::ArtifactConfig>(),
+        schema::Section::of::<pipelines::TriggerConfig>(),
+        schema::Section::of::<releases::ReleasesConfig>(),
+        schema::Section::of::<feeds::FeedConfig>(),
+        schema::Section::of::<tls::ClientCertificate>(),
+        schema::Section::of::<control::ControlToken>(),
+        schema::Section::of::<webhook::WebhookConfig>(),
+        schema::Section::of::<notify::NotificationConfig>(),
+        schema::Section::of::<alert::AlertConfig>(),
+        schema::Section::of::<change::ChangeRecordConfig>(),
+        schema::Section::of::<jira::JiraConfig>(),
+        schema::Section::of::<deployment::DeploymentStatusConfig>(),
+        schema::Section::of::<deployment::GithubDeploymentConfig>(),
+        schema::Section::of::<plugins::PluginConfig>(),
+        schema::Section::of::<policy::PolicyConfig>(),
+        schema::Section::of::<rules::RuleConfig>(),
+        schema::Section::of::<polling::ScheduleEntry>(),
+    ]
+}
+
+// What the agent is about to do, shown at startup so operators can confirm it. Secrets are redacted.
+fn startup_summary(config: &AppConfig, auto_branch: bool) -> Vec<String> {
+    let branch = if auto_branch {
+        format!("{} (following the default branch)", config.target_branch)
+    } else {
+        config.target_branch.clone()
+    };
+    let mut interval = config.intervals().describe();
+    if !config.schedule.is_empty() {
+        interval = format!(
+            "{} now, following {} [[schedule]] entry(s)",
+            interval,
+            config.schedule.len()
+        );
+    }
+    if config.interval_script.is_some() {
+        interval = format!("scripted (interval_script, {} default)", interval);
+    }
+    let post_sync = &config.post_sync;
+    let count = |n: usize, what: &str| format!("{} {}", n, what);
+
+    let mut lines = vec![
+        format!(
+            "DevOps_Repository_Sync {} ({}, built {})",
+            version::VERSION,
+            env!("BUILD_GIT_COMMIT"),
+            env!("BUILD_DATE")
+        ),
+        format!(
+            "  Repository:   {}/{}/{}{}",
+            config.organization,
+            config.project,
+            config.repository,
+            config
+                .repository_id
+                .as_ref()
+                .map(|id| format!(" ({})", id))
+                .unwrap_or_default()
+        ),
+        format!("  Branch:       {}", branch),
+        format!(
+            "  Mode:         {}",
+            match config.mode {
+                SyncMode::Sync => "sync",
+                SyncMode::Observe => "observe (read-only, nothing is pulled)",
+            }
+        ),
+        format!(
+            "  Sync on:      {}",
+            match config.sync_on {
+                SyncOn::Commits => "every commit",
+                SyncOn::PullRequests => "completed pull requests",
+            }
+        ),
+        format!(
+            "  Checkout:     {}",
+            match config.checkout_mode {
+                CheckoutMode::Branch => "local branch",
+                CheckoutMode::Detached => "detached at the remote commit",
+            }
+        ),
+        format!(
+            "  Compare:      {}",
+            match config.compare {
+                Compare::Exact => "commit ids",
+                Compare::Ancestry => "ancestry, local commits ahead of the remote are kept",
+            }
+        ),
+        format!(
+            "  Force push:   {}",
+            match config.force_push {
+                ForcePush::Merge => "merged",
+                ForcePush::Reset => "reset to the remote",
+                ForcePush::Hold => "held until approved",
+            }
+        ),
+        format!(
+            "  Moves:        {}",
+            match (&config.repository_id, config.update_remote_url) {
+                (None, _) => "not followed, the repository's ID is unknown",
+                (Some(_), false) => "followed by ID",
+                (Some(_), true) => "followed by ID, origin updated",
+            }
+        ),
+        format!("  Local path:   {}", config.repo_path),
+        format!("  Agent:        {}", notify::agent_name(&config.repo_ref())),
+        format!("  Interval:     {}", interval),
+        format!(
+            "  Rollout:      {}",
+            match config.sync_delay_seconds {
+                0 => "immediate".to_string(),
+                delay => format!("{}s after a commit appears", delay),
+            }
+        ),
+        format!("  Provider:     Azure DevOps (API {})", config.api_version),
+        format!(
+            "  Auth:         {} ({})",
+            secrets::pat_source(config.pat_env.as_deref(), config.pat_file.as_deref()),
+            secrets::redact(&config.pat)
+        ),
+        format!("  git:          {}", git::describe()),
+        format!(
+            "  Client cert:  {}",
+            config
+                .client_certificate
+                .as_ref()
+                .map(|certificate| certificate.describe())
+                .unwrap_or_else(|| "none".to_string())
+        ),
+        format!(
+            "  Post-sync:    {}, {}, {}, {}, compose {}, kubernetes {}, terraform {}, archive {}",
+            count(post_sync.hooks.len(), "hook(s)"),
+            count(post_sync.copy.len(), "copy(ies)"),
+            count(post_sync.images.len(), "image(s)"),
+            count(post_sync.restart.len(), "restart(s)"),
+            if post_sync.compose.is_some() {
+                "on"
+            } else {
+                "off"
+            },
+            match &post_sync.kubernetes {
+                Some(cluster) => cluster.describe(),
+                None => "off".to_string(),
+            },
+            match &post_sync.terraform {
+                Some(module) => module.describe(),
+                None => "off".to_string(),
+            },
+            match &post_sync.archive {
+                Some(archive) => format!("to {}", archive.directory),
+                None => "off".to_string(),
+            }
+        ),
+        format!(
+            "  Reporting:    {}, {}, {}, history in {}",
+            count(config.notifications.len(), "notifier(s)"),
+            count(config.alerts.len(), "alert(s)"),
+            count(config.change_records.len(), "change record system(s)"),
+            config.history_file
+        ),
+        format!(
+            "  Gates:        {}, {}, {}",
+            count(config.plugins.len(), "plugin(s)"),
+            count(config.policies.len(), "policy(ies)"),
+            count(config.rules.len(), "rule(s)")
+        ),
+    ];
+    if !config.after.is_empty() {
+        lines.push(format!("  After:        {}", config.after.join(", ")));
+    }
+    if let Some(batch) = &config.batch {
+        lines.push(format!("  Batch:        {}", batch));
+    }
+    if !config.ignore_authors.is_empty() || !config.ignore_paths.is_empty() {
+        lines.push(format!(
+            "  Ignore:       {}, {}",
+            count(config.ignore_authors.len(), "author(s)"),
+            count(config.ignore_paths.len(), "path(s)")
+        ));
+    }
+    if let Some(log_file) = &config.log_file {
+        lines.push(format!("  Log file:     {}", log_file));
+    }
+    if let Some(state_file) = &config.state_file {
+        lines.push(format!("  State file:   {}", state_file));
+    }
+    if let Some(changed_files_file) = &config.changed_files_file {
+        lines.push(format!("  Changes file: {}", changed_files_file));
+    }
+    if let Some(group) = &config.concurrency_group {
+        lines.push(format!("  Group:        {}", group));
+    }
+    if !config.virtual_repos.is_empty() {
+        let names: Vec<&str> = config
+            .virtual_repos
+            .iter()
+            .map(|virtual_repo| virtual_repo.name.as_str())
+            .collect();
+        lines.push(format!("  Virtual:      {}", names.join(", ")));
+    }
+    if let Some(artifact) = &config.artifact {
+        lines.push(format!(
+            "  Artifact:     '{}' into {}",
+            artifact.name, artifact.directory
+        ));
+    }
+    if let Some(trigger) = &config.trigger {
+        let kind = match trigger.kind {
+            pipelines::TriggerKind::Pipeline => "pipeline",
+            pipelines::TriggerKind::Release => "release definition",
+        };
+        lines.push(format!("  Trigger:      {} {}", kind, trigger.definition));
+    }
+    if let Some(jira) = &config.jira {
+        lines.push(format!("  Jira:         {}", jira.describe()));
+    }
+    if let Some(status) = &config.deployment_status {
+        lines.push(format!(
+            "  Status:       commits marked {}/{} in Azure DevOps{}",
+            status.genre,
+            status.environment(),
+            if status.failures {
+                ", failures included"
+            } else {
+                ""
+            }
+        ));
+    }
+    if let Some(github) = &config.github_deployment {
+        lines.push(format!(
+            "  GitHub:       deployments to '{}' in {}",
+            github.environment(),
+            github.repository
+        ));
+    }
+    if let Some(releases) = &config.releases {
+        lines.push(format!(
+            "  Releases:     last {} in {}, current at {}",
+            releases.keep,
+            releases.directory,
+            releases.link().display()
+        ));
+    }
+    if config.wait_for_build {
+        lines.push(if config.build_definitions.is_empty() {
+            "  Build gate:   every pipeline".to_string()
+        } else {
+            format!(
+                "  Build gate:   {}",
+                count(config.build_definitions.len(), "pipeline(s)")
+            )
+        });
+    }
+    if let Some(listen) = &config.control_listen {
+        lines.push(format!(
+            "  Control:      {} ({})",
+            listen,
+            if config.control_tokens.is_empty() {
+                "open, no control_tokens".to_string()
+            } else {
+                count(config.control_tokens.len(), "token(s)")
+            }
+        ));
+    }
+    if let Some(webhook) = &config.webhook {
+        lines.push(format!("  Webhook:      {}", webhook.url));
+    }
+    if config.announce {
+        lines.push(format!("  Announce:     mDNS as '{}'", notify::host_name()));
+    }
+    lines
+}
+
+// This agent's entry in the manifest as of the commit, None when there's no entry for it or
+// no manifest at all
+async fn read_assignment(
+    client: &Client,
+    config: &AppConfig,
+    path: &str,
+    commit: &str,
+) -> Result<Option<manifest::Assignment>, Box<dyn std::error::Error>> {
+    match azure::file_content(
+        client,
+        &config.repo_ref(),
+        path,
+        commit,
+        &config.api_version,
+    )
+    .await?
+    {
+        Some(text) => manifest::assignment(&text, &notify::host_name(), &config.agent_labels),
+        None => Ok(None),
+    }
+}
+
+// Log line for a new assignment from the manifest
+fn describe_assignment(path: &str, assignment: Option<&manifest::Assignment>) -> String {
+    match assignment {
+        None => format!(
+            "{} has no entry for this host, following the configured branch.",
+            path
+        ),
+        Some(assignment) => format!(
+            "{} assigns this host to {}{}.",
+            path,
+            assignment.branch.as_deref().map_or(
+                "the configured branch".to_string(),
+                |branch| format!("branch '{}'", branch)
+            ),
+            assignment
+                .commit
+                .as_deref()
+                .map_or(String::new(), |commit| format!(", pinned at {}", commit))
+        ),
+    }
+}
+
+// Whether the remote commit carries the halt file, and why, taken from the file's first line
+async fn halt_reason(
+    client: &Client,
+    config: &AppConfig,
+    commit: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(path) = &config.halt_file else {
+        return Ok(None);
+    };
+    let content = azure::file_content(
+        client,
+        &config.repo_ref(),
+        path,
+        commit,
+        &config.api_version,
+    )
+    .await?;
+    Ok(content.map(|content| {
+        content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{} is present", path))
+    }))
+}
+
+// The branch tip the remote commit replaced, if it rewrote the branch instead of moving it
+// forward. The previous tip is where origin/<target_branch> was last fetched to.
+async fn force_pushed_from(
+    client: &Client,
+    config: &AppConfig,
+    commit: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "gitoxide")]
+    let previous = match config.git_backend {
+        GitBackend::Gitoxide => {
+            gitoxide::remote_tip(&config.repo_path, &config.target_branch).await
+        }
+        GitBackend::Git => git::remote_tip(&config.repo_path, &config.target_branch).await,
+    };
+    #[cfg(not(feature = "gitoxide"))]
+    let previous = git::remote_tip(&config.repo_path, &config.target_branch).await;
+    let Some(previous) = previous else {
+        return Ok(None);
+    };
+    if previous == commit {
+        return Ok(None);
+    }
+    let rewritten = azure::is_rewrite(
+        client,
+        &config.repo_ref(),
+        &previous,
+        commit,
+        &config.api_version,
+    )
+    .await?;
+    Ok(rewritten.then_some(previous))
+}
+
+// How much longer the remote commit has to wait before it may be pulled, if at all. The wait
+// starts when this agent first sees the commit, and starts over for every newer commit, so the
+// commit that gets pulled is always one that has been out for the whole delay.
+fn delay_remaining(
+    delayed: &mut Option<(String, Instant)>,
+    commit: &str,
+    delay_seconds: u64,
+) -> Option<Duration> {
+    if delay_seconds == 0 {
+        return None;
+    }
+    let first_seen = match delayed {
+        Some((seen, first_seen)) if seen == commit => *first_seen,
+        _ => {
+            info!(
+                "New remote commit {}, syncing it in {}s unless it is replaced (sync_delay_seconds).",
+                commit, delay_seconds
+            );
+            let now = Instant::now();
+            *delayed = Some((commit.to_string(), now));
+            now
+        }
+    };
+    Duration::from_secs(delay_seconds)
+        .checked_sub(first_seen.elapsed())
+        .filter(|remaining| !remaining.is_zero())
+}
+
+// How much longer the remote branch has to stay at the commit before it may be pulled, if at
+// all. Every newer commit starts the wait over, so a burst of pushes is pulled once, after the
+// last of them.
+fn settle_remaining(
+    settling: &mut Option<(String, Instant)>,
+    commit: &str,
+    settle_seconds: u64,
+) -> Option<Duration> {
+    if settle_seconds == 0 {
+        return None;
+    }
+    let since = match settling {
+        Some((head, since)) if head == commit => *since,
+        _ => {
+            info!(
+                "Remote moved to {}, waiting for it to stay there {}s before syncing (settle_seconds).",
+                commit, settle_seconds
+            );
+            let now = Instant::now();
+            *settling = Some((commit.to_string(), now));
+            now
+        }
+    };
+    Duration::from_secs(settle_seconds)
+        .checked_sub(since.elapsed())
+        .filter(|remaining| !remaining.is_zero())
+}
+
+// target_branch value that follows the repository's default branch
+const AUTO_BRANCH: &str = "auto";
+
+fn default_active_window() -> u64 {
+    3600
+}
+
+fn default_quiet_after() -> u64 {
+    8 * 3600
+}
+
+// How often an "auto" target branch, and the name of a repository known by its ID, are looked up
+// again
+const REMOTE_REFRESH: Duration = Duration::from_secs(3600);
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_history_file() -> String {
+    "sync_history.jsonl".to_string()
+}
+
+fn default_api_version() -> String {
+    azure::DEFAULT_API_VERSION.to_string()
+}
+
+fn default_user_agent() -> String {
+    azure::DEFAULT_USER_AGENT.to_string()
+}
+
+fn default_request_timeout() -> u64 {
+    azure::DEFAULT_REQUEST_TIMEOUT
+}
+
+fn default_state_dir() -> String {
+    state::DEFAULT_DIR.to_string()
+}
+
+fn default_server_url() -> String {
+    azure::DEFAULT_SERVER_URL.to_string()
+}
+
+// Grabs API response and deserializes it into the struct
+#[derive(Deserialize)]
+struct ApiResponse {
+    value: Vec<GitRef>,
+}
+
+// A ref and the commit it points at, as the refs API lists it
+#[derive(Deserialize)]
+struct GitRef {
+    name: String,
+    #[serde(rename = "objectId")]
+    object_id: String,
+}
+
+// Looks for local changes to the checkout since it was synced to commit. With reset_on_conflict
+// set, the target branch is checked out and reset back to the commit, and the tree cleaned.
+async fn check_drift(
+    config: &AppConfig,
+    commit: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let verification = config.verify.unwrap_or(verify::Verification::Status);
+    let differences = verify::differences(
+        &config.repo_path,
+        commit,
+        verification,
+        &config.preserve_paths,
+    )
+    .await?;
+    if differences.is_empty() {
+        return Ok(differences);
+    }
+
+    for difference in &differences {
+        warn!("Local change since the last sync: {}", difference);
+    }
+    if config.reset_on_conflict && config.mode == SyncMode::Sync {
+        match config.checkout_mode {
+            CheckoutMode::Branch => {
+                git::checkout_force(&config.repo_path, &config.target_branch).await?;
+                git::reset_hard(&config.repo_path, commit).await?;
+            }
+            CheckoutMode::Detached => {
+                git::checkout_detached(&config.repo_path, commit, true).await?;
+            }
+        }
+        git::clean(
+            &config.repo_path,
+            config.clean_ignored,
+            &config.preserve_paths,
+        )
+        .await?;
+        warn!("Reset the checkout back to {}.", commit);
+    }
+    Ok(differences)
+}
+
+// Compares the working tree with the commit just pulled, returning what's wrong if anything
+async fn verify_checkout(
+    config: &AppConfig,
+    commit: &str,
+    verification: verify::Verification,
+) -> Option<String> {
+    match verify::differences(
+        &config.repo_path,
+        commit,
+        verification,
+        &config.preserve_paths,
+    )
+    .await
+    {
+        Ok(found) if found.is_empty() => {
+            info!("Verified the working tree matches {}.", commit);
+            None
+        }
+        Ok(found) => {
+            for difference in &found {
+                warn!("Working tree differs from {}: {}", commit, difference);
+            }
+            Some(format!(
+                "working tree does not match {} ({} difference(s), first: {})",
+                commit,
+                found.len(),
+                found[0]
+            ))
+        }
+        Err(e) => Some(format!("failed to verify the working tree: {}", e)),
+    }
+}
+
+// Reads the config file and parses it into one AppConfig per repository
+fn read_configs() -> Result<Vec<AppConfig>, Box<dyn std::error::Error>> {
+    let config_path = Path::new("config.toml");
+
+    if !config_path.exists() {
+        error!("Config file not found.");
+        eprintln!("Config file not found in the same directory as the executable. Please ensure 'config.toml' is present.");
+
+        // Prompt the user to press Enter before exiting
+        print!("Press Enter to exit...");
+        io::stdout().flush()?; // Ensure the message is printed before reading input
+        let _ = io::stdin().read_line(&mut String::new());
+
+        std::process::exit(1); // Exit the program with a non-zero status
+    }
+
+    let mut configs = parse_configs(config_path)?;
+
+    // Resolve the PATs and certificate passwords (decrypting them if needed) before the
+    // configs are used
+    for config in &mut configs {
+        config.pat = load_pat(config)?;
+        let identity = secrets::identity_path(config.secrets_identity.as_deref());
+        if let Some(certificate) = &mut config.client_certificate {
+            certificate.resolve_password(identity.as_deref())?;
+        }
+        if let Some(webhook) = &mut config.webhook {
+            webhook.resolve(identity.as_deref())?;
+        }
+        for system in &mut config.change_records {
+            system.resolve(identity.as_deref())?;
+        }
+        if let Some(jira) = &mut config.jira {
+            jira.resolve(identity.as_deref())?;
+        }
+        if let Some(github) = &mut config.github_deployment {
+            github.resolve(identity.as_deref())?;
+        }
+        let images = config.post_sync.images.iter_mut().chain(
+            config
+                .virtual_repos
+                .iter_mut()
+                .flat_map(|virtual_repo| virtual_repo.post_sync.images.iter_mut()),
+        );
+        for image in images {
+            image.resolve(identity.as_deref())?;
+        }
+        if let Some(releases) = &config.releases {
+            releases.check(&config.repo_path)?;
+        }
+        for virtual_repo in &config.virtual_repos {
+            virtual_repo.check()?;
+        }
+        let copies = config.post_sync.copy.iter().chain(
+            config
+                .virtual_repos
+                .iter()
+                .flat_map(|virtual_repo| &virtual_repo.post_sync.copy),
+        );
+        for copy in copies {
+            copy.check(&config.repo_path)?;
+        }
+        let archives = config.post_sync.archive.iter().chain(
+            config
+                .virtual_repos
+                .iter()
+                .filter_map(|virtual_repo| virtual_repo.post_sync.archive.as_ref()),
+        );
+        for archive in archives {
+            archive.check(&config.repo_path)?;
+        }
+        for entry in &config.schedule {
+            entry.check()?;
+        }
+        resolve_control_tokens(config)?;
+    }
+
+    info!(
+        "Config file read successfully, {} repo(s) to sync.",
+        configs.len()
+    );
+    Ok(configs)
+}
+
+// The --timeout of a subcommand, in seconds
+fn timeout_arg(args: &cli::Args, default: u64) -> Result<Duration, String> {
+    match args.value("--timeout") {
+        Some(seconds) => seconds
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| format!("Invalid --timeout: {}", seconds)),
+        None => Ok(Duration::from_secs(default)),
+    }
+}
+
+// Lists the agents announcing themselves on the LAN
+async fn discover_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("discover", args)?;
+    let wait = timeout_arg(&args, 3)?;
+
+    let agents = discovery::discover(wait).await?;
+    if agents.is_empty() {
+        println!("No agents answered within {} seconds.", wait.as_secs());
+        return Ok(());
+    }
+    println!(
+        "{:<24} {:<22} {:<10} {:<6} {:<10} LABELS",
+        "AGENT", "ADDRESS", "STATUS", "REPOS", "VERSION"
+    );
+    for agent in agents {
+        let detail = |key: &str| agent.details.get(key).cloned().unwrap_or_default();
+        let address = match agent.port {
+            0 => agent.address.to_string(),
+            port => format!("{}:{}", agent.address, port),
+        };
+        println!(
+            "{:<24} {:<22} {:<10} {:<6} {:<10} {}",
+            agent.name,
+            address,
+            detail("status"),
+            detail("repos"),
+            detail("version"),
+            detail("labels")
+        );
+    }
+    Ok(())
+}
+
+// Points a repository's current link back at an earlier kept release, without the network or
+// the running agent
+fn rollback_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("rollback", args)?;
+    let only = args.value("--repository");
+    let to = args.value("--to");
+
+    let configs = parse_configs(Path::new("config.toml"))?;
+    let candidates: Vec<(&AppConfig, &releases::ReleasesConfig)> = configs
+        .iter()
+        .filter(|config| {
+            only.as_ref()
+                .is_none_or(|name| name.eq_ignore_ascii_case(&config.repository))
+        })
+        .filter_map(|config| Some((config, config.releases.as_ref()?)))
+        .collect();
+    let (config, releases) = match candidates.as_slice() {
+        [] => return Err("No matching repository has [releases] set in config.toml".into()),
+        [candidate] => *candidate,
+        _ => return Err("Several repositories keep releases; pick one with --repository".into()),
+    };
+    let previous = releases.current();
+    let commit = releases::rollback(releases, to)?;
+    println!(
+        "{}: {} now points at {} (was {})",
+        config.repository,
+        releases.link().display(),
+        commit,
+        previous.as_deref().unwrap_or("nothing kept")
+    );
+    Ok(())
+}
+
+// Sends an approval (approve-force-push or approve-terraform) for a repository to the running
+// agent
+async fn approve_command(command: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse(command, args)?;
+    let only = args.value("--repository");
+    let project = args.value("--project");
+
+    let mut configs = parse_configs(Path::new("config.toml"))?;
+    let matching: Vec<control::RepoKey> = configs
+        .iter()
+        .filter(|config| {
+            only.is_none_or(|name| config.repository.eq_ignore_ascii_case(name))
+                && project.is_none_or(|name| config.project.eq_ignore_ascii_case(name))
+        })
+        .map(|config| control::RepoKey::new(&config.project, &config.repository))
+        .collect();
+    let repo = match matching.as_slice() {
+        [repo] => repo.clone(),
+        [] => return Err("No configured repository matches".into()),
+        _ if only.is_none() => {
+            return Err("Several repositories are configured; pick one with --repository".into())
+        }
+        _ => {
+            return Err(
+                "Several projects have a repository of that name; pick one with --project".into(),
+            )
+        }
+    };
+    let listen = configs[0]
+        .control_listen
+        .clone()
+        .ok_or("control_listen is not set in config.toml")?;
+    resolve_control_tokens(&mut configs[0])?;
+    let client = control_client(&configs[0])?;
+    println!(
+        "{}",
+        control::send_command(
+            &client,
+            &listen,
+            &repo.route(command),
+            &configs[0].control_tokens
+        )
+        .await?
+    );
+    Ok(())
+}
+
+// Rehearses a repository's config against a simulated remote: the agent syncs a scratch
+// checkout while scripted scenarios push to the remote, and each scenario passes once the
+// state file shows the agent reacted as it should. Notifications and other outside effects
+// are turned off; hooks run for real, in the scratch checkout.
+async fn simulate_command(
+    args: &[String],
+    console_mode: console::Mode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("simulate", args)?;
+    let only = args.value("--repository");
+    let timeout = timeout_arg(&args, 60)?;
+
+    let configs = parse_configs(Path::new("config.toml"))?;
+    let mut config = match (only, configs.len()) {
+        (Some(name), _) => configs
+            .into_iter()
+            .find(|config| config.repository.eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("No repository named '{}' in config.toml", name))?,
+        (None, 1) => configs
+            .into_iter()
+            .next()
+            .ok_or("config.toml has no repository")?,
+        (None, _) => {
+            return Err("Several repositories are configured; pick one with --repository".into())
+        }
+    };
+
+    // The stand-in remote answers to any name, so an ID only has to stand in for a missing one
+    if let Some(id) = config.repository_id.take() {
+        if config.repository.is_empty() {
+            config.repository = id;
+        }
+    }
+
+    let scratch = std::env::temp_dir().join(format!(
+        "DevOps_Repository_Sync-simulate-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&scratch)?;
+    logging::init_at(&scratch.join("app.log"), console_mode)?;
+    // The status ticker would run into the scenario results
+    console::init(match console_mode {
+        console::Mode::Verbose => console::Mode::Verbose,
+        _ => console::Mode::Quiet,
+    });
+    git::init(config.git_path.as_deref()).await?;
+
+    let branch = if config.target_branch == AUTO_BRANCH {
+        "main".to_string()
+    } else {
+        config.target_branch.clone()
+    };
+    let remote = simulate::Remote::create(&scratch, &branch, &config.repo_path).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    azure::set_base_url(format!("http://{}", listener.local_addr()?));
+    remote.serve(listener);
+    let checkout = scratch.join("checkout");
+    remote.clone_into(&checkout).await?;
+    println!(
+        "Simulating {} on '{}' in {}",
+        config.repository,
+        branch,
+        scratch.display()
+    );
+
+    let scratch_file = |name: &str| scratch.join(name).to_string_lossy().to_string();
+    let state_file = scratch.join("state.json");
+    state::set_dir(scratch.join("state"));
+    config.repo_path = checkout.to_string_lossy().to_string();
+    config.target_branch = branch.clone();
+    config.pat = "simulated".to_string();
+    config.check_interval_seconds = 1;
+    config.active_interval = None;
+    config.quiet_interval = None;
+    config.schedule.clear();
+    config.interval_script = None;
+    config.sync_delay_seconds = 0;
+    config.settle_seconds = 0;
+    config.sync_on = SyncOn::Commits;
+    config.check_connectivity = false;
+    config.wait_for_build = false;
+    config.artifact = None;
+    config.trigger = None;
+    config.manifest_file = None;
+    config.link_work_items = false;
+    config.notifications.clear();
+    config.alerts.clear();
+    config.change_records.clear();
+    config.jira = None;
+    config.deployment_status = None;
+    config.github_deployment = None;
+    config.after.clear();
+    config.batch = None;
+    config.concurrency_group = None;
+    config.log_file = None;
+    config.audit_file = None;
+    config.history_file = scratch_file("history.jsonl");
+    config.state_file = Some(state_file.to_string_lossy().to_string());
+    if config.changed_files_file.is_some() {
+        config.changed_files_file = Some(scratch_file("changed_files.txt"));
+    }
+    if let Some(releases) = &mut config.releases {
+        releases.directory = scratch_file("releases");
+        releases.current_link = None;
+    }
+    for virtual_repo in &mut config.virtual_repos {
+        virtual_repo.notifications.clear();
+        virtual_repo.history_file = None;
+    }
+    // Copies and archives land in the scratch directory rather than the real destinations
+    let copies = config.post_sync.copy.iter_mut().chain(
+        config
+            .virtual_repos
+            .iter_mut()
+            .flat_map(|virtual_repo| virtual_repo.post_sync.copy.iter_mut()),
+    );
+    for (index, copy) in copies.enumerate() {
+        copy.destination = scratch_file(&format!("copy-{}", index + 1));
+        copy.host = None;
+    }
+    let archives = config.post_sync.archive.iter_mut().chain(
+        config
+            .virtual_repos
+            .iter_mut()
+            .filter_map(|virtual_repo| virtual_repo.post_sync.archive.as_mut()),
+    );
+    for (index, archive) in archives.enumerate() {
+        archive.directory = scratch_file(&format!("archives-{}", index + 1));
+    }
+    // Images are still built, but never pushed
+    let images = config.post_sync.images.iter_mut().chain(
+        config
+            .virtual_repos
+            .iter_mut()
+            .flat_map(|virtual_repo| virtual_repo.post_sync.images.iter_mut()),
+    );
+    for image in images {
+        image.push = false;
+    }
+    // Manifests are checked without touching the cluster
+    let clusters = config.post_sync.kubernetes.iter_mut().chain(
+        config
+            .virtual_repos
+            .iter_mut()
+            .filter_map(|virtual_repo| virtual_repo.post_sync.kubernetes.as_mut()),
+    );
+    for cluster in clusters {
+        cluster.dry_run = kubernetes::DryRun::Client;
+    }
+    // Terraform plans, but nothing is applied
+    let modules = config.post_sync.terraform.iter_mut().chain(
+        config
+            .virtual_repos
+            .iter_mut()
+            .filter_map(|virtual_repo| virtual_repo.post_sync.terraform.as_mut()),
+    );
+    for module in modules {
+        module.auto_apply = false;
+    }
+
+    let azure_client = azure::client(
+        &config.user_agent,
+        &config.resolution()?,
+        None,
+        Duration::from_secs(config.request_timeout_seconds),
+    )?;
+    let (control_tx, control_rx) = broadcast::channel(16);
+    let links = ordering::link(&[(config.repository.clone(), Vec::new())])?
+        .pop()
+        .ok_or("no ordering links")?;
+    let repository = control::RepoKey::new(&config.project, &config.repository);
+    let holds_force_push = config.force_push == ForcePush::Hold;
+    let local = LocalSet::new();
+    let agent = local.spawn_local(logging::in_repo(
+        config.repository.clone(),
+        branch,
+        None,
+        sync_repo(config, azure_client, control_rx, None, links, None, false),
+    ));
+    let result = local
+        .run_until(async {
+            tokio::select! {
+                result = simulate::run_scenarios(&remote, &state_file, timeout, holds_force_push, || {
+                    let _ = control_tx.send(control::ControlCommand::ApproveForcePush(
+                        repository.clone(),
+                    ));
+                }) => result,
+                stopped = agent => Err(match stopped {
+                    Ok(Err(e)) => format!("The agent stopped: {}", e).into(),
+                    _ => "The agent stopped".into(),
+                }),
+            }
+        })
+        .await;
+    println!(
+        "The checkout, app.log and history are kept in {}",
+        scratch.display()
+    );
+    result
 }
 
-// Grabs API response and deserializes it into the struct
-#[derive(Deserialize)]
-struct ApiResponse {
-    value: Vec<Commit>,
+// Subscribes every configured repository's pushes to the agent's webhook, skipping the ones
+// already subscribed
+async fn register_webhook_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("register-webhook", args)?;
+    let only = args.value("--repository");
+
+    let mut configs = parse_configs(Path::new("config.toml"))?;
+    if configs[0].control_listen.is_none() {
+        return Err("control_listen is not set in config.toml; the webhook is received on the control endpoint".into());
+    }
+    let identity = secrets::identity_path(configs[0].secrets_identity.as_deref());
+    let mut webhook = configs[0]
+        .webhook
+        .clone()
+        .ok_or("[webhook] is not set in config.toml")?;
+    webhook.resolve(identity.as_deref())?;
+    let client = shared_client(&configs[0])?;
+
+    let mut failed = 0;
+    for config in &mut configs {
+        if only
+            .as_ref()
+            .is_some_and(|name| !name.eq_ignore_ascii_case(&config.repository))
+        {
+            continue;
+        }
+        config.pat = load_pat(config)?;
+        match webhook::register(&client, &config.repo_ref(), &webhook, &config.api_version).await {
+            Ok(webhook::Registration::Created(id)) => {
+                println!("{}: subscribed (subscription {})", config.repository, id)
+            }
+            Ok(webhook::Registration::Exists(id)) => println!(
+                "{}: already subscribed (subscription {})",
+                config.repository, id
+            ),
+            Err(e) => {
+                println!("{}: failed: {}", config.repository, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} repository(s) could not be subscribed", failed).into());
+    }
+    Ok(())
 }
 
-// Deserializes the commitId in the api response array into a string and renames to snake case
-#[derive(Deserialize)]
-struct Commit {
-    #[serde(rename = "commitId")]
-    commit_id: String,
+// Parses the config file without resolving any secrets. Each [[repos]] entry becomes a config
+// of its own: the top-level keys, overridden by the [credentials.<name>] block the entry names,
+// overridden by the entry itself. Without [[repos]] the top level is the only repository.
+fn parse_configs(config_path: &Path) -> Result<Vec<AppConfig>, Box<dyn std::error::Error>> {
+    parse_config_text(&fs::read_to_string(config_path)?)
 }
 
-// Reads the config file and parses it into the AppConfig struct
-fn read_config() -> Result<AppConfig, Box<dyn std::error::Error>> {
-    let config_path = Path::new("config.toml");
+fn parse_config_text(config_content: &str) -> Result<Vec<AppConfig>, Box<dyn std::error::Error>> {
+    let mut root: toml::Table = toml::from_str(config_content)?;
+    root.remove("feeds");
+    let credentials = root.remove("credentials");
+    let Some(repos) = root.remove("repos") else {
+        let config: AppConfig = toml::Value::Table(root).try_into()?;
+        config.check_repository()?;
+        return Ok(vec![config]);
+    };
 
-    if !config_path.exists() {
-        error!("Config file not found.");
-        eprintln!("Config file not found in the same directory as the executable. Please ensure 'config.toml' is present.");
+    let repos = repos
+        .as_array()
+        .filter(|repos| !repos.is_empty())
+        .ok_or("'repos' must be one or more [[repos]] entries")?;
+    let mut configs = Vec::new();
+    for (index, entry) in repos.iter().enumerate() {
+        let merged = merge_entry(&root, credentials.as_ref(), entry, "repos", index)?;
+        let config: AppConfig = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| format!("repos[{}]: {}", index, e))?;
+        config
+            .check_repository()
+            .map_err(|e| format!("repos[{}]: {}", index, e))?;
+        configs.push(AppConfig {
+            entry: index,
+            ..config
+        });
+    }
+    Ok(configs)
+}
 
-        // Prompt the user to press Enter before exiting
-        print!("Press Enter to exit...");
-        io::stdout().flush()?; // Ensure the message is printed before reading input
-        let _ = io::stdin().read_line(&mut String::new());
+// Parses the [[feeds]] entries without resolving any secrets, merged over the top-level keys and
+// their [credentials.<name>] block the same way as [[repos]] entries
+fn parse_feeds(config_path: &Path) -> Result<Vec<feeds::FeedConfig>, Box<dyn std::error::Error>> {
+    let config_content = fs::read_to_string(config_path)?;
+    let mut root: toml::Table = toml::from_str(&config_content)?;
+    root.remove("repos");
+    let credentials = root.remove("credentials");
+    let Some(entries) = root.remove("feeds") else {
+        return Ok(Vec::new());
+    };
 
-        std::process::exit(1); // Exit the program with a non-zero status
+    let entries = entries
+        .as_array()
+        .ok_or("'feeds' must be [[feeds]] entries")?;
+    let mut feeds = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let merged = merge_entry(&root, credentials.as_ref(), entry, "feeds", index)?;
+        let feed = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| format!("feeds[{}]: {}", index, e))?;
+        feeds.push(feed);
     }
+    Ok(feeds)
+}
 
-    let config_content = fs::read_to_string(config_path)?;
-    let config: AppConfig = toml::from_str(&config_content)?;
-    info!("Config file read successfully.");
-    Ok(config)
+// The top-level keys, overridden by the credentials block the entry names, overridden by the
+// entry itself
+fn merge_entry(
+    root: &toml::Table,
+    credentials: Option<&toml::Value>,
+    entry: &toml::Value,
+    list: &str,
+    index: usize,
+) -> Result<toml::Table, Box<dyn std::error::Error>> {
+    let entry = entry
+        .as_table()
+        .ok_or_else(|| format!("{}[{}] must be a table", list, index))?;
+    let mut merged = root.clone();
+
+    if let Some(name) = entry.get("credentials") {
+        let name = name
+            .as_str()
+            .ok_or_else(|| format!("{}[{}].credentials must be a name", list, index))?;
+        let block = credentials
+            .and_then(|credentials| credentials.get(name))
+            .and_then(toml::Value::as_table)
+            .ok_or_else(|| {
+                format!(
+                    "{}[{}] uses credentials '{}', but there is no [credentials.{}] block",
+                    list, index, name, name
+                )
+            })?;
+        merged.extend(block.clone());
+    }
+    // The entry's interval replaces the inherited one under either of its names
+    if entry.contains_key("check_interval") || entry.contains_key("check_interval_seconds") {
+        merged.remove("check_interval");
+        merged.remove("check_interval_seconds");
+    }
+    merged.extend(
+        entry
+            .iter()
+            .filter(|(key, _)| *key != "credentials")
+            .map(|(key, value)| (key.clone(), value.clone())),
+    );
+    Ok(merged)
+}
+
+// Reads the [[feeds]] entries and resolves their PATs
+fn read_feeds() -> Result<Vec<feeds::FeedConfig>, Box<dyn std::error::Error>> {
+    let mut feeds = parse_feeds(Path::new("config.toml"))?;
+    for feed in &mut feeds {
+        feed.pat = load_feed_pat(feed)?;
+    }
+    if !feeds.is_empty() {
+        info!("{} package feed(s) to watch.", feeds.len());
+    }
+    Ok(feeds)
+}
+
+fn load_feed_pat(feed: &feeds::FeedConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let identity = secrets::identity_path(feed.secrets_identity.as_deref());
+    secrets::read_pat(
+        &feed.pat,
+        feed.pat_env.as_deref(),
+        feed.pat_file.as_deref(),
+        identity.as_deref(),
+    )
+}
+
+// Reads the PAT from whichever source the config points at
+fn load_pat(config: &AppConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let identity = secrets::identity_path(config.secrets_identity.as_deref());
+    secrets::read_pat(
+        &config.pat,
+        config.pat_env.as_deref(),
+        config.pat_file.as_deref(),
+        identity.as_deref(),
+    )
+}
+
+// Re-reads the PAT from its source so rotated tokens are picked up without a restart
+fn reload_credentials(config: &mut AppConfig) {
+    // Inline tokens are rotated by editing config.toml, so the source settings come from a fresh read
+    let fresh = parse_configs(Path::new("config.toml")).and_then(|fresh| {
+        let fresh =
+            config_entry(fresh, config).ok_or("the repository is no longer in config.toml")?;
+        load_pat(&fresh)
+    });
+    match fresh {
+        Ok(pat) => {
+            if pat == config.pat {
+                info!("Credentials reloaded, token is unchanged.");
+            } else {
+                info!("Credentials reloaded, using the new token.");
+            }
+            config.pat = pat;
+        }
+        Err(e) => error!("Failed to reload credentials: {}", e),
+    }
+}
+
+// The freshly read entry of a running repo. resolve_repository replaces the project and
+// repository with the remote's names, so the entry is found by the ID it gives, or by its
+// position when it gives none.
+fn config_entry(fresh: Vec<AppConfig>, config: &AppConfig) -> Option<AppConfig> {
+    fresh.into_iter().find(
+        |fresh| match (&fresh.repository_id, &config.repository_id) {
+            (Some(fresh_id), Some(id)) => fresh_id.eq_ignore_ascii_case(id),
+            _ => fresh.entry == config.entry,
+        },
+    )
+}
+
+// Re-reads a feed's PAT from its source, as reload_credentials does for a repo
+fn reload_feed_credentials(feed: &mut feeds::FeedConfig) {
+    let fresh = parse_feeds(Path::new("config.toml")).and_then(|fresh| {
+        let fresh = fresh
+            .into_iter()
+            .find(|fresh| {
+                (&fresh.organization, &fresh.feed, &fresh.package)
+                    == (&feed.organization, &feed.feed, &feed.package)
+            })
+            .ok_or("the feed is no longer in config.toml")?;
+        load_feed_pat(&fresh)
+    });
+    match fresh {
+        Ok(pat) => {
+            if pat == feed.pat {
+                info!("Credentials reloaded, token is unchanged.");
+            } else {
+                info!("Credentials reloaded, using the new token.");
+            }
+            feed.pat = pat;
+        }
+        Err(e) => error!("Failed to reload credentials: {}", e),
+    }
 }
 
 // Checks the latest commit hash / id on the remote azure
-async fn get_latest_commit(config: &AppConfig) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let api_url = format!("https://dev.azure.com/{}/{}/_apis/git/repositories/{}/commits?branchName={}&searchCriteria.itemVersion.version={}&searchCriteria.itemVersion.versionType=branch", config.organization, config.project, config.repository, config.target_branch, config.target_branch);
+async fn get_latest_commit(
+    client: &Client,
+    config: &AppConfig,
+    branch: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // The branch's ref says exactly which commit git will fetch. The commits API searches the
+    // history instead, and with a merge commit at the head it can list another commit first,
+    // which made the branch look changed on every check.
+    let api_url = azure::refs_url(
+        &config.organization,
+        &config.project,
+        config.api_repository(),
+        &format!("heads/{}", branch),
+        &config.api_version,
+    )?;
     let response = client
         .get(api_url)
         .basic_auth("", Some(&config.pat))
@@ -71,25 +2593,103 @@ async fn get_latest_commit(config: &AppConfig) -> Result<String, Box<dyn std::er
 
     info!("API request sent successfully.");
 
+    // Azure DevOps answers a bad or expired PAT with 401/403 or a 203 sign-in page
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+        || status == StatusCode::NON_AUTHORITATIVE_INFORMATION
+    {
+        return Err(Box::new(secrets::AuthError(format!(
+            "remote API returned {}",
+            status
+        ))));
+    }
+
     let response_text = response.text().await?;
+    if status == StatusCode::NOT_FOUND {
+        return Err(Box::new(azure::RepositoryNotFound(response_text)));
+    }
+    if !status.is_success() {
+        return Err(format!("remote API returned {}", status).into());
+    }
+
     let api_response: ApiResponse = serde_json::from_str(&response_text)?;
+    // The filter matches every name starting with it, e.g. heads/main-old for main
+    let name = format!("refs/heads/{}", branch);
+    let Some(latest) = api_response
+        .value
+        .into_iter()
+        .find(|reference| reference.name == name)
+    else {
+        // A repository without any branch was just created and has no commits yet
+        let branches = azure::list_branches(
+            client,
+            &config.organization,
+            &config.project,
+            config.api_repository(),
+            &config.pat,
+            &config.api_version,
+        )
+        .await?;
+        return Err(Box::new(if branches.is_empty() {
+            azure::BranchError::Empty(branch.to_string())
+        } else {
+            azure::BranchError::NotFound(branch.to_string())
+        }));
+    };
     info!(
         "Received latest commit from remote: {}",
-        api_response.value[0].commit_id.clone().trim().to_string()
+        latest.object_id.trim()
     );
 
-    // Grabbing first commit in the array to check most recent commit on Main
-    Ok(api_response.value[0].commit_id.clone())
+    Ok(latest.object_id)
+}
+
+// Picks the branch to follow once the target branch is gone, if the config names one that exists
+async fn fallback_branch(client: &Client, config: &AppConfig) -> Option<String> {
+    let branch = match config.fallback_branch.as_deref()? {
+        "default" => match config.default_branch(client).await {
+            Ok(branch) => branch,
+            Err(e) => {
+                error!("Failed to look up the default branch: {}", e);
+                return None;
+            }
+        },
+        branch => branch.to_string(),
+    };
+
+    // A default branch that is the deleted branch itself is no way out
+    (branch != config.target_branch).then_some(branch)
 }
 
 // Checks the local commit head hash / id to then compare with the remote version
-fn get_local_commit(repo_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let output = Command::new("git")
+async fn get_local_commit(config: &AppConfig) -> Result<String, Box<dyn std::error::Error>> {
+    let repo_path = &config.repo_path;
+    #[cfg(feature = "gitoxide")]
+    if config.git_backend == GitBackend::Gitoxide {
+        let commit_id = gitoxide::head(repo_path).await?;
+        info!("Local commit ID: {}", commit_id);
+        return Ok(commit_id);
+    }
+    #[cfg(feature = "embedded-git")]
+    if git::embedded() {
+        let commit_id = embedded::head(repo_path).await?;
+        info!("Local commit ID: {}", commit_id);
+        return Ok(commit_id);
+    }
+    let output = git::command()
         .arg("-C")
         .arg(repo_path)
         .arg("rev-parse")
         .arg("HEAD")
-        .output()?;
+        .output()
+        .await?;
+
+    // Only an ownership refusal is an error, an unborn HEAD in a fresh clone just means everything is new
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && git::is_dubious_ownership(&stderr) {
+        return Err(Box::new(git::DubiousOwnership(repo_path.to_string())));
+    }
 
     let commit_id = String::from_utf8(output.stdout)?.trim().to_string();
     info!("Local commit ID: {}", commit_id);
@@ -97,36 +2697,210 @@ fn get_local_commit(repo_path: &str) -> Result<String, Box<dyn std::error::Error
     Ok(commit_id)
 }
 
-fn pull_changes(config: &AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+// Whether ancestor is in the history of descendant, read with the repo's git_backend
+async fn is_ancestor(config: &AppConfig, ancestor: &str, descendant: &str) -> bool {
+    #[cfg(feature = "gitoxide")]
+    if config.git_backend == GitBackend::Gitoxide {
+        return gitoxide::is_ancestor(&config.repo_path, ancestor, descendant).await;
+    }
+    git::is_ancestor(&config.repo_path, ancestor, descendant).await
+}
+
+// How git fetch connects, matching the API client
+struct FetchOptions {
+    // `-c` overrides given before the subcommand
+    config_args: Vec<String>,
+    // Options of the fetch itself
+    fetch_args: Vec<String>,
+    env: Vec<(&'static str, String)>,
+}
+
+// Makes git fetch resolve and connect like the API client: the addresses from dns_servers go
+// into curl's cache (ignored by git before 2.37), ip_version becomes --ipv4/--ipv6, and the
+// client certificate is passed on
+async fn fetch_options(config: &AppConfig) -> Result<FetchOptions, Box<dyn std::error::Error>> {
+    let mut options = FetchOptions {
+        config_args: Vec::new(),
+        fetch_args: Vec::new(),
+        env: Vec::new(),
+    };
+
+    let resolution = config.resolution()?;
+    if resolution.uses_own_servers() {
+        let (host, port) = azure::server_host();
+        let addresses = resolution
+            .lookup(&host, port)
+            .await
+            .map_err(|e| format!("Failed to resolve {}: {}", host, e))?;
+        let addresses: Vec<String> = addresses
+            .iter()
+            .map(|address| match address.ip() {
+                std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+                ip => ip.to_string(),
+            })
+            .collect();
+        options.config_args.push("-c".to_string());
+        options.config_args.push(format!(
+            "http.curloptResolve={}:{}:{}",
+            host,
+            port,
+            addresses.join(",")
+        ));
+    }
+
+    match resolution.ip_version() {
+        network::IpVersion::Any => {}
+        network::IpVersion::Ipv4 => options.fetch_args.push("--ipv4".to_string()),
+        network::IpVersion::Ipv6 => options.fetch_args.push("--ipv6".to_string()),
+    }
+
+    if let Some(certificate) = &config.client_certificate {
+        let settings = certificate.git_settings()?;
+        for setting in settings.config {
+            options.config_args.push("-c".to_string());
+            options.config_args.push(setting);
+        }
+        options.env = settings.env;
+    }
+    Ok(options)
+}
+
+async fn pull_changes(
+    config: &AppConfig,
+    pin: Option<&str>,
+    reset: bool,
+    timings: &mut metrics::Timings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fetch_changes(config, timings).await?;
+    apply_changes(config, pin, reset, timings).await
+}
+
+// Pulls on its own, or as a batch member only once every other member has fetched or is in
+// sync. The checkout isn't touched when the batch holds back.
+async fn pull_in_batch(
+    config: &AppConfig,
+    pin: Option<&str>,
+    reset: bool,
+    batch: Option<&batch::Batch>,
+    timings: &mut metrics::Timings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(batch) = batch else {
+        return pull_changes(config, pin, reset, timings).await;
+    };
+
+    let wave = batch.start_wave();
+    let fetched = fetch_changes(config, timings).await;
+    batch.report(match &fetched {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("failed to fetch: {}", e)),
+    });
+    fetched?;
+
+    let started = Instant::now();
+    let ready = batch.wait_for_others(wave).await;
+    metrics::record(timings, "batch_wait", started);
+    if let Err(reason) = ready {
+        return Err(format!("Held back with batch '{}': {}", batch.name(), reason).into());
+    }
+    info!("Every member of batch '{}' is ready.", batch.name());
+    apply_changes(config, pin, reset, timings).await
+}
+
+// Brings the remote's branches into origin/*, leaving the checkout untouched
+async fn fetch_changes(
+    config: &AppConfig,
+    timings: &mut metrics::Timings,
+) -> Result<(), Box<dyn std::error::Error>> {
     let repo_path = &config.repo_path;
 
-    let url_with_credentials = format!(
-        "https://{}:{}@dev.azure.com/{}/{}/_git/{}",
-        config.organization, config.pat, config.organization, config.project, config.repository
+    let url_with_credentials = azure::git_url(
+        &config.organization,
+        &config.project,
+        &config.repository,
+        &config.pat,
     );
 
-    // Fetch all branches from the remote repository using the URL with credentials
     let fetch_refspec = "+refs/heads/*:refs/remotes/origin/*";
 
-    let status_fetch = Command::new("git")
+    // gix and libgit2 open their own connections, with no way to resolve through dns_servers,
+    // force an IP version or present a client certificate
+    #[cfg(feature = "embedded-git")]
+    let embedded = git::embedded();
+    #[cfg(not(feature = "embedded-git"))]
+    let embedded = false;
+    if config.git_backend == GitBackend::Gitoxide || embedded {
+        let resolution = config.resolution()?;
+        if resolution.uses_own_servers()
+            || resolution.ip_version() != network::IpVersion::Any
+            || config.client_certificate.is_some()
+        {
+            return Err("dns_servers, ip_version and client_certificate need git_backend = \"git\" and a git program".into());
+        }
+    }
+    #[cfg(feature = "gitoxide")]
+    if config.git_backend == GitBackend::Gitoxide {
+        let url = azure::repository_url(&config.organization, &config.project, &config.repository);
+        let started = Instant::now();
+        gitoxide::fetch(
+            repo_path,
+            &url,
+            &config.pat,
+            &config.user_agent,
+            fetch_refspec,
+        )
+        .await?;
+        metrics::record(timings, "fetch", started);
+        info!("Fetched all branches from remote.");
+        return Ok(());
+    }
+    #[cfg(feature = "embedded-git")]
+    if git::embedded() {
+        let started = Instant::now();
+        embedded::fetch(repo_path, &url_with_credentials, fetch_refspec).await?;
+        metrics::record(timings, "fetch", started);
+        info!("Fetched all branches from remote.");
+        return Ok(());
+    }
+
+    // git identifies itself to Azure DevOps with the same User-Agent as the API requests
+    let user_agent = format!("http.userAgent={}", config.user_agent);
+
+    // Fetch all branches from the remote repository using the URL with credentials
+    let options = fetch_options(config).await?;
+
+    let started = Instant::now();
+    let status_fetch = git::command()
         .arg("-C")
         .arg(repo_path)
+        .arg("-c")
+        .arg(&user_agent)
+        .args(&options.config_args)
+        .envs(options.env.iter().cloned())
         .arg("fetch")
+        .args(&options.fetch_args)
         .arg("--prune")
         .arg(&url_with_credentials)
-        .arg(&fetch_refspec)
-        .status()?; // Use status to avoid blocking
+        .arg(fetch_refspec)
+        .status()
+        .await?;
+    metrics::record(timings, "fetch", started);
 
     if !status_fetch.success() {
         // If fetch failed, capture stdout and stderr
-        let output_fetch = Command::new("git")
+        let output_fetch = git::command()
             .arg("-C")
             .arg(repo_path)
+            .arg("-c")
+            .arg(&user_agent)
+            .args(&options.config_args)
+            .envs(options.env.iter().cloned())
             .arg("fetch")
+            .args(&options.fetch_args)
             .arg("--prune")
             .arg(&url_with_credentials)
-            .arg(&fetch_refspec)
-            .output()?; // Use output only when the command fails
+            .arg(fetch_refspec)
+            .output()
+            .await?; // Use output only when the command fails
 
         let stdout = String::from_utf8_lossy(&output_fetch.stdout);
         let stderr = String::from_utf8_lossy(&output_fetch.stderr);
@@ -134,161 +2908,754 @@ fn pull_changes(config: &AppConfig) -> Result<(), Box<dyn std::error::Error>> {
             "Failed to fetch from remote. stdout: {}, stderr: {}",
             stdout, stderr
         );
+        if stderr.contains("Authentication failed") || stderr.contains("401") {
+            return Err(Box::new(secrets::AuthError(
+                "git fetch was rejected".into(),
+            )));
+        }
         return Err("Failed to fetch from remote".into());
     } else {
         info!("Fetched all branches from remote.");
     }
 
-    // Check if the target branch exists locally
-    let status_branch_check = Command::new("git")
-        .arg("-C")
-        .arg(repo_path)
-        .arg("rev-parse")
-        .arg("--verify")
-        .arg(&config.target_branch)
-        .status()?; // Use status to avoid blocking
+    Ok(())
+}
+
+// Moves the checkout to what was fetched: onto the target branch, then to the pinned commit or
+// merged with origin/<target_branch>, or reset to it when `reset` (a force-pushed branch)
+async fn apply_changes(
+    config: &AppConfig,
+    pin: Option<&str>,
+    reset: bool,
+    timings: &mut metrics::Timings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = &config.repo_path;
 
-    if !status_branch_check.success() {
-        // Branch doesn't exist locally, create it tracking the remote branch
+    if config.checkout_mode == CheckoutMode::Detached {
         let remote_branch = format!("origin/{}", &config.target_branch);
-        let status_checkout_new = Command::new("git")
-            .arg("-C")
-            .arg(repo_path)
-            .arg("checkout")
-            .arg("-b")
-            .arg(&config.target_branch)
-            .arg("--track")
-            .arg(&remote_branch)
-            .status()?; // Use status to avoid blocking
-
-        if !status_checkout_new.success() {
-            // If creating the branch failed, capture output
-            let output_checkout_new = Command::new("git")
-                .arg("-C")
-                .arg(repo_path)
-                .arg("checkout")
-                .arg("-b")
-                .arg(&config.target_branch)
-                .arg("--track")
-                .arg(&remote_branch)
-                .output()?; // Use output only when the command fails
-
-            let stdout_new = String::from_utf8_lossy(&output_checkout_new.stdout);
-            let stderr_new = String::from_utf8_lossy(&output_checkout_new.stderr);
-            error!(
-                "Failed to create and checkout branch '{}'. stdout: {}, stderr: {}",
-                config.target_branch, stdout_new, stderr_new
-            );
-            return Err("Failed to create and checkout branch".into());
-        } else {
-            info!("Created and checked out branch '{}'", config.target_branch);
-        }
-    } else {
-        // Branch exists locally, checkout the target branch
-        let status_checkout = Command::new("git")
-            .arg("-C")
-            .arg(repo_path)
-            .arg("checkout")
-            .arg(&config.target_branch)
-            .status()?; // Use status to avoid blocking
-
-        if !status_checkout.success() {
-            // If checkout failed, capture stdout and stderr
-            let output_checkout = Command::new("git")
-                .arg("-C")
-                .arg(repo_path)
-                .arg("checkout")
-                .arg(&config.target_branch)
-                .output()?; // Use output only when the command fails
-
-            let stdout = String::from_utf8_lossy(&output_checkout.stdout);
-            let stderr = String::from_utf8_lossy(&output_checkout.stderr);
-            error!(
-                "Failed to checkout branch '{}'. stdout: {}, stderr: {}",
-                config.target_branch, stdout, stderr
-            );
-            return Err("Failed to checkout branch".into());
-        } else {
-            info!("Checked out branch '{}'", config.target_branch);
+        return detach(config, pin.unwrap_or(&remote_branch), timings).await;
+    }
+
+    let started = Instant::now();
+    if let Err(e) = git::switch_branch(repo_path, &config.target_branch).await {
+        error!(
+            "Failed to checkout branch '{}': {}",
+            config.target_branch, e
+        );
+        return Err("Failed to checkout branch".into());
+    }
+    info!("Checked out branch '{}'", config.target_branch);
+
+    metrics::record(timings, "checkout", started);
+
+    // A commit pinned by the manifest is where the branch goes, forwards or back. --keep holds on
+    // to local edits, and refuses rather than overwrite the ones the move would touch.
+    if let Some(pin) = pin {
+        let started = Instant::now();
+        git::reset_keep(repo_path, pin).await?;
+        metrics::record(timings, "pull", started);
+        info!("Moved '{}' to pinned commit {}.", config.target_branch, pin);
+        if config.clean_untracked {
+            git::clean(repo_path, config.clean_ignored, &config.preserve_paths).await?;
         }
+        return Ok(());
     }
 
-    let status_pull = Command::new("git")
-        .arg("-C")
-        .arg(repo_path)
-        .arg("pull")
-        .arg(&url_with_credentials)
-        .arg(&config.target_branch)
-        .status()?; // Use status to avoid blocking
+    // The fetch above already brought the branch in, so merge it locally instead of a
+    // `git pull` that would fetch from Azure DevOps a second time
+    let remote_branch = format!("origin/{}", &config.target_branch);
+    // Merging a rewritten history would bring the replaced commits back
+    if reset {
+        let started = Instant::now();
+        git::reset_hard(repo_path, &remote_branch).await?;
+        metrics::record(timings, "pull", started);
+        warn!(
+            "Reset '{}' to the force-pushed {}.",
+            config.target_branch, remote_branch
+        );
+        git::clean(repo_path, config.clean_ignored, &config.preserve_paths).await?;
+        return Ok(());
+    }
+    let started = Instant::now();
+    let merged = git::merge(repo_path, &remote_branch).await;
+    metrics::record(timings, "pull", started);
 
-    if !status_pull.success() {
-        // If pull failed, capture stdout and stderr
-        let output_pull = Command::new("git")
-            .arg("-C")
-            .arg(repo_path)
-            .arg("pull")
-            .arg(&url_with_credentials)
-            .arg(&config.target_branch)
-            .output()?; // Use output only when the command fails
+    if let Err(e) = &merged {
+        if !config.reset_on_conflict {
+            error!("Failed to pull changes: {}", e);
+            return Err("Failed to pull changes".into());
+        }
 
-        let stdout = String::from_utf8_lossy(&output_pull.stdout);
-        let stderr = String::from_utf8_lossy(&output_pull.stderr);
-        error!(
-            "Failed to pull changes. stdout: {}, stderr: {}",
-            stdout, stderr
+        // Forced convergence: the remote wins over whatever is in the way locally
+        warn!(
+            "Merge failed ({}), resetting '{}' to {}.",
+            e, config.target_branch, remote_branch
         );
+        git::reset_hard(repo_path, &remote_branch).await?;
+        info!("Reset '{}' to {}.", config.target_branch, remote_branch);
     } else {
-        info!("Changes pulled successfully: {}", status_pull.success());
+        info!("Merged {} into '{}'.", remote_branch, config.target_branch);
+    }
+
+    // A reset cleans up after itself, and kiosk-style checkouts that must mirror the repo
+    // exactly are cleaned after every sync
+    if merged.is_err() || config.clean_untracked {
+        git::clean(repo_path, config.clean_ignored, &config.preserve_paths).await?;
     }
 
     Ok(())
 }
 
+// Detaches HEAD at the commit. Like a pin, local edits are kept unless the move would overwrite
+// them; reset_on_conflict then forces the checkout and cleans the tree.
+async fn detach(
+    config: &AppConfig,
+    commit: &str,
+    timings: &mut metrics::Timings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = &config.repo_path;
+    let started = Instant::now();
+    let checked_out = git::checkout_detached(repo_path, commit, false).await;
+    let forced = match checked_out {
+        Ok(()) => false,
+        Err(e) if config.reset_on_conflict => {
+            warn!("Checkout of {} failed ({}), forcing it.", commit, e);
+            git::checkout_detached(repo_path, commit, true).await?;
+            true
+        }
+        Err(e) => return Err(e),
+    };
+    metrics::record(timings, "pull", started);
+    info!("Checked out {} (detached).", commit);
+
+    if forced || config.clean_untracked {
+        git::clean(repo_path, config.clean_ignored, &config.preserve_paths).await?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging to a file
-    CombinedLogger::init(vec![WriteLogger::new(
-        LevelFilter::Info,
-        simplelog::Config::default(),
-        File::create("app.log").unwrap(),
-    )])?;
+    // Handle one-off subcommands before the log file is touched
+    let mut args: Vec<String> = std::env::args().collect();
+    let console_mode = console::take_mode_flag(&mut args)?;
+    if let Some(command) = args.get(1) {
+        match command.as_str() {
+            "encrypt-secret" => return secrets::encrypt_secret_command(&args[2..]),
+            "store-identity" => return secrets::store_identity_command(&args[2..]),
+            "completions" => return completions::completions_command(&args[2..]),
+            "init" => return init_command(&args[2..]).await,
+            "list-repos" => return list_repos_command(&args[2..]).await,
+            "discover" => return discover_command(&args[2..]).await,
+            "register-webhook" => return register_webhook_command(&args[2..]).await,
+            "rollback" => return rollback_command(&args[2..]),
+            "audit" => return audit_command(&args[2..]),
+            "stats" => return stats_command(&args[2..]),
+            "history" => return history_command(&args[2..]),
+            "config-schema" => {
+                print!("{}", schema::reference(&config_sections()));
+                return Ok(());
+            }
+            "--version" | "-V" | "version" => {
+                println!("{}", version::long_version());
+                return Ok(());
+            }
+            "reload-credentials" => {
+                let mut configs = parse_configs(Path::new("config.toml"))?;
+                let listen = configs[0]
+                    .control_listen
+                    .clone()
+                    .ok_or("control_listen is not set in config.toml")?;
+                resolve_control_tokens(&mut configs[0])?;
+                let client = control_client(&configs[0])?;
+                println!(
+                    "{}",
+                    control::send_command(
+                        &client,
+                        &listen,
+                        "/reload-credentials",
+                        &configs[0].control_tokens
+                    )
+                    .await?
+                );
+                return Ok(());
+            }
+            command @ ("approve-force-push" | "approve-terraform") => {
+                return approve_command(command, &args[2..]).await
+            }
+            "simulate" => return simulate_command(&args[2..], console_mode).await,
+            other => return Err(format!("Unknown command: {}", other).into()),
+        }
+    }
+
+    // Initialize logging to a file, mirrored to the console as --quiet / --verbose ask
+    logging::init(console_mode)?;
+    console::init(console_mode);
 
     info!("Starting application");
 
-    let config = read_config()?;
-    let mut last_change_time = SystemTime::now();
+    let mut configs = read_configs()?;
+    let feeds = read_feeds()?;
+    logging::configure(
+        configs[0].log_format,
+        &configs
+            .iter()
+            .filter_map(|config| config.log_file.as_deref())
+            .chain(feeds.iter().filter_map(|feed| feed.log_file.as_deref()))
+            .collect::<Vec<_>>(),
+    )?;
+    // One client for every repo
+    let azure_client = shared_client(&configs[0])?;
+    state::set_dir(PathBuf::from(&configs[0].state_dir));
+
+    if let Err(e) = git::init(configs[0].git_path.as_deref()).await {
+        error!("{}", e);
+        return Err(e);
+    }
+
+    // Repositories set by ID get their current name, those set by name get their ID
+    for config in &mut configs {
+        if let Err(e) = config.resolve_repository(&azure_client).await {
+            if config.repository.is_empty() {
+                let e = format!(
+                    "Failed to look up repository {}: {}",
+                    config.api_repository(),
+                    e
+                );
+                error!("{}", e);
+                return Err(e.into());
+            }
+            warn!(
+                "Failed to look up repository '{}', going by its name: {}",
+                config.repository, e
+            );
+        }
+    }
+
+    if configs[0].announce {
+        let mut details = vec![
+            ("version".to_string(), version::VERSION.to_string()),
+            ("repos".to_string(), configs.len().to_string()),
+        ];
+        let labels = configs[0].agent_labels.join(",");
+        if !labels.is_empty() {
+            details.push(("labels".to_string(), labels));
+        }
+        tokio::spawn(discovery::announce(discovery::Announcement {
+            name: notify::host_name(),
+            // The control endpoint is where a technician goes next, e.g. for /metrics
+            port: configs[0]
+                .control_listen
+                .as_deref()
+                .and_then(|listen| listen.rsplit_once(':'))
+                .and_then(|(_, port)| port.parse().ok())
+                .unwrap_or(0),
+            details,
+        }));
+    }
+
+    if configs[0].check_for_updates {
+        let feed_url = configs[0]
+            .update_feed_url
+            .clone()
+            .unwrap_or_else(|| version::DEFAULT_UPDATE_FEED.to_string());
+        tokio::spawn(version::watch_for_updates(azure_client.clone(), feed_url));
+    }
+
+    let (control_tx, _) = broadcast::channel(16);
+    if let Some(listen) = configs[0].control_listen.clone() {
+        tokio::spawn(control::serve(
+            listen,
+            control_tx.clone(),
+            configs[0].control_tokens.clone(),
+            configs[0]
+                .webhook
+                .as_ref()
+                .map(|webhook| webhook.secret.clone()),
+            configs
+                .iter()
+                .map(|config| control::RepoKey::new(&config.project, &config.repository))
+                .collect(),
+        ));
+    }
+
+    // Each repo gets its own loop; they share the thread, so their state needn't be Send
+    let several = configs.len() > 1;
+    // One lock per concurrency_group, held by whichever of its repos is syncing
+    let mut groups: HashMap<String, Rc<Mutex<()>>> = HashMap::new();
+    let order: Vec<(String, Vec<String>)> = configs
+        .iter()
+        .map(|config| (config.repository.clone(), config.after.clone()))
+        .collect();
+    let links = ordering::link(&order)?;
+    // A member holding its group's turn while it waits for the batch would stall the others
+    for (index, config) in configs.iter().enumerate() {
+        if let (Some(name), Some(group)) = (&config.batch, &config.concurrency_group) {
+            if let Some(other) = configs[index + 1..].iter().find(|other| {
+                other.batch.as_ref() == Some(name)
+                    && other.concurrency_group.as_ref() == Some(group)
+            }) {
+                return Err(format!(
+                    "{} and {} are in both batch '{}' and concurrency_group '{}'; a batch already waits for all its members, so drop the group",
+                    config.repository, other.repository, name, group
+                )
+                .into());
+            }
+        }
+    }
+    let batches = batch::form(
+        &configs
+            .iter()
+            .map(|config| (config.repository.clone(), config.batch.clone()))
+            .collect::<Vec<_>>(),
+    );
+    let local = LocalSet::new();
+    let mut loops: Vec<_> = configs
+        .into_iter()
+        .zip(links)
+        .zip(batches)
+        .map(|((config, links), batch)| {
+            let group = config.concurrency_group.as_ref().map(|name| {
+                let lock = groups.entry(name.clone()).or_default().clone();
+                (name.clone(), lock)
+            });
+            local.spawn_local(logging::in_repo(
+                config.repository.clone(),
+                config.target_branch.clone(),
+                config.log_file.clone(),
+                sync_repo(
+                    config,
+                    azure_client.clone(),
+                    control_tx.subscribe(),
+                    group,
+                    links,
+                    batch,
+                    several,
+                ),
+            ))
+        })
+        .collect();
+    // Package feeds are watched alongside the repos, each in a loop of its own
+    for feed in feeds {
+        info!(
+            "Feed: {} from '{}' into {}.",
+            feed.package, feed.feed, feed.directory
+        );
+        loops.push(local.spawn_local(logging::in_repo(
+            feed.package.clone(),
+            feed.feed.clone(),
+            feed.log_file.clone(),
+            watch_feed(feed, azure_client.clone(), control_tx.subscribe()),
+        )));
+    }
+
+    local
+        .run_until(async move {
+            let mut result = Ok(());
+            for repo_loop in loops {
+                match repo_loop.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        error!("{}", e);
+                        result = result.and(Err(e));
+                    }
+                    Err(e) => result = result.and(Err(e.into())),
+                }
+            }
+            result
+        })
+        .await
+}
+
+// Checks one repository and syncs it whenever its remote branch moves, until the process stops
+async fn sync_repo(
+    config: AppConfig,
+    azure_client: Client,
+    mut control_rx: broadcast::Receiver<control::ControlCommand>,
+    group: Option<(String, Rc<Mutex<()>>)>,
+    links: ordering::Links,
+    batch: Option<batch::Batch>,
+    several: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut repo =
+        cycle::RepoSync::start(config, azure_client, group, links, batch, several).await?;
+    loop {
+        repo.run_cycle(&mut control_rx).await?;
+    }
+}
+
+// Replaces the state file, if one is configured; a failed write is only logged
+fn write_state(
+    config: &AppConfig,
+    commit: Option<&str>,
+    remote_commit: Option<&str>,
+    status: state::Status,
+    error: Option<&str>,
+    last_change_at: DateTime<Utc>,
+) {
+    let Some(path) = &config.state_file else {
+        return;
+    };
+    let written = state::write(
+        path,
+        &state::SyncState {
+            repository: &config.repository,
+            branch: &config.target_branch,
+            commit,
+            remote_commit,
+            status,
+            error,
+            last_change: state::timestamp(last_change_at),
+            updated_at: state::timestamp(Utc::now()),
+        },
+    );
+    if let Err(e) = written {
+        error!("Failed to write the state file {}: {}", path, e);
+    }
+}
+
+// Checks one package feed and deploys every new version of its package, until the process stops
+async fn watch_feed(
+    mut feed: feeds::FeedConfig,
+    azure_client: Client,
+    mut control_rx: broadcast::Receiver<control::ControlCommand>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut installed = feed.installed_version();
+    match &installed {
+        Some(version) => info!(
+            "{} {} is installed in {}.",
+            feed.package, version, feed.directory
+        ),
+        None => info!(
+            "No version of {} is installed in {} yet.",
+            feed.package, feed.directory
+        ),
+    }
+    // History, notifications and alerts follow the feed through the same events as a repo
+    let mut events = events::EventBus::default();
+    events.subscribe(history::HistoryLog::new(feed.history_file.clone()));
+    events.subscribe(notify::Notifier::new(
+        std::mem::take(&mut feed.notifications),
+        azure_client.clone(),
+    ));
+    events.subscribe(alert::Alerts::new(
+        std::mem::take(&mut feed.alerts),
+        azure_client.clone(),
+    ));
+    // Feeds take no part in after = [...] ordering
+    let links = ordering::Links::default();
 
     loop {
-        match get_latest_commit(&config).await {
-            Ok(remote_commit) => match get_local_commit(&config.repo_path) {
-                Ok(local_commit) => {
-                    if remote_commit != local_commit {
-                        info!("New changes detected. Pulling updates...");
-                        if let Err(e) = pull_changes(&config) {
-                            error!("Failed to pull changes: {}", e);
-                        } else {
-                            last_change_time = SystemTime::now();
+        logging::start_cycle();
+        let mut timings = metrics::Timings::new();
+        events
+            .publish(SyncEvent::SyncStarted, &feed.repo_ref())
+            .await;
+        let started = Instant::now();
+        let latest = feed.latest_version(&azure_client).await;
+        metrics::record(&mut timings, "api_check", started);
+        match latest {
+            Ok(latest) if installed.as_deref() == Some(latest.as_str()) => {
+                events
+                    .publish(SyncEvent::UpToDate { commit: &latest }, &feed.repo_ref())
+                    .await;
+            }
+            Ok(latest) => {
+                let old = installed.clone().unwrap_or_default();
+                info!("New version of {} detected: {}.", feed.package, latest);
+                let sync_started = Instant::now();
+                let mut record = history::SyncRecord::new(&old, &latest);
+                events
+                    .publish(
+                        SyncEvent::ChangesDetected {
+                            old_commit: &old,
+                            new_commit: &latest,
+                            commits: &[],
+                        },
+                        &feed.repo_ref(),
+                    )
+                    .await;
+
+                let started = Instant::now();
+                let result = feed.install(&azure_client, &latest).await;
+                metrics::record(&mut timings, "download", started);
+                match result {
+                    Err(e) => {
+                        error!("Failed to install {} {}: {}", feed.package, latest, e);
+                        record.status = history::SyncStatus::PullFailed;
+                        record.error = Some(e.to_string());
+                        events
+                            .publish(
+                                SyncEvent::PullFailed {
+                                    error: &e.to_string(),
+                                },
+                                &feed.repo_ref(),
+                            )
+                            .await;
+                    }
+                    Ok(()) => {
+                        installed = Some(latest.clone());
+                        // Hooks run in order and stop at the first failure, as after a pull
+                        let started = Instant::now();
+                        let vars = feed.hook_vars(&old, &latest);
+                        for hook in &feed.hooks {
+                            let result = hooks::run_hook(hook, &feed.directory, &vars).await;
+                            let succeeded = result.succeeded();
+                            record.hooks.push(result);
+                            if !succeeded {
+                                record.status = history::SyncStatus::PostSyncFailed;
+                                record.error = Some(format!("Hook '{}' failed", hook.label()));
+                                break;
+                            }
+                        }
+                        metrics::record(&mut timings, "hooks", started);
+                        for hook in record.hooks.iter().filter(|hook| !hook.succeeded()) {
+                            events
+                                .publish(SyncEvent::HookFailed { hook }, &feed.repo_ref())
+                                .await;
+                        }
+                        if record.status == history::SyncStatus::Success {
+                            let summary = format!(
+                                "Deployed {} {} in {:.1}s.",
+                                feed.package,
+                                latest,
+                                sync_started.elapsed().as_secs_f64()
+                            );
+                            info!("{}", summary);
+                            console::line(&summary);
                         }
-                    } else {
-                        let elapsed = last_change_time.elapsed()?.as_secs();
-                        let last_change_time: DateTime<Utc> = last_change_time.into();
-                        let formatted_time = last_change_time.format("%Y-%m-%d %H:%M:%S");
-                        print!(
-                            "\rNo new changes since {}. Elapsed time: {} seconds.",
-                            formatted_time, elapsed
-                        );
-                        io::stdout().flush()?;
                     }
                 }
-                Err(e) => {
-                    error!("Failed to get local commit: {}", e);
+
+                record.timings = timings.clone();
+                events
+                    .publish(
+                        SyncEvent::SyncFinished { record: &record },
+                        &feed.repo_ref(),
+                    )
+                    .await;
+            }
+            Err(e) => {
+                error!("Failed to check feed '{}': {}", feed.feed, e);
+                events
+                    .publish(
+                        SyncEvent::CheckFailed {
+                            error: &e.to_string(),
+                        },
+                        &feed.repo_ref(),
+                    )
+                    .await;
+                if e.is::<secrets::AuthError>() {
+                    reload_feed_credentials(&mut feed);
                 }
+            }
+        }
+        info!("Cycle timings: {}", metrics::summary(&timings));
+
+        match wait_for_next_check(
+            Duration::from_secs(feed.check_interval_seconds),
+            &control::RepoKey::new(&feed.project, &feed.package),
+            &mut control_rx,
+            &links,
+            None,
+        )
+        .await
+        {
+            Wake::Due => {}
+            Wake::Resumed(gap) => info!(
+                "Resumed after a suspected suspend of about {} seconds, checking now.",
+                gap.as_secs()
+            ),
+            Wake::Command(control::ControlCommand::ReloadCredentials) => {
+                reload_feed_credentials(&mut feed)
+            }
+            // Pushes are about repositories, not feeds
+            Wake::Command(
+                control::ControlCommand::CheckNow(_)
+                | control::ControlCommand::ApproveForcePush(_)
+                | control::ControlCommand::ApproveTerraform(_),
+            ) => {}
+        }
+    }
+}
+
+// How often a commit's builds are looked up again while they run
+const BUILD_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often the provider is probed again while it's unreachable
+const OFFLINE_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Longest single sleep while waiting for the next check, so a resume is noticed within it
+const WAIT_SLICE: Duration = Duration::from_secs(5);
+
+// A sleep overrunning by this much means the machine was most likely suspended
+const SUSPEND_GAP: Duration = Duration::from_secs(30);
+
+// Why the wait for the next check ended
+enum Wake {
+    // The interval is over
+    Due,
+    // The machine seems to have been asleep for about this long, so the check shouldn't wait
+    Resumed(Duration),
+    Command(control::ControlCommand),
+}
+
+// Waits out the interval in short slices. The monotonic clock may stand still while the machine
+// sleeps, so a slice that took far longer by either clock means a suspend, and the check is
+// due right away rather than a full interval after resuming.
+async fn wait_for_next_check(
+    interval: Duration,
+    repo: &control::RepoKey,
+    control_rx: &mut broadcast::Receiver<control::ControlCommand>,
+    links: &ordering::Links,
+    batch: Option<&batch::Batch>,
+) -> Wake {
+    let deadline = Instant::now() + interval;
+    loop {
+        let slice_started = Instant::now();
+        let Some(remaining) = deadline.checked_duration_since(slice_started) else {
+            return Wake::Due;
+        };
+        let slice = remaining.min(WAIT_SLICE);
+        let wall_started = Utc::now();
+        tokio::select! {
+            _ = sleep(slice) => {}
+            Ok(command) = control_rx.recv() => match command {
+                // A push to another repo, or an approval for one, is none of this loop's business
+                control::ControlCommand::CheckNow(pushed)
+                | control::ControlCommand::ApproveForcePush(pushed)
+                | control::ControlCommand::ApproveTerraform(pushed)
+                    if !pushed.is(repo) => {}
+                command => return Wake::Command(command),
             },
-            Err(e) => {
-                error!("Failed to get latest commit from remote: {}", e);
+            // A repo this one syncs after or before wants a check now
+            _ = links.check_requested() => return Wake::Due,
+            // Another member of the batch found changes
+            _ = async {
+                match batch {
+                    Some(batch) => batch.check_requested().await,
+                    None => std::future::pending().await,
+                }
+            } => return Wake::Due,
+        }
+
+        let wall = (Utc::now() - wall_started).to_std().unwrap_or_default();
+        let overrun = wall.max(slice_started.elapsed()).saturating_sub(slice);
+        if overrun >= SUSPEND_GAP {
+            return Wake::Resumed(overrun);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies;
+    use proptest::prelude::*;
+
+    // The keys every repository needs, with generated values
+    fn required() -> impl Strategy<Value = toml::Table> {
+        (
+            strategies::path(),
+            strategies::name(),
+            strategies::name(),
+            strategies::name(),
+            strategies::branch(),
+            strategies::secret(),
+        )
+            .prop_map(
+                |(repo_path, organization, project, repository, branch, pat)| {
+                    let mut table = toml::Table::new();
+                    for (key, value) in [
+                        ("repo_path", repo_path),
+                        ("organization", organization),
+                        ("project", project),
+                        ("repository", repository),
+                        ("target_branch", branch),
+                        ("pat", pat),
+                    ] {
+                        table.insert(key.to_string(), toml::Value::String(value));
+                    }
+                    table.insert(
+                        "check_interval_seconds".to_string(),
+                        toml::Value::Integer(60),
+                    );
+                    table
+                },
+            )
+    }
+
+    fn matches(config: &AppConfig, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in [
+            ("repo_path", &config.repo_path),
+            ("organization", &config.organization),
+            ("project", &config.project),
+            ("repository", &config.repository),
+            ("target_branch", &config.target_branch),
+            ("pat", &config.pat),
+        ] {
+            if table[key].as_str() != Some(value.as_str()) {
+                return Err(format!("{} {:?} was read as {:?}", key, table[key], value));
             }
         }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn config_values_survive_parsing(table in required()) {
+            let text = toml::to_string(&table).unwrap();
+            let configs = parse_config_text(&text).map_err(|e| e.to_string()).unwrap();
+            prop_assert_eq!(matches(&configs[0], &table), Ok(()));
+        }
+
+        #[test]
+        fn repos_entries_keep_their_own_values(shared in required(), mut entry in required()) {
+            entry.remove("pat");
+            let mut root = shared.clone();
+            root.insert(
+                "repos".to_string(),
+                toml::Value::Array(vec![toml::Value::Table(entry.clone())]),
+            );
+            let text = toml::to_string(&root).unwrap();
+            let configs = parse_config_text(&text).map_err(|e| e.to_string()).unwrap();
+            // The entry's values win, the PAT it leaves out comes from the top level
+            entry.insert("pat".to_string(), shared["pat"].clone());
+            prop_assert_eq!(matches(&configs[0], &entry), Ok(()));
+        }
+    }
+
+    #[test]
+    fn credential_reload_finds_the_entry_after_the_names_were_resolved() {
+        let text = r#"
+            organization = "contoso"
+            project = "Shop"
+            target_branch = "main"
+            check_interval_seconds = 60
+            pat = "old"
+
+            [[repos]]
+            repo_path = "/srv/a"
+            repository_id = "2F3B1C44-0000-4000-8000-000000000001"
+
+            [[repos]]
+            repo_path = "/srv/b"
+            repository = "web"
+        "#;
+        let mut running = parse_config_text(text).unwrap();
+        for (config, (project, name, id)) in running.iter_mut().zip([
+            ("Shop", "api", "2f3b1c44-0000-4000-8000-000000000001"),
+            ("Retail", "Web", "9a1e0000-0000-4000-8000-000000000002"),
+        ]) {
+            // As resolve_repository leaves them, in the remote's spelling
+            config.project = project.to_string();
+            config.repository = name.to_string();
+            config.repository_id = Some(id.to_string());
+        }
 
-        sleep(Duration::from_secs(config.check_interval_seconds)).await;
+        for config in &running {
+            let fresh = config_entry(parse_config_text(text).unwrap(), config).unwrap();
+            assert_eq!(fresh.repo_path, config.repo_path);
+        }
     }
 }
@@ -0,0 +1,63 @@
+// The deployment manifest: a TOML file in the repository assigning agents, by host name or
+// label, to a branch or a pinned commit, so what each machine runs is decided centrally.
+use crate::glob;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    agents: Vec<Assignment>,
+}
+
+// One [[agents]] entry: the hosts it applies to and the revision they converge to
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct Assignment {
+    // Host name patterns, where * and ? are wildcards, matched case-insensitively
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    // agent_labels it applies to, any one of them being enough
+    #[serde(default)]
+    pub labels: Vec<String>,
+    // Branch to follow instead of target_branch
+    pub branch: Option<String>,
+    // Full commit id to hold at, on the assigned (or configured) branch
+    pub commit: Option<String>,
+}
+
+impl Assignment {
+    fn applies_to(&self, host: &str, labels: &[String]) -> bool {
+        let host = host.to_lowercase();
+        self.hosts
+            .iter()
+            .any(|pattern| glob::matches(&pattern.to_lowercase(), &host))
+            || self.labels.iter().any(|label| labels.contains(label))
+    }
+}
+
+// The first entry of the manifest that applies to the host or one of its labels, if any
+pub fn assignment(
+    text: &str,
+    host: &str,
+    labels: &[String],
+) -> Result<Option<Assignment>, Box<dyn std::error::Error>> {
+    let manifest: Manifest = toml::from_str(text)?;
+    for (index, agent) in manifest.agents.iter().enumerate() {
+        if let Some(commit) = &agent.commit {
+            if commit.len() != 40 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "agents[{}].commit must be a full 40 character commit id, not '{}'",
+                    index, commit
+                )
+                .into());
+            }
+        }
+    }
+    Ok(manifest
+        .agents
+        .into_iter()
+        .find(|agent| agent.applies_to(host, labels))
+        .map(|mut agent| {
+            agent.commit = agent.commit.map(|commit| commit.to_lowercase());
+            agent
+        }))
+}
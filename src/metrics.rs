@@ -0,0 +1,167 @@
+// Timing of the phases of each check cycle, kept per cycle for the log and history and
+// accumulated into Prometheus histograms for the control endpoint's /metrics, next to gauges
+// of each repository's state.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+// Seconds spent in each phase of one cycle
+pub type Timings = BTreeMap<String, f64>;
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+static HISTOGRAMS: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+// Latest value of each gauge per repository
+static GAUGES: Mutex<BTreeMap<(&'static str, String), f64>> = Mutex::new(BTreeMap::new());
+
+// Help text of every gauge that can be set
+const GAUGE_HELP: &[(&str, &str)] = &[
+    (
+        "devops_sync_behind",
+        "1 when the checkout is behind the remote branch, 0 when it is at it.",
+    ),
+    (
+        "devops_sync_online",
+        "1 when the provider answered the last connectivity probe, 0 when it didn't.",
+    ),
+    (
+        "devops_sync_drifted_files",
+        "Differences between the checkout and the last synced commit at the last drift check.",
+    ),
+];
+
+// Labels of this agent, exposed as an info metric so series can be grouped by them
+static AGENT_LABELS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+pub fn add_agent_labels(labels: &[String]) {
+    let mut agent_labels = AGENT_LABELS.lock().unwrap_or_else(|e| e.into_inner());
+    agent_labels.extend(labels.iter().cloned());
+}
+
+// Sets a gauge for one repository
+pub fn set_gauge(name: &'static str, repository: &str, value: f64) {
+    let mut gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner());
+    gauges.insert((name, repository.to_string()), value);
+}
+
+// One word for the state of every repository together, from their gauges: "offline",
+// "behind", "drifted", "in_sync", or "starting" before the first check
+pub fn agent_status() -> &'static str {
+    let gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner());
+    let any = |name: &str, matches: fn(f64) -> bool| {
+        gauges
+            .iter()
+            .any(|((gauge, _), value)| *gauge == name && matches(*value))
+    };
+    if any("devops_sync_online", |value| value == 0.0) {
+        "offline"
+    } else if any("devops_sync_behind", |value| value > 0.0) {
+        "behind"
+    } else if any("devops_sync_drifted_files", |value| value > 0.0) {
+        "drifted"
+    } else if gauges.is_empty() {
+        "starting"
+    } else {
+        "in_sync"
+    }
+}
+
+// Records how long a phase took since it started, for this cycle and the histograms
+pub fn record(timings: &mut Timings, phase: &'static str, started: Instant) {
+    let seconds = started.elapsed().as_secs_f64();
+    *timings.entry(phase.to_string()).or_default() += seconds;
+
+    let mut histograms = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    let histogram = histograms.entry(phase).or_default();
+    if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+        histogram.counts[bucket] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+// One log line for the cycle, e.g. "api_check 0.21s, fetch 1.30s"
+pub fn summary(timings: &Timings) -> String {
+    timings
+        .iter()
+        .map(|(phase, seconds)| format!("{} {:.2}s", phase, seconds))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The histograms and gauges in the Prometheus text exposition format
+pub fn render() -> String {
+    let name = "devops_sync_phase_duration_seconds";
+    let mut out = format!(
+        "# HELP {} Time spent in each phase of a check cycle.\n# TYPE {} histogram\n",
+        name, name
+    );
+
+    let histograms = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    for (phase, histogram) in histograms.iter() {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{phase=\"{}\",le=\"{}\"}} {}",
+                name, phase, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{phase=\"{}\",le=\"+Inf\"}} {}",
+            name, phase, histogram.count
+        );
+        let _ = writeln!(out, "{}_sum{{phase=\"{}\"}} {}", name, phase, histogram.sum);
+        let _ = writeln!(
+            out,
+            "{}_count{{phase=\"{}\"}} {}",
+            name, phase, histogram.count
+        );
+    }
+    drop(histograms);
+
+    let gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, help) in GAUGE_HELP {
+        let mut set = gauges
+            .iter()
+            .filter(|((gauge, _), _)| gauge == name)
+            .peekable();
+        if set.peek().is_none() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+        for ((_, repository), value) in set {
+            let _ = writeln!(out, "{}{{repository=\"{}\"}} {}", name, repository, value);
+        }
+    }
+    drop(gauges);
+
+    let agent_labels = AGENT_LABELS.lock().unwrap_or_else(|e| e.into_inner());
+    if !agent_labels.is_empty() {
+        let name = "devops_sync_agent_label";
+        let _ = writeln!(
+            out,
+            "# HELP {} One series per agent_labels entry of this agent, always 1.\n# TYPE {} gauge",
+            name, name
+        );
+        for label in agent_labels.iter() {
+            let _ = writeln!(out, "{}{{label=\"{}\"}} 1", name, label);
+        }
+    }
+    out
+}
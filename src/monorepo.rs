@@ -0,0 +1,238 @@
+// Virtual repos: path subsets of one checkout that deploy independently, so a monorepo can drive
+// several deployments on one machine from a single clone. The repo is fetched and pulled once;
+// each [[virtual_repos]] entry whose paths the sync touched then runs its own post-sync actions
+// and reports to its own notifications and history, under its own name.
+use crate::events::{EventBus, SyncEvent};
+use crate::git;
+use crate::glob;
+use crate::history::{self, CommitSummary, SyncRecord, SyncStatus};
+use crate::notify::{self, NotificationConfig, RepoRef};
+use crate::post_sync::{self, Cooldowns, PostSyncConfig, SyncContext};
+use crate::schema::{self, Documented, Field};
+use log::{error, info};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+
+// One [[virtual_repos]] entry
+#[derive(Deserialize)]
+pub struct VirtualRepoConfig {
+    // Name it is reported under, in place of the repository name
+    pub name: String,
+    // Globs of the files that belong to it
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub post_sync: PostSyncConfig,
+    #[serde(default)]
+    pub notifications: Vec<NotificationConfig>,
+    // JSON lines file its syncs are appended to, none unless set
+    pub history_file: Option<String>,
+}
+
+impl Documented for VirtualRepoConfig {
+    const SECTION: &'static str = "[[virtual_repos]]";
+    const ABOUT: &'static str = "Path subsets of the repository deployed independently, each with its own post-sync actions, notifications and history. Inside [[repos]], written [[repos.virtual_repos]].";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "name",
+            "string",
+            r#""api""#,
+            "Name used in logs, notifications and history instead of the repository name",
+        ),
+        schema::required(
+            "paths",
+            "list of globs",
+            r#"["services/api/**", "libs/common/**"]"#,
+            "Files that belong to it; a sync touching none of them leaves it alone",
+        ),
+        schema::defaulted(
+            "post_sync",
+            "[post_sync] table",
+            "none",
+            "Hooks, restarts and compose redeploy run in the checkout when its files change; compose needs rollback_on_failure = false",
+        ),
+        schema::defaulted(
+            "notifications",
+            "list of [[notifications]] tables",
+            "[]",
+            "Where its syncs are reported, with only the commits touching its paths",
+        ),
+        schema::optional(
+            "history_file",
+            "string",
+            r#""api_history.jsonl""#,
+            "File its sync records are appended to",
+        ),
+    ];
+}
+
+impl VirtualRepoConfig {
+    // A compose rollback resets the shared checkout, which would undo the sync for every other
+    // virtual repo and for the repo itself
+    pub fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.paths.is_empty() {
+            return Err(format!("virtual repo '{}' has no paths", self.name).into());
+        }
+        if self
+            .post_sync
+            .compose
+            .as_ref()
+            .is_some_and(|compose| compose.rollback_on_failure)
+        {
+            return Err(format!(
+                "virtual repo '{}': compose rollback_on_failure would reset the whole checkout; set it to false",
+                self.name
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+// A virtual repo with what it keeps between syncs
+pub struct VirtualRepo {
+    config: VirtualRepoConfig,
+    events: EventBus,
+    cooldowns: Cooldowns,
+    // The agent's shared HTTP client, for health checks of deferred restarts
+    client: Client,
+}
+
+impl VirtualRepo {
+    pub fn new(mut config: VirtualRepoConfig, client: &Client) -> Self {
+        let mut events = EventBus::default();
+        events.subscribe(notify::Notifier::new(
+            std::mem::take(&mut config.notifications),
+            client.clone(),
+        ));
+        if let Some(path) = &config.history_file {
+            events.subscribe(history::HistoryLog::new(path.clone()));
+        }
+        VirtualRepo {
+            config,
+            events,
+            cooldowns: Cooldowns::default(),
+            client: client.clone(),
+        }
+    }
+
+    // Runs its post-sync actions and reports the sync, if the sync touched its paths. `commits`
+    // are the pulled commits, cut down to the ones touching its paths.
+    pub async fn deploy(
+        &mut self,
+        repo: &RepoRef<'_>,
+        context: &SyncContext<'_>,
+        changed: &[String],
+        commits: &[CommitSummary],
+    ) {
+        if !changed
+            .iter()
+            .any(|file| glob::matches_any(&self.config.paths, file))
+        {
+            info!(
+                "Virtual repo '{}' unchanged, nothing to deploy.",
+                self.config.name
+            );
+            return;
+        }
+        info!("Deploying virtual repo '{}'.", self.config.name);
+
+        let mut record = SyncRecord::new(context.old_commit, context.new_commit);
+        match git::commit_files(context.repo_path, context.old_commit, context.new_commit).await {
+            Ok(files) => {
+                record.commits = commits
+                    .iter()
+                    .filter(|commit| {
+                        files.iter().any(|touched| {
+                            touched.id == commit.id
+                                && touched
+                                    .files
+                                    .iter()
+                                    .any(|file| glob::matches_any(&self.config.paths, file))
+                        })
+                    })
+                    .cloned()
+                    .collect()
+            }
+            Err(e) => error!("Failed to list the files of the pulled commits: {}", e),
+        }
+
+        let result = post_sync::run(
+            &self.config.post_sync,
+            context,
+            &mut self.cooldowns,
+            &mut record.hooks,
+        )
+        .await;
+        if let Err(e) = result {
+            error!(
+                "Post-sync actions of virtual repo '{}' failed: {}",
+                self.config.name, e
+            );
+            record.status = SyncStatus::PostSyncFailed;
+            record.error = Some(e.to_string());
+        }
+        self.report(repo, &record).await;
+    }
+
+    pub fn post_sync(&self) -> &PostSyncConfig {
+        &self.config.post_sync
+    }
+
+    // Runs its hooks and restarts whose min_interval_seconds wait is over
+    pub async fn run_deferred(&mut self, repo: &RepoRef<'_>, repo_path: &str, commit: &str) {
+        if self
+            .cooldowns
+            .next_due(&self.config.post_sync)
+            .is_none_or(|wait| !wait.is_zero())
+        {
+            return;
+        }
+        let mut hooks = Vec::new();
+        if let Err(e) = post_sync::run_deferred(
+            &self.config.post_sync,
+            &self.client,
+            repo_path,
+            repo.branch,
+            commit,
+            &mut self.cooldowns,
+            &mut hooks,
+        )
+        .await
+        {
+            error!(
+                "Deferred post-sync actions of virtual repo '{}' failed: {}",
+                self.config.name, e
+            );
+        }
+        let repo = RepoRef {
+            repository: &self.config.name,
+            ..*repo
+        };
+        for hook in hooks.iter().filter(|hook| !hook.succeeded()) {
+            self.events
+                .publish(SyncEvent::HookFailed { hook }, &repo)
+                .await;
+        }
+    }
+
+    // When its first deferred action is due
+    pub fn next_due(&self) -> Option<Duration> {
+        self.cooldowns.next_due(&self.config.post_sync)
+    }
+
+    async fn report(&mut self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        let repo = RepoRef {
+            repository: &self.config.name,
+            ..*repo
+        };
+        for hook in record.hooks.iter().filter(|hook| !hook.succeeded()) {
+            self.events
+                .publish(SyncEvent::HookFailed { hook }, &repo)
+                .await;
+        }
+        self.events
+            .publish(SyncEvent::SyncFinished { record }, &repo)
+            .await;
+    }
+}
@@ -0,0 +1,309 @@
+// Cheap reachability checks of the provider, so a machine without network waits quietly
+// instead of failing every check, and the name resolution every connection goes through:
+// optionally one address family only, or DNS servers other than the system's.
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+    ServerOrderingStrategy,
+};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::TokioAsyncResolver;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+
+// How long the lookup and the connection may each take before the host counts as unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Why the provider couldn't be reached
+#[derive(Debug)]
+pub enum Unreachable {
+    // The name didn't resolve, typically no network or DNS at all
+    Dns(String),
+    // The name resolved but no address accepted a connection
+    Connect(String),
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unreachable::Dns(e) => write!(f, "DNS lookup failed: {}", e),
+            Unreachable::Connect(e) => write!(f, "TCP connection failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+// Resolves the host and opens (then drops) a TCP connection to one of its addresses
+pub async fn probe(host: &str, port: u16, resolution: &Resolution) -> Result<(), Unreachable> {
+    let addresses = match timeout(PROBE_TIMEOUT, resolution.lookup(host, port)).await {
+        Ok(Ok(addresses)) => addresses,
+        Ok(Err(e)) => return Err(Unreachable::Dns(e)),
+        Err(_) => return Err(Unreachable::Dns("timed out".to_string())),
+    };
+    if addresses.is_empty() {
+        return Err(Unreachable::Dns(format!("no addresses for {}", host)));
+    }
+
+    let mut last_error = String::new();
+    for address in addresses {
+        match timeout(PROBE_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => last_error = format!("{}: {}", address, e),
+            Err(_) => last_error = format!("{}: timed out", address),
+        }
+    }
+    Err(Unreachable::Connect(last_error))
+}
+
+// Address family used for outgoing connections
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    // Whatever the name resolves to, as the system would connect
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpVersion {
+    fn allows(self, address: &IpAddr) -> bool {
+        match self {
+            IpVersion::Any => true,
+            IpVersion::Ipv4 => address.is_ipv4(),
+            IpVersion::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+// Port DNS servers listen on when dns_servers doesn't name one
+const DNS_PORT: u16 = 53;
+
+// How long one DNS server may take to answer before the next one is asked
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How names are turned into addresses, from the ip_version and dns_servers settings
+#[derive(Clone, Debug, Default)]
+pub struct Resolution {
+    ip_version: IpVersion,
+    // Asks the dns_servers in order instead of the system resolver; None for the system resolver
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl Resolution {
+    // Accepts servers as "10.0.0.53", "10.0.0.53:5353", "fd00::53" or "[fd00::53]:5353"
+    pub fn new(
+        ip_version: IpVersion,
+        servers: &[String],
+    ) -> Result<Resolution, Box<dyn std::error::Error>> {
+        let servers = servers
+            .iter()
+            .map(|server| {
+                server
+                    .parse::<SocketAddr>()
+                    .or_else(|_| {
+                        server
+                            .parse::<IpAddr>()
+                            .map(|ip| SocketAddr::new(ip, DNS_PORT))
+                    })
+                    .map_err(|_| format!("dns_servers: '{}' is not an IP address", server))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let resolver = (!servers.is_empty()).then(|| resolver(&servers, ip_version));
+        Ok(Resolution {
+            ip_version,
+            resolver,
+        })
+    }
+
+    // Whether anything differs from resolving and connecting the way the system does
+    pub fn is_custom(&self) -> bool {
+        self.ip_version != IpVersion::Any || self.resolver.is_some()
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    pub fn uses_own_servers(&self) -> bool {
+        self.resolver.is_some()
+    }
+
+    // Addresses of the host, of the allowed family only
+    pub async fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+        let addresses: Vec<SocketAddr> = match &self.resolver {
+            None => lookup_host((host, port))
+                .await
+                .map_err(|e| e.to_string())?
+                .filter(|address| self.ip_version.allows(&address.ip()))
+                .collect(),
+            // Health checks of local services go through the same client, and the configured
+            // servers needn't know localhost
+            Some(_) if is_localhost(host) => [
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                IpAddr::from(Ipv6Addr::LOCALHOST),
+            ]
+            .into_iter()
+            .filter(|ip| self.ip_version.allows(ip))
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect(),
+            Some(resolver) => match resolver.lookup_ip(host).await {
+                Ok(found) => found
+                    .iter()
+                    .filter(|ip| self.ip_version.allows(ip))
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect(),
+                // No records is reported below, like an empty answer from the system
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Vec::new(),
+                Err(e) => return Err(e.to_string()),
+            },
+        };
+        if addresses.is_empty() {
+            return Err(match self.ip_version {
+                IpVersion::Any => format!("no addresses for {}", host),
+                IpVersion::Ipv4 => format!("no IPv4 addresses for {}", host),
+                IpVersion::Ipv6 => format!("no IPv6 addresses for {}", host),
+            });
+        }
+        Ok(addresses)
+    }
+}
+
+// A resolver asking the servers in the order given, over UDP and again over TCP when an answer
+// comes back truncated. Only the records of the allowed family are asked for.
+fn resolver(servers: &[SocketAddr], ip_version: IpVersion) -> TokioAsyncResolver {
+    let mut config = ResolverConfig::new();
+    for server in servers {
+        config.add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+        config.add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+    }
+    let mut options = ResolverOpts::default();
+    options.timeout = DNS_TIMEOUT;
+    options.attempts = 1;
+    options.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+    options.ip_strategy = match ip_version {
+        IpVersion::Any => LookupIpStrategy::Ipv4AndIpv6,
+        IpVersion::Ipv4 => LookupIpStrategy::Ipv4Only,
+        IpVersion::Ipv6 => LookupIpStrategy::Ipv6Only,
+    };
+    // Names are looked up as given, not completed with the machine's search domains
+    options.ndots = 0;
+    TokioAsyncResolver::tokio(config, options)
+}
+
+// Names that always mean this machine (RFC 6761)
+fn is_localhost(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    host == "localhost" || host.ends_with(".localhost")
+}
+
+// Lets the HTTP client resolve names the same way
+impl Resolve for Resolution {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolution = self.clone();
+        Box::pin(async move {
+            // The connector fills in the port of the URL
+            let addresses = resolution.lookup(name.as_str(), 0).await?;
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::{rdata, RData, Record, RecordType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    // The answer to a query: a CNAME to edge.dev.azure.com and its A record
+    fn answer(query: &[u8], truncated: bool) -> Vec<u8> {
+        let query = Message::from_vec(query).unwrap();
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_recursion_desired(true)
+            .set_recursion_available(true)
+            .set_truncated(truncated)
+            .set_response_code(ResponseCode::NoError)
+            .add_queries(query.queries().to_vec());
+        let question = &query.queries()[0];
+        if !truncated && question.query_type() == RecordType::A {
+            let edge = "edge.dev.azure.com.".parse().unwrap();
+            response.add_answer(Record::from_rdata(
+                question.name().clone(),
+                60,
+                RData::CNAME(rdata::CNAME(edge)),
+            ));
+            response.add_answer(Record::from_rdata(
+                "edge.dev.azure.com.".parse().unwrap(),
+                60,
+                RData::A(rdata::A(Ipv4Addr::new(13, 107, 42, 20))),
+            ));
+        }
+        response.to_vec().unwrap()
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answers_are_asked_again_over_tcp() {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(server).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut request = [0u8; 512];
+            loop {
+                let (length, client) = udp.recv_from(&mut request).await.unwrap();
+                udp.send_to(&answer(&request[..length], true), client)
+                    .await
+                    .unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tcp.accept().await.unwrap();
+                tokio::spawn(async move {
+                    while let Ok(length) = stream.read_u16().await {
+                        let mut request = vec![0u8; length as usize];
+                        stream.read_exact(&mut request).await.unwrap();
+                        let response = answer(&request, false);
+                        stream.write_u16(response.len() as u16).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let resolution = Resolution::new(IpVersion::Ipv4, &[server.to_string()]).unwrap();
+        assert_eq!(
+            resolution.lookup("dev.azure.com", 443).await,
+            Ok(vec![SocketAddr::from(([13, 107, 42, 20], 443))])
+        );
+    }
+
+    #[test]
+    fn servers_need_an_ip_address() {
+        assert!(Resolution::new(IpVersion::Any, &["[fd00::53]:5353".to_string()]).is_ok());
+        assert!(Resolution::new(IpVersion::Any, &["dns.contoso.com".to_string()]).is_err());
+        assert!(!Resolution::new(IpVersion::Any, &[]).unwrap().is_custom());
+    }
+
+    #[tokio::test]
+    async fn localhost_resolves_without_asking_the_servers() {
+        // Nothing listens on the discard port, so a query would fail
+        let resolution = Resolution::new(IpVersion::Ipv4, &["127.0.0.1:9".to_string()]).unwrap();
+        assert_eq!(
+            resolution.lookup("localhost", 8080).await,
+            Ok(vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 8080))])
+        );
+        assert!(is_localhost("app.localhost."));
+        assert!(!is_localhost("localhost.contoso.com"));
+    }
+}
@@ -0,0 +1,815 @@
+use crate::azure;
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::{CommitSummary, SyncRecord, SyncStatus};
+use crate::schema::{self, Documented, Field};
+use crate::template::{self, Fields};
+use crate::terraform;
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+
+// A destination for sync notifications
+#[derive(Deserialize)]
+pub struct NotificationConfig {
+    pub kind: NotifierKind,
+    // Webhook URL (Slack, Teams, Discord)
+    #[serde(default)]
+    pub url: String,
+    // Telegram bot token and the chat to post into
+    pub bot_token: Option<String>,
+    pub chat_id: Option<String>,
+    // Sync outcomes to notify about: "success", "failure" (any failure) or a specific status.
+    // Empty means every outcome.
+    #[serde(default)]
+    pub events: Vec<String>,
+    // Message templates, falling back to the built-in ones when unset
+    pub success_template: Option<String>,
+    pub failure_template: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    Slack,
+    Teams,
+    Discord,
+    Telegram,
+}
+
+impl NotificationConfig {
+    // Whether this notifier is subscribed to the outcome
+    fn wants(&self, status: SyncStatus) -> bool {
+        self.events.is_empty()
+            || self.events.iter().any(|event| {
+                event == status_name(status)
+                    || (event == "failure" && status != SyncStatus::Success)
+            })
+    }
+}
+
+impl Documented for NotificationConfig {
+    const SECTION: &'static str = "[[notifications]]";
+    const ABOUT: &'static str = "Where to send sync notifications, one block per destination.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "kind",
+            "\"slack\", \"teams\", \"discord\" or \"telegram\"",
+            r#""slack""#,
+            "Chat service to post to",
+        ),
+        schema::optional(
+            "url",
+            "string",
+            r#""https://hooks.slack.com/services/...""#,
+            "Incoming webhook or workflow URL (not used by telegram)",
+        ),
+        schema::optional("bot_token", "string", r#""123456:ABC...""#, "Telegram only: bot token"),
+        schema::optional("chat_id", "string", r#""-1001234567890""#, "Telegram only: chat to post into"),
+        schema::defaulted(
+            "events",
+            "list of strings",
+            "[]",
+            "\"success\", \"failure\" or specific statuses to notify about (empty means every outcome)",
+        ),
+        schema::optional(
+            "success_template",
+            "string",
+            r#""{{repo}} synced to {{short_commit}}""#,
+            "Message template for successful syncs",
+        ),
+        schema::optional(
+            "failure_template",
+            "string",
+            r#""{{repo}} failed: {{error}}""#,
+            "Message template for failed syncs",
+        ),
+    ];
+}
+
+// Message length limits of the chat services
+const DISCORD_MAX_LENGTH: usize = 2000;
+const TELEGRAM_MAX_LENGTH: usize = 4096;
+
+// The repository a notification is about
+pub struct RepoRef<'a> {
+    pub organization: &'a str,
+    pub project: &'a str,
+    pub repository: &'a str,
+    pub branch: &'a str,
+    pub pat: &'a str,
+    // agent_labels of this agent, e.g. its group and store
+    pub labels: &'a [String],
+}
+
+// Most commits listed individually in a Teams card
+const TEAMS_MAX_COMMITS: usize = 10;
+
+const DEFAULT_SUCCESS_TEMPLATE: &str = "{{repo}} ({{branch}}) on {{host}}{% if labels %} [{{labels}}]{% endif %} synced to {{short_commit}} with {{commit_count}} new commit(s):\n{% for commit in commits %}- {{commit.short_id}} {{commit.message}} ({{commit.author}})\n{% endfor %}{% if work_items %}Work items:\n{% for item in work_items %}- {{item.type}} {{item.id}}: {{item.title}} ({{item.state}}) {{item.url}}\n{% endfor %}{% endif %}{% if pull_requests %}Pull requests:\n{% for pr in pull_requests %}- !{{pr.id}} {{pr.title}} by {{pr.author}}{% if pr.reviewers %}, reviewed by {{pr.reviewers}}{% endif %} {{pr.url}}\n{% endfor %}{% endif %}{% if terraform_plan %}Terraform plan:\n{{terraform_plan}}\n{% endif %}";
+
+// The Teams card lists the commits itself, so its summary line stays short
+const TEAMS_SUCCESS_TEMPLATE: &str =
+    "Synced to {{short_commit}} on {{host}}{% if labels %} [{{labels}}]{% endif %} with {{commit_count}} new commit(s).";
+
+const DEFAULT_FAILURE_TEMPLATE: &str =
+    "{{repo}} ({{branch}}) on {{host}}{% if labels %} [{{labels}}]{% endif %} failed to sync to {{short_commit}} ({{status}}): {{error}}";
+
+// Longest Terraform plan put into a message; chat services cap messages at a few thousand
+const MAX_PLAN_CHARS: usize = 2500;
+
+// Most messages kept per notifier while it can't be reached; older ones are dropped first
+const MAX_MISSED: usize = 50;
+
+// Notifies about every finished sync
+pub struct Notifier {
+    notifications: Vec<NotificationConfig>,
+    client: Client,
+    // Per notifier, the messages that failed to send because the network was down
+    missed: Vec<Missed>,
+}
+
+#[derive(Default)]
+struct Missed {
+    messages: Vec<String>,
+    // Messages dropped because more than MAX_MISSED piled up
+    dropped: usize,
+}
+
+impl Notifier {
+    pub fn new(notifications: Vec<NotificationConfig>, client: Client) -> Self {
+        let missed = notifications.iter().map(|_| Missed::default()).collect();
+        Notifier {
+            notifications,
+            client,
+            missed,
+        }
+    }
+
+    // Sends the outcome of a sync to every configured notifier, logging any that fail
+    async fn notify(&mut self, repo: &RepoRef<'_>, record: &SyncRecord) {
+        for (index, notification) in self.notifications.iter().enumerate() {
+            if !notification.wants(record.status) {
+                continue;
+            }
+
+            let text = render(notification, repo, record);
+            let result = match notification.kind {
+                NotifierKind::Slack => send_slack(&self.client, &notification.url, &text).await,
+                NotifierKind::Teams => {
+                    send_teams(&self.client, &notification.url, &text, repo, record).await
+                }
+                NotifierKind::Discord => send_discord(&self.client, &notification.url, &text).await,
+                NotifierKind::Telegram => send_telegram(&self.client, notification, &text).await,
+            };
+
+            match result {
+                Ok(()) => info!("Sent {:?} sync notification.", notification.kind),
+                Err(e) if is_offline(&*e) => {
+                    warn!("Network down, queued sync notification: {}", e);
+                    self.missed[index].push(text);
+                }
+                Err(e) => error!("Failed to send sync notification: {}", e),
+            }
+        }
+    }
+
+    // Sends a plain message about something other than a sync to the notifiers that want failures
+    async fn notify_text(&mut self, event: &str, text: &str) {
+        for (index, notification) in self.notifications.iter().enumerate() {
+            if !notification.events.is_empty()
+                && !notification
+                    .events
+                    .iter()
+                    .any(|e| e == event || e == "failure")
+            {
+                continue;
+            }
+
+            match send_text(&self.client, notification, text).await {
+                Ok(()) => {}
+                Err(e) if is_offline(&*e) => {
+                    warn!("Network down, queued {} notification: {}", event, e);
+                    self.missed[index].push(text.to_string());
+                }
+                Err(e) => error!("Failed to send {} notification: {}", event, e),
+            }
+        }
+    }
+
+    // Sends what was queued during an outage as one message per notifier, keeping it queued
+    // while the network is still down
+    async fn flush_missed(&mut self) {
+        for (index, notification) in self.notifications.iter().enumerate() {
+            let missed = &mut self.missed[index];
+            if missed.messages.is_empty() {
+                continue;
+            }
+
+            let mut text = format!(
+                "{} notification(s) could not be sent while offline:",
+                missed.messages.len() + missed.dropped
+            );
+            if missed.dropped > 0 {
+                text.push_str(&format!("\n({} older ones were dropped)", missed.dropped));
+            }
+            for message in &missed.messages {
+                text.push_str("\n- ");
+                text.push_str(message);
+            }
+
+            match send_text(&self.client, notification, &text).await {
+                Ok(()) => {
+                    info!(
+                        "Sent {} {:?} notification(s) missed while offline.",
+                        missed.messages.len(),
+                        notification.kind
+                    );
+                    *missed = Missed::default();
+                }
+                Err(e) if is_offline(&*e) => {}
+                Err(e) => {
+                    error!("Failed to send notifications missed while offline: {}", e);
+                    *missed = Missed::default();
+                }
+            }
+        }
+    }
+}
+
+impl Missed {
+    fn push(&mut self, text: String) {
+        if self.messages.len() == MAX_MISSED {
+            self.messages.remove(0);
+            self.dropped += 1;
+        }
+        self.messages.push(text);
+    }
+}
+
+impl Subscriber for Notifier {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            // Every event is a chance to catch up once the network is back, e.g. the
+            // up-to-date check after an outage
+            self.flush_missed().await;
+            match event {
+                SyncEvent::SyncFinished { record } => self.notify(repo, record).await,
+                SyncEvent::BranchMissing { branch, fallback } => {
+                    let text = match fallback {
+                        Some(fallback) => format!(
+                            "{} on {}: branch '{}' was deleted or renamed, now following '{}'.",
+                            repo.repository,
+                            agent_name(repo),
+                            branch,
+                            fallback
+                        ),
+                        None => format!(
+                            "{} on {}: branch '{}' was deleted or renamed, syncing has stopped.",
+                            repo.repository,
+                            agent_name(repo),
+                            branch
+                        ),
+                    };
+                    self.notify_text("branch_missing", &text).await;
+                }
+                SyncEvent::Halted {
+                    local_commit,
+                    reason,
+                    ..
+                } => {
+                    let text = format!(
+                        "{} on {}: the halt file is on {}, holding at {}: {}",
+                        repo.repository,
+                        agent_name(repo),
+                        repo.branch,
+                        local_commit,
+                        reason
+                    );
+                    self.notify_text("halted", &text).await;
+                }
+                SyncEvent::ForcePushed {
+                    old_commit,
+                    new_commit,
+                    held,
+                } => {
+                    let text = format!(
+                        "{} on {}: {} was force-pushed, {} replaced {}; {}.",
+                        repo.repository,
+                        agent_name(repo),
+                        repo.branch,
+                        new_commit,
+                        old_commit,
+                        if *held {
+                            "holding until the sync is approved"
+                        } else {
+                            "syncing to it"
+                        }
+                    );
+                    self.notify_text("force_pushed", &text).await;
+                }
+                SyncEvent::RepositoryMoved {
+                    old_location,
+                    new_location,
+                } => {
+                    let text = format!(
+                        "{} on {}: {} is now {} on Azure DevOps, following it; update config.toml to match.",
+                        repo.repository,
+                        agent_name(repo),
+                        old_location,
+                        new_location
+                    );
+                    self.notify_text("repository_moved", &text).await;
+                }
+                SyncEvent::Behind {
+                    local_commit,
+                    remote_commit,
+                } => {
+                    let text = format!(
+                        "{} on {}: behind the remote, at {} while {} is at {} (observe mode, not pulled).",
+                        repo.repository,
+                        agent_name(repo),
+                        local_commit,
+                        repo.branch,
+                        remote_commit
+                    );
+                    self.notify_text("behind", &text).await;
+                }
+                SyncEvent::DriftDetected {
+                    commit,
+                    differences,
+                    repaired,
+                } => {
+                    let text = format!(
+                        "{} on {}: the checkout was changed locally ({} difference(s), e.g. {}), {}.",
+                        repo.repository,
+                        agent_name(repo),
+                        differences.len(),
+                        differences.first().map(String::as_str).unwrap_or_default(),
+                        if *repaired {
+                            format!("reset it back to {}", commit)
+                        } else {
+                            "left as is".to_string()
+                        }
+                    );
+                    self.notify_text("drift_detected", &text).await;
+                }
+                _ => {}
+            }
+            Directive::default()
+        })
+    }
+}
+
+// Fills the notifier's template for this sync
+fn render(notification: &NotificationConfig, repo: &RepoRef<'_>, record: &SyncRecord) -> String {
+    let template = if record.status == SyncStatus::Success {
+        let default = match notification.kind {
+            NotifierKind::Teams => TEAMS_SUCCESS_TEMPLATE,
+            _ => DEFAULT_SUCCESS_TEMPLATE,
+        };
+        notification.success_template.as_deref().unwrap_or(default)
+    } else {
+        notification
+            .failure_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FAILURE_TEMPLATE)
+    };
+
+    let (vars, lists) = template_values(repo, record);
+    template::render_with_lists(template, &vars, &lists)
+}
+
+// Values available to notification templates
+pub fn template_values(
+    repo: &RepoRef<'_>,
+    record: &SyncRecord,
+) -> (Fields<'static>, Vec<(&'static str, Vec<Fields<'static>>)>) {
+    let vars = vec![
+        ("repo", repo.repository.to_string()),
+        ("branch", repo.branch.to_string()),
+        ("host", host_name()),
+        ("labels", repo.labels.join(", ")),
+        ("status", status_name(record.status).to_string()),
+        ("timestamp", record.timestamp.clone()),
+        ("old_commit", record.old_commit.clone()),
+        ("new_commit", record.new_commit.clone()),
+        (
+            "short_commit",
+            record.new_commit.chars().take(8).collect::<String>(),
+        ),
+        ("commit_count", record.commits.len().to_string()),
+        (
+            "commit_url",
+            azure::commit_url(
+                repo.organization,
+                repo.project,
+                repo.repository,
+                &record.new_commit,
+            ),
+        ),
+        (
+            "compare_url",
+            azure::compare_url(
+                repo.organization,
+                repo.project,
+                repo.repository,
+                &record.old_commit,
+                &record.new_commit,
+            ),
+        ),
+        ("error", record.error.clone().unwrap_or_default()),
+        (
+            "terraform_plan",
+            terraform::plan_summary(&record.hooks)
+                .map(|plan| truncate(&plan, MAX_PLAN_CHARS))
+                .unwrap_or_default(),
+        ),
+    ];
+
+    let commits = record
+        .commits
+        .iter()
+        .map(|commit| {
+            vec![
+                ("id", commit.id.clone()),
+                ("short_id", commit.short_id.clone()),
+                ("author", commit.author.clone()),
+                ("author_email", commit.author_email.clone()),
+                ("message", commit.message.clone()),
+                (
+                    "url",
+                    azure::commit_url(repo.organization, repo.project, repo.repository, &commit.id),
+                ),
+            ]
+        })
+        .collect();
+
+    let work_items = record
+        .work_items
+        .iter()
+        .map(|item| {
+            vec![
+                ("id", item.id.to_string()),
+                ("title", item.title.clone()),
+                ("type", item.work_item_type.clone()),
+                ("state", item.state.clone()),
+                ("url", item.url.clone()),
+            ]
+        })
+        .collect();
+
+    let pull_requests = record
+        .pull_requests
+        .iter()
+        .map(|pull_request| {
+            vec![
+                ("id", pull_request.id.to_string()),
+                ("title", pull_request.title.clone()),
+                ("author", pull_request.author.clone()),
+                ("reviewers", pull_request.reviewers.join(", ")),
+                ("merge_commit", pull_request.merge_commit.clone()),
+                ("url", pull_request.url.clone()),
+            ]
+        })
+        .collect();
+
+    (
+        vars,
+        vec![
+            ("commits", commits),
+            ("work_items", work_items),
+            ("pull_requests", pull_requests),
+        ],
+    )
+}
+
+// Name of the status as the history file writes it
+pub fn status_name(status: SyncStatus) -> &'static str {
+    match status {
+        SyncStatus::Success => "success",
+        SyncStatus::PullFailed => "pull_failed",
+        SyncStatus::PostSyncFailed => "post_sync_failed",
+        SyncStatus::RolledBack => "rolled_back",
+        SyncStatus::Aborted => "aborted",
+        SyncStatus::VerificationFailed => "verification_failed",
+    }
+}
+
+// The host name followed by the agent's labels, for messages about this agent
+pub fn agent_name(repo: &RepoRef<'_>) -> String {
+    if repo.labels.is_empty() {
+        host_name()
+    } else {
+        format!("{} [{}]", host_name(), repo.labels.join(", "))
+    }
+}
+
+// Name of this machine as reported by the environment
+pub fn host_name() -> String {
+    env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .or_else(|_| fs::read_to_string("/etc/hostname").map(|name| name.trim().to_string()))
+        .unwrap_or_else(|_| "unknown-host".to_string())
+}
+
+// Webhook URLs and bot tokens are secrets, so the senders strip the URL from request errors
+async fn send_slack(
+    client: &Client,
+    url: &str,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client
+        .post(url)
+        .json(&json!({ "text": text }))
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+
+    if !response.status().is_success() {
+        return Err(format!("Slack webhook returned {}", response.status()).into());
+    }
+
+    Ok(())
+}
+
+async fn send_discord(
+    client: &Client,
+    url: &str,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = client
+        .post(url)
+        .json(&json!({ "content": truncate(text, DISCORD_MAX_LENGTH) }))
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+
+    if !response.status().is_success() {
+        return Err(format!("Discord webhook returned {}", response.status()).into());
+    }
+
+    Ok(())
+}
+
+async fn send_telegram(
+    client: &Client,
+    notification: &NotificationConfig,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(bot_token), Some(chat_id)) = (&notification.bot_token, &notification.chat_id) else {
+        return Err("Telegram notifications need both bot_token and chat_id".into());
+    };
+
+    let response = client
+        .post(format!(
+            "https://api.telegram.org/bot{}/sendMessage",
+            bot_token
+        ))
+        .json(&json!({
+            "chat_id": chat_id,
+            "text": truncate(text, TELEGRAM_MAX_LENGTH),
+            "disable_web_page_preview": true,
+        }))
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+
+    if !response.status().is_success() {
+        return Err(format!("Telegram API returned {}", response.status()).into());
+    }
+
+    Ok(())
+}
+
+// Sends a plain message to one notifier, as a simple card for Teams
+async fn send_text(
+    client: &Client,
+    notification: &NotificationConfig,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match notification.kind {
+        NotifierKind::Slack => send_slack(client, &notification.url, text).await,
+        NotifierKind::Teams => send_teams_text(client, &notification.url, text).await,
+        NotifierKind::Discord => send_discord(client, &notification.url, text).await,
+        NotifierKind::Telegram => send_telegram(client, notification, text).await,
+    }
+}
+
+// Whether sending failed because the service couldn't be reached at all, rather than refused
+fn is_offline(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout())
+}
+
+// Cuts a message down to the service's limit, marking that it was shortened
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    let mut shortened: String = text.chars().take(max_chars - 3).collect();
+    shortened.push_str("...");
+    shortened
+}
+
+// Posts an Adaptive Card with the sync outcome, pulled commits and links back to Azure DevOps
+async fn send_teams(
+    client: &Client,
+    url: &str,
+    summary: &str,
+    repo: &RepoRef<'_>,
+    record: &SyncRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let succeeded = record.status == SyncStatus::Success;
+
+    let mut body = vec![
+        json!({
+            "type": "TextBlock",
+            "text": format!("{} ({})", repo.repository, repo.branch),
+            "weight": "Bolder",
+            "size": "Large",
+            "wrap": true,
+        }),
+        json!({
+            "type": "TextBlock",
+            "text": summary,
+            "color": if succeeded { "Good" } else { "Attention" },
+            "wrap": true,
+        }),
+        json!({
+            "type": "FactSet",
+            "facts": [
+                { "title": "Host", "value": agent_name(repo) },
+                { "title": "Status", "value": status_name(record.status) },
+                { "title": "Commit", "value": record.new_commit },
+                { "title": "Time", "value": record.timestamp },
+            ],
+        }),
+    ];
+
+    for commit in record.commits.iter().take(TEAMS_MAX_COMMITS) {
+        let avatar =
+            azure::avatar_data_uri(client, repo.organization, &commit.author_email, repo.pat).await;
+        body.push(commit_row(repo, commit, avatar));
+    }
+    if record.commits.len() > TEAMS_MAX_COMMITS {
+        body.push(json!({
+            "type": "TextBlock",
+            "text": format!("...and {} more commit(s)", record.commits.len() - TEAMS_MAX_COMMITS),
+            "isSubtle": true,
+        }));
+    }
+
+    if !record.work_items.is_empty() {
+        let lines: Vec<String> = record
+            .work_items
+            .iter()
+            .map(|item| {
+                format!(
+                    "- [{} {}]({}): {} ({})",
+                    item.work_item_type, item.id, item.url, item.title, item.state
+                )
+            })
+            .collect();
+        body.push(json!({
+            "type": "TextBlock",
+            "text": "Work items",
+            "weight": "Bolder",
+            "separator": true,
+        }));
+        body.push(json!({ "type": "TextBlock", "text": lines.join("\n"), "wrap": true }));
+    }
+
+    if !record.pull_requests.is_empty() {
+        let lines: Vec<String> = record
+            .pull_requests
+            .iter()
+            .map(|pull_request| {
+                let mut line = format!(
+                    "- [!{}]({}): {} by {}",
+                    pull_request.id, pull_request.url, pull_request.title, pull_request.author
+                );
+                if !pull_request.reviewers.is_empty() {
+                    line.push_str(&format!(
+                        ", reviewed by {}",
+                        pull_request.reviewers.join(", ")
+                    ));
+                }
+                line
+            })
+            .collect();
+        body.push(json!({
+            "type": "TextBlock",
+            "text": "Pull requests",
+            "weight": "Bolder",
+            "separator": true,
+        }));
+        body.push(json!({ "type": "TextBlock", "text": lines.join("\n"), "wrap": true }));
+    }
+
+    let commit_url = azure::commit_url(
+        repo.organization,
+        repo.project,
+        repo.repository,
+        &record.new_commit,
+    );
+    let compare_url = azure::compare_url(
+        repo.organization,
+        repo.project,
+        repo.repository,
+        &record.old_commit,
+        &record.new_commit,
+    );
+
+    let card = json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "msteams": { "width": "Full" },
+                "body": body,
+                "actions": [
+                    { "type": "Action.OpenUrl", "title": "View commit", "url": commit_url },
+                    { "type": "Action.OpenUrl", "title": "View changes", "url": compare_url },
+                ],
+            },
+        }],
+    });
+
+    let response = client
+        .post(url)
+        .json(&card)
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+    if !response.status().is_success() {
+        return Err(format!("Teams webhook returned {}", response.status()).into());
+    }
+
+    Ok(())
+}
+
+// Posts a card holding only a line of text
+async fn send_teams_text(
+    client: &Client,
+    url: &str,
+    text: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let card = json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "body": [{ "type": "TextBlock", "text": text, "wrap": true }],
+            },
+        }],
+    });
+
+    let response = client
+        .post(url)
+        .json(&card)
+        .send()
+        .await
+        .map_err(|e| e.without_url())?;
+    if !response.status().is_success() {
+        return Err(format!("Teams webhook returned {}", response.status()).into());
+    }
+
+    Ok(())
+}
+
+// One commit in the Teams card: avatar beside the message, author and link
+fn commit_row(repo: &RepoRef<'_>, commit: &CommitSummary, avatar: Option<String>) -> Value {
+    let mut columns = Vec::new();
+    if let Some(avatar) = avatar {
+        columns.push(json!({
+            "type": "Column",
+            "width": "auto",
+            "items": [{ "type": "Image", "url": avatar, "size": "Small", "style": "Person" }],
+        }));
+    }
+    let url = azure::commit_url(repo.organization, repo.project, repo.repository, &commit.id);
+    columns.push(json!({
+        "type": "Column",
+        "width": "stretch",
+        "items": [
+            {
+                "type": "TextBlock",
+                "text": format!("[{}]({}) {}", commit.short_id, url, commit.message),
+                "wrap": true,
+            },
+            { "type": "TextBlock", "text": commit.author, "isSubtle": true, "spacing": "None" },
+        ],
+    }));
+
+    json!({ "type": "ColumnSet", "columns": columns })
+}
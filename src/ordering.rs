@@ -0,0 +1,181 @@
+// Ordering between repos: a repo listing others in `after` only syncs once each of them has
+// just checked in and is in sync with its remote, and they wake it as soon as they sync, so
+// a change flows through the repos in dependency order within one wave.
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tokio::time::timeout;
+
+// How long a repo waits for its dependencies to report before holding until its next check
+const DEPENDENCY_WAIT: Duration = Duration::from_secs(600);
+
+// How a repo's latest check ended
+#[derive(Clone, Copy)]
+struct Report {
+    at: Instant,
+    in_sync: bool,
+}
+
+// What other repos see of one repo
+#[derive(Default)]
+struct Peer {
+    // Asks the repo to check now instead of at the end of its interval
+    check_now: Notify,
+    report: watch::Sender<Option<Report>>,
+}
+
+// A repo's place in the ordering
+#[derive(Default)]
+pub struct Links {
+    own: Rc<Peer>,
+    // The repos this one syncs after, by name
+    after: Vec<(String, Rc<Peer>)>,
+    // The repos that sync after this one
+    dependents: Vec<Rc<Peer>>,
+}
+
+// Links every repo, given each one's name and `after` list in config order. Fails on names
+// that don't match exactly one repo and on cycles.
+pub fn link(repos: &[(String, Vec<String>)]) -> Result<Vec<Links>, String> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    let mut ambiguous = Vec::new();
+    for (position, (name, _)) in repos.iter().enumerate() {
+        if index.insert(name.as_str(), position).is_some() {
+            ambiguous.push(name.as_str());
+        }
+    }
+
+    let mut edges = vec![Vec::new(); repos.len()];
+    for (position, (name, after)) in repos.iter().enumerate() {
+        for dependency in after {
+            if ambiguous.contains(&dependency.as_str()) {
+                return Err(format!(
+                    "{}: after names '{}', but several repos are called that",
+                    name, dependency
+                ));
+            }
+            let Some(&target) = index.get(dependency.as_str()) else {
+                return Err(format!(
+                    "{}: after names '{}', which isn't a repo",
+                    name, dependency
+                ));
+            };
+            if target == position {
+                return Err(format!("{}: a repo can't sync after itself", name));
+            }
+            edges[position].push(target);
+        }
+    }
+    if let Some(cycle) = find_cycle(&edges) {
+        let names: Vec<&str> = cycle.iter().map(|&i| repos[i].0.as_str()).collect();
+        return Err(format!("after forms a cycle: {}", names.join(" -> ")));
+    }
+
+    let peers: Vec<Rc<Peer>> = repos.iter().map(|_| Rc::default()).collect();
+    let mut links: Vec<Links> = peers
+        .iter()
+        .map(|peer| Links {
+            own: peer.clone(),
+            ..Links::default()
+        })
+        .collect();
+    for (position, targets) in edges.iter().enumerate() {
+        for &target in targets {
+            links[position]
+                .after
+                .push((repos[target].0.clone(), peers[target].clone()));
+            links[target].dependents.push(peers[position].clone());
+        }
+    }
+    Ok(links)
+}
+
+// A path that returns to where it started, if the dependencies have one
+fn find_cycle(edges: &[Vec<usize>]) -> Option<Vec<usize>> {
+    // 0: not visited, 1: on the current path, 2: done
+    fn visit(node: usize, edges: &[Vec<usize>], state: &mut [u8], path: &mut Vec<usize>) -> bool {
+        state[node] = 1;
+        path.push(node);
+        for &next in &edges[node] {
+            if state[next] == 1 {
+                path.push(next);
+                return true;
+            }
+            if state[next] == 0 && visit(next, edges, state, path) {
+                return true;
+            }
+        }
+        path.pop();
+        state[node] = 2;
+        false
+    }
+
+    let mut state = vec![0; edges.len()];
+    for start in 0..edges.len() {
+        let mut path = Vec::new();
+        if state[start] == 0 && visit(start, edges, &mut state, &mut path) {
+            // Only the loop itself, not the way into it
+            let last = *path.last()?;
+            let first = path.iter().position(|&node| node == last)?;
+            return Some(path.split_off(first));
+        }
+    }
+    None
+}
+
+impl Links {
+    pub fn has_dependencies(&self) -> bool {
+        !self.after.is_empty()
+    }
+
+    // Records how this repo's check ended, for the repos waiting on it
+    pub fn report(&self, in_sync: bool) {
+        self.own.report.send_replace(Some(Report {
+            at: Instant::now(),
+            in_sync,
+        }));
+    }
+
+    // Has the repos that sync after this one check right away, after it synced
+    pub fn wake_dependents(&self) {
+        for dependent in &self.dependents {
+            dependent.check_now.notify_one();
+        }
+    }
+
+    // Resolves when another repo asks this one to check now
+    pub async fn check_requested(&self) {
+        self.own.check_now.notified().await
+    }
+
+    // Asks each dependency to check now and waits for them all to report, failing with the
+    // reason to hold if one isn't in sync or doesn't report in time
+    pub async fn wait_for_dependencies(&self) -> Result<(), String> {
+        let asked = Instant::now();
+        for (_, peer) in &self.after {
+            peer.check_now.notify_one();
+        }
+
+        for (name, peer) in &self.after {
+            let mut reports = peer.report.subscribe();
+            let fresh = timeout(
+                DEPENDENCY_WAIT,
+                reports.wait_for(|report| report.is_some_and(|report| report.at >= asked)),
+            )
+            .await;
+            match fresh {
+                Ok(Ok(report)) if report.is_some_and(|report| report.in_sync) => {}
+                Ok(Ok(_)) => return Err(format!("'{}' isn't in sync with its remote", name)),
+                _ => {
+                    return Err(format!(
+                        "'{}' didn't finish a check within {} seconds",
+                        name,
+                        DEPENDENCY_WAIT.as_secs()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+// Handling of repo_path values, including Windows UNC shares and extended-length paths.
+use std::fs;
+use std::path::Path;
+
+// Resolves the configured repo path to an absolute path git and child processes understand
+pub fn normalize_repo_path(configured: &str) -> String {
+    let trimmed = trim_trailing_separators(configured.trim());
+    match fs::canonicalize(Path::new(&trimmed)) {
+        Ok(canonical) => strip_verbatim_prefix(&canonical.to_string_lossy()),
+        // Left as configured, git reports a clearer error than we could here
+        Err(_) => trimmed,
+    }
+}
+
+// Windows canonicalizes to \\?\C:\... and \\?\UNC\server\share\..., which git and cmd can't use.
+// The long path support those prefixes give is enabled through core.longpaths instead.
+pub fn strip_verbatim_prefix(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{}", rest)
+    } else if let Some(rest) = path.strip_prefix(r"\\?\") {
+        rest.to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+// Drops trailing slashes without touching a drive root, a UNC share root or "/"
+pub fn trim_trailing_separators(path: &str) -> String {
+    let is_separator = |c: char| c == '\\' || c == '/';
+    let trimmed = path.trim_end_matches(is_separator);
+
+    if trimmed.is_empty() {
+        // "/" or "\\" on its own
+        return path.chars().take(1).collect();
+    }
+    if trimmed.len() == 2 && trimmed.ends_with(':') {
+        // "C:\" must keep its separator, "C:" means the current directory on that drive
+        return path[..3.min(path.len())].to_string();
+    }
+    trimmed.to_string()
+}
+
+// Whether the path points at a network share (\\server\share or //server/share)
+pub fn is_unc(path: &str) -> bool {
+    let path = strip_verbatim_prefix(path);
+    (path.starts_with(r"\\") || path.starts_with("//")) && path.len() > 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_verbatim_drive_paths() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\C:\deploy\repo"),
+            r"C:\deploy\repo"
+        );
+    }
+
+    #[test]
+    fn strips_verbatim_unc_paths() {
+        assert_eq!(
+            strip_verbatim_prefix(r"\\?\UNC\fileserver\deploy\repo"),
+            r"\\fileserver\deploy\repo"
+        );
+    }
+
+    #[test]
+    fn leaves_ordinary_paths_alone() {
+        assert_eq!(strip_verbatim_prefix(r"C:\deploy"), r"C:\deploy");
+        assert_eq!(
+            strip_verbatim_prefix(r"\\fileserver\deploy"),
+            r"\\fileserver\deploy"
+        );
+        assert_eq!(strip_verbatim_prefix("/srv/repo"), "/srv/repo");
+    }
+
+    #[test]
+    fn trims_trailing_separators() {
+        assert_eq!(
+            trim_trailing_separators(r"C:\deploy\repo\"),
+            r"C:\deploy\repo"
+        );
+        assert_eq!(
+            trim_trailing_separators(r"\\fileserver\deploy\repo\\"),
+            r"\\fileserver\deploy\repo"
+        );
+        assert_eq!(trim_trailing_separators("/srv/repo/"), "/srv/repo");
+    }
+
+    #[test]
+    fn keeps_roots() {
+        assert_eq!(trim_trailing_separators(r"C:\"), r"C:\");
+        assert_eq!(trim_trailing_separators("C:/"), "C:/");
+        assert_eq!(trim_trailing_separators("/"), "/");
+    }
+
+    #[test]
+    fn detects_unc_paths() {
+        assert!(is_unc(r"\\fileserver\deploy\repo"));
+        assert!(is_unc("//fileserver/deploy/repo"));
+        assert!(is_unc(r"\\?\UNC\fileserver\deploy\repo"));
+        assert!(!is_unc(r"C:\deploy\repo"));
+        assert!(!is_unc(r"\\?\C:\deploy\repo"));
+        assert!(!is_unc("/srv/repo"));
+    }
+
+    #[test]
+    fn long_paths_survive_normalization() {
+        let long = format!(r"\\?\C:\{}", "d".repeat(300));
+        let normalized = strip_verbatim_prefix(&long);
+        assert_eq!(normalized.len(), 303);
+        assert!(normalized.starts_with(r"C:\"));
+    }
+
+    #[test]
+    fn missing_paths_are_kept_as_configured() {
+        assert_eq!(
+            normalize_repo_path(r"\\no-such-server\share\repo\"),
+            r"\\no-such-server\share\repo"
+        );
+    }
+}
@@ -0,0 +1,457 @@
+// Azure Pipelines builds of the commits being synced, looked up with the Builds API so agents
+// can hold a commit until its pipeline has passed, download what it published, and start
+// downstream pipelines or releases once it is synced.
+use crate::azure;
+use crate::notify::{self, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::template;
+use crate::unpack;
+use log::info;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// Builds looked at per request, newest first; a commit's builds are among the latest on its branch
+const BUILDS_PAGE: &str = "50";
+
+#[derive(Deserialize)]
+struct BuildList {
+    value: Vec<Build>,
+}
+
+#[derive(Deserialize)]
+struct Build {
+    id: u64,
+    #[serde(rename = "buildNumber")]
+    build_number: String,
+    // notStarted, inProgress, completed, ...
+    #[serde(default)]
+    status: String,
+    // succeeded, partiallySucceeded, failed or canceled once completed
+    #[serde(default)]
+    result: Option<String>,
+    #[serde(rename = "sourceVersion", default)]
+    source_version: String,
+    definition: Definition,
+    repository: Option<BuildRepository>,
+}
+
+#[derive(Deserialize)]
+struct Definition {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct BuildRepository {
+    name: String,
+}
+
+// Where the pipelines building a commit stand
+pub enum BuildState {
+    // Every pipeline building the commit succeeded
+    Passed,
+    // A pipeline hasn't finished, or hasn't started yet
+    Pending(String),
+    // A pipeline finished without succeeding
+    Failed(String),
+}
+
+// The latest build of the commit from each pipeline, or from each of `definitions` when given
+async fn commit_builds(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    commit: &str,
+    definitions: &[u64],
+    api_version: &str,
+) -> Result<Vec<Build>, Box<dyn std::error::Error>> {
+    let branch = format!("refs/heads/{}", repo.branch);
+    let mut query = vec![
+        ("branchName", branch.as_str()),
+        ("queryOrder", "queueTimeDescending"),
+        ("$top", BUILDS_PAGE),
+        ("api-version", api_version),
+    ];
+    let definition_ids = definitions
+        .iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    if !definitions.is_empty() {
+        query.push(("definitions", &definition_ids));
+    }
+
+    let response = client
+        .get(format!(
+            "{}/{}/{}/_apis/build/builds",
+            azure::base_url(),
+            azure::segment(repo.organization),
+            azure::segment(repo.project)
+        ))
+        .query(&query)
+        .basic_auth("", Some(repo.pat))
+        .send()
+        .await?
+        .error_for_status()?;
+    let list: BuildList = response.json().await?;
+
+    // Newest first, so the first build seen per pipeline is its latest run
+    let mut latest: Vec<Build> = Vec::new();
+    for build in list.value {
+        let same_repo = build
+            .repository
+            .as_ref()
+            .is_none_or(|built| built.name.eq_ignore_ascii_case(repo.repository));
+        if same_repo
+            && build.source_version.eq_ignore_ascii_case(commit)
+            && !latest
+                .iter()
+                .any(|seen| seen.definition.id == build.definition.id)
+        {
+            latest.push(build);
+        }
+    }
+    Ok(latest)
+}
+
+// Whether the commit may be pulled: every pipeline building it (or every one of `definitions`)
+// must have completed successfully
+pub async fn build_state(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    commit: &str,
+    definitions: &[u64],
+    api_version: &str,
+) -> Result<BuildState, Box<dyn std::error::Error>> {
+    let builds = commit_builds(client, repo, commit, definitions, api_version).await?;
+
+    if let Some(failed) = builds
+        .iter()
+        .find(|build| build.status == "completed" && build.result.as_deref() != Some("succeeded"))
+    {
+        return Ok(BuildState::Failed(format!(
+            "build {} of '{}' finished as {}",
+            failed.build_number,
+            failed.definition.name,
+            failed.result.as_deref().unwrap_or("unknown")
+        )));
+    }
+    if let Some(running) = builds.iter().find(|build| build.status != "completed") {
+        return Ok(BuildState::Pending(format!(
+            "build {} of '{}' is {}",
+            running.build_number, running.definition.name, running.status
+        )));
+    }
+    if builds.is_empty() {
+        return Ok(BuildState::Pending(
+            "no build has been queued yet".to_string(),
+        ));
+    }
+    if let Some(missing) = definitions
+        .iter()
+        .find(|&&id| !builds.iter().any(|build| build.definition.id == id))
+    {
+        return Ok(BuildState::Pending(format!(
+            "pipeline {} hasn't built it yet",
+            missing
+        )));
+    }
+    Ok(BuildState::Passed)
+}
+
+// The [artifact] table: a pipeline artifact downloaded for every synced commit
+#[derive(Deserialize, Clone, Debug)]
+pub struct ArtifactConfig {
+    // Artifact name as published by the pipeline
+    pub name: String,
+    // Directory its contents replace, relative to the working directory unless absolute
+    pub directory: String,
+    // Pipeline (definition) id to take it from, when several pipelines build the commit
+    pub definition: Option<u64>,
+}
+
+impl Documented for ArtifactConfig {
+    const SECTION: &'static str = "[artifact]";
+    const ABOUT: &'static str =
+        "Pipeline artifact downloaded for every synced commit, for agents that deploy build output.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "name",
+            "string",
+            r#""drop""#,
+            "Artifact name as published by the pipeline",
+        ),
+        schema::required(
+            "directory",
+            "string",
+            r#""C:\\Deploy\\app-bin""#,
+            "Directory the artifact's contents replace after each sync",
+        ),
+        schema::optional(
+            "definition",
+            "integer",
+            "12",
+            "Pipeline id to take the artifact from, when several pipelines build the commit",
+        ),
+    ];
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    resource: ArtifactResource,
+}
+
+#[derive(Deserialize)]
+struct ArtifactResource {
+    #[serde(rename = "downloadUrl")]
+    download_url: String,
+}
+
+// Downloads the artifact from the newest successful build of the commit and swaps it into the
+// configured directory, which keeps its old contents if anything goes wrong
+pub async fn download_artifact(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    commit: &str,
+    artifact: &ArtifactConfig,
+    api_version: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let definitions: Vec<u64> = artifact.definition.into_iter().collect();
+    let builds = commit_builds(client, repo, commit, &definitions, api_version).await?;
+    let build = builds
+        .iter()
+        .find(|build| build.result.as_deref() == Some("succeeded"))
+        .ok_or_else(|| {
+            format!(
+                "no successful build of {} to take '{}' from",
+                commit, artifact.name
+            )
+        })?;
+
+    let response = client
+        .get(format!(
+            "{}/{}/{}/_apis/build/builds/{}/artifacts",
+            azure::base_url(),
+            azure::segment(repo.organization),
+            azure::segment(repo.project),
+            build.id
+        ))
+        .query(&[
+            ("artifactName", artifact.name.as_str()),
+            ("api-version", api_version),
+        ])
+        .basic_auth("", Some(repo.pat))
+        .send()
+        .await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(format!(
+            "build {} has no artifact named '{}'",
+            build.build_number, artifact.name
+        )
+        .into());
+    }
+    let found: Artifact = response.error_for_status()?.json().await?;
+
+    info!(
+        "Downloading artifact '{}' of build {}.",
+        artifact.name, build.build_number
+    );
+    let download = client
+        .get(&found.resource.download_url)
+        .basic_auth("", Some(repo.pat))
+        .timeout(azure::DOWNLOAD_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    // Azure DevOps zips an artifact inside a folder named after it
+    let directory = Path::new(&artifact.directory);
+    unpack::unzip_into(download, directory, Some(&artifact.name)).await?;
+
+    info!(
+        "Artifact '{}' of build {} is in {}.",
+        artifact.name,
+        build.build_number,
+        directory.display()
+    );
+    Ok(())
+}
+
+// What the [trigger] table starts
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerKind {
+    // A YAML pipeline run
+    #[default]
+    Pipeline,
+    // A classic release from a release definition
+    Release,
+}
+
+// The [trigger] table: a pipeline or release started after every successful sync
+#[derive(Deserialize, Clone, Debug)]
+pub struct TriggerConfig {
+    #[serde(default)]
+    pub kind: TriggerKind,
+    // Pipeline id, or release definition id for releases
+    pub definition: u64,
+    // Project the pipeline or release lives in, defaults to the repo's
+    pub project: Option<String>,
+    // Branch a pipeline runs on; it runs at the synced commit when this is left unset
+    pub branch: Option<String>,
+    // Template parameters of a pipeline, or variables of a release, with {{placeholders}}
+    #[serde(default)]
+    pub parameters: BTreeMap<String, String>,
+}
+
+impl Documented for TriggerConfig {
+    const SECTION: &'static str = "[trigger]";
+    const ABOUT: &'static str =
+        "Azure Pipelines run or classic release started after every successful sync, for downstream automation.";
+    const FIELDS: &'static [Field] = &[
+        schema::defaulted(
+            "kind",
+            "\"pipeline\" or \"release\"",
+            r#""pipeline""#,
+            "Start a YAML pipeline run or a classic release",
+        ),
+        schema::required(
+            "definition",
+            "integer",
+            "42",
+            "Pipeline id, or release definition id",
+        ),
+        schema::defaulted(
+            "project",
+            "string",
+            "the repo's project",
+            "Project the pipeline or release definition lives in",
+        ),
+        schema::optional(
+            "branch",
+            "string",
+            r#""main""#,
+            "Branch a pipeline runs on; unset, it runs on the synced branch at the synced commit",
+        ),
+        schema::defaulted(
+            "parameters",
+            "table of strings",
+            "{}",
+            "Pipeline template parameters or release variables; {{old_commit}}, {{new_commit}}, {{branch}}, {{repo}} and {{host}} are substituted",
+        ),
+    ];
+}
+
+#[derive(Deserialize)]
+struct Started {
+    name: String,
+    #[serde(rename = "_links")]
+    links: Option<Links>,
+}
+
+#[derive(Deserialize)]
+struct Links {
+    web: Option<Link>,
+}
+
+#[derive(Deserialize)]
+struct Link {
+    href: String,
+}
+
+// Starts the configured pipeline or release for a synced commit and says which run it started
+pub async fn trigger(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    trigger: &TriggerConfig,
+    old_commit: &str,
+    new_commit: &str,
+    api_version: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let vars = [
+        ("old_commit", old_commit.to_string()),
+        ("new_commit", new_commit.to_string()),
+        ("branch", repo.branch.to_string()),
+        ("repo", repo.repository.to_string()),
+        ("host", notify::host_name()),
+    ];
+    let parameters: BTreeMap<&str, String> = trigger
+        .parameters
+        .iter()
+        .map(|(name, value)| (name.as_str(), template::render(value, &vars)))
+        .collect();
+    let project = trigger.project.as_deref().unwrap_or(repo.project);
+
+    let (url, body) = match trigger.kind {
+        TriggerKind::Pipeline => {
+            // Left on the synced branch, the run builds exactly the commit that was synced
+            let mut source = json!({
+                "refName": format!("refs/heads/{}", trigger.branch.as_deref().unwrap_or(repo.branch)),
+            });
+            if trigger.branch.is_none() {
+                source["version"] = new_commit.into();
+            }
+            (
+                format!(
+                    "{}/{}/{}/_apis/pipelines/{}/runs",
+                    azure::base_url(),
+                    azure::segment(repo.organization),
+                    azure::segment(project),
+                    trigger.definition
+                ),
+                json!({
+                    "resources": { "repositories": { "self": source } },
+                    "templateParameters": parameters,
+                }),
+            )
+        }
+        TriggerKind::Release => {
+            let variables: BTreeMap<&str, _> = parameters
+                .into_iter()
+                .map(|(name, value)| (name, json!({ "value": value })))
+                .collect();
+            (
+                format!(
+                    "{}/{}/{}/_apis/release/releases",
+                    azure::service_url("vsrm"),
+                    azure::segment(repo.organization),
+                    azure::segment(project)
+                ),
+                json!({
+                    "definitionId": trigger.definition,
+                    "description": format!(
+                        "Started by {} after syncing {} to {}",
+                        notify::host_name(),
+                        repo.repository,
+                        new_commit
+                    ),
+                    "variables": variables,
+                }),
+            )
+        }
+    };
+
+    let response = client
+        .post(url)
+        .query(&[("api-version", api_version)])
+        .basic_auth("", Some(repo.pat))
+        .json(&body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!("{} {}", status, text.trim()).into());
+    }
+    let started: Started = response.json().await?;
+
+    let what = match trigger.kind {
+        TriggerKind::Pipeline => "pipeline run",
+        TriggerKind::Release => "release",
+    };
+    Ok(match started.links.and_then(|links| links.web) {
+        Some(web) => format!("{} {} ({})", what, started.name, web.href),
+        None => format!("{} {}", what, started.name),
+    })
+}
@@ -0,0 +1,219 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::notify::{host_name, RepoRef};
+use crate::schema::{self, Documented, Field};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+// An external program that is handed sync events as JSON on stdin
+#[derive(Deserialize)]
+pub struct PluginConfig {
+    // Label used in logs, defaults to the command itself
+    pub name: Option<String>,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // Events to send, by name (empty means every event)
+    #[serde(default)]
+    pub events: Vec<String>,
+    // The plugin is killed and ignored after this long
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+}
+
+impl Documented for PluginConfig {
+    const SECTION: &'static str = "[[plugins]]";
+    const ABOUT: &'static str =
+        "External programs that receive sync events as JSON and may skip, abort or annotate syncs.";
+    const FIELDS: &'static [Field] = &[
+        schema::optional(
+            "name",
+            "string",
+            r#""change-freeze""#,
+            "Label used in the log, defaults to the command",
+        ),
+        schema::required(
+            "command",
+            "string",
+            r#""/opt/sync-plugins/freeze-check""#,
+            "Program to run",
+        ),
+        schema::defaulted(
+            "args",
+            "list of strings",
+            "[]",
+            "Arguments passed to the program",
+        ),
+        schema::defaulted(
+            "events",
+            "list of strings",
+            "[]",
+            "Event names to receive (empty means every event)",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "30",
+            "The plugin is killed and ignored after this long",
+        ),
+    ];
+}
+
+impl PluginConfig {
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.command)
+    }
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+// What a plugin may print on stdout in answer to an event
+#[derive(Deserialize, Default)]
+struct PluginReply {
+    #[serde(default)]
+    action: PluginAction,
+    reason: Option<String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum PluginAction {
+    #[default]
+    Continue,
+    Skip,
+    Abort,
+}
+
+// Runs the configured plugins for every event they subscribe to
+pub struct Plugins {
+    plugins: Vec<PluginConfig>,
+}
+
+impl Plugins {
+    pub fn new(plugins: Vec<PluginConfig>) -> Self {
+        Plugins { plugins }
+    }
+}
+
+impl Subscriber for Plugins {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            let mut directive = Directive::default();
+            for plugin in &self.plugins {
+                if !plugin.events.is_empty() && !plugin.events.iter().any(|e| e == event.name()) {
+                    continue;
+                }
+
+                let reply = match run_plugin(plugin, event, repo).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        error!("Plugin '{}' failed: {}", plugin.label(), e);
+                        continue;
+                    }
+                };
+
+                let reason = reply
+                    .reason
+                    .unwrap_or_else(|| format!("requested by plugin '{}'", plugin.label()));
+                match reply.action {
+                    PluginAction::Continue => {}
+                    PluginAction::Skip => {
+                        info!("Plugin '{}' skipped the sync: {}", plugin.label(), reason);
+                        directive.skip = true;
+                    }
+                    PluginAction::Abort => {
+                        warn!("Plugin '{}' aborted the sync: {}", plugin.label(), reason);
+                        directive.abort.get_or_insert(reason);
+                    }
+                }
+                directive.annotations.extend(reply.annotations);
+            }
+            directive
+        })
+    }
+}
+
+// Sends the event to the plugin and reads its reply, an empty reply meaning "continue"
+async fn run_plugin(
+    plugin: &PluginConfig,
+    event: &SyncEvent<'_>,
+    repo: &RepoRef<'_>,
+) -> Result<PluginReply, Box<dyn std::error::Error>> {
+    let payload = event_payload(event, repo)?;
+    let mut command = Command::new(&plugin.command);
+    command.args(&plugin.args);
+
+    let stdout = exchange(command, &payload.to_string(), plugin.timeout_seconds).await?;
+    if stdout.trim().is_empty() {
+        return Ok(PluginReply::default());
+    }
+    Ok(serde_json::from_str(stdout.trim())?)
+}
+
+// The event as sent to plugins and policies
+pub fn event_payload(
+    event: &SyncEvent<'_>,
+    repo: &RepoRef<'_>,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut payload = serde_json::to_value(event)?;
+    // The PAT is left out on purpose, plugins only learn which repo this is
+    payload["repository"] = json!({
+        "organization": repo.organization,
+        "project": repo.project,
+        "repository": repo.repository,
+        "branch": repo.branch,
+    });
+    payload["agent"] = json!({
+        "host": host_name(),
+        "labels": repo.labels,
+    });
+    Ok(payload)
+}
+
+// Runs the command with the payload on stdin and returns what it printed on stdout
+pub async fn exchange(
+    mut command: Command,
+    payload: &str,
+    timeout_seconds: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let exchange = async {
+        if let Some(mut stdin) = child.stdin.take() {
+            // A plugin that exits without reading its input is fine
+            let _ = stdin.write_all(payload.as_bytes()).await;
+        }
+        child.wait_with_output().await
+    };
+
+    // Dropping the exchange on timeout kills the process
+    let output = timeout(Duration::from_secs(timeout_seconds), exchange)
+        .await
+        .map_err(|_| format!("timed out after {} seconds", timeout_seconds))??;
+
+    if !output.status.success() {
+        return Err(format!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
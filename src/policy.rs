@@ -0,0 +1,167 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::notify::RepoRef;
+use crate::plugins;
+use crate::schema::{self, Documented, Field};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::process::Command;
+
+// A WebAssembly module deciding whether a sync may go ahead
+#[derive(Deserialize)]
+pub struct PolicyConfig {
+    // Label used in logs, defaults to the module path
+    pub name: Option<String>,
+    // Path to the .wasm module (a WASI command reading stdin and writing stdout)
+    pub module: String,
+    // WASI runtime used to run the module
+    #[serde(default = "default_runtime")]
+    pub runtime: String,
+    // The module is killed after this long, which denies the sync
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+}
+
+impl Documented for PolicyConfig {
+    const SECTION: &'static str = "[[policies]]";
+    const ABOUT: &'static str =
+        "WebAssembly modules run sandboxed to approve or deny syncs and pick the hooks to run.";
+    const FIELDS: &'static [Field] = &[
+        schema::optional(
+            "name",
+            "string",
+            r#""deploy-window""#,
+            "Label used in the log, defaults to the module",
+        ),
+        schema::required(
+            "module",
+            "string",
+            r#""policies/deploy_window.wasm""#,
+            "WASI command module reading the event on stdin",
+        ),
+        schema::defaulted(
+            "runtime",
+            "string",
+            r#""wasmtime""#,
+            "WASI runtime used to run the module",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "10",
+            "The module is killed and the sync denied after this long",
+        ),
+    ];
+}
+
+impl PolicyConfig {
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.module)
+    }
+}
+
+fn default_runtime() -> String {
+    "wasmtime".to_string()
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+// A policy's verdict on the event it was shown
+#[derive(Deserialize, Default)]
+struct PolicyReply {
+    #[serde(default)]
+    decision: Decision,
+    reason: Option<String>,
+    // Names of the hooks to run, in order, replacing the configured list
+    hooks: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Decision {
+    #[default]
+    Approve,
+    Deny,
+}
+
+// Asks every policy about detected changes and completed pulls
+pub struct Policies {
+    policies: Vec<PolicyConfig>,
+    // Labels of the configured post-sync hooks, shown to the policies
+    hooks: Vec<String>,
+}
+
+impl Policies {
+    pub fn new(policies: Vec<PolicyConfig>, hooks: Vec<String>) -> Self {
+        Policies { policies, hooks }
+    }
+}
+
+impl Subscriber for Policies {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            let mut directive = Directive::default();
+            if !matches!(
+                event,
+                SyncEvent::ChangesDetected { .. } | SyncEvent::PullCompleted { .. }
+            ) {
+                return directive;
+            }
+
+            for policy in &self.policies {
+                // A policy that can't be evaluated denies the sync rather than waving it through
+                let reply = match evaluate(policy, event, repo, &self.hooks).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        error!("Policy '{}' could not be evaluated: {}", policy.label(), e);
+                        directive.abort.get_or_insert(format!(
+                            "policy '{}' could not be evaluated: {}",
+                            policy.label(),
+                            e
+                        ));
+                        continue;
+                    }
+                };
+
+                if let Decision::Deny = reply.decision {
+                    let reason = reply
+                        .reason
+                        .unwrap_or_else(|| format!("denied by policy '{}'", policy.label()));
+                    warn!("Policy '{}' denied the sync: {}", policy.label(), reason);
+                    directive.abort.get_or_insert(reason);
+                }
+                if let Some(hooks) = reply.hooks {
+                    info!(
+                        "Policy '{}' set the hooks to run: {:?}",
+                        policy.label(),
+                        hooks
+                    );
+                    directive.hooks = Some(hooks);
+                }
+            }
+            directive
+        })
+    }
+}
+
+// Runs the module under the WASI runtime, which grants it no directories, environment or network
+async fn evaluate(
+    policy: &PolicyConfig,
+    event: &SyncEvent<'_>,
+    repo: &RepoRef<'_>,
+    hooks: &[String],
+) -> Result<PolicyReply, Box<dyn std::error::Error>> {
+    let mut payload = plugins::event_payload(event, repo)?;
+    payload["hooks"] = json!(hooks);
+
+    let mut command = Command::new(&policy.runtime);
+    command.arg("run").arg(&policy.module);
+
+    let stdout = plugins::exchange(command, &payload.to_string(), policy.timeout_seconds).await?;
+    if stdout.trim().is_empty() {
+        return Ok(PolicyReply::default());
+    }
+    Ok(serde_json::from_str(stdout.trim())?)
+}
@@ -0,0 +1,220 @@
+// How long the agent waits between checks, from the time of day and from how long ago the
+// repository last changed
+use crate::duration;
+use crate::schema::{self, Documented, Field};
+use chrono::{NaiveTime, Weekday};
+use serde::Deserialize;
+
+// A stretch of the week with its own check interval, such as business hours
+#[derive(Deserialize)]
+pub struct ScheduleEntry {
+    // Days it applies on, "Mon" to "Sun"; every day when empty
+    #[serde(default)]
+    pub days: Vec<String>,
+    // Local times it starts and ends at, "HH:MM"; from midnight and until midnight when left out.
+    // A "to" before "from" runs past midnight into the next day.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Replaces check_interval_seconds while the entry applies
+    #[serde(deserialize_with = "duration::seconds")]
+    pub interval: u64,
+}
+
+impl Documented for ScheduleEntry {
+    const SECTION: &'static str = "[[schedule]]";
+    const ABOUT: &'static str = "Check intervals by day and time of day, the first entry covering the current local time replacing check_interval_seconds.";
+    const FIELDS: &'static [Field] = &[
+        schema::optional(
+            "days",
+            "array of strings",
+            r#"["Mon", "Tue", "Wed", "Thu", "Fri"]"#,
+            "Days the entry applies on, Mon to Sun; every day when left out",
+        ),
+        schema::optional(
+            "from",
+            "string",
+            r#""08:00""#,
+            "Local time the entry starts at; midnight when left out",
+        ),
+        schema::optional(
+            "to",
+            "string",
+            r#""18:00""#,
+            "Local time the entry ends at; midnight when left out, the next day when before from",
+        ),
+        schema::required(
+            "interval",
+            "integer or duration",
+            r#""30s""#,
+            "Check interval while the entry applies",
+        ),
+    ];
+}
+
+impl ScheduleEntry {
+    // Fails on days and times the entry can't be matched with
+    pub fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for day in &self.days {
+            day.parse::<Weekday>()
+                .map_err(|_| format!("schedule: unknown day '{}', expected Mon to Sun", day))?;
+        }
+        for time in [&self.from, &self.to].into_iter().flatten() {
+            parse_time(time)?;
+        }
+        if self.interval == 0 {
+            return Err("schedule: interval must be at least 1s".into());
+        }
+        Ok(())
+    }
+
+    // Whether the entry covers this local day and time
+    fn covers(&self, day: Weekday, time: NaiveTime) -> bool {
+        let on = |day: Weekday| {
+            self.days.is_empty()
+                || self
+                    .days
+                    .iter()
+                    .any(|name| name.parse::<Weekday>() == Ok(day))
+        };
+        let from = self.from.as_deref().and_then(|time| parse_time(time).ok());
+        let to = self.to.as_deref().and_then(|time| parse_time(time).ok());
+        match (from, to) {
+            // Past midnight, the evening part belongs to the day it started on
+            (Some(from), Some(to)) if to <= from => {
+                (on(day) && time >= from) || (on(day.pred()) && time < to)
+            }
+            (from, to) => {
+                on(day) && from.is_none_or(|from| time >= from) && to.is_none_or(|to| time < to)
+            }
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, Box<dyn std::error::Error>> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|_| format!("schedule: invalid time '{}', expected HH:MM", time).into())
+}
+
+// The interval of the first schedule entry covering this local day and time
+pub fn scheduled(schedule: &[ScheduleEntry], day: Weekday, time: NaiveTime) -> Option<u64> {
+    schedule
+        .iter()
+        .find(|entry| entry.covers(day, time))
+        .map(|entry| entry.interval)
+}
+
+// The interval settings of a repository
+#[derive(Debug)]
+pub struct Intervals {
+    pub check: u64,
+    // Used while the last change is within active_window
+    pub active: Option<u64>,
+    pub active_window: u64,
+    // Slowest interval, reached once the repository has been quiet for quiet_after
+    pub quiet: Option<u64>,
+    pub quiet_after: u64,
+}
+
+impl Intervals {
+    // Seconds until the next check, `since_change` seconds after the last change. Past the active
+    // window the interval slows down from `check` to `quiet` by the same factor every hour, so the
+    // first quiet hours are still checked often and long quiet nights cost few requests.
+    pub fn at(&self, since_change: u64) -> u64 {
+        if since_change < self.active_window {
+            return self.active.unwrap_or(self.check);
+        }
+        let Some(quiet) = self.quiet else {
+            return self.check;
+        };
+        if since_change >= self.quiet_after || quiet <= self.check {
+            return quiet.max(self.check);
+        }
+        let progress = (since_change - self.active_window) as f64
+            / self.quiet_after.saturating_sub(self.active_window).max(1) as f64;
+        let slowed = self.check as f64 * (quiet as f64 / self.check.max(1) as f64).powf(progress);
+        (slowed as u64).clamp(self.check, quiet)
+    }
+
+    // What the intervals are, for the startup summary
+    pub fn describe(&self) -> String {
+        let mut text = duration::format(self.check);
+        if let Some(active) = self.active {
+            text = format!(
+                "{}, {} for {} after a change",
+                text,
+                duration::format(active),
+                duration::format(self.active_window)
+            );
+        }
+        if let Some(quiet) = self.quiet {
+            text = format!(
+                "{}, slowing to {} after {} quiet",
+                text,
+                duration::format(quiet),
+                duration::format(self.quiet_after)
+            );
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn schedule_entries_past_midnight_cover_the_next_morning() {
+        let entry = |days: &[&str], from: &str, to: &str| ScheduleEntry {
+            days: days.iter().map(|day| day.to_string()).collect(),
+            from: Some(from.to_string()),
+            to: Some(to.to_string()),
+            interval: 900,
+        };
+        let at = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let night = [entry(&["Fri"], "22:00", "06:00")];
+        assert_eq!(scheduled(&night, Weekday::Fri, at("23:30")), Some(900));
+        assert_eq!(scheduled(&night, Weekday::Sat, at("05:59")), Some(900));
+        assert_eq!(scheduled(&night, Weekday::Sat, at("06:00")), None);
+        assert_eq!(scheduled(&night, Weekday::Fri, at("05:00")), None);
+        let day = [entry(&[], "08:00", "18:00")];
+        assert_eq!(scheduled(&day, Weekday::Sun, at("08:00")), Some(900));
+        assert_eq!(scheduled(&day, Weekday::Sun, at("18:00")), None);
+    }
+
+    // Intervals as the config allows them: active below check, quiet above
+    fn intervals() -> impl Strategy<Value = Intervals> {
+        (1..=3600u64).prop_flat_map(|check| {
+            (1..=check, 0..7200u64, check..check + 86400, 0..86400u64).prop_map(
+                move |(active, active_window, quiet, quiet_after)| Intervals {
+                    check,
+                    active: Some(active),
+                    active_window,
+                    quiet: Some(quiet),
+                    quiet_after,
+                },
+            )
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn interval_only_slows_down_and_stays_in_bounds(intervals in intervals()) {
+            let mut previous = intervals.at(intervals.active_window);
+            for hour in 0..30 {
+                let since_change = intervals.active_window + hour * 3600;
+                let interval = intervals.at(since_change);
+                prop_assert!(
+                    interval >= previous
+                        && interval >= intervals.check
+                        && Some(interval) <= intervals.quiet,
+                    "{}s after {}s, after {}s before",
+                    interval,
+                    since_change,
+                    previous
+                );
+                previous = interval;
+            }
+        }
+    }
+}
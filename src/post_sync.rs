@@ -0,0 +1,937 @@
+use crate::archive::{self, ArchiveConfig};
+use crate::copy::{self, CopyConfig};
+use crate::git;
+use crate::glob;
+use crate::hooks::{self, HookConfig, HookResult};
+use crate::image::{self, ImageConfig};
+use crate::kubernetes::{self, KubernetesConfig};
+use crate::schema::{self, Documented, Field};
+use crate::terraform::{self, TerraformConfig};
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::time::{sleep, timeout};
+
+// Actions to run after a successful pull
+#[derive(Deserialize, Default)]
+pub struct PostSyncConfig {
+    #[serde(default)]
+    pub hooks: Vec<HookConfig>,
+    #[serde(default)]
+    pub copy: Vec<CopyConfig>,
+    pub archive: Option<ArchiveConfig>,
+    #[serde(default)]
+    pub images: Vec<ImageConfig>,
+    pub kubernetes: Option<KubernetesConfig>,
+    pub terraform: Option<TerraformConfig>,
+    #[serde(default)]
+    pub restart: Vec<ServiceRestart>,
+    pub compose: Option<ComposeConfig>,
+}
+
+// Redeploys a docker compose stack from the synced repo
+#[derive(Deserialize)]
+pub struct ComposeConfig {
+    // Compose file relative to the repo, defaults to the first standard name found
+    pub file: Option<String>,
+    pub project: Option<String>,
+    #[serde(default = "default_true")]
+    pub rollback_on_failure: bool,
+    // Only redeploy when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Each of pull and up is stopped after this long
+    #[serde(default = "default_compose_timeout")]
+    pub timeout_seconds: u64,
+}
+
+impl Documented for ComposeConfig {
+    const SECTION: &'static str = "[post_sync.compose]";
+    const ABOUT: &'static str =
+        "Redeploys a docker compose stack from the repo after every successful pull.";
+    const FIELDS: &'static [Field] = &[
+        schema::optional(
+            "file",
+            "string",
+            r#""docker-compose.yml""#,
+            "Compose file, defaults to the first of compose.yaml, compose.yml, docker-compose.yaml, docker-compose.yml",
+        ),
+        schema::optional("project", "string", r#""myapp""#, "Compose project name"),
+        schema::defaulted(
+            "rollback_on_failure",
+            "boolean",
+            "true",
+            "Reset to the previous commit and redeploy it if pull/up fails",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only redeploy when a changed file matches one of these (empty means always)",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "600",
+            "Each of pull and up is stopped after this long",
+        ),
+    ];
+}
+
+impl Documented for ServiceRestart {
+    const SECTION: &'static str = "[[post_sync.restart]]";
+    const ABOUT: &'static str =
+        "Services restarted after every successful pull, then health checked.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "kind",
+            "\"systemd\", \"windows\" or \"compose\"",
+            r#""systemd""#,
+            "Service manager used for the restart",
+        ),
+        schema::required(
+            "name",
+            "string",
+            r#""myapp.service""#,
+            "systemd unit, Windows service or compose project",
+        ),
+        schema::optional(
+            "health_url",
+            "string",
+            r#""http://localhost:8080/health""#,
+            "URL that must answer 2xx after the restart",
+        ),
+        schema::defaulted(
+            "health_timeout_seconds",
+            "integer",
+            "30",
+            "How long to wait for the service to become healthy",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "120",
+            "How long the restart command may take before it is stopped",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only restart when a changed file matches one of these (empty means always)",
+        ),
+        schema::defaulted(
+            "min_interval_seconds",
+            "integer",
+            "0",
+            "Shortest time between two restarts; syncs within it defer the restart, which then happens once",
+        ),
+    ];
+}
+
+// What the post-sync actions know about the sync that just happened
+pub struct SyncContext<'a> {
+    // The agent's shared HTTP client, for health checks
+    pub client: &'a Client,
+    pub repo_path: &'a str,
+    pub branch: &'a str,
+    pub old_commit: &'a str,
+    pub new_commit: &'a str,
+    // Hook names chosen by a policy, in the order to run them (None runs the configured list)
+    pub hooks: Option<&'a [String]>,
+}
+
+// Returned when a failed compose redeploy was rolled back to the previous commit, with the
+// steps that ran before it and stay at the new commit
+#[derive(Debug)]
+pub struct RolledBack(pub String, pub Vec<String>);
+
+impl fmt::Display for RolledBack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Deployment failed, the checkout and compose stack were rolled back: {}",
+            self.0
+        )?;
+        if !self.1.is_empty() {
+            write!(f, ". Not undone: {}", self.1.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RolledBack {}
+
+// A service to restart after a pull, followed by a health check
+#[derive(Deserialize)]
+pub struct ServiceRestart {
+    pub kind: ServiceKind,
+    // systemd unit, Windows service name or docker compose project
+    pub name: String,
+    // Optional URL that must answer with a 2xx status once the service is back
+    pub health_url: Option<String>,
+    #[serde(default = "default_health_timeout")]
+    pub health_timeout_seconds: u64,
+    // How long the restart command may take before it is stopped
+    #[serde(default = "default_restart_timeout")]
+    pub timeout_seconds: u64,
+    // Only restart when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+    // Shortest time between two restarts; a sync within it defers the restart until it has passed
+    #[serde(default)]
+    pub min_interval_seconds: u64,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceKind {
+    Systemd,
+    Windows,
+    Compose,
+}
+
+fn default_health_timeout() -> u64 {
+    30
+}
+
+fn default_restart_timeout() -> u64 {
+    120
+}
+
+fn default_compose_timeout() -> u64 {
+    600
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// When each rate-limited hook and restart last ran, and the commit a deferred one has to catch
+// up from. Kept for the life of the repo's loop.
+#[derive(Default)]
+pub struct Cooldowns {
+    actions: HashMap<String, Cooldown>,
+}
+
+struct Cooldown {
+    last_run: Instant,
+    // Commit before the first sync the action was deferred for
+    deferred_from: Option<String>,
+}
+
+impl Cooldowns {
+    // How long the action has to wait before it may run again, if at all
+    fn remaining(&self, key: &str, min_interval_seconds: u64) -> Option<Duration> {
+        let cooldown = self.actions.get(key)?;
+        Duration::from_secs(min_interval_seconds)
+            .checked_sub(cooldown.last_run.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // Holds the action back, keeping the oldest commit it has to catch up from
+    fn defer(&mut self, key: &str, old_commit: &str) {
+        if let Some(cooldown) = self.actions.get_mut(key) {
+            cooldown
+                .deferred_from
+                .get_or_insert_with(|| old_commit.to_string());
+        }
+    }
+
+    fn ran(&mut self, key: &str) {
+        self.actions.insert(
+            key.to_string(),
+            Cooldown {
+                last_run: Instant::now(),
+                deferred_from: None,
+            },
+        );
+    }
+
+    // The commit a deferred action catches up from, once its cooldown is over
+    fn due(&self, key: &str, min_interval_seconds: u64) -> Option<String> {
+        let from = self.actions.get(key)?.deferred_from.clone()?;
+        self.remaining(key, min_interval_seconds)
+            .is_none()
+            .then_some(from)
+    }
+
+    // When the first deferred action may run, for waking up in time for it
+    pub fn next_due(&self, config: &PostSyncConfig) -> Option<Duration> {
+        let hooks = config
+            .hooks
+            .iter()
+            .map(|hook| (hook_key(hook), hook.min_interval_seconds));
+        let restarts = config
+            .restart
+            .iter()
+            .map(|service| (restart_key(service), service.min_interval_seconds));
+        hooks
+            .chain(restarts)
+            .filter(|(key, _)| {
+                self.actions
+                    .get(key)
+                    .is_some_and(|cooldown| cooldown.deferred_from.is_some())
+            })
+            .map(|(key, interval)| self.remaining(&key, interval).unwrap_or_default())
+            .min()
+    }
+}
+
+// Numbers the scratch files of this process, so no two runs ever get the same name
+static SCRATCH_FILES: AtomicU64 = AtomicU64::new(0);
+
+// A file in the temp directory handing input to a command, unique to one run so repositories
+// syncing at the same time never read or delete each other's, and removed when dropped
+pub struct ScratchFile {
+    path: PathBuf,
+}
+
+impl ScratchFile {
+    pub fn create(kind: &str, repo_path: &str, contents: &[u8]) -> io::Result<ScratchFile> {
+        let mut hasher = DefaultHasher::new();
+        repo_path.hash(&mut hasher);
+        let repo = hasher.finish();
+        loop {
+            let number = SCRATCH_FILES.fetch_add(1, Ordering::Relaxed);
+            let path = env::temp_dir().join(format!(
+                "sync-{}-{}-{:016x}-{}.txt",
+                kind,
+                std::process::id(),
+                repo,
+                number
+            ));
+            // create_new refuses a file left behind by an earlier process with the same id
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            let scratch = ScratchFile { path };
+            file.write_all(contents)?;
+            return Ok(scratch);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn hook_key(hook: &HookConfig) -> String {
+    format!("hook:{}", hook.label())
+}
+
+fn restart_key(service: &ServiceRestart) -> String {
+    format!("restart:{}", service.name)
+}
+
+// How long a service manager may take to say whether a service is running
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Compose file names docker compose itself looks for, in its order of preference
+const COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+// Runs every configured post-sync action, stopping at the first failure
+pub async fn run(
+    config: &PostSyncConfig,
+    context: &SyncContext<'_>,
+    cooldowns: &mut Cooldowns,
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let repo_path = context.repo_path;
+    let first_result = hook_results.len();
+    let changed = git::changed_files(repo_path, context.old_commit, context.new_commit).await?;
+    info!("{} file(s) changed in this sync.", changed.len());
+
+    let hooks = select_hooks(&config.hooks, context.hooks);
+    if !hooks.is_empty() {
+        // Hooks get the changed file list as a file since it can be too long for an environment variable
+        let changed_files =
+            ScratchFile::create("changed-files", repo_path, changed.join("\n").as_bytes())?;
+        let vars = hook_vars(context, changed_files.path());
+
+        run_hooks(&hooks, &changed, &vars, context, cooldowns, hook_results).await?;
+    }
+
+    // Before the compose redeploy, which may use the freshly built image
+    let image_vars = [
+        ("old_commit", context.old_commit.to_string()),
+        ("new_commit", context.new_commit.to_string()),
+        (
+            "short_commit",
+            context.new_commit.chars().take(8).collect::<String>(),
+        ),
+        ("branch", context.branch.to_string()),
+    ];
+    for config in &config.images {
+        if !paths_changed(&config.paths, &changed) {
+            info!(
+                "Skipping image '{}', no matching files changed.",
+                config.label()
+            );
+            continue;
+        }
+        image::build(config, repo_path, &image_vars, hook_results).await?;
+    }
+
+    // Right after the images it may use, and before the steps a compose rollback can't undo
+    if let Some(compose) = &config.compose {
+        if paths_changed(&compose.paths, &changed) {
+            let ran_before: Vec<String> = hook_results[first_result..]
+                .iter()
+                .map(|result| result.name.clone())
+                .collect();
+            redeploy_compose(compose, context, &ran_before, hook_results).await?;
+        } else {
+            info!("Skipping compose redeploy, no matching files changed.");
+        }
+    }
+
+    // After the hooks, so files they build are copied too, and after compose, so a stack that
+    // was rolled back leaves the destinations alone
+    for copy in &config.copy {
+        if !paths_changed(&copy.paths, &changed) {
+            info!(
+                "Skipping copy to '{}', no matching files changed.",
+                copy.describe()
+            );
+            continue;
+        }
+        let copied = copy::run(copy, repo_path, context.old_commit, context.new_commit)
+            .await
+            .map_err(|e| format!("Copy to '{}' failed: {}", copy.describe(), e))?;
+        info!(
+            "Copied {} file(s) to '{}', {} deleted, {} unchanged.",
+            copied.copied,
+            copy.describe(),
+            copied.deleted,
+            copied.unchanged
+        );
+    }
+
+    if let Some(archive) = &config.archive {
+        archive::create(archive, repo_path, context.new_commit)
+            .await
+            .map_err(|e| format!("Archive failed: {}", e))?;
+    }
+
+    if let Some(cluster) = &config.kubernetes {
+        if paths_changed(&cluster.paths, &changed) {
+            kubernetes::apply(cluster, repo_path, hook_results).await?;
+        } else {
+            info!("Skipping kubectl apply, no matching files changed.");
+        }
+    }
+
+    if let Some(module) = &config.terraform {
+        if paths_changed(&module.paths, &changed) {
+            terraform::plan(module, repo_path, context.new_commit, hook_results).await?;
+        } else {
+            info!("Skipping terraform plan, no matching files changed.");
+        }
+    }
+
+    for service in &config.restart {
+        if !paths_changed(&service.paths, &changed) {
+            info!(
+                "Skipping restart of '{}', no matching files changed.",
+                service.name
+            );
+            continue;
+        }
+        let key = restart_key(service);
+        if let Some(remaining) = cooldowns.remaining(&key, service.min_interval_seconds) {
+            info!(
+                "Deferring restart of '{}' for {}s (min_interval_seconds).",
+                service.name,
+                remaining.as_secs()
+            );
+            cooldowns.defer(&key, context.old_commit);
+            continue;
+        }
+        cooldowns.ran(&key);
+        restart_service(service, hook_results).await?;
+        wait_until_healthy(service, repo_path, context.client).await?;
+        info!("Service '{}' restarted and healthy.", service.name);
+    }
+
+    Ok(())
+}
+
+// Runs the hooks and restarts deferred by their min_interval_seconds whose wait is over, for
+// every change since they were deferred up to the commit the repo is at now. Stops at the
+// first failure.
+pub async fn run_deferred(
+    config: &PostSyncConfig,
+    client: &Client,
+    repo_path: &str,
+    branch: &str,
+    commit: &str,
+    cooldowns: &mut Cooldowns,
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for hook in &config.hooks {
+        let key = hook_key(hook);
+        let Some(from) = cooldowns.due(&key, hook.min_interval_seconds) else {
+            continue;
+        };
+        info!(
+            "Running deferred hook '{}' for the changes from {} to {}.",
+            hook.label(),
+            from,
+            commit
+        );
+        let context = SyncContext {
+            client,
+            repo_path,
+            branch,
+            old_commit: &from,
+            new_commit: commit,
+            hooks: None,
+        };
+        // Counted as run even if it can't start, so a broken catch-up isn't retried every cycle
+        cooldowns.ran(&key);
+        let changed = git::changed_files(repo_path, &from, commit).await?;
+        let changed_files =
+            ScratchFile::create("changed-files", repo_path, changed.join("\n").as_bytes())?;
+        let vars = hook_vars(&context, changed_files.path());
+
+        let result = hooks::run_hook(hook, repo_path, &vars).await;
+        drop(changed_files);
+        let succeeded = result.succeeded();
+        hook_results.push(result);
+        if !succeeded {
+            return Err(format!("Deferred hook '{}' failed", hook.label()).into());
+        }
+    }
+
+    for service in &config.restart {
+        let key = restart_key(service);
+        if cooldowns.due(&key, service.min_interval_seconds).is_none() {
+            continue;
+        }
+        info!("Running deferred restart of '{}'.", service.name);
+        cooldowns.ran(&key);
+        restart_service(service, hook_results).await?;
+        wait_until_healthy(service, repo_path, client).await?;
+        info!("Service '{}' restarted and healthy.", service.name);
+    }
+
+    Ok(())
+}
+
+// Runs the hooks whose path filters match, stopping at the first failure
+async fn run_hooks(
+    hooks: &[&HookConfig],
+    changed: &[String],
+    vars: &[(&str, String)],
+    context: &SyncContext<'_>,
+    cooldowns: &mut Cooldowns,
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for hook in hooks {
+        if !paths_changed(&hook.paths, changed) {
+            info!(
+                "Skipping hook '{}', no matching files changed.",
+                hook.label()
+            );
+            continue;
+        }
+        let key = hook_key(hook);
+        if let Some(remaining) = cooldowns.remaining(&key, hook.min_interval_seconds) {
+            info!(
+                "Deferring hook '{}' for {}s (min_interval_seconds).",
+                hook.label(),
+                remaining.as_secs()
+            );
+            cooldowns.defer(&key, context.old_commit);
+            continue;
+        }
+        cooldowns.ran(&key);
+        let result = hooks::run_hook(hook, context.repo_path, vars).await;
+        let succeeded = result.succeeded();
+        hook_results.push(result);
+        if !succeeded {
+            return Err(format!("Hook '{}' failed", hook.label()).into());
+        }
+    }
+
+    Ok(())
+}
+
+// The configured hooks, or the ones a policy picked by name
+fn select_hooks<'a>(hooks: &'a [HookConfig], names: Option<&[String]>) -> Vec<&'a HookConfig> {
+    let Some(names) = names else {
+        return hooks.iter().collect();
+    };
+
+    names
+        .iter()
+        .filter_map(|name| {
+            let hook = hooks.iter().find(|hook| hook.label() == name);
+            if hook.is_none() {
+                warn!("Ignoring unknown hook '{}' chosen by a policy.", name);
+            }
+            hook
+        })
+        .collect()
+}
+
+// Sync details exposed to hooks, as SYNC_<NAME> variables and {{name}} placeholders
+fn hook_vars(context: &SyncContext<'_>, changed_files_path: &Path) -> Vec<(&'static str, String)> {
+    vec![
+        ("old_commit", context.old_commit.to_string()),
+        ("new_commit", context.new_commit.to_string()),
+        ("branch", context.branch.to_string()),
+        ("repo_path", context.repo_path.to_string()),
+        (
+            "changed_files",
+            changed_files_path.to_string_lossy().to_string(),
+        ),
+    ]
+}
+
+// An empty filter always matches, otherwise at least one changed file must match a pattern
+fn paths_changed(patterns: &[String], changed: &[String]) -> bool {
+    patterns.is_empty() || changed.iter().any(|file| glob::matches_any(patterns, file))
+}
+
+// Issues the restart for the service using the tool matching its kind, within its timeout
+async fn restart_service(
+    service: &ServiceRestart,
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let name = service.name.clone();
+    let (program, args) = match service.kind {
+        ServiceKind::Systemd => ("systemctl", vec!["restart".to_string(), name]),
+        ServiceKind::Windows => (
+            "powershell",
+            vec![
+                "-NoProfile".to_string(),
+                "-Command".to_string(),
+                format!("Restart-Service -Name '{}'", name),
+            ],
+        ),
+        ServiceKind::Compose => (
+            "docker",
+            vec![
+                "compose".to_string(),
+                "-p".to_string(),
+                name,
+                "restart".to_string(),
+            ],
+        ),
+    };
+
+    hooks::run_tool(
+        &format!("restart of '{}'", service.name),
+        program,
+        &args,
+        None,
+        service.timeout_seconds,
+        hook_results,
+    )
+    .await
+}
+
+// Pulls images and recreates the compose stack, rolling back to the old commit if that fails
+async fn redeploy_compose(
+    compose: &ComposeConfig,
+    context: &SyncContext<'_>,
+    ran_before: &[String],
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = match &compose.file {
+        Some(file) => file.clone(),
+        None => match COMPOSE_FILES
+            .iter()
+            .find(|name| Path::new(context.repo_path).join(name).exists())
+        {
+            Some(name) => name.to_string(),
+            None => {
+                info!("No compose file found in the repo, skipping compose redeploy.");
+                return Ok(());
+            }
+        },
+    };
+
+    // The file is passed with the repo in front, since docker runs in the agent's directory
+    let mut args = vec![
+        "compose".to_string(),
+        "-f".to_string(),
+        Path::new(context.repo_path)
+            .join(&file)
+            .to_string_lossy()
+            .to_string(),
+    ];
+    if let Some(project) = &compose.project {
+        args.extend(["-p".to_string(), project.clone()]);
+    }
+
+    info!("Redeploying compose stack from '{}'", file);
+    let result = match compose_command(compose, &args, "pull", hook_results).await {
+        Ok(()) => compose_command(compose, &args, "up", hook_results).await,
+        Err(e) => Err(e),
+    };
+
+    let error = match result {
+        Ok(()) => {
+            info!("Compose stack redeployed at {}", context.new_commit);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    if !compose.rollback_on_failure {
+        return Err(error);
+    }
+
+    warn!(
+        "Compose redeploy failed ({}), rolling back to {}",
+        error, context.old_commit
+    );
+    git::reset_hard(context.repo_path, context.old_commit).await?;
+    compose_command(compose, &args, "up", hook_results).await?;
+    info!("Rolled back compose stack to {}", context.old_commit);
+
+    if !ran_before.is_empty() {
+        warn!(
+            "Only the checkout and compose stack were rolled back, {} stay at {}.",
+            ran_before.join(", "),
+            context.new_commit
+        );
+    }
+    Err(Box::new(RolledBack(error.to_string(), ran_before.to_vec())))
+}
+
+// Runs one compose step within the timeout, recording it with the end of its output
+async fn compose_command(
+    compose: &ComposeConfig,
+    base_args: &[String],
+    step: &str,
+    hook_results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = base_args.to_vec();
+    match step {
+        "up" => args.extend(["up", "-d", "--remove-orphans"].map(String::from)),
+        other => args.push(other.to_string()),
+    }
+
+    hooks::run_tool(
+        &format!("docker compose {}", step),
+        "docker",
+        &args,
+        None,
+        compose.timeout_seconds,
+        hook_results,
+    )
+    .await
+}
+
+// Polls the service (and its health URL if set) until it reports healthy or the timeout passes
+async fn wait_until_healthy(
+    service: &ServiceRestart,
+    repo_path: &str,
+    client: &Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let deadline = Instant::now() + Duration::from_secs(service.health_timeout_seconds);
+
+    loop {
+        let running = is_running(service, repo_path).await;
+        // A probe gets at most the time that is left, so a service that accepts the connection
+        // but never answers still fails the check on time
+        let remaining = deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_secs(1));
+        let responding = match &service.health_url {
+            Some(url) => matches!(
+                client.get(url).timeout(remaining).send().await,
+                Ok(response) if response.status().is_success()
+            ),
+            None => true,
+        };
+
+        if running && responding {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            error!(
+                "Service '{}' did not become healthy within {} seconds (running: {}, health check passed: {})",
+                service.name, service.health_timeout_seconds, running, responding
+            );
+            return Err(format!("Service '{}' failed its health check", service.name).into());
+        }
+
+        sleep(Duration::from_secs(2)).await;
+    }
+}
+
+// Asks the service manager whether the service is running
+async fn is_running(service: &ServiceRestart, repo_path: &str) -> bool {
+    match service.kind {
+        ServiceKind::Systemd => probe("systemctl", &["is-active", &service.name], None)
+            .await
+            .is_some(),
+        ServiceKind::Windows => probe("sc", &["query", &service.name], None)
+            .await
+            .is_some_and(|out| out.contains("RUNNING")),
+        ServiceKind::Compose => probe(
+            "docker",
+            &[
+                "compose",
+                "-p",
+                &service.name,
+                "ps",
+                "--status",
+                "running",
+                "--quiet",
+            ],
+            Some(repo_path),
+        )
+        .await
+        .is_some_and(|out| !out.trim().is_empty()),
+    }
+}
+
+// Runs a status command quietly, returning stdout only when it succeeds within STATUS_TIMEOUT
+async fn probe(program: &str, args: &[&str], dir: Option<&str>) -> Option<String> {
+    let mut command = Command::new(program);
+    command.args(args).kill_on_drop(true);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    let output = timeout(STATUS_TIMEOUT, command.output()).await.ok()?.ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Runs a command to completion and returns everything it produced
+async fn command_output(
+    program: &str,
+    args: &[&str],
+    dir: Option<&str>,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let mut command = Command::new(program);
+    command.args(args).kill_on_drop(true);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    Ok(command
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run '{}': {}", program, e))?)
+}
+
+// Runs a command to completion, returning stdout or an error carrying its output
+pub async fn run_command(
+    program: &str,
+    args: &[&str],
+    dir: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let output = command_output(program, args, dir).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!(
+            "'{} {}' failed. stdout: {}, stderr: {}",
+            program,
+            args.join(" "),
+            stdout,
+            stderr
+        );
+        return Err(format!("'{}' exited with {}", program, output.status).into());
+    }
+
+    Ok(stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_rollback_names_what_it_did_not_undo() {
+        let only_compose = RolledBack("docker compose up failed".to_string(), Vec::new());
+        assert_eq!(
+            only_compose.to_string(),
+            "Deployment failed, the checkout and compose stack were rolled back: docker compose up failed"
+        );
+        let after_hooks = RolledBack(
+            "docker compose up failed".to_string(),
+            vec!["build".to_string(), "docker build app".to_string()],
+        );
+        assert!(after_hooks
+            .to_string()
+            .ends_with(". Not undone: build, docker build app"));
+    }
+
+    #[tokio::test]
+    async fn a_health_url_that_never_answers_fails_on_time() {
+        // Accepts the connection, then never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let service: ServiceRestart = toml::from_str(&format!(
+            "kind = \"compose\"\nname = \"no-such-project\"\nhealth_url = \"http://{}/health\"\nhealth_timeout_seconds = 1",
+            address
+        ))
+        .unwrap();
+        let started = Instant::now();
+        let checked = timeout(
+            Duration::from_secs(20),
+            wait_until_healthy(&service, ".", &Client::new()),
+        )
+        .await;
+        assert!(matches!(checked, Ok(Err(_))));
+        assert!(started.elapsed() < Duration::from_secs(15));
+    }
+
+    #[test]
+    fn scratch_files_are_unique_per_run_and_removed_when_dropped() {
+        let first = ScratchFile::create("test", "/srv/repo", b"a.txt").unwrap();
+        let second = ScratchFile::create("test", "/srv/repo", b"b.txt").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(fs::read_to_string(first.path()).unwrap(), "a.txt");
+        assert_eq!(fs::read_to_string(second.path()).unwrap(), "b.txt");
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.path().exists());
+    }
+}
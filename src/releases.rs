@@ -0,0 +1,276 @@
+// The last few synced commits kept as read-only git worktrees beside the checkout, with a
+// `current` link services run from. Rolling back only points the link at an older worktree, so
+// it is instant and needs no network.
+use crate::git;
+use crate::post_sync::run_command;
+use crate::schema::{self, Documented, Field};
+use crate::unpack;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// File in the directory listing the kept commits, oldest first
+const INDEX_FILE: &str = ".releases";
+
+// Characters of the commit id a worktree is named after
+const NAME_LENGTH: usize = 12;
+
+// The [releases] table
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReleasesConfig {
+    // Directory holding one worktree per kept commit; it must not be inside the checkout
+    pub directory: String,
+    // Commits kept, the current one included
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    // Link pointing at the current commit's worktree, <directory>/current unless set
+    pub current_link: Option<String>,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+impl Documented for ReleasesConfig {
+    const SECTION: &'static str = "[releases]";
+    const ABOUT: &'static str = "Previous commits kept as read-only worktrees behind a `current` link, for an offline `rollback`.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "directory",
+            "string",
+            r#""/srv/website-releases""#,
+            "Directory holding one worktree per kept commit, outside repo_path",
+        ),
+        schema::defaulted(
+            "keep",
+            "integer",
+            "5",
+            "Commits kept, the current one included; older worktrees are removed",
+        ),
+        schema::defaulted(
+            "current_link",
+            "string",
+            "<directory>/current",
+            "Symbolic link pointed at the current commit's worktree, for services to run from",
+        ),
+    ];
+}
+
+impl ReleasesConfig {
+    // Refuses a directory inside the checkout, where cleaning would remove the link and index
+    pub fn check(&self, repo_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if self.keep == 0 {
+            return Err("[releases] keep must be at least 1".into());
+        }
+        let absolute = |path: &str| {
+            std::path::absolute(path).map_err(|e| format!("Invalid path '{}': {}", path, e))
+        };
+        if absolute(&self.directory)?.starts_with(absolute(repo_path)?) {
+            return Err(format!(
+                "[releases] directory '{}' is inside repo_path '{}'",
+                self.directory, repo_path
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn link(&self) -> PathBuf {
+        match &self.current_link {
+            Some(link) => PathBuf::from(link),
+            None => Path::new(&self.directory).join("current"),
+        }
+    }
+
+    fn worktree(&self, commit: &str) -> PathBuf {
+        Path::new(&self.directory).join(&commit[..commit.len().min(NAME_LENGTH)])
+    }
+
+    // The kept commits, oldest first
+    pub fn kept(&self) -> Vec<String> {
+        fs::read_to_string(Path::new(&self.directory).join(INDEX_FILE))
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn save(&self, kept: &[String]) -> io::Result<()> {
+        let index = Path::new(&self.directory).join(INDEX_FILE);
+        let staged = unpack::sibling(&index, "new");
+        fs::write(&staged, kept.join("\n") + "\n")?;
+        fs::rename(&staged, &index)
+    }
+
+    // The commit the link points at, if it points into the directory
+    pub fn current(&self) -> Option<String> {
+        let target = fs::read_link(self.link()).ok()?;
+        let name = target.file_name()?.to_str()?;
+        self.kept()
+            .into_iter()
+            .find(|commit| commit.starts_with(name))
+    }
+}
+
+// Adds a worktree for the commit, points the link at it and removes the oldest worktrees beyond
+// `keep`. The link only moves once the worktree is complete.
+pub async fn publish(
+    repo_path: &str,
+    releases: &ReleasesConfig,
+    commit: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(&releases.directory)?;
+    // Worktrees deleted by hand are otherwise still registered and block a new one at their path
+    run_command(
+        git::program(),
+        &["-C", repo_path, "worktree", "prune"],
+        None,
+    )
+    .await?;
+
+    let worktree = releases.worktree(commit);
+    if !worktree.exists() {
+        let path = worktree.to_string_lossy();
+        run_command(
+            git::program(),
+            &[
+                "-C", repo_path, "worktree", "add", "--detach", &path, commit,
+            ],
+            None,
+        )
+        .await?;
+        set_writable(&worktree, false)?;
+        info!("Added release worktree {}.", worktree.display());
+    }
+    activate(releases, commit)?;
+
+    let mut kept = releases.kept();
+    kept.retain(|kept| kept != commit);
+    kept.push(commit.to_string());
+    let excess = kept.len().saturating_sub(releases.keep);
+    for old in kept.drain(..excess) {
+        if let Err(e) = remove(repo_path, releases, &old).await {
+            warn!("Failed to remove the release worktree of {}: {}", old, e);
+        }
+    }
+    releases.save(&kept)?;
+    Ok(())
+}
+
+// Points the link at a kept commit's worktree, replacing it in one rename so services never see
+// it missing
+pub fn activate(releases: &ReleasesConfig, commit: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let worktree = releases.worktree(commit);
+    if !worktree.is_dir() {
+        return Err(format!(
+            "No release worktree for {} in {}",
+            commit, releases.directory
+        )
+        .into());
+    }
+    let target = std::path::absolute(&worktree)?;
+    let link = releases.link();
+    let staged = unpack::sibling(&link, "new");
+    remove_link(&staged);
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&target, &staged)?;
+    #[cfg(windows)]
+    {
+        std::os::windows::fs::symlink_dir(&target, &staged)?;
+        // Windows won't rename over an existing directory link
+        remove_link(&link);
+    }
+    fs::rename(&staged, &link)?;
+    info!("{} now points at {}.", link.display(), worktree.display());
+    Ok(())
+}
+
+// Points the link back at the kept commit before the current one, or at the one starting with
+// `to`, returning the commit now current
+pub fn rollback(
+    releases: &ReleasesConfig,
+    to: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let kept = releases.kept();
+    let commit = match to {
+        Some(prefix) => kept
+            .iter()
+            .rev()
+            .find(|commit| commit.starts_with(prefix))
+            .ok_or_else(|| format!("No kept release matches '{}'", prefix))?,
+        None => {
+            let current = releases
+                .current()
+                .ok_or("The current link doesn't point at a kept release")?;
+            let position = kept
+                .iter()
+                .position(|commit| *commit == current)
+                .unwrap_or(0);
+            kept[..position]
+                .last()
+                .ok_or_else(|| format!("No release older than {} is kept", current))?
+        }
+    };
+    activate(releases, commit)?;
+    Ok(commit.clone())
+}
+
+async fn remove(
+    repo_path: &str,
+    releases: &ReleasesConfig,
+    commit: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let worktree = releases.worktree(commit);
+    if worktree.exists() {
+        set_writable(&worktree, true)?;
+        let path = worktree.to_string_lossy();
+        run_command(
+            git::program(),
+            &["-C", repo_path, "worktree", "remove", "--force", &path],
+            None,
+        )
+        .await?;
+        info!("Removed release worktree {}.", worktree.display());
+    }
+    Ok(())
+}
+
+fn remove_link(link: &Path) {
+    // Directory links are removed as directories on Windows
+    if fs::remove_file(link).is_err() {
+        let _ = fs::remove_dir(link);
+    }
+}
+
+// Takes write permission off every file in the worktree, or gives the owner's back. Links are
+// skipped, since changing them would change what they point at.
+fn set_writable(path: &Path, writable: bool) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            set_writable(&entry?.path(), writable)?;
+        }
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = permissions.mode();
+        permissions.set_mode(if writable {
+            mode | 0o200
+        } else {
+            mode & !0o222
+        });
+    }
+    #[cfg(not(unix))]
+    permissions.set_readonly(!writable);
+    fs::set_permissions(path, permissions)
+}
@@ -0,0 +1,301 @@
+use crate::events::{Directive, Handled, Subscriber, SyncEvent};
+use crate::history::CommitSummary;
+use crate::notify::{host_name, RepoRef};
+use crate::schema::{self, Documented, Field};
+use crate::script::Script;
+use chrono::{Local, Timelike};
+use log::{error, info};
+use rhai::Dynamic;
+use serde::Deserialize;
+
+// What a rule can see, as sync_vars() sets them
+const RULE_VARS: [&str; 15] = [
+    "repo",
+    "branch",
+    "host",
+    "old_commit",
+    "new_commit",
+    "commit_count",
+    "author",
+    "author_email",
+    "message",
+    "authors",
+    "author_emails",
+    "messages",
+    "hour",
+    "minute",
+    "weekday",
+];
+
+// What interval_script can see
+const INTERVAL_VARS: [&str; 5] = [
+    "since_change",
+    "default_interval",
+    "hour",
+    "minute",
+    "weekday",
+];
+
+// An inline policy snippet checked when new commits are detected, before they are pulled.
+// When it matches, the sync is skipped and recorded as aborted.
+#[derive(Deserialize)]
+pub struct RuleConfig {
+    // Expression that triggers the rule when it is true
+    pub when: String,
+    pub reason: Option<String>,
+}
+
+impl Documented for RuleConfig {
+    const SECTION: &'static str = "[[rules]]";
+    const ABOUT: &'static str =
+        "Inline expressions that skip syncing the new commits when they match.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "when",
+            "Rhai expression",
+            r#"'author_email == "build-bot@contoso.com"'"#,
+            "The new commits aren't pulled when this is true",
+        ),
+        schema::optional(
+            "reason",
+            "string",
+            r#""commits from the build bot are not deployed""#,
+            "Logged and recorded when the rule matches",
+        ),
+    ];
+}
+
+// Checks the rules against the new commits of every sync, before they are pulled
+pub struct Rules {
+    rules: Vec<(RuleConfig, Script)>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<RuleConfig>) -> Result<Self, String> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let script = compile_rule(&rule)?;
+                Ok((rule, script))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Rules { rules })
+    }
+}
+
+fn compile_rule(rule: &RuleConfig) -> Result<Script, String> {
+    Script::compile(&rule.when, &RULE_VARS).map_err(|e| format!("rule '{}': {}", rule.when, e))
+}
+
+// Compiles the rules and interval_script when the config is read, failing on the first that
+// doesn't compile
+pub fn check(rules: &[RuleConfig], interval_script: Option<&str>) -> Result<(), String> {
+    for rule in rules {
+        compile_rule(rule)?;
+    }
+    if let Some(script) = interval_script {
+        IntervalScript::new(script)?;
+    }
+    Ok(())
+}
+
+impl Subscriber for Rules {
+    fn handle<'a>(&'a mut self, event: &'a SyncEvent<'a>, repo: &'a RepoRef<'a>) -> Handled<'a> {
+        Box::pin(async move {
+            let mut directive = Directive::default();
+            let SyncEvent::ChangesDetected {
+                old_commit,
+                new_commit,
+                commits,
+            } = event
+            else {
+                return directive;
+            };
+
+            let vars = sync_vars(repo, old_commit, new_commit, commits);
+            for (rule, script) in &self.rules {
+                match script.eval_bool(&vars) {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        let reason = rule
+                            .reason
+                            .clone()
+                            .unwrap_or_else(|| format!("rule '{}' matched", rule.when));
+                        info!("Skipping the sync of {}: {}", new_commit, reason);
+                        directive.abort.get_or_insert(reason);
+                    }
+                    // A rule that can't decide doesn't let the commits through
+                    Err(e) => {
+                        error!("Failed to evaluate rule '{}': {}", rule.when, e);
+                        directive
+                            .abort
+                            .get_or_insert(format!("rule '{}' failed: {}", rule.when, e));
+                    }
+                }
+            }
+            directive
+        })
+    }
+}
+
+// A compiled interval_script
+pub struct IntervalScript(Script);
+
+impl IntervalScript {
+    pub fn new(source: &str) -> Result<Self, String> {
+        Script::compile(source, &INTERVAL_VARS)
+            .map(IntervalScript)
+            .map_err(|e| format!("interval_script: {}", e))
+    }
+}
+
+// Works out how long to wait before the next check, falling back to the fixed interval
+pub fn check_interval(script: Option<&IntervalScript>, default: u64, since_change: u64) -> u64 {
+    let Some(IntervalScript(script)) = script else {
+        return default;
+    };
+
+    let mut vars = clock_vars();
+    vars.push(("since_change", Dynamic::from_int(since_change as i64)));
+    vars.push(("default_interval", Dynamic::from_int(default as i64)));
+    match script.eval_number(&vars) {
+        Ok(seconds) if seconds >= 1.0 => seconds as u64,
+        Ok(seconds) => {
+            error!("interval_script returned {}, using {}s.", seconds, default);
+            default
+        }
+        Err(e) => {
+            error!("Failed to evaluate interval_script: {}", e);
+            default
+        }
+    }
+}
+
+// What a rule can see about the sync
+fn sync_vars(
+    repo: &RepoRef<'_>,
+    old_commit: &str,
+    new_commit: &str,
+    commits: &[CommitSummary],
+) -> Vec<(&'static str, Dynamic)> {
+    // Commits are listed newest first
+    let newest = commits.first();
+    let text =
+        |f: fn(&CommitSummary) -> &str| Dynamic::from(newest.map(f).unwrap_or("").to_string());
+    let joined = |f: fn(&CommitSummary) -> &str, separator| {
+        Dynamic::from(commits.iter().map(f).collect::<Vec<_>>().join(separator))
+    };
+
+    let mut vars = vec![
+        ("repo", Dynamic::from(repo.repository.to_string())),
+        ("branch", Dynamic::from(repo.branch.to_string())),
+        ("host", Dynamic::from(host_name())),
+        ("old_commit", Dynamic::from(old_commit.to_string())),
+        ("new_commit", Dynamic::from(new_commit.to_string())),
+        ("commit_count", Dynamic::from_int(commits.len() as i64)),
+        ("author", text(|c| &c.author)),
+        ("author_email", text(|c| &c.author_email)),
+        ("message", text(|c| &c.message)),
+        ("authors", joined(|c| &c.author, ", ")),
+        ("author_emails", joined(|c| &c.author_email, ", ")),
+        ("messages", joined(|c| &c.message, "\n")),
+    ];
+    vars.extend(clock_vars());
+    vars
+}
+
+// Local time of day, for rules and intervals that depend on it
+fn clock_vars() -> Vec<(&'static str, Dynamic)> {
+    let now = Local::now();
+    vec![
+        ("hour", Dynamic::from_int(now.hour() as i64)),
+        ("minute", Dynamic::from_int(now.minute() as i64)),
+        ("weekday", Dynamic::from(now.format("%a").to_string())),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo() -> RepoRef<'static> {
+        RepoRef {
+            organization: "contoso",
+            project: "web",
+            repository: "site",
+            branch: "main",
+            pat: "",
+            labels: &[],
+        }
+    }
+
+    fn rule(when: &str) -> RuleConfig {
+        RuleConfig {
+            when: when.to_string(),
+            reason: None,
+        }
+    }
+
+    fn commit(author_email: &str) -> CommitSummary {
+        CommitSummary {
+            id: "abc123".to_string(),
+            short_id: "abc123".to_string(),
+            author: "Build Bot".to_string(),
+            author_email: author_email.to_string(),
+            message: "Bump version".to_string(),
+            committed_at: None,
+            work_item_ids: Vec::new(),
+        }
+    }
+
+    async fn decide(rules: Vec<RuleConfig>, commits: &[CommitSummary]) -> Option<String> {
+        let mut rules = Rules::new(rules).unwrap();
+        let event = SyncEvent::ChangesDetected {
+            old_commit: "old",
+            new_commit: "new",
+            commits,
+        };
+        rules.handle(&event, &repo()).await.abort
+    }
+
+    #[test]
+    fn rules_see_every_variable_they_may_name() {
+        let names: Vec<&str> = sync_vars(&repo(), "old", "new", &[])
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, RULE_VARS);
+    }
+
+    #[test]
+    fn broken_scripts_are_refused_when_the_config_is_read() {
+        assert!(check(&[rule(r#"author_email == "bot@contoso.com""#)], Some("60")).is_ok());
+        assert!(check(&[rule("author_emial == 1")], None).is_err());
+        assert!(check(&[rule("author ==")], None).is_err());
+        assert!(check(&[], Some("if hour > 22 { 600 }  else")).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_rule_that_fails_holds_the_sync() {
+        let commits = [commit("bot@contoso.com")];
+        let matching = rule(r#"author_email == "bot@contoso.com""#);
+        assert!(decide(vec![matching], &commits).await.is_some());
+        assert!(decide(vec![rule("commit_count > 5")], &commits)
+            .await
+            .is_none());
+
+        // Compiles, but a string has no hours
+        let reason = decide(vec![rule("author.hours() > 1")], &commits).await;
+        assert!(reason.is_some_and(|reason| reason.contains("failed")));
+    }
+
+    #[test]
+    fn intervals_fall_back_when_the_script_fails() {
+        let script = IntervalScript::new("if since_change < 300 { 5 } else { default_interval }");
+        assert_eq!(check_interval(script.as_ref().ok(), 60, 10), 5);
+        assert_eq!(check_interval(script.as_ref().ok(), 60, 600), 60);
+        let failing = IntervalScript::new("weekday * 2").unwrap();
+        assert_eq!(check_interval(Some(&failing), 60, 10), 60);
+        assert_eq!(check_interval(None, 60, 10), 60);
+    }
+}
@@ -0,0 +1,155 @@
+// Reference documentation of config.toml. Each config struct describes its keys next to its
+// definition, and `config-schema` prints them all.
+use std::fmt::Write;
+
+// Whether a key must be set, and what it falls back to when it isn't
+pub enum Presence {
+    Required,
+    Optional,
+    // The TOML value used when the key is left out
+    Default(&'static str),
+}
+
+// One key of a config section
+pub struct Field {
+    pub key: &'static str,
+    pub kind: &'static str,
+    pub presence: Presence,
+    // A TOML value the key could be set to
+    pub example: &'static str,
+    pub about: &'static str,
+}
+
+pub const fn required(
+    key: &'static str,
+    kind: &'static str,
+    example: &'static str,
+    about: &'static str,
+) -> Field {
+    Field {
+        key,
+        kind,
+        presence: Presence::Required,
+        example,
+        about,
+    }
+}
+
+pub const fn optional(
+    key: &'static str,
+    kind: &'static str,
+    example: &'static str,
+    about: &'static str,
+) -> Field {
+    Field {
+        key,
+        kind,
+        presence: Presence::Optional,
+        example,
+        about,
+    }
+}
+
+pub const fn defaulted(
+    key: &'static str,
+    kind: &'static str,
+    default: &'static str,
+    about: &'static str,
+) -> Field {
+    Field {
+        key,
+        kind,
+        presence: Presence::Default(default),
+        example: default,
+        about,
+    }
+}
+
+// A config struct that documents its keys
+pub trait Documented {
+    // TOML table header, e.g. "[[post_sync.hooks]]", or "" for the top level
+    const SECTION: &'static str;
+    const ABOUT: &'static str;
+    const FIELDS: &'static [Field];
+}
+
+// A documented config struct, type-erased so different structs can be listed together
+pub struct Section {
+    pub header: &'static str,
+    pub about: &'static str,
+    pub fields: &'static [Field],
+}
+
+impl Section {
+    pub fn of<T: Documented>() -> Section {
+        Section {
+            header: T::SECTION,
+            about: T::ABOUT,
+            fields: T::FIELDS,
+        }
+    }
+}
+
+// The annotated reference printed by `config-schema`
+pub fn reference(sections: &[Section]) -> String {
+    let mut out = String::new();
+    for section in sections {
+        let title = if section.header.is_empty() {
+            "Top-level keys"
+        } else {
+            section.header
+        };
+        let _ = writeln!(out, "{}\n    {}\n", title, section.about);
+
+        for field in section.fields {
+            let presence = match field.presence {
+                Presence::Required => "required".to_string(),
+                Presence::Optional => "optional".to_string(),
+                Presence::Default(value) => format!("default {}", value),
+            };
+            let _ = writeln!(
+                out,
+                "  {} ({}, {})\n      {}\n      e.g. {} = {}\n",
+                field.key, field.kind, presence, field.about, field.key, field.example
+            );
+        }
+    }
+    out
+}
+
+// Column the trailing comments of the example config line up at
+const COMMENT_COLUMN: usize = 60;
+
+// A config.toml listing every key: required keys set to their examples, everything else
+// commented out at its default (or an example when it has none)
+pub fn example_config(sections: &[Section]) -> String {
+    let mut out = String::from(
+        "# DevOps_Repository_Sync configuration. Required keys are set to placeholder values,\n# optional ones are commented out. Run `config-schema` for the full reference.\n",
+    );
+    for section in sections {
+        let _ = writeln!(out, "\n# {}", section.about);
+        // Tables are all optional, so their blocks start commented out
+        let table = !section.header.is_empty();
+        if table {
+            let _ = writeln!(out, "# {}", section.header);
+        }
+
+        for field in section.fields {
+            let commented = table || !matches!(field.presence, Presence::Required);
+            let line = format!(
+                "{}{} = {}",
+                if commented { "# " } else { "" },
+                field.key,
+                field.example
+            );
+            let _ = writeln!(
+                out,
+                "{:<width$} # {}",
+                line,
+                field.about,
+                width = COMMENT_COLUMN
+            );
+        }
+    }
+    out
+}
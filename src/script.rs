@@ -0,0 +1,164 @@
+// Inline policy snippets in config.toml, written in Rhai (https://rhai.rs). Only expressions are
+// accepted, and they are compiled when the config is read, so a typo or an unknown variable
+// stops the agent at startup instead of turning up at the first sync.
+use rhai::{Dynamic, Engine, Scope, AST};
+
+// Most operations one evaluation may take, so an expression can't hold up the loop
+const MAX_OPERATIONS: u64 = 100_000;
+
+thread_local! {
+    static ENGINE: Engine = engine();
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_strict_variables(true);
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine
+}
+
+pub type Vars<'a> = [(&'a str, Dynamic)];
+
+// A compiled expression
+pub struct Script {
+    ast: AST,
+}
+
+impl Script {
+    // Compiles the expression, which may only use the named variables
+    pub fn compile(source: &str, names: &[&str]) -> Result<Script, String> {
+        let mut scope = Scope::new();
+        for name in names {
+            scope.push_dynamic(*name, Dynamic::UNIT);
+        }
+        let ast = ENGINE
+            .with(|engine| engine.compile_expression_with_scope(&scope, source))
+            .map_err(|e| e.to_string())?;
+        Ok(Script { ast })
+    }
+
+    // Evaluates the expression against the variables
+    pub fn eval(&self, vars: &Vars<'_>) -> Result<Dynamic, String> {
+        let mut scope = Scope::new();
+        for (name, value) in vars {
+            scope.push_dynamic(*name, value.clone());
+        }
+        ENGINE
+            .with(|engine| engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast))
+            .map_err(|e| e.to_string())
+    }
+
+    // Evaluates the expression, requiring a true/false answer
+    pub fn eval_bool(&self, vars: &Vars<'_>) -> Result<bool, String> {
+        let value = self.eval(vars)?;
+        value
+            .as_bool()
+            .map_err(|_| format!("expected true or false, got {}", value))
+    }
+
+    // Evaluates the expression, requiring a number
+    pub fn eval_number(&self, vars: &Vars<'_>) -> Result<f64, String> {
+        let value = self.eval(vars)?;
+        value
+            .as_int()
+            .map(|number| number as f64)
+            .or_else(|_| value.as_float())
+            .map_err(|_| format!("expected a number, got {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 4] = ["author", "message", "hour", "default_interval"];
+
+    fn vars() -> Vec<(&'static str, Dynamic)> {
+        vec![
+            ("author", "Build Bot".into()),
+            ("message", "Bump version [skip deploy]".into()),
+            ("hour", Dynamic::from_int(23)),
+            ("default_interval", Dynamic::from_int(60)),
+        ]
+    }
+
+    fn eval_bool(source: &str, vars: &Vars<'_>) -> Result<bool, String> {
+        Script::compile(source, &NAMES)?.eval_bool(vars)
+    }
+
+    fn eval_number(source: &str, vars: &Vars<'_>) -> Result<f64, String> {
+        Script::compile(source, &NAMES)?.eval_number(vars)
+    }
+
+    #[test]
+    fn arithmetic_follows_precedence() {
+        assert_eq!(eval_number("1 + 2 * 3", &[]), Ok(7.0));
+        assert_eq!(eval_number("(1 + 2) * 3", &[]), Ok(9.0));
+        assert_eq!(eval_number("10 - 4 - 3", &[]), Ok(3.0));
+        assert_eq!(eval_number("7 % 4 + -2", &[]), Ok(1.0));
+        assert_eq!(eval_number("default_interval / 4", &vars()), Ok(15.0));
+        assert_eq!(eval_number("default_interval * 1.5", &vars()), Ok(90.0));
+    }
+
+    #[test]
+    fn comparisons_and_text_operators() {
+        let vars = vars();
+        assert_eq!(eval_bool("hour >= 22 && hour != 24", &vars), Ok(true));
+        assert_eq!(eval_bool(r#"author == "Build Bot""#, &vars), Ok(true));
+        assert_eq!(eval_bool(r#""abc" < "abd""#, &vars), Ok(true));
+        assert_eq!(
+            eval_bool(r#"message.contains("[skip deploy]")"#, &vars),
+            Ok(true)
+        );
+        assert_eq!(eval_bool(r#""[skip deploy]" in message"#, &vars), Ok(true));
+        assert_eq!(eval_bool(r#"author.starts_with("Build")"#, &vars), Ok(true));
+        assert_eq!(eval_bool(r#"author.ends_with("Build")"#, &vars), Ok(false));
+        assert_eq!(eval_bool("!(hour < 6) || false", &vars), Ok(true));
+        assert_eq!(eval_bool("true == false", &vars), Ok(false));
+    }
+
+    #[test]
+    fn if_chains_pick_the_first_true_branch() {
+        let source =
+            "if hour >= 22 || hour < 6 { 600 } else if hour < 12 { 5 } else { default_interval }";
+        assert_eq!(eval_number(source, &vars()), Ok(600.0));
+        let mut morning = vars();
+        morning[2].1 = Dynamic::from_int(9);
+        assert_eq!(eval_number(source, &morning), Ok(5.0));
+        morning[2].1 = Dynamic::from_int(15);
+        assert_eq!(eval_number(source, &morning), Ok(60.0));
+    }
+
+    #[test]
+    fn mistakes_are_caught_when_compiling() {
+        for source in [
+            "nope == 1",
+            r#""open"#,
+            "1 $ 2",
+            "(1 + 2",
+            "1 +",
+            "let x = 1; x",
+            "loop { }",
+        ] {
+            assert!(
+                Script::compile(source, &NAMES).is_err(),
+                "{} compiled",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_types_are_reported_when_evaluating() {
+        let vars = vars();
+        assert!(eval_bool(r#"hour.contains("x")"#, &vars).is_err());
+        assert_eq!(
+            eval_bool("hour", &vars),
+            Err("expected true or false, got 23".to_string())
+        );
+        assert_eq!(
+            eval_number("author", &vars),
+            Err("expected a number, got Build Bot".to_string())
+        );
+    }
+}
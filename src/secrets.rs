@@ -0,0 +1,296 @@
+use crate::cli;
+use crate::post_sync::ScratchFile;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::info;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+// Prefix marking a config value as age-encrypted and base64 encoded
+pub const ENCRYPTED_PREFIX: &str = "enc:";
+
+// Environment variable pointing at the age identity used to decrypt secrets
+pub const IDENTITY_ENV: &str = "SYNC_AGE_IDENTITY";
+
+// Marks an identity kept in the OS keyring rather than a file, e.g. "keyring:prod"
+pub const KEYRING_PREFIX: &str = "keyring:";
+
+// Service the identities are stored under in the keyring
+const KEYRING_SERVICE: &str = "DevOps_Repository_Sync";
+
+// Where to get age, for the error when it isn't installed
+const AGE_INSTALL_HINT: &str = "Install age 1.1 or newer from https://github.com/FiloSottile/age/releases (or with winget install FiloSottile.age, brew install age, apt install age) and make sure it is on PATH";
+
+// Error returned when the remote rejects the PAT, used to trigger a credential reload
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authentication failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+// Picks the identity file, preferring the environment over the config value
+pub fn identity_path(configured: Option<&str>) -> Option<String> {
+    env::var(IDENTITY_ENV)
+        .ok()
+        .filter(|path| !path.trim().is_empty())
+        .or_else(|| configured.map(str::to_string))
+}
+
+// Returns the plaintext for a config value, decrypting it if it carries the enc: prefix
+pub fn resolve_secret(
+    value: &str,
+    identity: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let encoded = match value.strip_prefix(ENCRYPTED_PREFIX) {
+        Some(encoded) => encoded.trim(),
+        None => return Ok(value.to_string()),
+    };
+
+    let identity = identity.ok_or_else(|| {
+        format!(
+            "Config contains an encrypted value but no identity was provided. Set {} or 'secrets_identity' in config.toml",
+            IDENTITY_ENV
+        )
+    })?;
+
+    let ciphertext = BASE64.decode(encoded)?;
+    let plaintext = match identity.strip_prefix(KEYRING_PREFIX) {
+        Some(name) => {
+            // age reads the identity from stdin, so the ciphertext goes in as a file
+            let input = ScratchFile::create("secret", name, &ciphertext)?;
+            let key = keyring_identity(name)?;
+            let input = input.path().to_string_lossy();
+            run_age(&["--decrypt", "-i", "-", &input], key.as_bytes())?
+        }
+        None => run_age(&["--decrypt", "-i", identity], &ciphertext)?,
+    };
+    info!("Decrypted secret from config.");
+
+    Ok(String::from_utf8(plaintext)?.trim_end().to_string())
+}
+
+// Reads the PAT from its configured source: a file, an environment variable or the inline value
+pub fn read_pat(
+    inline: &str,
+    pat_env: Option<&str>,
+    pat_file: Option<&str>,
+    identity: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let raw = if let Some(path) = pat_file {
+        fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read PAT file '{}': {}", path, e))?
+    } else if let Some(name) = pat_env {
+        env::var(name).map_err(|_| format!("PAT environment variable '{}' is not set", name))?
+    } else {
+        inline.to_string()
+    };
+
+    let pat = resolve_secret(raw.trim(), identity)?;
+    if pat.is_empty() {
+        return Err("No PAT configured. Set 'pat', 'pat_env' or 'pat_file' in config.toml".into());
+    }
+
+    Ok(pat)
+}
+
+// Where the PAT is read from, for the startup summary
+pub fn pat_source(pat_env: Option<&str>, pat_file: Option<&str>) -> String {
+    match (pat_file, pat_env) {
+        (Some(path), _) => format!("PAT from file '{}'", path),
+        (None, Some(name)) => format!("PAT from environment variable '{}'", name),
+        (None, None) => "PAT in config.toml".to_string(),
+    }
+}
+
+// Shows just enough of a secret to tell tokens apart, e.g. "****wxyz"
+pub fn redact(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    // Short values would give too much away, so nothing of them is shown
+    if chars.len() < 16 {
+        return "****".to_string();
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("****{}", tail)
+}
+
+// Encrypts a value for the given recipient (or the identity's own recipient) and returns the config string
+pub fn encrypt_secret(
+    plaintext: &str,
+    recipient: Option<&str>,
+    identity: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // The recipient of a keyring identity is worked out from it, so the plaintext can stay on stdin
+    let keyring_recipient =
+        match identity.and_then(|identity| identity.strip_prefix(KEYRING_PREFIX)) {
+            Some(name) if recipient.is_none() => {
+                let key = keyring_identity(name)?;
+                Some(String::from_utf8(run_tool(
+                    "age-keygen",
+                    &["-y"],
+                    key.as_bytes(),
+                )?)?)
+            }
+            _ => None,
+        };
+
+    let args = match (recipient.or(keyring_recipient.as_deref()), identity) {
+        (Some(recipient), _) => vec!["--encrypt", "-r", recipient.trim()],
+        (None, Some(identity)) => vec!["--encrypt", "-i", identity],
+        (None, None) => {
+            return Err(format!(
+                "No recipient or identity given. Pass --recipient, --identity or set {}",
+                IDENTITY_ENV
+            )
+            .into())
+        }
+    };
+
+    let ciphertext = run_age(&args, plaintext.as_bytes())?;
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(ciphertext)))
+}
+
+// Handles the encrypt-secret subcommand: reads the secret from stdin and prints the enc: value
+pub fn encrypt_secret_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("encrypt-secret", args)?;
+    let recipient = args.value("--recipient");
+    let identity = args
+        .value("--identity")
+        .map(str::to_string)
+        .or_else(|| identity_path(None));
+
+    eprint!("Enter the secret to encrypt: ");
+    io::stderr().flush()?;
+    let mut plaintext = String::new();
+    io::stdin().read_line(&mut plaintext)?;
+
+    let value = encrypt_secret(
+        plaintext.trim_end_matches(['\r', '\n']),
+        recipient,
+        identity.as_deref(),
+    )?;
+    println!("{}", value);
+    Ok(())
+}
+
+// Handles the store-identity subcommand: keeps an age identity in the OS keyring, so
+// secrets_identity = "keyring:<name>" decrypts without a key file on disk
+pub fn store_identity_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args = cli::parse("store-identity", args)?;
+    let file = args.value("--file");
+    let name = args
+        .operand
+        .as_deref()
+        .ok_or("Usage: store-identity <name> [--file key.txt]")?;
+
+    let key = match file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read identity file '{}': {}", path, e))?,
+        None => {
+            eprint!("Paste the age identity (AGE-SECRET-KEY-...): ");
+            io::stderr().flush()?;
+            let mut key = String::new();
+            io::stdin().read_line(&mut key)?;
+            key
+        }
+    };
+    if !key
+        .lines()
+        .any(|line| line.trim().starts_with("AGE-SECRET-KEY-"))
+    {
+        return Err("That is not an age identity, it has no AGE-SECRET-KEY- line".into());
+    }
+
+    keyring::Entry::new(KEYRING_SERVICE, name)?
+        .set_password(key.trim())
+        .map_err(|e| format!("Failed to store the identity in the keyring: {}", e))?;
+    println!(
+        "Stored. Set secrets_identity = \"{}{}\" or {}={}{} to use it.",
+        KEYRING_PREFIX, name, IDENTITY_ENV, KEYRING_PREFIX, name
+    );
+    Ok(())
+}
+
+// Reads an identity stored with store-identity
+fn keyring_identity(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    keyring::Entry::new(KEYRING_SERVICE, name)?
+        .get_password()
+        .map_err(|e| {
+            format!(
+                "Failed to read the age identity '{}' from the keyring ({}). Store it with `store-identity {}` as the user the agent runs as",
+                name, e, name
+            )
+            .into()
+        })
+}
+
+// Runs the age binary with the given arguments, piping input through stdin
+fn run_age(args: &[&str], input: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    run_tool("age", args, input)
+}
+
+// Runs age or age-keygen, piping input through stdin
+fn run_tool(
+    program: &str,
+    args: &[&str],
+    input: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => {
+                format!("'{}' was not found. {}", program, AGE_INSTALL_HINT)
+            }
+            _ => format!("Failed to run '{}': {}", program, e),
+        })?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", program, stderr.trim()).into());
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_missing_age_binary_says_how_to_install_it() {
+        let error = run_tool("age-not-installed-here", &["--decrypt"], b"")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("was not found"), "{}", error);
+        assert!(error.contains("github.com/FiloSottile/age"), "{}", error);
+    }
+
+    #[test]
+    fn only_age_identities_are_stored() {
+        let file = ScratchFile::create("identity", "test", b"ghp_not_an_age_key\n").unwrap();
+        let args = [
+            "prod".to_string(),
+            "--file".to_string(),
+            file.path().to_string_lossy().to_string(),
+        ];
+        let error = store_identity_command(&args).unwrap_err().to_string();
+        assert!(error.contains("not an age identity"), "{}", error);
+        assert!(store_identity_command(&[]).is_err());
+    }
+}
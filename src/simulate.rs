@@ -0,0 +1,482 @@
+// `simulate`: a rehearsal of the agent against a remote it fully controls. A local bare repo
+// stands in for the Azure DevOps repository, served with just enough of the REST API and of
+// git's dumb HTTP protocol, while scripted scenarios push to it and watch what the agent makes
+// of them through its state file. The checkout lives in a scratch directory, so a config and
+// its hooks can be tried out without touching the real checkout.
+use crate::git;
+use crate::post_sync::run_command;
+use log::error;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, Instant};
+
+// How often the state file is read while waiting for the agent
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// File every simulated commit appends a line to
+const SIMULATED_FILE: &str = "SIMULATED.md";
+
+// The stand-in remote: a bare repo, a clone commits are made in, and whether the server
+// currently rejects every request as unauthorized
+pub struct Remote {
+    bare: PathBuf,
+    author: PathBuf,
+    branch: String,
+    failing_auth: Arc<AtomicBool>,
+}
+
+impl Remote {
+    // Creates the bare repo in `dir` with the branch in it, seeded from the history of `seed`
+    // when that is a git checkout, so the simulated commits land on top of the real files
+    pub async fn create(
+        dir: &Path,
+        branch: &str,
+        seed: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let bare = dir.join("remote.git");
+        let author = dir.join("author");
+        let bare_path = bare.to_string_lossy().to_string();
+        let author_path = author.to_string_lossy().to_string();
+        run_command(
+            git::program(),
+            &["init", "--quiet", "--bare", &bare_path],
+            None,
+        )
+        .await?;
+        if Path::new(seed).join(".git").exists() {
+            run_command(
+                git::program(),
+                &["clone", "--quiet", "--no-checkout", seed, &author_path],
+                None,
+            )
+            .await?;
+            run_command(
+                git::program(),
+                &["-C", &author_path, "checkout", "--quiet", "-B", branch],
+                None,
+            )
+            .await?;
+        } else {
+            run_command(git::program(), &["init", "--quiet", &author_path], None).await?;
+            run_command(
+                git::program(),
+                &["-C", &author_path, "checkout", "--quiet", "-b", branch],
+                None,
+            )
+            .await?;
+        }
+        let remote = Remote {
+            bare,
+            author,
+            branch: branch.to_string(),
+            failing_auth: Arc::new(AtomicBool::new(false)),
+        };
+        remote.commit("Initial simulated commit").await?;
+        remote
+            .git_bare(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", branch)])
+            .await?;
+        Ok(remote)
+    }
+
+    // Adds a commit to the branch and pushes it, returning its id
+    pub async fn commit(&self, message: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.write_change(message)?;
+        self.git_author(&["add", SIMULATED_FILE]).await?;
+        self.git_author(&["commit", "--quiet", "-m", message])
+            .await?;
+        self.push(false).await
+    }
+
+    // Replaces the branch's last commit with another one and force-pushes it, returning the
+    // commit it replaced and the new one
+    pub async fn force_push(
+        &self,
+        message: &str,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let old = self.tip().await?;
+        self.write_change(message)?;
+        self.git_author(&["add", SIMULATED_FILE]).await?;
+        self.git_author(&["commit", "--quiet", "--amend", "-m", message])
+            .await?;
+        Ok((old, self.push(true).await?))
+    }
+
+    // Deletes the branch from the remote
+    pub async fn delete_branch(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.git_bare(&["update-ref", "-d", &format!("refs/heads/{}", self.branch)])
+            .await?;
+        self.git_bare(&["update-server-info"]).await
+    }
+
+    // Makes the server answer every request with 401, or stops it
+    pub fn fail_auth(&self, failing: bool) {
+        self.failing_auth.store(failing, Ordering::SeqCst);
+    }
+
+    // Clones the remote into a fresh checkout for the agent to sync
+    pub async fn clone_into(&self, checkout: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let bare = self.bare.to_string_lossy();
+        let checkout = checkout.to_string_lossy();
+        run_command(
+            git::program(),
+            &[
+                "clone",
+                "--quiet",
+                "--branch",
+                &self.branch,
+                &bare,
+                &checkout,
+            ],
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn tip(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let output = git::command()
+            .arg("-C")
+            .arg(&self.author)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .await?;
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    // Serves the bare repo until the process ends
+    pub fn serve(&self, listener: TcpListener) {
+        let bare = self.bare.clone();
+        let branch = self.branch.clone();
+        let failing_auth = self.failing_auth.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                let bare = bare.clone();
+                let branch = branch.clone();
+                let failing = failing_auth.load(Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if let Err(e) = answer(stream, &bare, &branch, failing).await {
+                        error!("Simulated remote failed to answer: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    fn write_change(&self, message: &str) -> std::io::Result<()> {
+        let path = self.author.join(SIMULATED_FILE);
+        let mut contents = std::fs::read_to_string(&path).unwrap_or_default();
+        contents.push_str(&format!("- {}\n", message));
+        std::fs::write(path, contents)
+    }
+
+    async fn push(&self, force: bool) -> Result<String, Box<dyn std::error::Error>> {
+        let bare = self.bare.to_string_lossy().to_string();
+        let refspec = format!("HEAD:refs/heads/{}", self.branch);
+        let mut args = vec!["push", "--quiet"];
+        if force {
+            args.push("--force");
+        }
+        args.extend([bare.as_str(), refspec.as_str()]);
+        self.git_author(&args).await?;
+        // The dumb protocol reads the refs and packs from files this writes
+        self.git_bare(&["update-server-info"]).await?;
+        self.tip().await
+    }
+
+    async fn git_author(&self, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let author = self.author.to_string_lossy();
+        let mut full = vec![
+            "-C",
+            &author,
+            "-c",
+            "user.name=Simulation",
+            "-c",
+            "user.email=simulate@localhost",
+        ];
+        full.extend_from_slice(args);
+        run_command(git::program(), &full, None).await?;
+        Ok(())
+    }
+
+    async fn git_bare(&self, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let bare = self.bare.to_string_lossy();
+        let mut full = vec!["-C", &bare];
+        full.extend_from_slice(args);
+        run_command(git::program(), &full, None).await?;
+        Ok(())
+    }
+}
+
+// Answers one request: repository files under /_git/ for git, a few REST API routes for the
+// agent, and 404 for anything else
+async fn answer(
+    mut stream: TcpStream,
+    bare: &Path,
+    branch: &str,
+    failing_auth: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| decode(key) == name)
+            .map(|(_, value)| decode(value))
+            .unwrap_or_default()
+    };
+
+    if failing_auth {
+        return respond(&mut stream, "401 Unauthorized", b"{}").await;
+    }
+    if let Some((_, file)) = path.split_once("/_git/") {
+        // Past the repository name, the path is a file of the bare repo
+        let file = file.split_once('/').map(|(_, file)| file).unwrap_or("");
+        if file.split('/').any(|part| part == "..") {
+            return respond(&mut stream, "404 Not Found", b"").await;
+        }
+        return match std::fs::read(bare.join(file)) {
+            Ok(contents) => respond(&mut stream, "200 OK", &contents).await,
+            Err(_) => respond(&mut stream, "404 Not Found", b"").await,
+        };
+    }
+
+    let (repository, route) = path
+        .split_once("/_apis/git/repositories/")
+        .map(|(_, rest)| rest.split_once('/').unwrap_or((rest, "")))
+        .unzip();
+    let body = match route {
+        Some("") => Some(json!({
+            "id": "00000000-0000-0000-0000-000000000000",
+            "name": repository,
+            "project": { "name": path.split('/').nth(2) },
+            "defaultBranch": format!("refs/heads/{}", branch),
+        })),
+        Some("refs") => {
+            let prefix = format!("refs/{}", param("filter"));
+            let listed = git_output(bare, &["for-each-ref", "--format=%(refname) %(objectname)"])
+                .await
+                .unwrap_or_default();
+            let refs: Vec<Value> = listed
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .filter(|(name, _)| name.starts_with(&prefix))
+                .map(|(name, id)| json!({ "name": name, "objectId": id }))
+                .collect();
+            Some(json!({ "count": refs.len(), "value": refs }))
+        }
+        Some("diffs/commits") => {
+            let range = format!("{}..{}", param("targetVersion"), param("baseVersion"));
+            git_output(bare, &["rev-list", "--count", &range])
+                .await
+                .map(|behind| json!({ "behindCount": behind.trim().parse::<u64>().unwrap_or(0) }))
+        }
+        Some("items") => {
+            let object = format!("{}:{}", param("versionDescriptor.version"), param("path"));
+            git_output(bare, &["show", &object])
+                .await
+                .map(|content| json!({ "content": content }))
+        }
+        Some("pullrequests") | Some("commitsbatch") => Some(json!({ "count": 0, "value": [] })),
+        _ => None,
+    };
+    match body {
+        Some(body) => respond(&mut stream, "200 OK", body.to_string().as_bytes()).await,
+        None => respond(&mut stream, "404 Not Found", b"{}").await,
+    }
+}
+
+async fn git_output(bare: &Path, args: &[&str]) -> Option<String> {
+    let output = git::command()
+        .arg("-C")
+        .arg(bare)
+        .args(args)
+        .output()
+        .await
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Undoes the percent-encoding of a query string value
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Runs the scenarios one after another, each waiting up to `timeout` for the agent to react as
+// expected, and prints how each went. `approve` approves a sync held by force_push = "hold".
+pub async fn run_scenarios(
+    remote: &Remote,
+    state_file: &Path,
+    timeout: Duration,
+    holds_force_push: bool,
+    approve: impl Fn(),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut failed = Vec::new();
+    let mut expect = |scenario: &'static str, seen: Result<(), String>| match seen {
+        Ok(()) => println!("PASS  {}", scenario),
+        Err(last) => {
+            println!("FAIL  {}: {}", scenario, last);
+            failed.push(scenario);
+        }
+    };
+
+    let tip = remote.tip().await?;
+    expect(
+        "initial sync",
+        wait_for(state_file, timeout, |state| in_sync_at(state, &tip)).await,
+    );
+
+    let commit = remote.commit("New simulated commit").await?;
+    expect(
+        "new commit",
+        wait_for(state_file, timeout, |state| in_sync_at(state, &commit)).await,
+    );
+
+    let (_, rewritten) = remote.force_push("Force-pushed simulated commit").await?;
+    if holds_force_push {
+        expect(
+            "force push held",
+            wait_for(state_file, timeout, |state| {
+                field(state, "status") == "behind" && field(state, "remote_commit") == rewritten
+            })
+            .await,
+        );
+        approve();
+    }
+    expect(
+        "force push",
+        wait_for(state_file, timeout, |state| in_sync_at(state, &rewritten)).await,
+    );
+
+    remote.fail_auth(true);
+    expect(
+        "authentication failure",
+        wait_for(state_file, timeout, |state| {
+            field(state, "status") == "failed"
+        })
+        .await,
+    );
+    remote.fail_auth(false);
+    let commit = remote.commit("Simulated commit after the outage").await?;
+    expect(
+        "recovery after authentication failure",
+        wait_for(state_file, timeout, |state| in_sync_at(state, &commit)).await,
+    );
+
+    remote.delete_branch().await?;
+    expect(
+        "branch deleted",
+        wait_for(state_file, timeout, |state| {
+            field(state, "status") == "failed"
+        })
+        .await,
+    );
+
+    if failed.is_empty() {
+        println!("All scenarios passed.");
+        Ok(())
+    } else {
+        Err(format!("{} scenario(s) failed: {}", failed.len(), failed.join(", ")).into())
+    }
+}
+
+// Waits for the state file to show what the scenario expects, or gives up with the last
+// state seen
+async fn wait_for(
+    state_file: &Path,
+    timeout: Duration,
+    expected: impl Fn(&Value) -> bool,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    let mut last = Value::Null;
+    while Instant::now() < deadline {
+        if let Some(state) = std::fs::read_to_string(state_file)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Value>(&contents).ok())
+        {
+            if expected(&state) {
+                return Ok(());
+            }
+            last = state;
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    Err(format!(
+        "not seen within {}s, last state: status {}, commit {}, error {}",
+        timeout.as_secs(),
+        field(&last, "status"),
+        field(&last, "commit"),
+        field(&last, "error")
+    ))
+}
+
+fn in_sync_at(state: &Value, commit: &str) -> bool {
+    field(state, "status") == "in_sync" && field(state, "commit") == commit
+}
+
+fn field<'a>(state: &'a Value, name: &str) -> &'a str {
+    state[name].as_str().unwrap_or("none")
+}
@@ -0,0 +1,106 @@
+// Where the repo stands, rewritten as a small JSON file after every check cycle, and what the
+// last sync changed, for deployment scripts and monitoring agents on the machine that would
+// rather read a file than talk to the control endpoint or run git.
+use crate::git::FileChange;
+use crate::unpack;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// Default of the top-level state_dir, relative to the working directory like app.log
+pub const DEFAULT_DIR: &str = "state";
+
+// Directory for files the agent keeps between syncs, such as Terraform plans waiting for approval
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn dir() -> &'static Path {
+    DIR.get()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(DEFAULT_DIR))
+}
+
+// Only the first directory set counts, so `simulate` keeps its scratch one. A relative path is
+// resolved now, since tools such as terraform are run from other directories.
+pub fn set_dir(path: PathBuf) {
+    let _ = DIR.set(std::path::absolute(&path).unwrap_or(path));
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    // The checkout is at the remote commit, or only ignored commits are missing
+    InSync,
+    // The remote is ahead and the checkout waits for it: a delay, a hold, a halt or observe mode
+    Behind,
+    // Checking the remote or syncing failed this cycle
+    Failed,
+    // The provider can't be reached (check_connectivity)
+    Offline,
+}
+
+#[derive(Serialize)]
+pub struct SyncState<'a> {
+    pub repository: &'a str,
+    pub branch: &'a str,
+    // Commit the checkout is at, as last synced or read
+    pub commit: Option<&'a str>,
+    // Commit the remote branch was at, unknown when it couldn't be read
+    pub remote_commit: Option<&'a str>,
+    pub status: Status,
+    pub error: Option<&'a str>,
+    // When the checkout last changed
+    pub last_change: String,
+    pub updated_at: String,
+}
+
+pub fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub fn write(path: &str, state: &SyncState) -> Result<(), Box<dyn std::error::Error>> {
+    replace(path, serde_json::to_string_pretty(state)? + "\n")
+}
+
+#[derive(Serialize)]
+struct ChangedFiles<'a> {
+    old_commit: &'a str,
+    new_commit: &'a str,
+    timestamp: String,
+    files: &'a [FileChange],
+}
+
+// Writes the files a sync changed: a JSON document when the path ends in .json, otherwise one
+// path per line
+pub fn write_changed_files(
+    path: &str,
+    old_commit: &str,
+    new_commit: &str,
+    files: &[FileChange],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = if path.to_ascii_lowercase().ends_with(".json") {
+        let changed = ChangedFiles {
+            old_commit,
+            new_commit,
+            timestamp: timestamp(Utc::now()),
+            files,
+        };
+        serde_json::to_string_pretty(&changed)? + "\n"
+    } else {
+        files
+            .iter()
+            .map(|file| format!("{}\n", file.path))
+            .collect()
+    };
+    replace(path, contents)
+}
+
+// Replaces the file with one rename, so readers never see it half written
+fn replace(path: &str, contents: String) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(path);
+    let staged = unpack::sibling(path, "new");
+    fs::write(&staged, contents)?;
+    fs::rename(&staged, path)?;
+    Ok(())
+}
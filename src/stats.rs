@@ -0,0 +1,172 @@
+// Aggregates of a sync history for `stats`: how often syncs happen, how they end, how long a
+// commit waits before it is synced and which hours are the busiest.
+use crate::duration;
+use crate::history::{SyncRecord, SyncStatus};
+use crate::notify::status_name;
+use chrono::{DateTime, Local, Timelike};
+use std::collections::BTreeMap;
+
+// Hours listed under "Busiest hours"
+const BUSIEST: usize = 3;
+
+// The summary lines of the records, in any order
+pub fn summarize(records: &[SyncRecord]) -> Vec<String> {
+    let dated: Vec<(DateTime<Local>, &SyncRecord)> = records
+        .iter()
+        .filter_map(|record| Some((parse_time(&record.timestamp)?, record)))
+        .collect();
+    let (Some(first), Some(last)) = (
+        dated.iter().map(|(time, _)| *time).min(),
+        dated.iter().map(|(time, _)| *time).max(),
+    ) else {
+        return vec!["No syncs recorded.".to_string()];
+    };
+    let total = dated.len();
+    let percent = |n: usize| 100.0 * n as f64 / total as f64;
+    let days = ((last - first).num_seconds() as f64 / 86400.0).max(1.0);
+
+    let mut outcomes: BTreeMap<&str, usize> = BTreeMap::new();
+    for (_, record) in &dated {
+        *outcomes.entry(status_name(record.status)).or_default() += 1;
+    }
+    let succeeded = outcomes.get("success").copied().unwrap_or(0);
+    let aborted = outcomes.get("aborted").copied().unwrap_or(0);
+    let failures: Vec<String> = outcomes
+        .iter()
+        .filter(|(name, _)| !["success", "aborted"].contains(name))
+        .map(|(name, count)| format!("{} {}", count, name))
+        .collect();
+    let failed = total - succeeded - aborted;
+
+    let mut lines = vec![
+        format!(
+            "{} sync(s) from {} to {}",
+            total,
+            first.format("%Y-%m-%d"),
+            last.format("%Y-%m-%d")
+        ),
+        format!("  Syncs per day:  {:.1}", total as f64 / days),
+        format!(
+            "  Succeeded:      {} ({:.1}%)",
+            succeeded,
+            percent(succeeded)
+        ),
+        if failures.is_empty() {
+            "  Failure rate:   0.0%".to_string()
+        } else {
+            format!(
+                "  Failure rate:   {:.1}% ({})",
+                percent(failed),
+                failures.join(", ")
+            )
+        },
+    ];
+    if aborted > 0 {
+        lines.push(format!(
+            "  Aborted:        {} (stopped by a plugin)",
+            aborted
+        ));
+    }
+    lines.push(format!("  Time to sync:   {}", time_to_sync(&dated)));
+    lines.push(format!("  Busiest hours:  {}", busiest_hours(&dated)));
+    lines
+}
+
+fn parse_time(timestamp: &str) -> Option<DateTime<Local>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|time| time.with_timezone(&Local))
+}
+
+// How long the newest commit of each successful sync waited from its commit date until the sync
+// started
+fn time_to_sync(dated: &[(DateTime<Local>, &SyncRecord)]) -> String {
+    let mut waits: Vec<u64> = dated
+        .iter()
+        .filter(|(_, record)| record.status == SyncStatus::Success)
+        .filter_map(|(synced, record)| {
+            let committed = parse_time(record.commits.first()?.committed_at.as_deref()?)?;
+            // A clock running ahead on the committer's machine would make it negative
+            u64::try_from((*synced - committed).num_seconds()).ok()
+        })
+        .collect();
+    if waits.is_empty() {
+        return "unknown, no successful sync has a commit date".to_string();
+    }
+    waits.sort_unstable();
+    let average = waits.iter().sum::<u64>() / waits.len() as u64;
+    format!(
+        "{} on average, {} median, {} at most (over {} sync(s))",
+        duration::format(average),
+        duration::format(waits[waits.len() / 2]),
+        duration::format(waits[waits.len() - 1]),
+        waits.len()
+    )
+}
+
+// The local hours most syncs started in, with their counts
+fn busiest_hours(dated: &[(DateTime<Local>, &SyncRecord)]) -> String {
+    let mut hours = [0usize; 24];
+    for (time, _) in dated {
+        hours[time.hour() as usize] += 1;
+    }
+    let mut ranked: Vec<(usize, usize)> = hours
+        .into_iter()
+        .enumerate()
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked
+        .iter()
+        .take(BUSIEST)
+        .map(|(hour, count)| format!("{:02}:00 ({})", hour, count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: &str, status: SyncStatus, committed_at: Option<&str>) -> SyncRecord {
+        let mut record = SyncRecord::new("a", "b");
+        record.timestamp = timestamp.to_string();
+        record.status = status;
+        record.commits = vec![crate::history::CommitSummary {
+            id: "b".to_string(),
+            short_id: "b".to_string(),
+            author: "Dev".to_string(),
+            author_email: "dev@contoso.com".to_string(),
+            message: "Change".to_string(),
+            committed_at: committed_at.map(str::to_string),
+            work_item_ids: Vec::new(),
+        }];
+        record
+    }
+
+    #[test]
+    fn summarizes_rates_and_waits() {
+        let records = [
+            record(
+                "2024-05-01T09:00:00Z",
+                SyncStatus::Success,
+                Some("2024-05-01T08:59:00Z"),
+            ),
+            record(
+                "2024-05-02T09:00:00Z",
+                SyncStatus::Success,
+                Some("2024-05-02T08:55:00Z"),
+            ),
+            record("2024-05-03T09:00:00Z", SyncStatus::PullFailed, None),
+            record("2024-05-05T09:00:00Z", SyncStatus::Success, None),
+        ];
+        let lines = summarize(&records);
+        assert_eq!(lines[0], "4 sync(s) from 2024-05-01 to 2024-05-05");
+        assert_eq!(lines[1], "  Syncs per day:  1.0");
+        assert_eq!(lines[3], "  Failure rate:   25.0% (1 pull_failed)");
+        assert_eq!(
+            lines[4],
+            "  Time to sync:   3m on average, 5m median, 5m at most (over 2 sync(s))"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+// proptest strategies for the values that break URLs, TOML and command lines: names with
+// spaces, quotes, percent signs and non-ASCII characters, git branch names and local paths
+use proptest::prelude::*;
+
+// An organization, project or repository name as Azure DevOps allows them
+pub fn name() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9_.-]{0,3}[a-zA-Z0-9_ éß日本&+'()$-]{0,9}[a-zA-Z0-9_-]".prop_map(|name| {
+        // Names can't start or end with a space or a dot
+        name.trim_matches(|c| c == ' ' || c == '.').to_string()
+    })
+}
+
+// A branch name git accepts: slash-separated parts, without the dots git restricts
+pub fn branch() -> impl Strategy<Value = String> {
+    prop::collection::vec("b[a-zA-Z0-9_#+%&=ü@,?日本-]{0,8}", 1..=3)
+        .prop_map(|parts| parts.join("/"))
+}
+
+// A local path with spaces, quotes, backslashes and non-ASCII characters
+pub fn path() -> impl Strategy<Value = String> {
+    (
+        prop::sample::select(vec!["/srv/", r"C:\deploy\", r"\\fileserver\share\", "./"]),
+        prop::collection::vec(
+            r#"[a-zA-Z0-9_.-]{1,3}[a-zA-Z0-9_. "'é日本#$\\-]{0,5}"#,
+            1..=3,
+        ),
+    )
+        .prop_map(|(root, parts)| format!("{}{}", root, parts.join("/")))
+}
+
+// A secret such as a PAT, with the characters URLs and TOML give meaning to
+pub fn secret() -> impl Strategy<Value = String> {
+    r#"[a-zA-Z0-9_.-]{1,4}[a-zA-Z0-9_:@/%#"\\=\n'-]{0,16}"#
+}
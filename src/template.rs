@@ -0,0 +1,190 @@
+// Templates used for hook commands and notification messages, rendered with minijinja.
+//
+// `{{name}}` is replaced by the value of `name` (spaces inside the braces are allowed).
+// `{% for commit in commits %}...{% endfor %}` repeats its body for every item of a list, with the
+// item's fields available as `{{commit.id}}` alongside the outer values.
+// `{% if name %}...{% endif %}` keeps its body only when `name` is set and not empty.
+// Printing a value that doesn't exist is an error rather than an empty string, so a typo can't go
+// unnoticed: messages are then sent with the template as written and the error is logged.
+// Values are inserted as they are and a value holding `{{...}}` (e.g. a commit message) is never
+// expanded itself.
+use log::warn;
+use minijinja::{Environment, Error, ErrorKind, UndefinedBehavior, Value};
+
+// A set of named values
+pub type Fields<'a> = Vec<(&'a str, String)>;
+
+// Named lists of items, each item being its own set of fields
+pub type Lists<'a> = [(&'a str, Vec<Fields<'a>>)];
+
+// Why render_escaped failed
+#[derive(Debug, PartialEq)]
+pub enum EscapeError {
+    // The value of this placeholder can't be escaped
+    Value(String),
+    // The template doesn't render, e.g. an unclosed block or an unknown placeholder
+    Template(String),
+}
+
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+    render_with_lists(template, vars, &[])
+}
+
+pub fn render_with_lists(template: &str, vars: &[(&str, String)], lists: &Lists<'_>) -> String {
+    environment()
+        .render_str(template, context(vars, lists))
+        .unwrap_or_else(|e| {
+            warn!(
+                "Template {:?} doesn't render, using it as written: {}",
+                template, e
+            );
+            template.to_string()
+        })
+}
+
+// Renders with every inserted value passed through escape first, e.g. to quote it for a shell.
+// Fails with the placeholder's name when escape can't represent its value.
+pub fn render_escaped(
+    template: &str,
+    vars: &[(&str, String)],
+    escape: fn(&str) -> Option<String>,
+) -> Result<String, EscapeError> {
+    let mut environment = environment();
+    environment.set_formatter(move |out, _, value| {
+        let escaped = escape(&value.to_string())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidOperation, "value can't be escaped"))?;
+        out.write_str(&escaped).map_err(Error::from)
+    });
+    let compiled = environment
+        .template_from_str(template)
+        .map_err(|e| EscapeError::Template(e.to_string()))?;
+    compiled.render(context(vars, &[])).map_err(|e| {
+        // The formatter doesn't know which placeholder it is printing, so name the one whose
+        // value is the problem
+        let used = compiled.undeclared_variables(false);
+        match vars
+            .iter()
+            .find(|(name, value)| used.contains(*name) && escape(value).is_none())
+        {
+            Some((name, _)) => EscapeError::Value(name.to_string()),
+            None => EscapeError::Template(e.to_string()),
+        }
+    })
+}
+
+fn environment<'source>() -> Environment<'source> {
+    let mut environment = Environment::new();
+    environment.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+    environment.set_keep_trailing_newline(true);
+    environment
+}
+
+// The values, then the lists, each list item being a map of its fields
+fn context(vars: &[(&str, String)], lists: &Lists<'_>) -> Value {
+    vars.iter()
+        .map(|(name, value)| (*name, Value::from(value.as_str())))
+        .chain(
+            lists
+                .iter()
+                .map(|(name, items)| (*name, items.iter().map(|item| fields(item)).collect())),
+        )
+        .collect()
+}
+
+fn fields(fields: &[(&str, String)]) -> Value {
+    fields
+        .iter()
+        .map(|(name, value)| (*name, Value::from(value.as_str())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&'static str, &str)]) -> Fields<'static> {
+        pairs
+            .iter()
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn placeholders_are_replaced_and_unknown_ones_keep_the_template() {
+        let vars = vars(&[("branch", "main"), ("commit", "abc123")]);
+        assert_eq!(
+            render("{{branch}} at {{ commit }}\n", &vars),
+            "main at abc123\n"
+        );
+        assert_eq!(
+            render("{{branch}} at {{typo}}", &vars),
+            "{{branch}} at {{typo}}"
+        );
+        assert_eq!(
+            render("unclosed {% if branch %}", &vars),
+            "unclosed {% if branch %}"
+        );
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let vars = vars(&[
+            ("message", "Use {{branch}} and {% if branch %}x{% endif %}"),
+            ("branch", "main"),
+        ]);
+        assert_eq!(
+            render("{{message}} on {{branch}}", &vars),
+            "Use {{branch}} and {% if branch %}x{% endif %} on main"
+        );
+    }
+
+    #[test]
+    fn if_blocks_test_for_a_value_that_is_set_and_not_empty() {
+        let template = "{% if a %}A{% if b %}B{% endif %}-{% endif %}.";
+        assert_eq!(render(template, &vars(&[("a", "1"), ("b", "1")])), "AB-.");
+        assert_eq!(render(template, &vars(&[("a", "1"), ("b", "")])), "A-.");
+        assert_eq!(render(template, &vars(&[("b", "1")])), ".");
+    }
+
+    #[test]
+    fn for_repeats_its_body_with_the_item_fields() {
+        let lists = [(
+            "commits",
+            vec![
+                vars(&[("id", "a1"), ("author", "Ann")]),
+                vars(&[("id", "b2"), ("author", "")]),
+            ],
+        )];
+        let template = "{% for commit in commits %}{{commit.id}}{% if commit.author %} by {{commit.author}}{% endif %} on {{branch}}; {% endfor %}";
+        assert_eq!(
+            render_with_lists(template, &vars(&[("branch", "main")]), &lists),
+            "a1 by Ann on main; b2 on main; "
+        );
+        assert_eq!(
+            render_with_lists(
+                "{% if commits %}some{% endif %}{% if none %}x{% endif %}",
+                &[],
+                &lists
+            ),
+            "some"
+        );
+    }
+
+    #[test]
+    fn escaped_values_go_through_escape_and_unescapable_ones_fail() {
+        let vars = vars(&[("branch", "a b"), ("path", "50%")]);
+        let escape = |value: &str| (!value.contains('%')).then(|| format!("<{}>", value));
+        assert_eq!(
+            render_escaped("x {{branch}}{% if path %}!{% endif %}", &vars, escape),
+            Ok("x <a b>!".to_string())
+        );
+        assert_eq!(
+            render_escaped("{{path}}", &vars, escape),
+            Err(EscapeError::Value("path".to_string()))
+        );
+        assert!(matches!(
+            render_escaped("{{other}}", &vars, escape),
+            Err(EscapeError::Template(_))
+        ));
+    }
+}
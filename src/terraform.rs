@@ -0,0 +1,393 @@
+// Terraform runs for infrastructure repos: every sync plans, and the saved plan is applied
+// right away with auto_apply, otherwise once an operator approves it
+use crate::hooks::{self, HookResult};
+use crate::schema::{self, Documented, Field};
+use crate::state;
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// [post_sync.terraform]
+#[derive(Deserialize)]
+pub struct TerraformConfig {
+    // Root module, relative to the repo
+    #[serde(default = "default_working_dir")]
+    pub working_dir: String,
+    #[serde(default = "default_terraform")]
+    pub terraform: String,
+    // Apply the plan without waiting for approve-terraform
+    #[serde(default)]
+    pub auto_apply: bool,
+    pub workspace: Option<String>,
+    // Extra arguments for terraform init, e.g. -backend-config=...
+    #[serde(default)]
+    pub init_args: Vec<String>,
+    // -var-file arguments, relative to working_dir
+    #[serde(default)]
+    pub var_files: Vec<String>,
+    // Each of init, plan and apply is stopped after this long
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    // Only plan when a changed file matches one of these globs (empty means always)
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+fn default_working_dir() -> String {
+    ".".to_string()
+}
+
+fn default_terraform() -> String {
+    "terraform".to_string()
+}
+
+fn default_timeout() -> u64 {
+    1800
+}
+
+// Name of the plan step in the sync history, which notifications take the plan from
+const PLAN_STEP: &str = "terraform plan";
+
+// Exit code of terraform plan -detailed-exitcode when the plan has changes; 0 means none
+const HAS_CHANGES: i32 = 2;
+
+// Directory under the state_dir the plans waiting for approval are kept in
+const PLAN_DIR: &str = "terraform";
+
+impl Documented for TerraformConfig {
+    const SECTION: &'static str = "[post_sync.terraform]";
+    const ABOUT: &'static str = "Plans Terraform changes after every successful pull, applying them automatically or once approved.";
+    const FIELDS: &'static [Field] = &[
+        schema::defaulted(
+            "working_dir",
+            "string",
+            r#"".""#,
+            "Root module, relative to the repo",
+        ),
+        schema::defaulted(
+            "terraform",
+            "string",
+            r#""terraform""#,
+            "terraform (or tofu) executable",
+        ),
+        schema::defaulted(
+            "auto_apply",
+            "boolean",
+            "false",
+            "Apply the plan right away instead of waiting for `approve-terraform`",
+        ),
+        schema::optional(
+            "workspace",
+            "string",
+            r#""production""#,
+            "Workspace to select, created when missing",
+        ),
+        schema::defaulted(
+            "init_args",
+            "list of strings",
+            "[]",
+            "Extra terraform init arguments, e.g. -backend-config=...",
+        ),
+        schema::defaulted(
+            "var_files",
+            "list of strings",
+            "[]",
+            "-var-file arguments, relative to working_dir",
+        ),
+        schema::defaulted(
+            "timeout_seconds",
+            "integer",
+            "1800",
+            "Each of init, plan and apply is stopped after this long",
+        ),
+        schema::defaulted(
+            "paths",
+            "list of globs",
+            "[]",
+            "Only plan when a changed file matches one of these (empty means always)",
+        ),
+    ];
+}
+
+impl TerraformConfig {
+    pub fn describe(&self) -> String {
+        let mode = if self.auto_apply {
+            "auto apply"
+        } else {
+            "apply on approval"
+        };
+        format!("{} ({})", self.working_dir, mode)
+    }
+
+    // Where the plan waiting for approval is kept: under the state_dir, so it never shows up as
+    // a local change and other users can't read the secrets a plan may hold
+    fn plan_file(&self, repo_path: &str) -> PathBuf {
+        let module = Path::new(repo_path).join(&self.working_dir);
+        let key: String = module
+            .to_string_lossy()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        state::dir()
+            .join(PLAN_DIR)
+            .join(format!("{}.tfplan", key.trim_matches('-')))
+    }
+
+    // terraform arguments running the subcommand in the root module
+    fn args(&self, repo_path: &str, subcommand: &[&str]) -> Vec<String> {
+        let module = Path::new(repo_path).join(&self.working_dir);
+        let mut args = vec![format!("-chdir={}", module.to_string_lossy())];
+        args.extend(subcommand.iter().map(|arg| arg.to_string()));
+        args.extend(["-input=false".to_string(), "-no-color".to_string()]);
+        args
+    }
+
+    async fn run(
+        &self,
+        name: &str,
+        args: &[String],
+        results: &mut Vec<HookResult>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        hooks::run_tool(
+            name,
+            &self.terraform,
+            args,
+            None,
+            self.timeout_seconds,
+            results,
+        )
+        .await
+    }
+}
+
+// The commit a plan was made at, kept next to it
+fn commit_file(plan_file: &Path) -> PathBuf {
+    plan_file.with_extension("commit")
+}
+
+fn discard(plan_file: &Path) {
+    let _ = fs::remove_file(plan_file);
+    let _ = fs::remove_file(commit_file(plan_file));
+}
+
+// Limits a plan's directory and files to the agent's user. Elsewhere than on Unix they keep the
+// permissions they inherit from the state_dir.
+fn restrict(path: &Path, directory: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = if directory { 0o700 } else { 0o600 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, directory);
+        Ok(())
+    }
+}
+
+// Initializes the module and plans the commit, then applies the plan with auto_apply. Without
+// it, a plan with changes is kept for `approve-terraform`, replacing any earlier one.
+pub async fn plan(
+    config: &TerraformConfig,
+    repo_path: &str,
+    commit: &str,
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let plan_file = config.plan_file(repo_path);
+    discard(&plan_file);
+    if let Some(directory) = plan_file.parent() {
+        fs::create_dir_all(directory)?;
+        restrict(directory, true)?;
+    }
+
+    let mut args = config.args(repo_path, &["init"]);
+    args.extend(config.init_args.iter().cloned());
+    config.run("terraform init", &args, results).await?;
+
+    if let Some(workspace) = &config.workspace {
+        // workspace select takes neither -input nor -no-color
+        let module = Path::new(repo_path).join(&config.working_dir);
+        let args = [
+            format!("-chdir={}", module.to_string_lossy()),
+            "workspace".to_string(),
+            "select".to_string(),
+            "-or-create".to_string(),
+            workspace.clone(),
+        ];
+        config.run("terraform workspace", &args, results).await?;
+    }
+
+    let mut args = config.args(repo_path, &["plan", "-detailed-exitcode"]);
+    args.push(format!("-out={}", plan_file.to_string_lossy()));
+    for file in &config.var_files {
+        args.push(format!("-var-file={}", file));
+    }
+    let code = hooks::run_tool_accepting(
+        PLAN_STEP,
+        &config.terraform,
+        &args,
+        None,
+        config.timeout_seconds,
+        &[HAS_CHANGES],
+        results,
+    )
+    .await?;
+
+    if code != HAS_CHANGES {
+        info!("Terraform plan has no changes, nothing to apply.");
+        discard(&plan_file);
+        return Ok(());
+    }
+    restrict(&plan_file, false)?;
+    fs::write(commit_file(&plan_file), commit)?;
+    restrict(&commit_file(&plan_file), false)?;
+    if config.auto_apply {
+        apply(config, repo_path, commit, results).await?;
+    } else {
+        info!(
+            "Terraform plan saved, waiting for approve-terraform to apply it ({}).",
+            plan_file.display()
+        );
+    }
+    Ok(())
+}
+
+// Applies the saved plan, if one is waiting and was made at the commit the checkout is at. The
+// plan is used up either way: terraform refuses a plan that is stale, so a failed apply needs a
+// new sync or plan.
+pub async fn apply(
+    config: &TerraformConfig,
+    repo_path: &str,
+    commit: &str,
+    results: &mut Vec<HookResult>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let plan_file = config.plan_file(repo_path);
+    if !plan_file.is_file() {
+        warn!(
+            "No Terraform plan is waiting for {}, nothing to apply.",
+            config.working_dir
+        );
+        return Ok(());
+    }
+    let planned = fs::read_to_string(commit_file(&plan_file)).unwrap_or_default();
+    if planned.trim() != commit {
+        discard(&plan_file);
+        return Err(format!(
+            "the plan for {} was made at {} but the checkout is at {}; the next sync plans again",
+            config.working_dir,
+            if planned.trim().is_empty() {
+                "an unknown commit"
+            } else {
+                planned.trim()
+            },
+            commit
+        )
+        .into());
+    }
+
+    let mut args = config.args(repo_path, &["apply"]);
+    args.push(plan_file.to_string_lossy().to_string());
+    let applied = config.run("terraform apply", &args, results).await;
+    discard(&plan_file);
+    applied
+}
+
+// The plan output of a sync for notifications, without the state refresh lines that lead it
+pub fn plan_summary(hooks: &[HookResult]) -> Option<String> {
+    let output = hooks
+        .iter()
+        .rev()
+        .find(|hook| hook.name == PLAN_STEP)?
+        .output
+        .as_deref()?;
+    let summary: Vec<&str> = output
+        .lines()
+        .filter(|line| {
+            !(line.contains(": Refreshing state...")
+                || line.contains(": Reading...")
+                || line.contains(": Read complete after"))
+        })
+        .collect();
+    Some(summary.join("\n").trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_summary_drops_the_state_refresh() {
+        let plan = HookResult {
+            name: PLAN_STEP.to_string(),
+            exit_code: Some(0),
+            duration_ms: 0,
+            timed_out: false,
+            output_truncated: false,
+            error: None,
+            output: Some(
+                "aws_s3_bucket.site: Refreshing state... [id=site]\n\n  # aws_s3_bucket.logs will be created\nPlan: 1 to add, 0 to change, 0 to destroy."
+                    .to_string(),
+            ),
+        };
+        assert_eq!(
+            plan_summary(&[plan]).unwrap(),
+            "# aws_s3_bucket.logs will be created\nPlan: 1 to add, 0 to change, 0 to destroy."
+        );
+        assert!(plan_summary(&[]).is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_plan_is_private_and_applies_only_at_its_commit() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = std::env::temp_dir().join(format!("sync-terraform-{}", std::process::id()));
+        let repo = scratch.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        state::set_dir(scratch.join("state"));
+        // Stands in for terraform: plan writes its -out file and reports changes
+        let fake = scratch.join("terraform");
+        fs::write(
+            &fake,
+            "#!/bin/sh\nfor arg; do case $arg in -out=*) echo plan > \"${arg#-out=}\";; esac; done\n[ \"$2\" = plan ] && exit 2\nexit 0\n",
+        )
+        .unwrap();
+        fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+        let config: TerraformConfig =
+            toml::from_str(&format!("terraform = {:?}", fake.to_string_lossy())).unwrap();
+        let repo_path = repo.to_string_lossy();
+        let plan_file = config.plan_file(&repo_path);
+
+        let mut results = Vec::new();
+        plan(&config, &repo_path, "abc123", &mut results)
+            .await
+            .unwrap();
+        assert!(results.iter().all(HookResult::succeeded));
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&plan_file), 0o600);
+        assert_eq!(mode(plan_file.parent().unwrap()), 0o700);
+        assert_eq!(
+            fs::read_to_string(commit_file(&plan_file)).unwrap(),
+            "abc123"
+        );
+
+        // The checkout moved on since the plan was made
+        assert!(apply(&config, &repo_path, "def456", &mut results)
+            .await
+            .is_err());
+        assert!(!plan_file.exists());
+
+        plan(&config, &repo_path, "abc123", &mut results)
+            .await
+            .unwrap();
+        apply(&config, &repo_path, "abc123", &mut results)
+            .await
+            .unwrap();
+        assert_eq!(results.last().unwrap().name, "terraform apply");
+        assert!(!plan_file.exists());
+        let _ = fs::remove_dir_all(&scratch);
+    }
+}
@@ -0,0 +1,154 @@
+// Client certificates for servers and gateways that require mutual TLS, loaded into the API
+// client and passed to git for fetches.
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use serde::Deserialize;
+use std::fs;
+
+// Environment variable git's credential helper reads the PKCS#12 password from, so it never
+// shows up in the process list
+const PASSWORD_ENV: &str = "SYNC_CLIENT_CERT_PASSWORD";
+
+// The [client_certificate] table: a PEM certificate and key, or a PKCS#12 bundle
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClientCertificate {
+    // PEM certificate, optionally followed by its intermediate certificates
+    cert: Option<String>,
+    // PEM private key of the certificate, in PKCS#8 form
+    key: Option<String>,
+    // PKCS#12 (.pfx/.p12) bundle holding both, instead of cert and key
+    pkcs12: Option<String>,
+    // Password of the PKCS#12 bundle, may be encrypted with encrypt-secret
+    #[serde(default)]
+    password: String,
+}
+
+impl Documented for ClientCertificate {
+    const SECTION: &'static str = "[client_certificate]";
+    const ABOUT: &'static str =
+        "Client certificate presented to servers and gateways that require one (mTLS).";
+    const FIELDS: &'static [Field] = &[
+        schema::optional(
+            "cert",
+            "string",
+            r#""C:\\certs\\agent.crt""#,
+            "PEM certificate, with any intermediates after it",
+        ),
+        schema::optional(
+            "key",
+            "string",
+            r#""C:\\certs\\agent.key""#,
+            "PEM private key of cert, in PKCS#8 form (BEGIN PRIVATE KEY)",
+        ),
+        schema::optional(
+            "pkcs12",
+            "string",
+            r#""C:\\certs\\agent.pfx""#,
+            "PKCS#12 bundle with the certificate and key, instead of cert and key",
+        ),
+        schema::defaulted(
+            "password",
+            "string",
+            r#""""#,
+            "Password of the PKCS#12 bundle, plain or enc: encrypted",
+        ),
+    ];
+}
+
+// Config overrides and environment that make git present the certificate
+pub struct GitSettings {
+    pub config: Vec<String>,
+    pub env: Vec<(&'static str, String)>,
+}
+
+impl ClientCertificate {
+    // Decrypts an enc: password, once when the config is read
+    pub fn resolve_password(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.password = secrets::resolve_secret(&self.password, secrets_identity)?;
+        Ok(())
+    }
+
+    // The certificate for the API client, failing on anything that couldn't be used
+    pub fn identity(&self) -> Result<reqwest::Identity, Box<dyn std::error::Error>> {
+        match self.source()? {
+            Source::Pkcs12(bundle) => {
+                let der = fs::read(bundle).map_err(|e| {
+                    format!("Failed to read client certificate '{}': {}", bundle, e)
+                })?;
+                Ok(
+                    reqwest::Identity::from_pkcs12_der(&der, &self.password).map_err(|e| {
+                        format!("Failed to load client certificate '{}': {}", bundle, e)
+                    })?,
+                )
+            }
+            Source::Pem(cert, key) => {
+                let cert_pem = fs::read(cert)
+                    .map_err(|e| format!("Failed to read client certificate '{}': {}", cert, e))?;
+                let key_pem = fs::read(key)
+                    .map_err(|e| format!("Failed to read client key '{}': {}", key, e))?;
+                Ok(reqwest::Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+                    .map_err(|e| format!("Failed to load client certificate '{}': {}", cert, e))?)
+            }
+        }
+    }
+
+    // How git is told about the certificate. git reads the files itself, so PEM keys may be in
+    // any form it supports, and PKCS#12 bundles need git 2.42 or newer.
+    pub fn git_settings(&self) -> Result<GitSettings, Box<dyn std::error::Error>> {
+        match self.source()? {
+            Source::Pkcs12(bundle) => {
+                let mut settings = GitSettings {
+                    config: vec![
+                        format!("http.sslCert={}", bundle),
+                        "http.sslCertType=P12".to_string(),
+                    ],
+                    env: Vec::new(),
+                };
+                // git asks a credential helper for the password, which just echoes it back
+                if !self.password.is_empty() {
+                    settings
+                        .config
+                        .push("http.sslCertPasswordProtected=true".to_string());
+                    settings.config.push(format!(
+                        "credential.helper=!f() {{ echo password=${}; }}; f",
+                        PASSWORD_ENV
+                    ));
+                    settings.env.push((PASSWORD_ENV, self.password.clone()));
+                }
+                Ok(settings)
+            }
+            Source::Pem(cert, key) => Ok(GitSettings {
+                config: vec![
+                    format!("http.sslCert={}", cert),
+                    format!("http.sslKey={}", key),
+                ],
+                env: Vec::new(),
+            }),
+        }
+    }
+
+    // The file(s) the certificate is read from, for the startup summary
+    pub fn describe(&self) -> String {
+        match self.source() {
+            Ok(Source::Pkcs12(bundle)) => format!("PKCS#12 '{}'", bundle),
+            Ok(Source::Pem(cert, _)) => format!("PEM '{}'", cert),
+            Err(_) => "incomplete".to_string(),
+        }
+    }
+
+    fn source(&self) -> Result<Source<'_>, Box<dyn std::error::Error>> {
+        match (&self.pkcs12, &self.cert, &self.key) {
+            (Some(bundle), None, None) => Ok(Source::Pkcs12(bundle)),
+            (None, Some(cert), Some(key)) => Ok(Source::Pem(cert, key)),
+            _ => Err("client_certificate needs either cert and key, or pkcs12".into()),
+        }
+    }
+}
+
+enum Source<'a> {
+    Pkcs12(&'a str),
+    Pem(&'a str, &'a str),
+}
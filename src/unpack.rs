@@ -0,0 +1,119 @@
+// Downloaded artifacts and packages are unpacked beside the directory they go to and swapped in
+// whole, so the directory keeps its previous contents if a download or unpack fails.
+use reqwest::Response;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+// A path next to the directory, e.g. bin.partial beside bin
+pub fn sibling(directory: &Path, extension: &str) -> PathBuf {
+    let mut name = directory.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", extension));
+    directory.with_file_name(name)
+}
+
+// An empty directory beside `directory` to unpack into, left over ones are cleared first
+pub fn fresh_staging(directory: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let staging = sibling(directory, "partial");
+    discard(&staging);
+    fs::create_dir_all(&staging)?;
+    Ok(staging)
+}
+
+// Removes a staging directory that wasn't swapped in
+pub fn discard(staging: &Path) {
+    if staging.exists() {
+        let _ = fs::remove_dir_all(staging);
+    }
+}
+
+// Saves a zip download and swaps its contents into the directory. `wrapper` names a single
+// top-level folder the files are packed in, which is left out.
+pub async fn unzip_into(
+    mut response: Response,
+    directory: &Path,
+    wrapper: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let archive = sibling(directory, "zip");
+    let mut staging = None;
+    let result = async {
+        let mut file = File::create(&archive)?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)?;
+        }
+        drop(file);
+
+        let unpacked = staging.insert(fresh_staging(directory)?);
+        extract(&archive, unpacked).await?;
+        swap_into(unpacked, directory, wrapper)
+    }
+    .await;
+    let _ = fs::remove_file(&archive);
+    if let Some(staging) = &staging {
+        discard(staging);
+    }
+    result
+}
+
+// Unpacks the zip with the platform's own tool: tar on Windows, which reads zips there, and
+// unzip elsewhere
+async fn extract(archive: &Path, into: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("tar");
+        command.arg("-xf").arg(archive).arg("-C").arg(into);
+        command
+    } else {
+        let mut command = Command::new("unzip");
+        command.arg("-q").arg("-o").arg(archive).arg("-d").arg(into);
+        command
+    };
+    let output = command
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run the unzip tool: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to unpack the download: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Moves the unpacked files into place, putting the previous contents back if that fails
+pub fn swap_into(
+    staging: &Path,
+    directory: &Path,
+    wrapper: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let contents = match wrapper.map(|name| staging.join(name)) {
+        Some(nested) if nested.is_dir() && fs::read_dir(staging)?.count() == 1 => nested,
+        _ => staging.to_path_buf(),
+    };
+
+    let old = sibling(directory, "old");
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+    if directory.exists() {
+        fs::rename(directory, &old)?;
+    }
+    if let Err(e) = fs::rename(&contents, directory) {
+        if old.exists() {
+            let _ = fs::rename(&old, directory);
+        }
+        return Err(format!(
+            "Failed to move the files into {}: {}",
+            directory.display(),
+            e
+        )
+        .into());
+    }
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+// Checks that the working tree still holds exactly the synced commit, to catch tampering or
+// disk corruption on unattended machines.
+use crate::git;
+use crate::post_sync::run_command;
+use serde::Deserialize;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+
+// How thoroughly the working tree is compared with the commit
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Verification {
+    // HEAD is at the commit and `git status` is clean
+    Status,
+    // As status, and every tracked file is hashed again and compared with the commit's tree,
+    // which also catches changes that kept the file size and timestamp
+    Hashes,
+}
+
+// Mode of the tree entries that are regular files, as listed by `git ls-tree`
+const FILE_MODES: &[&str] = &["100644", "100755"];
+
+// Everything that differs between the working tree and the commit, one line per finding.
+// Untracked files count as differences unless they are gitignored or under preserve_paths.
+pub async fn differences(
+    repo_path: &str,
+    commit: &str,
+    verification: Verification,
+    preserve: &[String],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    #[cfg(feature = "embedded-git")]
+    if git::embedded() {
+        return crate::embedded::differences(repo_path, commit, verification, preserve).await;
+    }
+    let mut found = Vec::new();
+
+    let head = run_command(
+        git::program(),
+        &["-C", repo_path, "rev-parse", "HEAD"],
+        None,
+    )
+    .await?;
+    if head.trim() != commit {
+        found.push(format!("HEAD is at {} instead of {}", head.trim(), commit));
+    }
+
+    let excludes: Vec<String> = preserve
+        .iter()
+        .map(|path| format!(":(exclude){}", path))
+        .collect();
+    let mut args = vec!["-C", repo_path, "status", "--porcelain", "--", "."];
+    args.extend(excludes.iter().map(String::as_str));
+    let status = run_command(git::program(), &args, None).await?;
+    found.extend(status.lines().map(|line| format!("status: {}", line)));
+
+    if verification == Verification::Hashes {
+        found.extend(hash_mismatches(repo_path, commit).await?);
+    }
+    Ok(found)
+}
+
+// Tracked files whose content no longer hashes to the blob recorded in the commit
+async fn hash_mismatches(
+    repo_path: &str,
+    commit: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let tree = run_command(
+        git::program(),
+        &["-C", repo_path, "ls-tree", "-r", "-z", commit],
+        None,
+    )
+    .await?;
+
+    // "<mode> blob <hash>\t<path>" entries; symlinks and submodules are left to git status
+    let mut mismatches = Vec::new();
+    let mut files = Vec::new();
+    for entry in tree.split('\0').filter(|entry| !entry.is_empty()) {
+        let Some((meta, path)) = entry.split_once('\t') else {
+            continue;
+        };
+        let mut fields = meta.split_whitespace();
+        let (Some(mode), Some(_), Some(hash)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if !FILE_MODES.contains(&mode) {
+            continue;
+        }
+        if Path::new(repo_path).join(path).is_file() {
+            files.push((path.to_string(), hash.to_string()));
+        } else {
+            mismatches.push(format!("missing: {}", path));
+        }
+    }
+    if files.is_empty() {
+        return Ok(mismatches);
+    }
+
+    // Hashed the way `git add` would, so line-ending and other filters are applied as on checkout
+    let mut child = git::command()
+        .args(["-C", repo_path, "hash-object", "--stdin-paths"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let paths: String = files
+        .iter()
+        .map(|(path, _)| format!("{}\n", path))
+        .collect();
+    let mut stdin = child.stdin.take().ok_or("git hash-object has no stdin")?;
+    // Written alongside reading the output, so a large tree can't fill both pipes and stall
+    let writer = tokio::spawn(async move { stdin.write_all(paths.as_bytes()).await });
+    let output = child.wait_with_output().await?;
+    writer.await??;
+    if !output.status.success() {
+        return Err(format!(
+            "git hash-object exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let hashes = String::from_utf8_lossy(&output.stdout);
+    for ((path, expected), actual) in files.iter().zip(hashes.lines()) {
+        if actual.trim() != expected {
+            mismatches.push(format!("content changed: {}", path));
+        }
+    }
+    Ok(mismatches)
+}
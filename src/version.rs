@@ -0,0 +1,84 @@
+// Build information for --version and the opt-in check for newer releases.
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Where new releases are published, unless update_feed_url points elsewhere (e.g. a mirror)
+pub const DEFAULT_UPDATE_FEED: &str =
+    "https://api.github.com/repos/Feromond/DevOps_Repository_Sync/releases/latest";
+
+// How often a running agent looks for a newer release
+const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+// Version, commit, build date and target, as printed by --version
+pub fn long_version() -> String {
+    format!(
+        "DevOps_Repository_Sync {}\ncommit: {}\nbuilt: {}\ntarget: {}",
+        VERSION,
+        env!("BUILD_GIT_COMMIT"),
+        env!("BUILD_DATE"),
+        env!("BUILD_TARGET")
+    )
+}
+
+// The release feed entry, in the shape of GitHub's "latest release" API
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: Option<String>,
+}
+
+// Checks the release feed now and then for the lifetime of the agent, logging newer versions
+pub async fn watch_for_updates(client: Client, feed_url: String) {
+    loop {
+        match latest_release(&client, &feed_url).await {
+            Ok(release) if is_newer(&release.tag_name, VERSION) => warn!(
+                "A newer version is available: {} (running {}). {}",
+                release.tag_name,
+                VERSION,
+                release.html_url.unwrap_or_default()
+            ),
+            Ok(release) => info!(
+                "Running the latest version ({}, newest release {}).",
+                VERSION, release.tag_name
+            ),
+            Err(e) => error!("Failed to check for updates: {}", e),
+        }
+        sleep(UPDATE_CHECK_INTERVAL).await;
+    }
+}
+
+async fn latest_release(
+    client: &Client,
+    feed_url: &str,
+) -> Result<Release, Box<dyn std::error::Error>> {
+    let response = client.get(feed_url).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("release feed returned {}", response.status()).into());
+    }
+    Ok(response.json().await?)
+}
+
+// Whether the release tag (e.g. "v1.4.0") is a higher version than the running one
+fn is_newer(tag: &str, current: &str) -> bool {
+    match (parse(tag), parse(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+// Major, minor and patch of "1.4.0" or "v1.4.0", ignoring any pre-release or build suffix
+fn parse(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|part| part.parse::<u64>());
+    Some((
+        parts.next()?.ok()?,
+        parts.next().unwrap_or(Ok(0)).ok()?,
+        parts.next().unwrap_or(Ok(0)).ok()?,
+    ))
+}
@@ -0,0 +1,232 @@
+// Push notifications from Azure DevOps service hooks. `register-webhook` subscribes each
+// configured repo's git.push events to the control endpoint's /webhook route, which wakes that
+// repo's loop for an immediate check instead of leaving the push until the next interval.
+use crate::azure;
+use crate::control::RepoKey;
+use crate::notify::RepoRef;
+use crate::schema::{self, Documented, Field};
+use crate::secrets;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+// User name sent with the secret; only the secret is checked
+const USERNAME: &str = "devops-sync";
+
+// The [webhook] table
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    // Address Azure DevOps posts pushes to; it has to reach control_listen's /webhook route
+    pub url: String,
+    // Shared secret Azure DevOps sends as the basic auth password, plain or enc: encrypted
+    pub secret: String,
+}
+
+impl Documented for WebhookConfig {
+    const SECTION: &'static str = "[webhook]";
+    const ABOUT: &'static str = "Service hook that tells the agent about pushes right away; set it up with `register-webhook`. Needs control_listen.";
+    const FIELDS: &'static [Field] = &[
+        schema::required(
+            "url",
+            "string",
+            r#""https://agent01.example.com:7878/webhook""#,
+            "Address Azure DevOps posts pushes to, reaching control_listen's /webhook route",
+        ),
+        schema::required(
+            "secret",
+            "string",
+            r#""enc:...""#,
+            "Shared secret Azure DevOps sends with every push; plain or enc: encrypted",
+        ),
+    ];
+}
+
+impl WebhookConfig {
+    // Decrypts an enc: secret, once when the config is read
+    pub fn resolve(
+        &mut self,
+        secrets_identity: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.secret = secrets::resolve_secret(&self.secret, secrets_identity)?;
+        if self.secret.is_empty() {
+            return Err("[webhook] has an empty secret".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct PushEvent {
+    #[serde(rename = "eventType", default)]
+    event_type: String,
+    resource: PushResource,
+}
+
+#[derive(Deserialize)]
+struct PushResource {
+    repository: PushedRepository,
+}
+
+#[derive(Deserialize)]
+struct PushedRepository {
+    name: String,
+    project: Named,
+}
+
+#[derive(Deserialize)]
+struct Named {
+    name: String,
+}
+
+// The repository a git.push notification is about, None for any other payload
+pub fn pushed_repository(body: &[u8]) -> Option<RepoKey> {
+    let event: PushEvent = serde_json::from_slice(body).ok()?;
+    let repository = event.resource.repository;
+    (event.event_type == "git.push").then_some(RepoKey {
+        project: repository.project.name,
+        repository: repository.name,
+    })
+}
+
+#[derive(Deserialize)]
+struct Identified {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionList {
+    value: Vec<Subscription>,
+}
+
+#[derive(Deserialize)]
+struct Subscription {
+    id: String,
+    #[serde(rename = "publisherInputs", default)]
+    publisher_inputs: serde_json::Map<String, serde_json::Value>,
+    #[serde(rename = "consumerInputs", default)]
+    consumer_inputs: serde_json::Map<String, serde_json::Value>,
+}
+
+// What register() did
+pub enum Registration {
+    Created(String),
+    // A subscription for the repo and URL was already there
+    Exists(String),
+}
+
+// Creates the git.push subscription for the repo, unless one already posts to the URL. Pushes to
+// any branch are sent, so a manifest or fallback branch change is picked up too.
+pub async fn register(
+    client: &Client,
+    repo: &RepoRef<'_>,
+    webhook: &WebhookConfig,
+    api_version: &str,
+) -> Result<Registration, Box<dyn std::error::Error>> {
+    // Subscriptions filter on ids rather than names
+    let project: Identified = send(
+        client.get(format!(
+            "{}/{}/_apis/projects/{}",
+            azure::base_url(),
+            azure::segment(repo.organization),
+            azure::segment(repo.project)
+        )),
+        repo.pat,
+        api_version,
+    )
+    .await?;
+    let repository: Identified = send(
+        client.get(format!(
+            "{}/{}/{}/_apis/git/repositories/{}",
+            azure::base_url(),
+            azure::segment(repo.organization),
+            azure::segment(repo.project),
+            azure::segment(repo.repository)
+        )),
+        repo.pat,
+        api_version,
+    )
+    .await?;
+
+    let existing: SubscriptionList = send(
+        client
+            .get(format!(
+                "{}/{}/_apis/hooks/subscriptions",
+                azure::base_url(),
+                azure::segment(repo.organization)
+            ))
+            .query(&[
+                ("publisherId", "tfs"),
+                ("eventType", "git.push"),
+                ("consumerId", "webHooks"),
+                ("consumerActionId", "httpRequest"),
+            ]),
+        repo.pat,
+        api_version,
+    )
+    .await?;
+    let input = |inputs: &serde_json::Map<String, serde_json::Value>, name: &str| {
+        inputs
+            .get(name)
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    if let Some(found) = existing.value.iter().find(|subscription| {
+        input(&subscription.publisher_inputs, "repository").eq_ignore_ascii_case(&repository.id)
+            && input(&subscription.consumer_inputs, "url") == webhook.url
+    }) {
+        return Ok(Registration::Exists(found.id.clone()));
+    }
+
+    let created: Identified = send(
+        client
+            .post(format!(
+                "{}/{}/_apis/hooks/subscriptions",
+                azure::base_url(),
+                azure::segment(repo.organization)
+            ))
+            .json(&json!({
+                "publisherId": "tfs",
+                "eventType": "git.push",
+                "resourceVersion": "1.0",
+                "consumerId": "webHooks",
+                "consumerActionId": "httpRequest",
+                "publisherInputs": {
+                    "projectId": project.id,
+                    "repository": repository.id,
+                },
+                "consumerInputs": {
+                    "url": webhook.url,
+                    "basicAuthUsername": USERNAME,
+                    "basicAuthPassword": webhook.secret,
+                },
+            })),
+        repo.pat,
+        api_version,
+    )
+    .await?;
+    Ok(Registration::Created(created.id))
+}
+
+// Sends an authenticated request and fails with Azure DevOps' message when it isn't a success
+async fn send<T: for<'de> Deserialize<'de>>(
+    request: reqwest::RequestBuilder,
+    pat: &str,
+    api_version: &str,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let response = request
+        .query(&[("api-version", api_version)])
+        .basic_auth("", Some(pat))
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|value| value["message"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        return Err(format!("{}: {}", status, message).into());
+    }
+    Ok(response.json().await?)
+}